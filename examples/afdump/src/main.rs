@@ -0,0 +1,301 @@
+//!tcpdump-like capture tool
+//!
+//!afdump -i eth0 [-c count] [-s snaplen] [-x] [-p] [-w file.pcapng] [expression]
+
+extern crate af_packet;
+extern crate libc;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::process;
+use std::ptr;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use af_packet::filter::FilterProgram;
+use af_packet::flow::FlowKey;
+use af_packet::group::unique_fanout_group;
+use af_packet::pcapng::PcapngWriter;
+use af_packet::rx::{Block, Promiscuous, RawPacket, Ring, RingSettings};
+use af_packet::shutdown::ShutdownHandle;
+use af_packet::sll::DLT_LINUX_SLL;
+use af_packet::util::hexdump;
+use af_packet::Error;
+
+const USAGE: &str =
+    "usage: afdump -i interface [-c count] [-s snaplen] [-x] [-p] [-w file] [expression]";
+
+struct Options {
+    if_name: String,
+    count: Option<u64>,
+    snaplen: Option<u32>,
+    hex: bool,
+    promiscuous: bool,
+    write: Option<String>,
+    expr: String,
+}
+
+fn main() {
+    let opts = parse_args().unwrap_or_else(|msg| {
+        eprintln!("afdump: {}\n{}", msg, USAGE);
+        process::exit(2);
+    });
+    if let Err(err) = run(opts) {
+        eprintln!("afdump: {}", err);
+        process::exit(1);
+    }
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut opts = Options {
+        if_name: String::new(),
+        count: None,
+        snaplen: None,
+        hex: false,
+        promiscuous: true,
+        write: None,
+        expr: String::new(),
+    };
+    let mut args = env::args().skip(1);
+    let mut expr = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "-i" => opts.if_name = value("-i")?,
+            "-c" => opts.count = Some(number(&value("-c")?)?),
+            "-s" => opts.snaplen = Some(number(&value("-s")?)?).filter(|&snaplen| snaplen > 0),
+            "-w" => opts.write = Some(value("-w")?),
+            "-x" => opts.hex = true,
+            "-p" => opts.promiscuous = false,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            flag if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+            _ => expr.push(arg),
+        }
+    }
+    if opts.if_name.is_empty() {
+        return Err(String::from("no interface given"));
+    }
+    opts.expr = expr.join(" ");
+    Ok(opts)
+}
+
+fn number<T: std::str::FromStr>(arg: &str) -> Result<T, String> {
+    arg.parse().map_err(|_| format!("not a number: {}", arg))
+}
+
+fn run(opts: Options) -> af_packet::Result<()> {
+    let settings = RingSettings {
+        if_name: opts.if_name.clone(),
+        fanout_group: Some(unique_fanout_group()),
+        promiscuous: if opts.promiscuous {
+            Promiscuous::Membership
+        } else {
+            Promiscuous::Off
+        },
+        snaplen: opts.snaplen,
+        ..RingSettings::default()
+    };
+    let mut ring = Ring::new(settings)?;
+    let link_type = ring.link_type();
+    if !opts.expr.is_empty() {
+        ring.set_filter(&FilterProgram::compile(&opts.expr, link_type)?)?;
+    }
+    let shutdown = ring.shutdown_handle()?;
+    stop_on_signals(shutdown)?;
+
+    let pcap = match &opts.write {
+        Some(path) => {
+            let file = File::create(path).map_err(|e| Error::os("create", e))?;
+            let mut pcap = PcapngWriter::new(BufWriter::new(file))?;
+            pcap.add_interface(&opts.if_name, link_type, opts.snaplen.unwrap_or(0))?;
+            Some(pcap)
+        }
+        None => None,
+    };
+    eprintln!(
+        "afdump: listening on {}, link-type {}, {} bytes per packet",
+        opts.if_name,
+        link_type,
+        opts.snaplen
+            .map(|snaplen| snaplen.to_string())
+            .unwrap_or_else(|| String::from("all"))
+    );
+
+    let stdout = io::stdout();
+    let mut output = Output {
+        out: stdout.lock(),
+        pcap,
+        link_type,
+        hex: opts.hex,
+        count: opts.count,
+        captured: 0,
+    };
+    loop {
+        match ring.recv_block() {
+            Ok(mut block) => {
+                if output.write_block(&mut block)? {
+                    break;
+                }
+            }
+            //packets already in the ring are written out before stopping
+            Err(Error::Shutdown) => {
+                let mut drain = ring.drain()?;
+                while let Some(mut block) = drain.next_block() {
+                    if output.write_block(&mut block)? {
+                        break;
+                    }
+                }
+                break;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    output.out.flush().map_err(|e| Error::os("write", e))?;
+    let Output { pcap, captured, .. } = output;
+
+    let stats = ring.statistics()?;
+    if let Some(mut pcap) = pcap {
+        pcap.write_statistics(0, &stats)?;
+        pcap.flush()?;
+    }
+    eprintln!("{} packets captured", captured);
+    eprintln!("{} packets received by filter", stats.packets);
+    eprintln!("{} packets dropped by kernel", stats.drops);
+    Ok(())
+}
+
+struct Output<W> {
+    out: W,
+    pcap: Option<PcapngWriter<BufWriter<File>>>,
+    link_type: u16,
+    hex: bool,
+    count: Option<u64>,
+    captured: u64,
+}
+
+impl<W: Write> Output<W> {
+    //writes out the packets of `block` and releases it, true once `count` are captured
+    fn write_block(&mut self, block: &mut Block) -> af_packet::Result<bool> {
+        let mut done = false;
+        for packet in block.get_raw_packets() {
+            match self.pcap.as_mut() {
+                Some(pcap) if self.link_type == DLT_LINUX_SLL => {
+                    pcap.write_cooked_packet(0, &packet, None)?
+                }
+                Some(pcap) => pcap.write_raw_packet(0, &packet, None)?,
+                None => print_packet(&mut self.out, &packet, self.hex)
+                    .map_err(|e| Error::os("write", e))?,
+            }
+            self.captured += 1;
+            if self.count == Some(self.captured) {
+                done = true;
+                break;
+            }
+        }
+        block.mark_as_consumed();
+        Ok(done)
+    }
+}
+
+fn print_packet<W: Write>(out: &mut W, packet: &RawPacket, hex: bool) -> io::Result<()> {
+    write!(out, "{} ", time_of_day(packet.timestamp()))?;
+    if let Some(vlan) = packet.vlan() {
+        write!(out, "vlan {} ", vlan.id())?;
+    }
+    let len = packet.tpacket3_hdr.tp_len;
+    match FlowKey::from_packet(packet) {
+        Some(flow) if flow.src_port != 0 || flow.dst_port != 0 => writeln!(
+            out,
+            "{} {}.{} > {}.{}: {}, length {}",
+            ip_version(&flow),
+            flow.src,
+            flow.src_port,
+            flow.dst,
+            flow.dst_port,
+            protocol_name(flow.protocol),
+            len
+        )?,
+        Some(flow) => writeln!(
+            out,
+            "{} {} > {}: {}, length {}",
+            ip_version(&flow),
+            flow.src,
+            flow.dst,
+            protocol_name(flow.protocol),
+            len
+        )?,
+        None => {
+            let l2 = packet.l2_header();
+            match l2.get(12..14) {
+                Some(ethertype) => writeln!(
+                    out,
+                    "ethertype 0x{:02x}{:02x}, length {}",
+                    ethertype[0], ethertype[1], len
+                )?,
+                None => writeln!(out, "length {}", len)?,
+            }
+        }
+    }
+    if hex {
+        writeln!(out, "{}", hexdump(packet.payload()))?;
+    }
+    Ok(())
+}
+
+fn ip_version(flow: &FlowKey) -> &'static str {
+    if flow.src.is_ipv4() {
+        "IP"
+    } else {
+        "IP6"
+    }
+}
+
+fn protocol_name(protocol: u8) -> String {
+    match protocol {
+        1 => String::from("ICMP"),
+        6 => String::from("TCP"),
+        17 => String::from("UDP"),
+        58 => String::from("ICMP6"),
+        132 => String::from("SCTP"),
+        other => format!("proto {}", other),
+    }
+}
+
+//UTC, tcpdump prints local time
+fn time_of_day(timestamp: SystemTime) -> String {
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() % 86_400;
+    format!(
+        "{:02}:{:02}:{:02}.{:06}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_micros()
+    )
+}
+
+//SIGINT and SIGTERM are blocked in every thread and picked up by one that stops the capture,
+//so that the statistics are still printed
+fn stop_on_signals(shutdown: ShutdownHandle) -> af_packet::Result<()> {
+    let set = unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        if libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) != 0 {
+            return Err(Error::last_os_error("pthread_sigmask"));
+        }
+        set
+    };
+    thread::spawn(move || {
+        let mut signal = 0;
+        unsafe { libc::sigwait(&set, &mut signal) };
+        shutdown.signal();
+    });
+    Ok(())
+}
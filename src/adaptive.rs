@@ -0,0 +1,371 @@
+//!Ring that tunes its block retire timeout to the packet rate
+//!
+//!`tp_retire_blk_tov` trades latency for batching: while traffic is light, blocks only reach
+//!userspace once the timeout retires them, so a short timeout keeps latency down; while it is
+//!heavy, blocks fill up on their own and a long timeout avoids retiring half empty ones. The
+//!kernel fixes the timeout when the ring is set up, so `AdaptiveRing` measures the packet
+//!rate and rebuilds the ring whenever its policy asks for another timeout.
+//!
+//!The new ring joins the old one's fanout group before the old one is closed, and the blocks
+//!the old ring still holds are handed out first. Packets in the block the kernel is filling
+//!on the old ring when it is closed are lost, at most one retire timeout worth of its share
+//!of the traffic.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use crate::filter::FilterProgram;
+use crate::rx::{Block, Ring, RingSettings, TpacketVersion};
+use crate::shutdown::ShutdownHandle;
+use crate::source::PacketSource;
+
+///Thresholds of the default policy, see `AdaptiveRing::new()`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TovSettings {
+    ///Retire timeout in ms while the rate is at most `low_rate`
+    pub min_tov: u32,
+    ///Retire timeout in ms while the rate is at least `high_rate`
+    pub max_tov: u32,
+    ///Packets per second at or below which `min_tov` is used
+    pub low_rate: f64,
+    ///Packets per second at or above which `max_tov` is used
+    pub high_rate: f64,
+    ///How often the rate is measured and the policy asked for a timeout
+    pub interval: Duration,
+}
+
+impl Default for TovSettings {
+    fn default() -> TovSettings {
+        TovSettings {
+            min_tov: 4,
+            max_tov: 100,
+            low_rate: 1_000.0,
+            high_rate: 100_000.0,
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl TovSettings {
+    ///For latency sensitive users: short timeouts until traffic is very heavy
+    pub fn latency() -> TovSettings {
+        TovSettings {
+            min_tov: 1,
+            max_tov: 10,
+            low_rate: 10_000.0,
+            high_rate: 500_000.0,
+            ..TovSettings::default()
+        }
+    }
+
+    ///For throughput sensitive users: long timeouts as soon as there is some traffic
+    pub fn throughput() -> TovSettings {
+        TovSettings {
+            min_tov: 10,
+            max_tov: 200,
+            low_rate: 100.0,
+            high_rate: 10_000.0,
+            ..TovSettings::default()
+        }
+    }
+
+    ///Timeout for `rate` packets per second, scaled geometrically between `min_tov` and
+    ///`max_tov`
+    pub fn tov_for(&self, rate: f64) -> u32 {
+        let (min, max) = (self.min_tov.max(1), self.max_tov.max(self.min_tov).max(1));
+        if rate <= self.low_rate || self.high_rate <= self.low_rate {
+            return if rate >= self.high_rate { max } else { min };
+        }
+        if rate >= self.high_rate {
+            return max;
+        }
+        let low = self.low_rate.max(1.0);
+        let x = (rate / low).ln() / (self.high_rate / low).ln();
+        let tov = min as f64 * (max as f64 / min as f64).powf(x.clamp(0.0, 1.0));
+        (tov.round() as u32).clamp(min, max)
+    }
+}
+
+///A change of retire timeout, passed to the `on_retune()` hook
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Retune {
+    ///Previous timeout in ms
+    pub from: u32,
+    ///New timeout in ms
+    pub to: u32,
+    ///Packets per second measured over the last interval
+    pub rate: f64,
+}
+
+type Policy = Box<dyn FnMut(f64, u32) -> u32 + Send>;
+type RetuneHook = Box<dyn FnMut(&Retune) + Send>;
+
+///Wraps a `Ring` and rebuilds it with another retire timeout as the packet rate changes
+///
+///TPACKET_V2 rings have no retire timeout and are never rebuilt.
+pub struct AdaptiveRing {
+    settings: RingSettings,
+    ring: Ring,
+    //the ring replaced by the last rebuild until drained, with the time by which the kernel
+    //retired the block it was filling
+    retiring: Option<(Ring, Instant)>,
+    policy: Policy,
+    on_retune: Option<RetuneHook>,
+    interval: Duration,
+    window_start: Instant,
+    window_packets: u64,
+    shutdown: Option<ShutdownHandle>,
+    rebuilds: u64,
+}
+
+impl AdaptiveRing {
+    ///Opens a ring with the `min_tov` of `tov` and switches timeouts with `TovSettings::tov_for()`
+    ///
+    ///The timeout is only changed when the new one is at least twice or at most half the
+    ///current one, so rates close to a boundary do not keep rebuilding the ring.
+    pub fn new(settings: RingSettings, tov: TovSettings) -> Result<AdaptiveRing> {
+        let initial_tov = tov.min_tov.max(1);
+        let mut ring = AdaptiveRing::with_policy(settings, initial_tov, move |rate, current| {
+            let target = tov.tov_for(rate);
+            if target >= current.saturating_mul(2) || target.saturating_mul(2) <= current {
+                target
+            } else {
+                current
+            }
+        })?;
+        ring.set_interval(tov.interval);
+        Ok(ring)
+    }
+
+    ///Opens a ring with the retire timeout `initial_tov` and asks `policy` for a timeout once
+    ///a second, see `set_interval()`, passing the packets per second received and the current
+    ///timeout; the ring is rebuilt whenever it returns another one
+    pub fn with_policy<F>(
+        mut settings: RingSettings,
+        initial_tov: u32,
+        policy: F,
+    ) -> Result<AdaptiveRing>
+    where
+        F: FnMut(f64, u32) -> u32 + Send + 'static,
+    {
+        if initial_tov == 0 {
+            return Err(Error::InvalidGeometry(String::from(
+                "the block retire timeout must be at least 1 ms",
+            )));
+        }
+        settings.ring_settings.tp_retire_blk_tov = initial_tov;
+        let ring = Ring::new(settings.clone())?;
+        Ok(AdaptiveRing {
+            settings,
+            ring,
+            retiring: None,
+            policy: Box::new(policy),
+            on_retune: None,
+            interval: Duration::from_secs(1),
+            window_start: Instant::now(),
+            window_packets: 0,
+            shutdown: None,
+            rebuilds: 0,
+        })
+    }
+
+    ///Sets how often the rate is measured and the policy asked for a timeout
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    ///Calls `f` after every rebuild, from the receiving thread
+    pub fn on_retune<F>(&mut self, f: F)
+    where
+        F: FnMut(&Retune) + Send + 'static,
+    {
+        self.on_retune = Some(Box::new(f));
+    }
+
+    ///Waits for the next block. Returns `Error::Shutdown` once the `ShutdownHandle` is
+    ///signaled.
+    pub fn recv_block(&mut self) -> Result<Block<'_>> {
+        loop {
+            self.retune()?;
+            if let Some(block) = self.next_ready_block() {
+                self.window_packets += block.packet_count() as u64;
+                return Ok(block);
+            }
+            //wake up for the next measurement also while there is no traffic, and for the
+            //last blocks of a ring being replaced
+            let mut timeout = self.interval.saturating_sub(self.window_start.elapsed());
+            if let Some((_, drained_by)) = &self.retiring {
+                timeout = timeout.min(drained_by.saturating_duration_since(Instant::now()));
+            }
+            let timeout = timeout.as_millis().clamp(1, i32::MAX as u128) as i32;
+            if self.ring.wait_for_block(timeout) {
+                return Err(Error::Shutdown);
+            }
+            self.ring.check_link_down()?;
+        }
+    }
+
+    ///Returns a handle that stops `recv_block()` from another thread, kept across rebuilds
+    pub fn shutdown_handle(&mut self) -> Result<ShutdownHandle> {
+        let handle = self.ring.shutdown_handle()?;
+        self.shutdown = Some(handle.clone());
+        Ok(handle)
+    }
+
+    ///Replaces the filter on the current ring and on every ring built after it
+    pub fn set_filter(&mut self, filter: FilterProgram) -> Result<()> {
+        self.ring.set_filter(&filter)?;
+        self.settings.filter = Some(filter);
+        Ok(())
+    }
+
+    ///Retire timeout of the current ring in ms
+    pub fn tov(&self) -> u32 {
+        self.settings.ring_settings.tp_retire_blk_tov
+    }
+
+    ///Current ring, e.g. for statistics
+    pub fn ring(&self) -> &Ring {
+        &self.ring
+    }
+
+    ///Current ring
+    pub fn ring_mut(&mut self) -> &mut Ring {
+        &mut self.ring
+    }
+
+    ///How many times the ring was rebuilt
+    pub fn rebuilds(&self) -> u64 {
+        self.rebuilds
+    }
+
+    fn next_ready_block<'a>(&mut self) -> Option<Block<'a>> {
+        if let Some((old, drained_by)) = self.retiring.as_mut() {
+            if let Some(block) = old.next_ready_block() {
+                return Some(block);
+            }
+            if Instant::now() >= *drained_by {
+                self.retiring = None;
+            }
+        }
+        self.ring.next_ready_block()
+    }
+
+    fn retune(&mut self) -> Result<()> {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < self.interval {
+            return Ok(());
+        }
+        let rate = self.window_packets as f64 / elapsed.as_secs_f64();
+        self.window_start = Instant::now();
+        self.window_packets = 0;
+        //the previous rebuild is still being drained
+        if self.retiring.is_some() || self.ring.version() == TpacketVersion::V2 {
+            return Ok(());
+        }
+        let current = self.tov();
+        let tov = (self.policy)(rate, current).max(1);
+        if tov == current {
+            return Ok(());
+        }
+        let mut settings = self.settings.clone();
+        settings.ring_settings.tp_retire_blk_tov = tov;
+        let mut ring = Ring::new(settings.clone())?;
+        if let Some(handle) = &self.shutdown {
+            ring.set_shutdown_handle(handle.clone());
+        }
+        let old = std::mem::replace(&mut self.ring, ring);
+        let drained_by = Instant::now() + Duration::from_millis(current as u64 * 2);
+        self.retiring = Some((old, drained_by));
+        self.settings = settings;
+        self.rebuilds += 1;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            if_name = %self.settings.if_name,
+            from = current,
+            to = tov,
+            rate,
+            "retire timeout retuned"
+        );
+        if let Some(hook) = self.on_retune.as_mut() {
+            hook(&Retune {
+                from: current,
+                to: tov,
+                rate,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl PacketSource for AdaptiveRing {
+    ///Returns `None` once the `ShutdownHandle` is signaled
+    fn next_block(&mut self) -> Result<Option<Block<'_>>> {
+        match self.recv_block() {
+            Ok(block) => Ok(Some(block)),
+            Err(Error::Shutdown) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl fmt::Debug for AdaptiveRing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AdaptiveRing")
+            .field("settings", &self.settings)
+            .field("ring", &self.ring)
+            .field("retiring", &self.retiring.is_some())
+            .field("rebuilds", &self.rebuilds)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_scale_geometrically_between_the_rates() {
+        let tov = TovSettings::default();
+        assert_eq!(tov.tov_for(0.0), 4);
+        assert_eq!(tov.tov_for(1_000.0), 4);
+        //halfway between the rates on a log scale
+        assert_eq!(tov.tov_for(10_000.0), 20);
+        assert_eq!(tov.tov_for(100_000.0), 100);
+        assert_eq!(tov.tov_for(1e9), 100);
+
+        let mut last = 0;
+        for rate in (0..200).map(|i| i as f64 * 1_000.0) {
+            let next = tov.tov_for(rate);
+            assert!(
+                next >= last,
+                "{} ms at {} pps after {} ms",
+                next,
+                rate,
+                last
+            );
+            last = next;
+        }
+    }
+
+    #[test]
+    fn odd_settings_stay_within_bounds() {
+        //the rates the wrong way round switch at `high_rate`
+        let swapped = TovSettings {
+            low_rate: 5_000.0,
+            high_rate: 100.0,
+            ..TovSettings::default()
+        };
+        assert_eq!(swapped.tov_for(50.0), 4);
+        assert_eq!(swapped.tov_for(500.0), 100);
+
+        let zero = TovSettings {
+            min_tov: 0,
+            max_tov: 0,
+            ..TovSettings::default()
+        };
+        assert_eq!(zero.tov_for(0.0), 1);
+        assert_eq!(zero.tov_for(1e9), 1);
+    }
+}
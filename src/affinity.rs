@@ -0,0 +1,249 @@
+//!CPU affinity of the threads reading rings and of the NIC queue interrupts feeding them
+//!
+//!PACKET_FANOUT_CPU hands a packet to the ring of the CPU that received it, which is the CPU
+//!the NIC queue's interrupt ran on. Spreading the queue IRQs over a set of CPUs with
+//!`align_irqs()` and pinning the thread of ring `i` to CPU `i` of the same set, e.g. with
+//!`RingGroup::set_cpus()`, keeps every packet on one core from the NIC to the consumer.
+//!Changing IRQ affinity needs root and irqbalance may undo it.
+//!
+//!Where the IRQs actually land can be measured with `incoming_cpus()`, e.g. to pin a single
+//!consumer thread next to its traffic.
+
+use std::fs;
+use std::io;
+use std::mem;
+use std::thread;
+use std::time::Duration;
+
+use libc::{
+    c_int, close, cpu_set_t, sched_getaffinity, sched_getcpu, sched_setaffinity, sysconf,
+    _SC_NPROCESSORS_CONF, _SC_NPROCESSORS_ONLN, CPU_ISSET, CPU_SET, CPU_SETSIZE, SOCK_RAW,
+};
+
+use crate::error::{Error, Result};
+use crate::group;
+use crate::rx::{self, PACKET_FANOUT_CPU};
+use crate::socket::{self, EtherType, Socket, PACKET_FANOUT};
+
+//MSI-X vectors that do not serve a queue
+const CONTROL_VECTORS: [&str; 5] = ["config", "misc", "async", "ctrl", "fw"];
+//names of vectors serving receive queues, for drivers naming them
+const RX_VECTORS: [&str; 4] = ["rx", "input", "comp", "fp"];
+
+///Interrupt of a NIC's MSI-X vector
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Irq {
+    pub irq: u32,
+    ///Name from /proc/interrupts, e.g. `eth0-TxRx-3` or `virtio3-input.0`
+    pub name: String,
+    ///CPUs allowed to handle it
+    pub cpus: Vec<usize>,
+}
+
+impl Irq {
+    ///Whether the vector likely serves a receive queue, going by its name
+    pub fn is_rx_queue(&self) -> bool {
+        let name = self.name.to_ascii_lowercase();
+        !CONTROL_VECTORS.iter().any(|c| name.contains(c))
+            && RX_VECTORS.iter().any(|rx| name.contains(rx))
+    }
+}
+
+///Number of online CPUs
+pub fn online_cpus() -> usize {
+    (unsafe { sysconf(_SC_NPROCESSORS_ONLN) }).max(1) as usize
+}
+
+///CPU the calling thread is running on
+pub fn current_cpu() -> Option<usize> {
+    match unsafe { sched_getcpu() } {
+        cpu if cpu >= 0 => Some(cpu as usize),
+        _ => None,
+    }
+}
+
+///CPUs the calling thread may run on
+pub fn current_thread_cpus() -> Result<Vec<usize>> {
+    let mut set: cpu_set_t = unsafe { mem::zeroed() };
+    if unsafe { sched_getaffinity(0, mem::size_of::<cpu_set_t>(), &mut set) } != 0 {
+        return Err(Error::last_os_error("sched_getaffinity"));
+    }
+    Ok((0..CPU_SETSIZE as usize)
+        .filter(|&cpu| unsafe { CPU_ISSET(cpu, &set) })
+        .collect())
+}
+
+///Restricts the calling thread to `cpus`
+pub fn set_current_thread_cpus(cpus: &[usize]) -> Result<()> {
+    unsafe {
+        let mut set: cpu_set_t = mem::zeroed();
+        for &cpu in cpus {
+            CPU_SET(cpu, &mut set);
+        }
+        match sched_setaffinity(0, mem::size_of::<cpu_set_t>(), &set) {
+            0 => Ok(()),
+            _ => Err(Error::last_os_error("sched_setaffinity")),
+        }
+    }
+}
+
+///Packets of `if_name` received by each CPU during `window`, indexed by CPU
+///
+///Packet sockets leave SO_INCOMING_CPU unset, so this counts instead: one socket per CPU joins
+///a PACKET_FANOUT_CPU group of its own and the kernel counts what each one is handed. The
+///sockets get copies of the traffic, rings reading the interface are not affected.
+pub fn incoming_cpus(if_name: &str, window: Duration) -> Result<Vec<u64>> {
+    let cpus = (unsafe { sysconf(_SC_NPROCESSORS_CONF) }).max(1) as usize;
+    let fanout = c_int::from(group::unique_fanout_group()) | (PACKET_FANOUT_CPU << 16);
+    let mut sockets = Vec::with_capacity(cpus);
+    for _ in 0..cpus {
+        let mut socket = CountingSocket(Socket::open(
+            if_name,
+            socket::PF_PACKET,
+            SOCK_RAW,
+            EtherType::All,
+        )?);
+        //packets are only counted, queue as few as the kernel allows
+        socket.0.set_recv_buffer(0, false)?;
+        rx::bind_socket(&socket.0, EtherType::All)?;
+        socket.0.setsockopt(PACKET_FANOUT, fanout)?;
+        sockets.push(socket);
+    }
+    //counters start once the whole group is in place
+    for socket in &sockets {
+        rx::get_rx_statistics(socket.0.fd)?;
+    }
+    thread::sleep(window);
+    sockets
+        .iter()
+        .map(|socket| Ok(rx::get_rx_statistics(socket.0.fd)?.tp_packets as u64))
+        .collect()
+}
+
+///CPU that received most packets of `if_name` during `window`, None without traffic, see
+///`incoming_cpus()`
+pub fn busiest_incoming_cpu(if_name: &str, window: Duration) -> Result<Option<usize>> {
+    let counts = incoming_cpus(if_name, window)?;
+    Ok(counts
+        .iter()
+        .enumerate()
+        .filter(|(_, &packets)| packets > 0)
+        .max_by_key(|(_, &packets)| packets)
+        .map(|(cpu, _)| cpu))
+}
+
+///MSI-X interrupts of the NIC behind `if_name`, in vector order; empty for virtual interfaces
+pub fn interface_irqs(if_name: &str) -> Result<Vec<Irq>> {
+    //virtio devices keep their vectors on the PCI device above them
+    let device = format!("/sys/class/net/{}/device", if_name);
+    let dir = match fs::read_dir(format!("{}/msi_irqs", device))
+        .or_else(|_| fs::read_dir(format!("{}/../msi_irqs", device)))
+    {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::os("read msi_irqs", e)),
+    };
+    let mut irqs: Vec<u32> = dir
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    irqs.sort_unstable();
+    let interrupts =
+        fs::read_to_string("/proc/interrupts").map_err(|e| Error::os("read interrupts", e))?;
+    irqs.into_iter()
+        .map(|irq| {
+            Ok(Irq {
+                irq,
+                name: irq_name(&interrupts, irq).unwrap_or_default(),
+                cpus: irq_affinity(irq)?,
+            })
+        })
+        .collect()
+}
+
+///CPUs allowed to handle `irq`
+pub fn irq_affinity(irq: u32) -> Result<Vec<usize>> {
+    let path = format!("/proc/irq/{}/smp_affinity_list", irq);
+    let list = fs::read_to_string(path).map_err(|e| Error::os("read smp_affinity_list", e))?;
+    parse_cpu_list(list.trim()).ok_or_else(|| {
+        Error::os(
+            "read smp_affinity_list",
+            io::Error::new(io::ErrorKind::InvalidData, list.trim().to_string()),
+        )
+    })
+}
+
+///Restricts `irq` to `cpus`, needs root
+pub fn set_irq_affinity(irq: u32, cpus: &[usize]) -> Result<()> {
+    let list = cpus
+        .iter()
+        .map(|cpu| cpu.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let path = format!("/proc/irq/{}/smp_affinity_list", irq);
+    fs::write(path, list).map_err(|e| Error::os("write smp_affinity_list", e))
+}
+
+///Assigns the receive queue IRQs of `if_name` to `cpus`, queue `i` to `cpus[i % cpus.len()]`,
+///and returns the IRQs with the CPU each one got
+///
+///Queues are told apart from control vectors by their names, see `Irq::is_rx_queue()`; if no
+///name says so, every vector that is not a known control vector is taken as a queue.
+pub fn align_irqs(if_name: &str, cpus: &[usize]) -> Result<Vec<(Irq, usize)>> {
+    if cpus.is_empty() {
+        return Ok(Vec::new());
+    }
+    let irqs = interface_irqs(if_name)?;
+    let mut queues: Vec<Irq> = irqs
+        .iter()
+        .filter(|irq| irq.is_rx_queue())
+        .cloned()
+        .collect();
+    if queues.is_empty() {
+        queues = irqs
+            .into_iter()
+            .filter(|irq| {
+                let name = irq.name.to_ascii_lowercase();
+                !CONTROL_VECTORS.iter().any(|c| name.contains(c))
+            })
+            .collect();
+    }
+    let mut aligned = Vec::with_capacity(queues.len());
+    for (i, mut irq) in queues.into_iter().enumerate() {
+        let cpu = cpus[i % cpus.len()];
+        set_irq_affinity(irq.irq, &[cpu])?;
+        irq.cpus = vec![cpu];
+        aligned.push((irq, cpu));
+    }
+    Ok(aligned)
+}
+
+struct CountingSocket(Socket);
+
+impl Drop for CountingSocket {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.0.fd);
+        }
+    }
+}
+
+//name of `irq` in the last column of /proc/interrupts
+fn irq_name(interrupts: &str, irq: u32) -> Option<String> {
+    let prefix = format!("{}:", irq);
+    let line = interrupts
+        .lines()
+        .find(|line| line.trim_start().starts_with(&prefix))?;
+    line.split_whitespace().last().map(String::from)
+}
+
+//"0-3,8,10-11"
+pub(crate) fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.split(',').filter(|r| !r.is_empty()) {
+        match range.split_once('-') {
+            Some((lo, hi)) => cpus.extend(lo.parse::<usize>().ok()?..=hi.parse().ok()?),
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
@@ -0,0 +1,431 @@
+//!Async ring built on `async-io`, usable from smol, async-std or any other executor
+//!
+//!`AsyncRing::split()` hands the packet loop and the control of the ring to different tasks:
+//!
+//!```no_run
+//!# async fn run(ring: af_packet::rx::Ring) -> af_packet::Result<()> {
+//!use af_packet::async_ring::AsyncRing;
+//!
+//!let (mut rx, mut control) = AsyncRing::new(ring)?.split()?;
+//!//e.g. in a task of its own
+//!let stats = control.statistics()?;
+//!control.shutdown();
+//!while let Ok(mut block) = rx.recv_block().await {
+//!    block.mark_as_consumed();
+//!}
+//!# Ok(())
+//!# }
+//!```
+
+use std::future::{self, Future};
+use std::os::unix::io::{AsFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_io::{Async, Timer};
+use futures_core::Stream;
+use libc::{fcntl, F_DUPFD_CLOEXEC};
+
+use crate::error::{Error, Result};
+use crate::filter::FilterProgram;
+use crate::rx::{Block, Ring};
+use crate::shared::SharedBlock;
+use crate::shutdown::ShutdownHandle;
+use crate::source::AsyncPacketSource;
+use crate::stats::RingStats;
+
+//filter set through the control half that the receiving ring has not recorded yet
+type FilterUpdate = Arc<Mutex<Option<Option<FilterProgram>>>>;
+
+///Borrowed descriptor registered with the reactor; the ring keeps ownership of the socket
+#[derive(Debug)]
+pub(crate) struct RawFdRef(pub(crate) RawFd);
+
+impl AsFd for RawFdRef {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        unsafe { BorrowedFd::borrow_raw(self.0) }
+    }
+}
+
+//registers a descriptor of its own for the eventfd of `handle`, the reactor refuses to add the
+//same descriptor twice and one handle may stop many rings
+fn register_shutdown(handle: &ShutdownHandle) -> Result<Async<OwnedFd>> {
+    let fd = unsafe { fcntl(handle.fd(), F_DUPFD_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error("dup"));
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    Async::new(fd).map_err(|e| Error::os("async-io", e))
+}
+
+///Ring whose readiness is awaited through the `async-io` reactor instead of a blocking poll()
+///
+///Unlike `Ring`, it never hands out empty blocks: those retired by the timeout are consumed
+///and skipped.
+#[derive(Debug)]
+pub struct AsyncRing {
+    ring: Ring,
+    io: Async<RawFdRef>,
+    shutdown: Option<Async<OwnedFd>>,
+}
+
+///Receiving half of a split `AsyncRing`, see `AsyncRing::split()`
+#[derive(Debug)]
+pub struct RecvHalf {
+    inner: AsyncRing,
+    filter: FilterUpdate,
+}
+
+///Control half of a split `AsyncRing`: statistics, filters and shutdown from any task
+///
+///It shares the ring's socket and mapping, so it stays usable after the receiving half is
+///dropped.
+#[derive(Debug)]
+pub struct ControlHalf {
+    //clone of the receiving half's ring, never hands out blocks
+    ring: Ring,
+    filter: FilterUpdate,
+    shutdown: ShutdownHandle,
+}
+
+impl AsyncRing {
+    ///Registers a ring with the reactor; set up its `ShutdownHandle` before calling this
+    pub fn new(ring: Ring) -> Result<AsyncRing> {
+        let io = Async::new(RawFdRef(ring.socket.fd)).map_err(|e| Error::os("async-io", e))?;
+        let shutdown = match ring.get_shutdown_handle() {
+            Some(handle) => Some(register_shutdown(handle)?),
+            None => None,
+        };
+        Ok(AsyncRing { ring, io, shutdown })
+    }
+
+    ///Waits for the next block, returns `Error::Shutdown` once the ring's `ShutdownHandle` is
+    ///signaled
+    ///
+    ///Cancellation safe: a block is only taken from the ring in the same poll that returns it,
+    ///so dropping the future, e.g. when it loses a `select`, never skips a block.
+    pub async fn recv_block(&mut self) -> Result<Block<'_>> {
+        future::poll_fn(|cx| self.poll_block(cx)).await
+    }
+
+    ///Waits for the next block like `recv_block()`, for at most `timeout`; `Ok(None)` when none
+    ///arrived in time
+    ///
+    ///Cancellation safe like `recv_block()`.
+    pub async fn recv_block_timeout(&mut self, timeout: Duration) -> Result<Option<Block<'_>>> {
+        let mut timer = Timer::after(timeout);
+        future::poll_fn(|cx| {
+            if let Poll::Ready(res) = self.poll_block(cx) {
+                return Poll::Ready(res.map(Some));
+            }
+            match Pin::new(&mut timer).poll(cx) {
+                Poll::Ready(_) => Poll::Ready(Ok(None)),
+                Poll::Pending => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    ///Polls for the next block, registering the task for wakeup if none is ready
+    ///
+    ///`Pending` never leaves a block taken from the ring behind.
+    pub fn poll_recv_block(&mut self, cx: &mut Context<'_>) -> Poll<Result<Block<'_>>> {
+        self.poll_block(cx)
+    }
+
+    ///Stream of whole, never empty blocks, each lent out of the ring until it and its packets
+    ///are dropped, see `shared::SharedBlock`
+    pub fn block_stream(&mut self) -> BlockStream<'_> {
+        BlockStream {
+            ring: self,
+            done: false,
+        }
+    }
+
+    ///Waits for blocks like `recv_block()`, then returns every non-empty block ready by then,
+    ///at most `max`; see `Ring::recv_blocks()`
+    ///
+    ///Cancellation safe like `recv_block()`.
+    pub async fn recv_blocks(&mut self, max: usize) -> Result<Vec<Block<'_>>> {
+        future::poll_fn(|cx| self.poll_blocks(cx, max)).await
+    }
+
+    ///Polls for the next blocks, at most `max`, registering the task for wakeup if none is
+    ///ready
+    pub fn poll_recv_blocks(
+        &mut self,
+        cx: &mut Context<'_>,
+        max: usize,
+    ) -> Poll<Result<Vec<Block<'_>>>> {
+        self.poll_blocks(cx, max)
+    }
+
+    fn poll_block<'a>(&mut self, cx: &mut Context<'_>) -> Poll<Result<Block<'a>>> {
+        self.poll_ready(cx, AsyncRing::next_nonempty_block)
+    }
+
+    fn poll_blocks<'a>(
+        &mut self,
+        cx: &mut Context<'_>,
+        max: usize,
+    ) -> Poll<Result<Vec<Block<'a>>>> {
+        if max == 0 {
+            return Poll::Ready(Ok(Vec::new()));
+        }
+        self.poll_ready(cx, |ring| {
+            let blocks = ring.next_nonempty_blocks(max);
+            Some(blocks).filter(|blocks| !blocks.is_empty())
+        })
+    }
+
+    //waits until `take` finds something in the ring
+    fn poll_ready<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut take: impl FnMut(&mut AsyncRing) -> Option<T>,
+    ) -> Poll<Result<T>> {
+        loop {
+            if let Some(shutdown) = &self.shutdown {
+                if let Poll::Ready(res) = shutdown.poll_readable(cx) {
+                    res.map_err(|e| Error::os("async-io", e))?;
+                    return Poll::Ready(Err(Error::Shutdown));
+                }
+            }
+            if let Some(taken) = take(self) {
+                return Poll::Ready(Ok(taken));
+            }
+            match self.io.poll_readable(cx) {
+                Poll::Ready(Ok(())) => {
+                    //readable also covers POLLERR, which stays set until the error is read
+                    self.ring.take_socket_error();
+                    self.ring.check_link_down()?;
+                    continue;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(Error::os("async-io", e))),
+                Poll::Pending => {
+                    //a block may have been retired between the check and the registration
+                    if let Some(taken) = take(self) {
+                        return Poll::Ready(Ok(taken));
+                    }
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+
+    //blocks retired empty by the timeout go straight back to the kernel, so a woken task
+    //always has packets to work on
+    fn next_nonempty_block<'a>(&mut self) -> Option<Block<'a>> {
+        while let Some(mut block) = self.ring.next_ready_block() {
+            if block.packet_count() > 0 {
+                return Some(block);
+            }
+            block.mark_as_consumed();
+        }
+        None
+    }
+
+    fn next_nonempty_blocks<'a>(&mut self, max: usize) -> Vec<Block<'a>> {
+        let mut blocks = self.ring.next_ready_blocks(max);
+        blocks.retain_mut(|block| {
+            if block.packet_count() > 0 {
+                return true;
+            }
+            block.mark_as_consumed();
+            false
+        });
+        blocks
+    }
+
+    ///Underlying ring, e.g. for statistics
+    pub fn get_ref(&self) -> &Ring {
+        &self.ring
+    }
+
+    ///Underlying ring, e.g. for statistics
+    pub fn get_mut(&mut self) -> &mut Ring {
+        &mut self.ring
+    }
+
+    ///Deregisters from the reactor and returns the ring
+    pub fn into_inner(self) -> Ring {
+        self.ring
+    }
+
+    ///Splits the ring into a half for the packet loop and a half that controls it from other
+    ///tasks, without locking between them
+    ///
+    ///The ring gets a `ShutdownHandle` if it has none yet, so that `ControlHalf::shutdown()`
+    ///always reaches the receiving half.
+    pub fn split(mut self) -> Result<(RecvHalf, ControlHalf)> {
+        let shutdown = match self.ring.get_shutdown_handle() {
+            Some(handle) => handle.clone(),
+            None => {
+                let handle = self.ring.shutdown_handle()?;
+                self.shutdown = Some(register_shutdown(&handle)?);
+                handle
+            }
+        };
+        let filter = FilterUpdate::default();
+        let control = ControlHalf {
+            ring: self.ring.clone(),
+            filter: filter.clone(),
+            shutdown,
+        };
+        Ok((
+            RecvHalf {
+                inner: self,
+                filter,
+            },
+            control,
+        ))
+    }
+}
+
+impl RecvHalf {
+    ///Waits for the next block, see `AsyncRing::recv_block()`
+    pub async fn recv_block(&mut self) -> Result<Block<'_>> {
+        self.sync_filter();
+        self.inner.recv_block().await
+    }
+
+    ///Waits for the next block for at most `timeout`, see `AsyncRing::recv_block_timeout()`
+    pub async fn recv_block_timeout(&mut self, timeout: Duration) -> Result<Option<Block<'_>>> {
+        self.sync_filter();
+        self.inner.recv_block_timeout(timeout).await
+    }
+
+    ///Polls for the next block, see `AsyncRing::poll_recv_block()`
+    pub fn poll_recv_block(&mut self, cx: &mut Context<'_>) -> Poll<Result<Block<'_>>> {
+        self.sync_filter();
+        self.inner.poll_block(cx)
+    }
+
+    ///Waits for the next blocks, at most `max`, see `AsyncRing::recv_blocks()`
+    pub async fn recv_blocks(&mut self, max: usize) -> Result<Vec<Block<'_>>> {
+        self.sync_filter();
+        self.inner.recv_blocks(max).await
+    }
+
+    ///Polls for the next blocks, see `AsyncRing::poll_recv_blocks()`
+    pub fn poll_recv_blocks(
+        &mut self,
+        cx: &mut Context<'_>,
+        max: usize,
+    ) -> Poll<Result<Vec<Block<'_>>>> {
+        self.sync_filter();
+        self.inner.poll_blocks(cx, max)
+    }
+
+    ///Underlying ring
+    pub fn get_ref(&self) -> &Ring {
+        self.inner.get_ref()
+    }
+
+    ///Underlying ring
+    pub fn get_mut(&mut self) -> &mut Ring {
+        self.sync_filter();
+        self.inner.get_mut()
+    }
+
+    ///Returns the whole `AsyncRing`; the control half keeps working
+    pub fn into_inner(mut self) -> AsyncRing {
+        self.sync_filter();
+        self.inner
+    }
+
+    //the ring restores its recorded filter on `Ring::resume()`
+    fn sync_filter(&mut self) {
+        let update = self.filter.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(filter) = update {
+            self.inner.ring.note_filter(filter);
+        }
+    }
+}
+
+impl ControlHalf {
+    ///Kernel counters since the last call, see `Ring::statistics()`
+    ///
+    ///The counters are reset by every read, through either half. Sequence gaps are only seen
+    ///by the receiving half and always 0 here.
+    pub fn statistics(&mut self) -> Result<RingStats> {
+        self.ring.statistics()
+    }
+
+    ///Replaces the ring's filter, taking effect right away
+    ///
+    ///Unlike `Ring::set_filter()` the blocks already captured are left alone, as the receiving
+    ///half may be reading them, so a few packets accepted by the old filter can still arrive.
+    ///Applies to a paused ring too.
+    pub fn set_filter(&mut self, filter: &FilterProgram) -> Result<()> {
+        self.ring.replace_filter(Some(filter.clone()))?;
+        *self.filter.lock().unwrap_or_else(|e| e.into_inner()) = Some(Some(filter.clone()));
+        Ok(())
+    }
+
+    ///Removes the ring's filter, see `Ring::clear_filter()` and `set_filter()`
+    pub fn clear_filter(&mut self) -> Result<()> {
+        self.ring.replace_filter(None)?;
+        *self.filter.lock().unwrap_or_else(|e| e.into_inner()) = Some(None);
+        Ok(())
+    }
+
+    ///Makes the receiving half return `Error::Shutdown`
+    pub fn shutdown(&self) {
+        self.shutdown.signal();
+    }
+
+    ///Handle that stops the receiving half, e.g. to share with other rings
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+}
+
+///Stream of the blocks of an `AsyncRing`, see `AsyncRing::block_stream()`
+///
+///Ends when the ring's `ShutdownHandle` is signaled; other errors are yielded and the stream
+///can be polled again afterwards.
+#[derive(Debug)]
+pub struct BlockStream<'a> {
+    ring: &'a mut AsyncRing,
+    done: bool,
+}
+
+impl Stream for BlockStream<'_> {
+    type Item = Result<SharedBlock>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<SharedBlock>>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        match this.ring.poll_recv_block(cx) {
+            Poll::Ready(Ok(block)) => Poll::Ready(Some(Ok(block.into_shared()))),
+            Poll::Ready(Err(Error::Shutdown)) => {
+                this.done = true;
+                Poll::Ready(None)
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Some(Err(err))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncPacketSource for AsyncRing {
+    fn poll_next_block<'a>(&'a mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Block<'a>>>> {
+        self.poll_block(cx).map(|res| match res {
+            Ok(block) => Ok(Some(block)),
+            Err(Error::Shutdown) => Ok(None),
+            Err(err) => Err(err),
+        })
+    }
+}
+
+impl AsyncPacketSource for RecvHalf {
+    fn poll_next_block<'a>(&'a mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Block<'a>>>> {
+        self.sync_filter();
+        self.inner.poll_next_block(cx)
+    }
+}
@@ -0,0 +1,826 @@
+//!Classic BPF assembler
+//!
+//!Builds `FilterProgram`s from typed instructions, with forward jumps to labels instead of
+//!hand-counted offsets:
+//!
+//!```
+//!use af_packet::bpf::{Assembler, Size, NEXT};
+//!
+//!//accept IPv4 frames whole, drop everything else
+//!let mut asm = Assembler::new();
+//!let drop = asm.label();
+//!asm.ld_abs(Size::Half, 12);
+//!asm.jeq(0x0800, NEXT, drop);
+//!asm.ret(u32::MAX);
+//!asm.bind(drop);
+//!asm.ret(0);
+//!let program = asm.assemble().unwrap();
+//!assert_eq!(program.len(), 4);
+//!```
+
+use crate::error::{Error, Result};
+use crate::filter::{FilterProgram, Instruction, MAX_INSTRUCTIONS};
+
+//instruction classes
+pub const BPF_LD: u16 = 0x00;
+pub const BPF_LDX: u16 = 0x01;
+pub const BPF_ST: u16 = 0x02;
+pub const BPF_STX: u16 = 0x03;
+pub const BPF_ALU: u16 = 0x04;
+pub const BPF_JMP: u16 = 0x05;
+pub const BPF_RET: u16 = 0x06;
+pub const BPF_MISC: u16 = 0x07;
+
+//load sizes
+pub const BPF_W: u16 = 0x00;
+pub const BPF_H: u16 = 0x08;
+pub const BPF_B: u16 = 0x10;
+
+//load modes
+pub const BPF_IMM: u16 = 0x00;
+pub const BPF_ABS: u16 = 0x20;
+pub const BPF_IND: u16 = 0x40;
+pub const BPF_MEM: u16 = 0x60;
+pub const BPF_LEN: u16 = 0x80;
+pub const BPF_MSH: u16 = 0xa0;
+
+//alu operations
+pub const BPF_ADD: u16 = 0x00;
+pub const BPF_SUB: u16 = 0x10;
+pub const BPF_MUL: u16 = 0x20;
+pub const BPF_DIV: u16 = 0x30;
+pub const BPF_OR: u16 = 0x40;
+pub const BPF_AND: u16 = 0x50;
+pub const BPF_LSH: u16 = 0x60;
+pub const BPF_RSH: u16 = 0x70;
+pub const BPF_NEG: u16 = 0x80;
+pub const BPF_MOD: u16 = 0x90;
+pub const BPF_XOR: u16 = 0xa0;
+
+//jump conditions
+pub const BPF_JA: u16 = 0x00;
+pub const BPF_JEQ: u16 = 0x10;
+pub const BPF_JGT: u16 = 0x20;
+pub const BPF_JGE: u16 = 0x30;
+pub const BPF_JSET: u16 = 0x40;
+
+//operand sources
+pub const BPF_K: u16 = 0x00;
+pub const BPF_X: u16 = 0x08;
+pub const BPF_A: u16 = 0x10;
+
+//misc operations
+pub const BPF_TAX: u16 = 0x00;
+pub const BPF_TXA: u16 = 0x80;
+
+///Number of scratch memory slots
+pub const BPF_MEMWORDS: u32 = 16;
+
+///Width of a packet load
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Size {
+    Byte,
+    Half,
+    Word,
+}
+
+impl Size {
+    fn code(self) -> u16 {
+        match self {
+            Size::Byte => BPF_B,
+            Size::Half => BPF_H,
+            Size::Word => BPF_W,
+        }
+    }
+}
+
+///Arithmetic on the accumulator
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AluOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Xor,
+    Lsh,
+    Rsh,
+}
+
+impl AluOp {
+    fn code(self) -> u16 {
+        match self {
+            AluOp::Add => BPF_ADD,
+            AluOp::Sub => BPF_SUB,
+            AluOp::Mul => BPF_MUL,
+            AluOp::Div => BPF_DIV,
+            AluOp::Mod => BPF_MOD,
+            AluOp::And => BPF_AND,
+            AluOp::Or => BPF_OR,
+            AluOp::Xor => BPF_XOR,
+            AluOp::Lsh => BPF_LSH,
+            AluOp::Rsh => BPF_RSH,
+        }
+    }
+}
+
+///Comparison of a conditional jump
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Cond {
+    ///A == operand
+    Eq,
+    ///A > operand
+    Gt,
+    ///A >= operand
+    Ge,
+    ///A & operand != 0
+    Set,
+}
+
+impl Cond {
+    fn code(self) -> u16 {
+        match self {
+            Cond::Eq => BPF_JEQ,
+            Cond::Gt => BPF_JGT,
+            Cond::Ge => BPF_JGE,
+            Cond::Set => BPF_JSET,
+        }
+    }
+}
+
+///Position in a program that jumps can target, see `Assembler::label()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Label(usize);
+
+///Where a conditional jump continues
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Target {
+    ///The following instruction
+    Next,
+    Label(Label),
+}
+
+///Shorthand for `Target::Next`
+pub const NEXT: Target = Target::Next;
+
+impl From<Label> for Target {
+    fn from(label: Label) -> Target {
+        Target::Label(label)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Item {
+    Insn(Instruction),
+    Jump {
+        code: u16,
+        k: u32,
+        jt: Target,
+        jf: Target,
+    },
+    Ja(Label),
+}
+
+///Builds a program instruction by instruction, resolving labels in `assemble()`
+///
+///Classic BPF only jumps forward, so labels must be bound after the jumps that use them.
+#[derive(Clone, Debug, Default)]
+pub struct Assembler {
+    items: Vec<Item>,
+    labels: Vec<Option<usize>>,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler::default()
+    }
+
+    ///Creates a label to be placed later with `bind()`
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    ///Places a label at the next instruction
+    pub fn bind(&mut self, label: Label) -> &mut Assembler {
+        self.labels[label.0] = Some(self.items.len());
+        self
+    }
+
+    ///Appends a raw instruction
+    pub fn insn(&mut self, insn: Instruction) -> &mut Assembler {
+        self.items.push(Item::Insn(insn));
+        self
+    }
+
+    fn op(&mut self, code: u16, k: u32) -> &mut Assembler {
+        self.insn(Instruction::new(code, 0, 0, k))
+    }
+
+    ///A = packet[offset]
+    pub fn ld_abs(&mut self, size: Size, offset: u32) -> &mut Assembler {
+        self.op(BPF_LD | size.code() | BPF_ABS, offset)
+    }
+
+    ///A = packet[X + offset]
+    pub fn ld_ind(&mut self, size: Size, offset: u32) -> &mut Assembler {
+        self.op(BPF_LD | size.code() | BPF_IND, offset)
+    }
+
+    ///A = packet length
+    pub fn ld_len(&mut self) -> &mut Assembler {
+        self.op(BPF_LD | BPF_W | BPF_LEN, 0)
+    }
+
+    ///A = k
+    pub fn ld_imm(&mut self, k: u32) -> &mut Assembler {
+        self.op(BPF_LD | BPF_W | BPF_IMM, k)
+    }
+
+    ///A = mem[slot]
+    pub fn ld_mem(&mut self, slot: u32) -> &mut Assembler {
+        self.op(BPF_LD | BPF_W | BPF_MEM, slot)
+    }
+
+    ///X = k
+    pub fn ldx_imm(&mut self, k: u32) -> &mut Assembler {
+        self.op(BPF_LDX | BPF_W | BPF_IMM, k)
+    }
+
+    ///X = mem[slot]
+    pub fn ldx_mem(&mut self, slot: u32) -> &mut Assembler {
+        self.op(BPF_LDX | BPF_W | BPF_MEM, slot)
+    }
+
+    ///X = packet length
+    pub fn ldx_len(&mut self) -> &mut Assembler {
+        self.op(BPF_LDX | BPF_W | BPF_LEN, 0)
+    }
+
+    ///X = 4 * (packet[offset] & 0xf), the IPv4 header length at `offset`
+    pub fn ldx_msh(&mut self, offset: u32) -> &mut Assembler {
+        self.op(BPF_LDX | BPF_B | BPF_MSH, offset)
+    }
+
+    ///mem[slot] = A
+    pub fn st(&mut self, slot: u32) -> &mut Assembler {
+        self.op(BPF_ST, slot)
+    }
+
+    ///mem[slot] = X
+    pub fn stx(&mut self, slot: u32) -> &mut Assembler {
+        self.op(BPF_STX, slot)
+    }
+
+    ///A = A op k
+    pub fn alu(&mut self, op: AluOp, k: u32) -> &mut Assembler {
+        self.op(BPF_ALU | op.code() | BPF_K, k)
+    }
+
+    ///A = A op X
+    pub fn alu_x(&mut self, op: AluOp) -> &mut Assembler {
+        self.op(BPF_ALU | op.code() | BPF_X, 0)
+    }
+
+    ///A = -A
+    pub fn neg(&mut self) -> &mut Assembler {
+        self.op(BPF_ALU | BPF_NEG, 0)
+    }
+
+    ///X = A
+    pub fn tax(&mut self) -> &mut Assembler {
+        self.op(BPF_MISC | BPF_TAX, 0)
+    }
+
+    ///A = X
+    pub fn txa(&mut self) -> &mut Assembler {
+        self.op(BPF_MISC | BPF_TXA, 0)
+    }
+
+    ///Jumps to `label` unconditionally
+    pub fn ja(&mut self, label: Label) -> &mut Assembler {
+        self.items.push(Item::Ja(label));
+        self
+    }
+
+    ///Continues at `jt` if the condition against k holds, at `jf` otherwise
+    pub fn jmp<T: Into<Target>, F: Into<Target>>(
+        &mut self,
+        cond: Cond,
+        k: u32,
+        jt: T,
+        jf: F,
+    ) -> &mut Assembler {
+        self.items.push(Item::Jump {
+            code: BPF_JMP | cond.code() | BPF_K,
+            k,
+            jt: jt.into(),
+            jf: jf.into(),
+        });
+        self
+    }
+
+    ///Like `jmp()`, comparing against X instead of a constant
+    pub fn jmp_x<T: Into<Target>, F: Into<Target>>(
+        &mut self,
+        cond: Cond,
+        jt: T,
+        jf: F,
+    ) -> &mut Assembler {
+        self.items.push(Item::Jump {
+            code: BPF_JMP | cond.code() | BPF_X,
+            k: 0,
+            jt: jt.into(),
+            jf: jf.into(),
+        });
+        self
+    }
+
+    ///Jumps to `jt` if A == k, to `jf` otherwise
+    pub fn jeq<T: Into<Target>, F: Into<Target>>(
+        &mut self,
+        k: u32,
+        jt: T,
+        jf: F,
+    ) -> &mut Assembler {
+        self.jmp(Cond::Eq, k, jt, jf)
+    }
+
+    ///Jumps to `jt` if A > k, to `jf` otherwise
+    pub fn jgt<T: Into<Target>, F: Into<Target>>(
+        &mut self,
+        k: u32,
+        jt: T,
+        jf: F,
+    ) -> &mut Assembler {
+        self.jmp(Cond::Gt, k, jt, jf)
+    }
+
+    ///Jumps to `jt` if A >= k, to `jf` otherwise
+    pub fn jge<T: Into<Target>, F: Into<Target>>(
+        &mut self,
+        k: u32,
+        jt: T,
+        jf: F,
+    ) -> &mut Assembler {
+        self.jmp(Cond::Ge, k, jt, jf)
+    }
+
+    ///Jumps to `jt` if A & k != 0, to `jf` otherwise
+    pub fn jset<T: Into<Target>, F: Into<Target>>(
+        &mut self,
+        k: u32,
+        jt: T,
+        jf: F,
+    ) -> &mut Assembler {
+        self.jmp(Cond::Set, k, jt, jf)
+    }
+
+    ///Returns k, the number of bytes to keep; 0 drops the packet
+    pub fn ret(&mut self, k: u32) -> &mut Assembler {
+        self.op(BPF_RET | BPF_K, k)
+    }
+
+    ///Returns A
+    pub fn ret_a(&mut self) -> &mut Assembler {
+        self.op(BPF_RET | BPF_A, 0)
+    }
+
+    ///Resolves labels and validates the program
+    pub fn assemble(&self) -> Result<FilterProgram> {
+        let mut insns = Vec::with_capacity(self.items.len());
+        for (pc, item) in self.items.iter().enumerate() {
+            insns.push(match *item {
+                Item::Insn(insn) => insn,
+                Item::Ja(label) => {
+                    Instruction::new(BPF_JMP | BPF_JA, 0, 0, self.offset(pc, label)?)
+                }
+                Item::Jump { code, k, jt, jf } => {
+                    let jt = self.short_offset(pc, jt)?;
+                    let jf = self.short_offset(pc, jf)?;
+                    Instruction::new(code, jt, jf, k)
+                }
+            });
+        }
+        validate(&insns)?;
+        FilterProgram::new(insns)
+    }
+
+    fn offset(&self, pc: usize, label: Label) -> Result<u32> {
+        match self.labels[label.0] {
+            Some(pos) if pos > pc => Ok((pos - pc - 1) as u32),
+            Some(_) => Err(invalid(pc, "jumps backwards")),
+            None => Err(invalid(pc, "jumps to a label that was never bound")),
+        }
+    }
+
+    fn short_offset(&self, pc: usize, target: Target) -> Result<u8> {
+        match target {
+            Target::Next => Ok(0),
+            Target::Label(label) => {
+                let offset = self.offset(pc, label)?;
+                if offset > u8::MAX as u32 {
+                    return Err(invalid(
+                        pc,
+                        "conditional jump is more than 255 instructions",
+                    ));
+                }
+                Ok(offset as u8)
+            }
+        }
+    }
+}
+
+///Checks a program the way the kernel does before attaching it: known opcodes, jumps that
+///stay inside the program, no division by a constant 0, scratch memory only read after it
+///was written, and a return as the last instruction
+pub fn validate(insns: &[Instruction]) -> Result<()> {
+    if insns.is_empty() || insns.len() > MAX_INSTRUCTIONS {
+        return Err(Error::InvalidFilter(format!(
+            "{} instructions, must be 1 to {}",
+            insns.len(),
+            MAX_INSTRUCTIONS
+        )));
+    }
+    let len = insns.len();
+    for (pc, insn) in insns.iter().enumerate() {
+        let code = insn.code;
+        if code > 0xff {
+            return Err(invalid(pc, "has an unknown opcode"));
+        }
+        match code & 0x07 {
+            BPF_LD => match code {
+                c if c == BPF_LD | BPF_W | BPF_IMM || c == BPF_LD | BPF_W | BPF_LEN => {}
+                c if c == BPF_LD | BPF_W | BPF_MEM => check_slot(pc, insn.k)?,
+                c if c & 0xe0 == BPF_ABS || c & 0xe0 == BPF_IND => {
+                    if c & 0x18 == 0x18 {
+                        return Err(invalid(pc, "unknown load size"));
+                    }
+                }
+                _ => return Err(invalid(pc, "unknown load")),
+            },
+            BPF_LDX => match code {
+                c if c == BPF_LDX | BPF_W | BPF_IMM
+                    || c == BPF_LDX | BPF_W | BPF_LEN
+                    || c == BPF_LDX | BPF_B | BPF_MSH => {}
+                c if c == BPF_LDX | BPF_W | BPF_MEM => check_slot(pc, insn.k)?,
+                _ => return Err(invalid(pc, "unknown load into X")),
+            },
+            BPF_ST | BPF_STX => {
+                if code > BPF_STX {
+                    return Err(invalid(pc, "unknown store"));
+                }
+                check_slot(pc, insn.k)?;
+            }
+            BPF_ALU => {
+                let op = code & 0xf0;
+                if code & !0xf8 != BPF_ALU || op > BPF_XOR || (op == BPF_NEG && code & BPF_X != 0) {
+                    return Err(invalid(pc, "unknown alu operation"));
+                }
+                if code & BPF_X == 0 {
+                    if (op == BPF_DIV || op == BPF_MOD) && insn.k == 0 {
+                        return Err(invalid(pc, "divides by zero"));
+                    }
+                    if (op == BPF_LSH || op == BPF_RSH) && insn.k >= 32 {
+                        return Err(invalid(pc, "shifts by 32 or more"));
+                    }
+                }
+            }
+            BPF_JMP => {
+                let op = code & 0xf0;
+                if code & !0xf8 != BPF_JMP || op > BPF_JSET {
+                    return Err(invalid(pc, "unknown jump"));
+                }
+                if op == BPF_JA {
+                    if code != BPF_JMP | BPF_JA || insn.k as usize >= len - pc - 1 {
+                        return Err(invalid(pc, "jumps out of the program"));
+                    }
+                } else if pc + 1 + insn.jt as usize >= len || pc + 1 + insn.jf as usize >= len {
+                    return Err(invalid(pc, "jumps out of the program"));
+                }
+            }
+            BPF_RET => {
+                if code != BPF_RET | BPF_K && code != BPF_RET | BPF_A {
+                    return Err(invalid(pc, "unknown return"));
+                }
+            }
+            _ => {
+                if code != BPF_MISC | BPF_TAX && code != BPF_MISC | BPF_TXA {
+                    return Err(invalid(pc, "unknown misc operation"));
+                }
+            }
+        }
+    }
+    if insns[len - 1].code & 0x07 != BPF_RET {
+        return Err(invalid(len - 1, "is the last one but does not return"));
+    }
+    check_memory(insns)
+}
+
+fn check_slot(pc: usize, slot: u32) -> Result<()> {
+    if slot >= BPF_MEMWORDS {
+        return Err(invalid(pc, "uses a scratch slot past 15"));
+    }
+    Ok(())
+}
+
+//same flow analysis as the kernel's check_load_and_stores(): a slot is valid at an
+//instruction only if every path leading there stored to it
+fn check_memory(insns: &[Instruction]) -> Result<()> {
+    let mut masks = vec![u16::MAX; insns.len()];
+    let mut valid = 0u16;
+    for (pc, insn) in insns.iter().enumerate() {
+        valid &= masks[pc];
+        let code = insn.code;
+        if code == BPF_ST || code == BPF_STX {
+            valid |= 1 << insn.k;
+        } else if code == BPF_LD | BPF_W | BPF_MEM || code == BPF_LDX | BPF_W | BPF_MEM {
+            if valid & (1 << insn.k) == 0 {
+                return Err(invalid(pc, "reads a scratch slot before writing it"));
+            }
+        } else if code == BPF_JMP | BPF_JA {
+            masks[pc + 1 + insn.k as usize] &= valid;
+            valid = u16::MAX;
+        } else if code & 0x07 == BPF_JMP {
+            masks[pc + 1 + insn.jt as usize] &= valid;
+            masks[pc + 1 + insn.jf as usize] &= valid;
+            valid = u16::MAX;
+        }
+    }
+    Ok(())
+}
+
+fn invalid(pc: usize, msg: &str) -> Error {
+    Error::InvalidFilter(format!("instruction {} {}", pc, msg))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::testing::frames::{self, ETH_P_ARP, ETH_P_IP};
+
+    const SKF_AD_OFF: u32 = 0xffff_f000;
+    const SKF_AD_PROTOCOL: u32 = 0;
+    const SKF_AD_VLAN_TAG: u32 = 44;
+    const SKF_AD_VLAN_TAG_PRESENT: u32 = 48;
+    const SKF_AD_RANDOM: u32 = 56;
+
+    //what a program sees of a packet besides its bytes
+    #[derive(Clone, Copy, Debug, Default)]
+    pub(crate) struct Meta {
+        pub(crate) protocol: u16,
+        pub(crate) vlan_tci: Option<u16>,
+        pub(crate) random: u32,
+    }
+
+    //runs a program the way the kernel does and returns the number of bytes to keep
+    pub(crate) fn run_with(program: &FilterProgram, packet: &[u8], meta: Meta) -> u32 {
+        let insns = program.instructions();
+        let (mut a, mut x, mut mem) = (0u32, 0u32, [0u32; BPF_MEMWORDS as usize]);
+        let load = |at: u32, size: u16| -> Option<u32> {
+            let at = at as usize;
+            let bytes = packet.get(at..at + [4, 2, 1][size as usize >> 3])?;
+            Some(bytes.iter().fold(0, |v, &b| v << 8 | b as u32))
+        };
+        let mut pc = 0;
+        loop {
+            let insn = insns[pc];
+            let (code, k) = (insn.code, insn.k);
+            pc += 1;
+            match code & 0x07 {
+                BPF_LD => {
+                    a = match code & 0xe0 {
+                        BPF_IMM => k,
+                        BPF_LEN => packet.len() as u32,
+                        BPF_MEM => mem[k as usize],
+                        BPF_ABS if k >= SKF_AD_OFF => match k - SKF_AD_OFF {
+                            SKF_AD_PROTOCOL => meta.protocol as u32,
+                            SKF_AD_VLAN_TAG => meta.vlan_tci.unwrap_or(0) as u32,
+                            SKF_AD_VLAN_TAG_PRESENT => meta.vlan_tci.is_some() as u32,
+                            SKF_AD_RANDOM => meta.random,
+                            other => panic!("unexpected ancillary load {}", other),
+                        },
+                        BPF_ABS => match load(k, code & 0x18) {
+                            Some(v) => v,
+                            None => return 0,
+                        },
+                        _ => match load(x.wrapping_add(k), code & 0x18) {
+                            Some(v) => v,
+                            None => return 0,
+                        },
+                    }
+                }
+                BPF_LDX => {
+                    x = match code & 0xe0 {
+                        BPF_IMM => k,
+                        BPF_LEN => packet.len() as u32,
+                        BPF_MEM => mem[k as usize],
+                        _ => match load(k, BPF_B) {
+                            Some(v) => (v & 0x0f) * 4,
+                            None => return 0,
+                        },
+                    }
+                }
+                BPF_ST => mem[k as usize] = a,
+                BPF_STX => mem[k as usize] = x,
+                BPF_ALU => {
+                    let operand = if code & BPF_X != 0 { x } else { k };
+                    a = match code & 0xf0 {
+                        BPF_ADD => a.wrapping_add(operand),
+                        BPF_SUB => a.wrapping_sub(operand),
+                        BPF_MUL => a.wrapping_mul(operand),
+                        BPF_DIV | BPF_MOD if operand == 0 => return 0,
+                        BPF_DIV => a / operand,
+                        BPF_MOD => a % operand,
+                        BPF_OR => a | operand,
+                        BPF_AND => a & operand,
+                        BPF_LSH => a.checked_shl(operand).unwrap_or(0),
+                        BPF_RSH => a.checked_shr(operand).unwrap_or(0),
+                        BPF_NEG => a.wrapping_neg(),
+                        _ => a ^ operand,
+                    }
+                }
+                BPF_JMP => {
+                    let operand = if code & BPF_X != 0 { x } else { k };
+                    let taken = match code & 0xf0 {
+                        BPF_JA => {
+                            pc += k as usize;
+                            continue;
+                        }
+                        BPF_JEQ => a == operand,
+                        BPF_JGT => a > operand,
+                        BPF_JGE => a >= operand,
+                        _ => a & operand != 0,
+                    };
+                    pc += if taken { insn.jt } else { insn.jf } as usize;
+                }
+                BPF_RET => return if code & 0x18 == BPF_A { a } else { k },
+                _ => {
+                    if code & 0xf8 == BPF_TXA {
+                        a = x;
+                    } else {
+                        x = a;
+                    }
+                }
+            }
+        }
+    }
+
+    //runs a program on an Ethernet frame as a raw socket would
+    pub(crate) fn run(program: &FilterProgram, frame: &[u8]) -> u32 {
+        let protocol = match frame.get(12..14) {
+            Some(ethertype) => u16::from_be_bytes([ethertype[0], ethertype[1]]),
+            None => 0,
+        };
+        run_with(
+            program,
+            frame,
+            Meta {
+                protocol,
+                ..Meta::default()
+            },
+        )
+    }
+
+    fn ipv4_only() -> FilterProgram {
+        let mut asm = Assembler::new();
+        let drop = asm.label();
+        asm.ld_abs(Size::Half, 12);
+        asm.jeq(ETH_P_IP as u32, NEXT, drop);
+        asm.ret(u32::MAX);
+        asm.bind(drop);
+        asm.ret(0);
+        asm.assemble().unwrap()
+    }
+
+    fn code(program: &FilterProgram) -> Vec<(u16, u8, u8, u32)> {
+        program
+            .instructions()
+            .iter()
+            .map(|insn| (insn.code, insn.jt, insn.jf, insn.k))
+            .collect()
+    }
+
+    #[test]
+    fn resolves_labels_to_relative_offsets() {
+        assert_eq!(
+            code(&ipv4_only()),
+            vec![
+                (BPF_LD | BPF_H | BPF_ABS, 0, 0, 12),
+                (BPF_JMP | BPF_JEQ | BPF_K, 0, 1, 0x0800),
+                (BPF_RET | BPF_K, 0, 0, u32::MAX),
+                (BPF_RET | BPF_K, 0, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn runs_like_the_kernel() {
+        let program = ipv4_only();
+        let ip = frames::udp4([10, 0, 0, 1], [10, 0, 0, 2], 1000, 53);
+        assert_eq!(run(&program, &ip), u32::MAX);
+        assert_eq!(run(&program, &frames::ethernet(ETH_P_ARP, &[0; 28])), 0);
+        //a load past the end drops the packet
+        assert_eq!(run(&program, &[0; 10]), 0);
+    }
+
+    #[test]
+    fn scratch_memory_and_index_registers() {
+        //keeps the IPv4 header length plus 8, computed through X and M[3]
+        let mut asm = Assembler::new();
+        asm.ldx_msh(14);
+        asm.txa();
+        asm.alu(AluOp::Add, 8);
+        asm.st(3);
+        asm.ldx_mem(3);
+        asm.txa();
+        asm.ret_a();
+        let program = asm.assemble().unwrap();
+        let frame = frames::udp4([10, 0, 0, 1], [10, 0, 0, 2], 1000, 53);
+        assert_eq!(run(&program, &frame), 28);
+    }
+
+    #[test]
+    fn rejects_jumps_that_cannot_be_resolved() {
+        let mut asm = Assembler::new();
+        let back = asm.label();
+        asm.bind(back);
+        asm.ld_imm(0);
+        asm.ja(back);
+        asm.ret(0);
+        assert!(matches!(asm.assemble(), Err(Error::InvalidFilter(_))));
+
+        let mut asm = Assembler::new();
+        let never = asm.label();
+        asm.jeq(0, never, NEXT);
+        asm.ret(0);
+        assert!(matches!(asm.assemble(), Err(Error::InvalidFilter(_))));
+
+        let mut asm = Assembler::new();
+        let far = asm.label();
+        asm.jeq(0, far, NEXT);
+        for _ in 0..300 {
+            asm.ld_imm(0);
+        }
+        asm.bind(far);
+        asm.ret(0);
+        assert!(matches!(asm.assemble(), Err(Error::InvalidFilter(_))));
+        //an unconditional jump has 32 bits of offset
+        let mut asm = Assembler::new();
+        let far = asm.label();
+        asm.ja(far);
+        for _ in 0..300 {
+            asm.ld_imm(0);
+        }
+        asm.bind(far);
+        asm.ret(0);
+        assert!(asm.assemble().is_ok());
+    }
+
+    #[test]
+    fn validates_like_the_kernel() {
+        let ret = Instruction::new(BPF_RET | BPF_K, 0, 0, 0);
+        let invalid =
+            |insns: &[Instruction]| matches!(validate(insns), Err(Error::InvalidFilter(_)));
+        assert!(!invalid(&[ret]));
+        assert!(invalid(&[]));
+        assert!(invalid(&vec![ret; MAX_INSTRUCTIONS + 1]));
+        //the last instruction must return
+        assert!(invalid(&[Instruction::new(BPF_LD | BPF_IMM, 0, 0, 1)]));
+        //division by a constant 0
+        assert!(invalid(&[
+            Instruction::new(BPF_ALU | BPF_DIV | BPF_K, 0, 0, 0),
+            ret
+        ]));
+        assert!(invalid(&[
+            Instruction::new(BPF_ALU | BPF_LSH | BPF_K, 0, 0, 32),
+            ret
+        ]));
+        //jumps past the end
+        assert!(invalid(&[
+            Instruction::new(BPF_JMP | BPF_JEQ | BPF_K, 1, 0, 0),
+            ret
+        ]));
+        assert!(invalid(&[Instruction::new(BPF_JMP | BPF_JA, 0, 0, 1), ret]));
+        assert!(invalid(&[
+            Instruction::new(BPF_ST, 0, 0, BPF_MEMWORDS),
+            ret
+        ]));
+        assert!(invalid(&[Instruction::new(0x1ff, 0, 0, 0), ret]));
+    }
+
+    #[test]
+    fn scratch_slots_must_be_written_on_every_path() {
+        let ld = Instruction::new(BPF_LD | BPF_W | BPF_MEM, 0, 0, 2);
+        let st = Instruction::new(BPF_ST, 0, 0, 2);
+        let ret = Instruction::new(BPF_RET | BPF_A, 0, 0, 0);
+        assert!(validate(&[st, ld, ret]).is_ok());
+        assert!(validate(&[ld, ret]).is_err());
+        //only the path that jumps over the store reaches the load without it
+        let skip = Instruction::new(BPF_JMP | BPF_JEQ | BPF_K, 1, 0, 0);
+        assert!(validate(&[skip, st, ld, ret]).is_err());
+        assert!(validate(&[st, skip, st, ld, ret]).is_ok());
+    }
+}
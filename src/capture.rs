@@ -0,0 +1,283 @@
+use crate::error::Result;
+use crate::filter::FilterProgram;
+use crate::group::{RingGroup, Workers};
+use crate::netns::NetNs;
+use crate::rx::{
+    BusyPoll, FanoutFlags, FanoutMethod, FreezeCallback, FreezeEvent, HugepageSize, Promiscuous,
+    RawPacket, Ring, RingSettings, SeqGap, SeqGapCallback, TpacketVersion, WaitStrategy,
+};
+use crate::shutdown::ShutdownHandle;
+use crate::socket::EtherType;
+use crate::stats::RingStats;
+use crate::tpacket3;
+
+///High-level entry point that opens a set of rings on one interface sharing a fanout group
+///
+///All rings listen to the same `ShutdownHandle`, so that one `shutdown()` stops every worker
+///after the block it is processing, however busy or idle its ring is.
+///
+///```no_run
+///use af_packet::prelude::*;
+///
+///let capture = Capture::builder().interface("eth0").workers(4).open()?;
+///let shutdown = capture.shutdown_handle();
+///let workers = capture.spawn(|ring_idx, packet| {
+///    //process frame data here
+///})?;
+/////e.g. from a signal handling thread
+///shutdown.signal();
+///workers.join();
+///# Ok::<(), af_packet::Error>(())
+///```
+#[derive(Debug)]
+pub struct Capture {
+    group: RingGroup,
+    shutdown: ShutdownHandle,
+}
+
+///Builder for `Capture`, see `Capture::builder()`
+#[derive(Clone, Debug)]
+pub struct CaptureBuilder {
+    settings: RingSettings,
+    workers: usize,
+}
+
+impl Capture {
+    ///Returns a builder with the same defaults as `RingSettings` and a single worker, except
+    ///that rings are sized from the interface speed within `DEFAULT_AUTO_SIZE_BUDGET`
+    pub fn builder() -> CaptureBuilder {
+        CaptureBuilder {
+            settings: RingSettings {
+                auto_size: Some(tpacket3::DEFAULT_AUTO_SIZE_BUDGET),
+                ..RingSettings::default()
+            },
+            workers: 1,
+        }
+    }
+
+    ///Rings of this capture, one per worker
+    pub fn rings(&mut self) -> &mut [Ring] {
+        self.group.rings()
+    }
+
+    ///Stops every ring's `recv_block()` with `Error::Shutdown`, including the workers of
+    ///`spawn()`
+    pub fn shutdown(&self) {
+        self.shutdown.signal();
+    }
+
+    ///Handle shared by all rings, e.g. to pass to a signal handler thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    ///Spawns one thread per ring that calls `f` with the ring index and every packet
+    ///received, until `shutdown()`; see `RingGroup::spawn()`
+    pub fn spawn<F>(self, f: F) -> Result<Workers>
+    where
+        F: Fn(usize, &RawPacket) + Send + Sync + 'static,
+    {
+        self.group.spawn(f)
+    }
+
+    ///Gives up the facade and returns the underlying ring group
+    pub fn into_group(self) -> RingGroup {
+        self.group
+    }
+
+    ///Statistics summed over all rings
+    pub fn statistics(&mut self) -> Result<RingStats> {
+        self.group.statistics()
+    }
+}
+
+impl CaptureBuilder {
+    ///Interface to capture on
+    pub fn interface(mut self, if_name: &str) -> CaptureBuilder {
+        self.settings.if_name = String::from(if_name);
+        self
+    }
+
+    ///Capture from all interfaces instead of a single one, see `RingSettings::any_interface`
+    pub fn any_interface(mut self) -> CaptureBuilder {
+        self.settings.any_interface = true;
+        self
+    }
+
+    ///Network namespace the interface lives in, see `netns`
+    pub fn netns(mut self, netns: NetNs) -> CaptureBuilder {
+        self.settings.netns = Some(netns);
+        self
+    }
+
+    ///Do not deliver packets transmitted by this host
+    pub fn ignore_outgoing(mut self, ignore_outgoing: bool) -> CaptureBuilder {
+        self.settings.ignore_outgoing = ignore_outgoing;
+        self
+    }
+
+    ///How the interface is put into promiscuous mode, see `Promiscuous`
+    pub fn promiscuous(mut self, promiscuous: Promiscuous) -> CaptureBuilder {
+        self.settings.promiscuous = promiscuous;
+        self
+    }
+
+    ///Read virtio_net_hdr metadata with every packet, see `RingSettings::vnet_header`
+    pub fn vnet_header(mut self, vnet_header: bool) -> CaptureBuilder {
+        self.settings.vnet_header = vnet_header;
+        self
+    }
+
+    ///Classic BPF filter every ring starts out with
+    pub fn filter(mut self, filter: FilterProgram) -> CaptureBuilder {
+        self.settings.filter = Some(filter);
+        self
+    }
+
+    ///Deliver on average one in `one_in` packets, see `RingSettings::sample`
+    pub fn sample(mut self, one_in: u32) -> CaptureBuilder {
+        self.settings.sample = Some(one_in);
+        self
+    }
+
+    ///Keep at most `snaplen` bytes of every packet, see `RingSettings::snaplen`
+    pub fn snaplen(mut self, snaplen: u32) -> CaptureBuilder {
+        self.settings.snaplen = Some(snaplen);
+        self
+    }
+
+    ///Number of rings to open, usually one per consuming thread
+    pub fn workers(mut self, workers: usize) -> CaptureBuilder {
+        self.workers = workers;
+        self
+    }
+
+    ///How the kernel distributes packets between the rings
+    pub fn fanout_method(mut self, fanout_method: FanoutMethod) -> CaptureBuilder {
+        self.settings.fanout_method = fanout_method;
+        self
+    }
+
+    ///Options of the rings' fanout group, see `RingSettings::fanout_flags`
+    pub fn fanout_flags(mut self, fanout_flags: FanoutFlags) -> CaptureBuilder {
+        self.settings.fanout_flags = fanout_flags;
+        self
+    }
+
+    ///Only capture frames of this protocol
+    pub fn protocol(mut self, protocol: EtherType) -> CaptureBuilder {
+        self.settings.protocol = protocol;
+        self
+    }
+
+    ///Capture without link-layer headers (SOCK_DGRAM), see `RingSettings::cooked`
+    pub fn cooked(mut self, cooked: bool) -> CaptureBuilder {
+        self.settings.cooked = cooked;
+        self
+    }
+
+    ///Ring geometry used for every ring instead of sizing it from the interface speed
+    pub fn ring_settings(mut self, ring_settings: tpacket3::TpacketReq3) -> CaptureBuilder {
+        self.settings.ring_settings = ring_settings;
+        self.settings.auto_size = None;
+        self
+    }
+
+    ///TPACKET version every ring uses instead of the negotiated one, see
+    ///`RingSettings::tpacket_version`
+    pub fn tpacket_version(mut self, version: TpacketVersion) -> CaptureBuilder {
+        self.settings.tpacket_version = Some(version);
+        self
+    }
+
+    ///Map every ring with huge pages, see `RingSettings::hugepages`
+    pub fn hugepages(mut self, size: HugepageSize) -> CaptureBuilder {
+        self.settings.hugepages = Some(size);
+        self
+    }
+
+    ///Whether to lock every ring in RAM, see `RingSettings::lock_memory`
+    pub fn lock_memory(mut self, lock_memory: bool) -> CaptureBuilder {
+        self.settings.lock_memory = lock_memory;
+        self
+    }
+
+    ///Whether to prefault every ring when it is mapped, see `RingSettings::populate`
+    pub fn populate(mut self, populate: bool) -> CaptureBuilder {
+        self.settings.populate = populate;
+        self
+    }
+
+    ///Place every ring's memory on a NUMA node, see `RingSettings::numa_node`
+    pub fn numa_node(mut self, node: u32) -> CaptureBuilder {
+        self.settings.numa_node = Some(node);
+        self
+    }
+
+    ///Busy poll for up to `micros` microseconds before sleeping, see `RingSettings::busy_poll`
+    pub fn busy_poll(mut self, micros: u32) -> CaptureBuilder {
+        self.settings.busy_poll = Some(BusyPoll::new(micros));
+        self
+    }
+
+    ///Calls `f` with every block sequence gap any ring sees, see `RingSettings::on_seq_gap`
+    pub fn on_seq_gap<F>(mut self, f: F) -> CaptureBuilder
+    where
+        F: Fn(SeqGap) + Send + Sync + 'static,
+    {
+        self.settings.on_seq_gap = Some(SeqGapCallback::new(f));
+        self
+    }
+
+    ///Calls `f` with every queue freeze any ring sees, see `RingSettings::on_freeze`
+    pub fn on_freeze<F>(mut self, f: F) -> CaptureBuilder
+    where
+        F: Fn(FreezeEvent) + Send + Sync + 'static,
+    {
+        self.settings.on_freeze = Some(FreezeCallback::new(f));
+        self
+    }
+
+    ///How every ring waits for blocks, see `WaitStrategy`
+    pub fn wait_strategy(mut self, wait_strategy: WaitStrategy) -> CaptureBuilder {
+        self.settings.wait_strategy = wait_strategy;
+        self
+    }
+
+    ///Socket receive buffer of every ring, see `RingSettings::rcvbuf`; `force` goes past
+    ///net.core.rmem_max
+    pub fn rcvbuf(mut self, size: u32, force: bool) -> CaptureBuilder {
+        self.settings.rcvbuf = Some(size);
+        self.settings.rcvbuf_force = force;
+        self
+    }
+
+    ///Copy threshold of every ring, see `RingSettings::copy_thresh`
+    pub fn copy_thresh(mut self, thresh: u32) -> CaptureBuilder {
+        self.settings.copy_thresh = Some(thresh);
+        self
+    }
+
+    ///Sizes rings to buffer a second of line-rate traffic within `max_memory` bytes each,
+    ///see `TpacketReq3::auto_size()`
+    pub fn auto_size(mut self, max_memory: u64) -> CaptureBuilder {
+        self.settings.auto_size = Some(max_memory);
+        self
+    }
+
+    ///Full settings used for every ring, overriding anything set before
+    pub fn settings(mut self, settings: RingSettings) -> CaptureBuilder {
+        self.settings = settings;
+        self
+    }
+
+    ///Opens all rings
+    pub fn open(self) -> Result<Capture> {
+        let shutdown = ShutdownHandle::new()?;
+        let mut group = RingGroup::with_settings(self.settings, self.workers)?;
+        for ring in group.rings() {
+            ring.set_shutdown_handle(shutdown.clone());
+        }
+        Ok(Capture { group, shutdown })
+    }
+}
@@ -0,0 +1,152 @@
+//!Packet streams over `AsyncRing` in the style of `tokio_util::codec`, enabled with the `codec`
+//!feature
+//!
+//!A `Decoder` turns every packet into an item, `FramedRing` yields the items one at a time as a
+//!`futures_core::Stream`, so a capture plugs into stream based pipelines. Blocks are lent out of
+//!the ring while they are decoded, see `shared`; `PacketCodec` yields the packets themselves
+//!without copying them. Consumers that work in batches can take whole blocks from
+//!`AsyncRing::block_stream()` instead.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+
+use crate::async_ring::AsyncRing;
+use crate::error::{Error, Result};
+use crate::rx::RawPacket;
+use crate::shared::Packet;
+
+///Turns packets into the items of a `FramedRing`
+pub trait Decoder {
+    type Item;
+
+    ///Decodes a single packet, `Ok(None)` skips it
+    ///
+    ///An error is yielded by the stream and the rest of the packet's block is dropped.
+    fn decode(&mut self, packet: &RawPacket) -> Result<Option<Self::Item>>;
+}
+
+///Decoder yielding every packet as a shared `Packet`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PacketCodec;
+
+impl Decoder for PacketCodec {
+    type Item = Packet;
+
+    fn decode(&mut self, packet: &RawPacket) -> Result<Option<Packet>> {
+        Ok(Some(packet.to_shared()))
+    }
+}
+
+///Stream of the items `decoder` makes of the packets of an `AsyncRing`
+///
+///Ends when the ring's `ShutdownHandle` is signaled; other errors are yielded and the stream
+///can be polled again afterwards.
+pub struct FramedRing<D: Decoder> {
+    ring: AsyncRing,
+    decoder: D,
+    pending: VecDeque<D::Item>,
+    done: bool,
+}
+
+impl<D: Decoder> FramedRing<D> {
+    ///Decodes the packets of `ring` with `decoder`
+    pub fn new(ring: AsyncRing, decoder: D) -> FramedRing<D> {
+        FramedRing {
+            ring,
+            decoder,
+            pending: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    ///Underlying ring, e.g. for statistics
+    pub fn get_ref(&self) -> &AsyncRing {
+        &self.ring
+    }
+
+    ///Underlying ring, e.g. for statistics
+    pub fn get_mut(&mut self) -> &mut AsyncRing {
+        &mut self.ring
+    }
+
+    ///Decoder, e.g. to read state it keeps
+    pub fn decoder(&self) -> &D {
+        &self.decoder
+    }
+
+    ///Decoder, e.g. to reset state it keeps
+    pub fn decoder_mut(&mut self) -> &mut D {
+        &mut self.decoder
+    }
+
+    ///Returns the ring and decoder; items decoded but not yet yielded are dropped
+    pub fn into_parts(self) -> (AsyncRing, D) {
+        (self.ring, self.decoder)
+    }
+
+    //decodes the next block into `pending`, Ready(Ok(())) once there is something to yield
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while self.pending.is_empty() {
+            let block = match self.ring.poll_recv_block(cx) {
+                Poll::Ready(Ok(block)) => block.into_shared(),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+            for packet in block.get_raw_packets() {
+                if let Some(item) = self.decoder.decode(&packet)? {
+                    self.pending.push_back(item);
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<D: Decoder + fmt::Debug> fmt::Debug for FramedRing<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FramedRing")
+            .field("ring", &self.ring)
+            .field("decoder", &self.decoder)
+            .field("pending", &self.pending.len())
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl<D> Stream for FramedRing<D>
+where
+    D: Decoder + Unpin,
+    D::Item: Unpin,
+{
+    type Item = Result<D::Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<D::Item>>> {
+        let this = self.get_mut();
+        if let Some(item) = this.pending.pop_front() {
+            return Poll::Ready(Some(Ok(item)));
+        }
+        if this.done {
+            return Poll::Ready(None);
+        }
+        match this.poll_fill(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(this.pending.pop_front().map(Ok)),
+            Poll::Ready(Err(Error::Shutdown)) => {
+                this.done = true;
+                Poll::Ready(None)
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Some(Err(err))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncRing {
+    ///Stream of the packets of the ring decoded by `decoder`, see `codec::FramedRing`
+    pub fn framed<D: Decoder>(self, decoder: D) -> FramedRing<D> {
+        FramedRing::new(self, decoder)
+    }
+}
@@ -0,0 +1,617 @@
+//!IPv4 and IPv6 fragment reassembly in userspace, enabled with the `defrag` feature
+//!
+//!PACKET_FANOUT_FLAG_DEFRAG has the kernel reassemble fragments before fanout, but only for
+//!IPv4 and only on rings in a fanout group. `Reassembler` collects fragments from any number
+//!of blocks and hands out whole datagrams, bounded in time and memory.
+//!
+//!Fragments that overlap data already received drop the whole datagram, as RFC 5722 asks for
+//!IPv6; IPv4 follows the same rule rather than guessing which copy is right. Exact duplicates
+//!of a fragment are ignored.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime};
+
+use crate::rx::RawPacket;
+
+const IPV6_HDR_LEN: usize = 40;
+const IPPROTO_HOPOPTS: u8 = 0;
+const IPPROTO_ROUTING: u8 = 43;
+const IPPROTO_FRAGMENT: u8 = 44;
+const IPPROTO_DSTOPTS: u8 = 60;
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+///Limits of a `Reassembler`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DefragSettings {
+    ///How long the fragments of a datagram are kept waiting for the rest, by packet time
+    pub timeout: Duration,
+    ///Datagrams reassembled at the same time at most, the oldest is dropped to make room
+    pub max_datagrams: usize,
+    ///Bytes of fragments buffered at most, the oldest datagrams are dropped to make room
+    pub max_bytes: usize,
+}
+
+impl Default for DefragSettings {
+    fn default() -> DefragSettings {
+        DefragSettings {
+            timeout: Duration::from_secs(30),
+            max_datagrams: 1024,
+            max_bytes: 4 << 20,
+        }
+    }
+}
+
+///Counters of a `Reassembler`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DefragStats {
+    ///Datagrams put back together
+    pub reassembled: u64,
+    ///Datagrams dropped because their fragments did not all arrive in time
+    pub timed_out: u64,
+    ///Datagrams dropped to stay within `max_datagrams` or `max_bytes`
+    pub evicted: u64,
+    ///Datagrams dropped for overlapping, oversized or malformed fragments
+    pub invalid: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FragKey {
+    src: IpAddr,
+    dst: IpAddr,
+    id: u32,
+    protocol: u8,
+}
+
+#[derive(Debug)]
+struct Pending {
+    //header of the fragment at offset 0, without the IPv6 fragment header
+    header: Option<Vec<u8>>,
+    fragments: BTreeMap<usize, Vec<u8>>,
+    //known once the last fragment arrived
+    total_len: Option<usize>,
+    bytes: usize,
+    started: SystemTime,
+}
+
+//one fragment, offsets are in bytes of the fragmentable part
+struct Fragment<'a> {
+    key: FragKey,
+    header: &'a [u8],
+    next_header_at: usize,
+    next_header: u8,
+    offset: usize,
+    more: bool,
+    data: &'a [u8],
+}
+
+impl Fragment<'_> {
+    //header of the reassembled datagram, pointing past the IPv6 fragment header
+    fn first_header(&self) -> Vec<u8> {
+        let mut header = self.header.to_vec();
+        if self.key.src.is_ipv6() {
+            header[self.next_header_at] = self.next_header;
+        }
+        header
+    }
+}
+
+enum Parsed<'a> {
+    Whole(&'a [u8]),
+    Fragment(Fragment<'a>),
+}
+
+///Collects IP fragments into datagrams
+#[derive(Debug)]
+pub struct Reassembler {
+    settings: DefragSettings,
+    pending: HashMap<FragKey, Pending>,
+    bytes: usize,
+    last_sweep: Option<SystemTime>,
+    stats: DefragStats,
+}
+
+impl Reassembler {
+    pub fn new(settings: DefragSettings) -> Reassembler {
+        Reassembler {
+            settings,
+            pending: HashMap::new(),
+            bytes: 0,
+            last_sweep: None,
+            stats: DefragStats::default(),
+        }
+    }
+
+    ///Feeds the network layer of `packet`, see `push_ip()`
+    pub fn push<'a>(&mut self, packet: &RawPacket<'a>) -> Option<Cow<'a, [u8]>> {
+        self.push_ip(packet.l3_payload(), packet.timestamp())
+    }
+
+    ///Feeds an IPv4 or IPv6 packet that arrived at `now`
+    ///
+    ///Returns the packet itself, without link-layer padding, if it is not a fragment, and the
+    ///reassembled datagram when it is the fragment that completes one. The reassembled datagram
+    ///carries the header of the first fragment, with the IPv6 fragment header removed and the
+    ///IPv4 fragment fields cleared. None for fragments of incomplete datagrams and anything
+    ///that is not IP.
+    pub fn push_ip<'a>(&mut self, l3: &'a [u8], now: SystemTime) -> Option<Cow<'a, [u8]>> {
+        self.sweep(now);
+        let fragment = match parse(l3)? {
+            Parsed::Whole(datagram) => return Some(Cow::Borrowed(datagram)),
+            Parsed::Fragment(fragment) => fragment,
+        };
+        //an atomic fragment is a datagram of its own
+        if fragment.offset == 0 && !fragment.more {
+            self.stats.reassembled += 1;
+            return Some(Cow::Owned(finish(fragment.first_header(), fragment.data)));
+        }
+        self.add(fragment, now).map(Cow::Owned)
+    }
+
+    ///Drops the datagrams whose fragments did not all arrive within the timeout as of `now`,
+    ///returns how many
+    ///
+    ///`push()` does this on its own as time passes.
+    pub fn expire(&mut self, now: SystemTime) -> usize {
+        let timeout = self.settings.timeout;
+        let before = self.pending.len();
+        let mut freed = 0;
+        self.pending.retain(|_, pending| {
+            let expired = now
+                .duration_since(pending.started)
+                .is_ok_and(|age| age > timeout);
+            if expired {
+                freed += pending.bytes;
+            }
+            !expired
+        });
+        self.bytes -= freed;
+        let expired = before - self.pending.len();
+        self.stats.timed_out += expired as u64;
+        expired
+    }
+
+    ///Datagrams waiting for more fragments
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    ///Bytes of fragments buffered
+    pub fn buffered_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn stats(&self) -> DefragStats {
+        self.stats
+    }
+
+    pub fn settings(&self) -> &DefragSettings {
+        &self.settings
+    }
+
+    //expires at most once a second of packet time
+    fn sweep(&mut self, now: SystemTime) {
+        let due = match self.last_sweep {
+            Some(last) => now
+                .duration_since(last)
+                .is_ok_and(|since| since >= Duration::from_secs(1)),
+            None => true,
+        };
+        if due {
+            self.last_sweep = Some(now);
+            if !self.pending.is_empty() {
+                self.expire(now);
+            }
+        }
+    }
+
+    fn add(&mut self, fragment: Fragment, now: SystemTime) -> Option<Vec<u8>> {
+        let key = fragment.key;
+        let end = fragment.offset + fragment.data.len();
+        if fragment.header.len() + end > MAX_DATAGRAM_LEN || fragment.data.is_empty() {
+            return self.invalid(&key);
+        }
+        //a retransmitted or looped back copy of a fragment we have
+        let duplicate = self.pending.get(&key).is_some_and(|pending| {
+            pending
+                .fragments
+                .get(&fragment.offset)
+                .is_some_and(|data| data.len() == fragment.data.len())
+        });
+        if duplicate {
+            return None;
+        }
+        if !self.pending.contains_key(&key) {
+            if self.settings.max_datagrams == 0 {
+                self.stats.evicted += 1;
+                return None;
+            }
+            while self.pending.len() >= self.settings.max_datagrams {
+                self.evict_oldest();
+            }
+            self.pending.insert(
+                key,
+                Pending {
+                    header: None,
+                    fragments: BTreeMap::new(),
+                    total_len: None,
+                    bytes: 0,
+                    started: now,
+                },
+            );
+        }
+        let size = fragment.header.len() + fragment.data.len();
+        while self.bytes + size > self.settings.max_bytes {
+            if !self.evict_oldest_except(&key) {
+                self.remove(&key);
+                self.stats.evicted += 1;
+                return None;
+            }
+        }
+
+        let pending = self.pending.get_mut(&key)?;
+        let overlaps = pending
+            .fragments
+            .range(..end)
+            .next_back()
+            .is_some_and(|(&start, data)| start + data.len() > fragment.offset);
+        let past_end = pending.total_len.is_some_and(|total| end > total);
+        let bad_last = !fragment.more
+            && (pending.total_len.is_some()
+                || pending
+                    .fragments
+                    .iter()
+                    .next_back()
+                    .is_some_and(|(&start, data)| start + data.len() > end));
+        if overlaps || past_end || bad_last {
+            return self.invalid(&key);
+        }
+        if fragment.offset == 0 {
+            let header = fragment.first_header();
+            pending.bytes += header.len();
+            self.bytes += header.len();
+            pending.header = Some(header);
+        }
+        if !fragment.more {
+            pending.total_len = Some(end);
+        }
+        pending.bytes += fragment.data.len();
+        self.bytes += fragment.data.len();
+        pending
+            .fragments
+            .insert(fragment.offset, fragment.data.to_vec());
+
+        let total = pending.total_len?;
+        pending.header.as_ref()?;
+        let mut covered = 0;
+        for (&start, data) in &pending.fragments {
+            if start != covered {
+                return None;
+            }
+            covered += data.len();
+        }
+        if covered != total {
+            return None;
+        }
+        let pending = self.remove(&key)?;
+        let mut payload = Vec::with_capacity(total);
+        for data in pending.fragments.values() {
+            payload.extend_from_slice(data);
+        }
+        self.stats.reassembled += 1;
+        Some(finish(pending.header?, &payload))
+    }
+
+    fn invalid(&mut self, key: &FragKey) -> Option<Vec<u8>> {
+        self.remove(key);
+        self.stats.invalid += 1;
+        None
+    }
+
+    fn remove(&mut self, key: &FragKey) -> Option<Pending> {
+        let pending = self.pending.remove(key)?;
+        self.bytes -= pending.bytes;
+        Some(pending)
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .pending
+            .iter()
+            .min_by_key(|(_, pending)| pending.started)
+            .map(|(key, _)| *key);
+        if let Some(key) = oldest {
+            self.remove(&key);
+            self.stats.evicted += 1;
+        }
+    }
+
+    //false if `keep` is the only datagram left
+    fn evict_oldest_except(&mut self, keep: &FragKey) -> bool {
+        let oldest = self
+            .pending
+            .iter()
+            .filter(|(key, _)| *key != keep)
+            .min_by_key(|(_, pending)| pending.started)
+            .map(|(key, _)| *key);
+        match oldest {
+            Some(key) => {
+                self.remove(&key);
+                self.stats.evicted += 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Reassembler {
+        Reassembler::new(DefragSettings::default())
+    }
+}
+
+fn parse(l3: &[u8]) -> Option<Parsed<'_>> {
+    match l3.first()? >> 4 {
+        4 => parse_v4(l3),
+        6 => parse_v6(l3),
+        _ => None,
+    }
+}
+
+fn parse_v4(l3: &[u8]) -> Option<Parsed<'_>> {
+    let ihl = (*l3.first()? & 0x0f) as usize * 4;
+    let total_len = u16::from_be_bytes([*l3.get(2)?, *l3.get(3)?]) as usize;
+    if ihl < 20 || total_len < ihl || total_len > l3.len() {
+        return None;
+    }
+    let l3 = &l3[..total_len];
+    let frag = u16::from_be_bytes([l3[6], l3[7]]);
+    let more = frag & 0x2000 != 0;
+    let offset = (frag & 0x1fff) as usize * 8;
+    if !more && offset == 0 {
+        return Some(Parsed::Whole(l3));
+    }
+    Some(Parsed::Fragment(Fragment {
+        key: FragKey {
+            src: IpAddr::V4(Ipv4Addr::new(l3[12], l3[13], l3[14], l3[15])),
+            dst: IpAddr::V4(Ipv4Addr::new(l3[16], l3[17], l3[18], l3[19])),
+            id: u16::from_be_bytes([l3[4], l3[5]]) as u32,
+            protocol: l3[9],
+        },
+        header: &l3[..ihl],
+        next_header_at: 0,
+        next_header: l3[9],
+        offset,
+        more,
+        data: &l3[ihl..],
+    }))
+}
+
+fn parse_v6(l3: &[u8]) -> Option<Parsed<'_>> {
+    if l3.len() < IPV6_HDR_LEN {
+        return None;
+    }
+    let payload_len = u16::from_be_bytes([l3[4], l3[5]]) as usize;
+    let l3 = l3.get(..IPV6_HDR_LEN + payload_len)?;
+    //extension headers that may come before the fragment header
+    let (mut next, mut next_at, mut off) = (l3[6], 6, IPV6_HDR_LEN);
+    while matches!(next, IPPROTO_HOPOPTS | IPPROTO_ROUTING | IPPROTO_DSTOPTS) {
+        let len = (*l3.get(off + 1)? as usize + 1) * 8;
+        next = l3[off];
+        next_at = off;
+        off += len;
+    }
+    if next != IPPROTO_FRAGMENT {
+        return Some(Parsed::Whole(l3));
+    }
+    let frag = l3.get(off..off + 8)?;
+    let addr = |at: usize| {
+        let mut octets = [0; 16];
+        octets.copy_from_slice(&l3[at..at + 16]);
+        IpAddr::V6(Ipv6Addr::from(octets))
+    };
+    let field = u16::from_be_bytes([frag[2], frag[3]]);
+    Some(Parsed::Fragment(Fragment {
+        key: FragKey {
+            src: addr(8),
+            dst: addr(24),
+            id: u32::from_be_bytes([frag[4], frag[5], frag[6], frag[7]]),
+            protocol: frag[0],
+        },
+        header: &l3[..off],
+        next_header_at: next_at,
+        next_header: frag[0],
+        offset: (field & 0xfff8) as usize,
+        more: field & 1 != 0,
+        data: &l3[off + 8..],
+    }))
+}
+
+//puts `header` in front of `payload` and fixes up the length fields
+fn finish(mut header: Vec<u8>, payload: &[u8]) -> Vec<u8> {
+    let hdr_len = header.len();
+    header.extend_from_slice(payload);
+    let mut datagram = header;
+    if datagram[0] >> 4 == 4 {
+        let total = datagram.len() as u16;
+        datagram[2..4].copy_from_slice(&total.to_be_bytes());
+        datagram[6..8].copy_from_slice(&[0, 0]);
+        datagram[10..12].copy_from_slice(&[0, 0]);
+        let csum = checksum(&datagram[..hdr_len]);
+        datagram[10..12].copy_from_slice(&csum.to_be_bytes());
+    } else {
+        let payload_len = (datagram.len() - IPV6_HDR_LEN) as u16;
+        datagram[4..6].copy_from_slice(&payload_len.to_be_bytes());
+    }
+    datagram
+}
+
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::testing::frames::{self, IPPROTO_UDP};
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn v6_fragment(id: u32, offset: usize, more: bool, data: &[u8]) -> Vec<u8> {
+        let mut body = vec![IPPROTO_UDP, 0];
+        body.extend_from_slice(&(offset as u16 | more as u16).to_be_bytes());
+        body.extend_from_slice(&id.to_be_bytes());
+        body.extend_from_slice(data);
+        let (src, dst) = (
+            "2001:db8::1".parse().unwrap(),
+            "2001:db8::2".parse().unwrap(),
+        );
+        frames::ipv6(IPPROTO_FRAGMENT, src, dst, &body)
+    }
+
+    #[test]
+    fn passes_whole_packets_through() {
+        let mut defrag = Reassembler::default();
+        let mut packet = frames::ipv4(17, [10, 0, 0, 1], [10, 0, 0, 2], &payload(8));
+        let len = packet.len();
+        //Ethernet pads short frames, the padding is not part of the datagram
+        packet.extend_from_slice(&[0; 6]);
+        match defrag.push_ip(&packet, at(0)) {
+            Some(Cow::Borrowed(datagram)) => assert_eq!(datagram, &packet[..len]),
+            other => panic!("{:?}", other),
+        }
+        assert!(defrag.push_ip(b"\x10junk", at(0)).is_none());
+        assert_eq!(defrag.stats(), DefragStats::default());
+    }
+
+    #[test]
+    fn reassembles_ipv4_in_any_order() {
+        let data = payload(24);
+        let parts = [
+            frames::ipv4_fragment(1, 0, true, &data[..8]),
+            frames::ipv4_fragment(1, 8, true, &data[8..16]),
+            frames::ipv4_fragment(1, 16, false, &data[16..]),
+        ];
+        for order in &[[0, 1, 2], [2, 0, 1], [1, 2, 0]] {
+            let mut defrag = Reassembler::default();
+            assert!(defrag.push_ip(&parts[order[0]], at(0)).is_none());
+            assert!(defrag.push_ip(&parts[order[1]], at(0)).is_none());
+            assert_eq!(defrag.pending(), 1);
+            let datagram = defrag.push_ip(&parts[order[2]], at(0)).unwrap();
+            assert_eq!(&datagram[20..], &data[..]);
+            assert_eq!(u16::from_be_bytes([datagram[2], datagram[3]]), 44);
+            assert_eq!(&datagram[6..8], &[0, 0]);
+            assert_eq!(checksum(&datagram[..20]), 0);
+            assert_eq!((defrag.pending(), defrag.buffered_bytes()), (0, 0));
+            assert_eq!(defrag.stats().reassembled, 1);
+        }
+    }
+
+    #[test]
+    fn reassembles_ipv6_without_the_fragment_header() {
+        let data = payload(32);
+        let mut defrag = Reassembler::default();
+        assert!(defrag
+            .push_ip(&v6_fragment(9, 16, false, &data[16..]), at(0))
+            .is_none());
+        let first = v6_fragment(9, 0, true, &data[..16]);
+        let datagram = defrag.push_ip(&first, at(0)).unwrap();
+        assert_eq!(datagram.len(), IPV6_HDR_LEN + 32);
+        assert_eq!(datagram[6], IPPROTO_UDP);
+        assert_eq!(u16::from_be_bytes([datagram[4], datagram[5]]), 32);
+        assert_eq!(&datagram[IPV6_HDR_LEN..], &data[..]);
+
+        //an atomic fragment is a datagram of its own
+        let atomic = v6_fragment(10, 0, false, &data);
+        let datagram = defrag.push_ip(&atomic, at(0)).unwrap();
+        assert_eq!(&datagram[IPV6_HDR_LEN..], &data[..]);
+        assert_eq!(defrag.stats().reassembled, 2);
+    }
+
+    #[test]
+    fn drops_overlapping_fragments_and_ignores_duplicates() {
+        let data = payload(24);
+        let mut defrag = Reassembler::default();
+        let first = frames::ipv4_fragment(2, 0, true, &data[..16]);
+        defrag.push_ip(&first, at(0));
+        defrag.push_ip(&first, at(0));
+        assert_eq!((defrag.pending(), defrag.stats().invalid), (1, 0));
+        assert!(defrag
+            .push_ip(&frames::ipv4_fragment(2, 8, false, &data[8..]), at(0))
+            .is_none());
+        assert_eq!((defrag.pending(), defrag.buffered_bytes()), (0, 0));
+        assert_eq!(defrag.stats().invalid, 1);
+
+        //a second last fragment
+        defrag.push_ip(&frames::ipv4_fragment(3, 8, false, &data[8..16]), at(0));
+        defrag.push_ip(&frames::ipv4_fragment(3, 16, false, &data[16..]), at(0));
+        assert_eq!((defrag.pending(), defrag.stats().invalid), (0, 2));
+    }
+
+    #[test]
+    fn times_out_incomplete_datagrams() {
+        let mut defrag = Reassembler::new(DefragSettings {
+            timeout: Duration::from_secs(5),
+            ..DefragSettings::default()
+        });
+        defrag.push_ip(&frames::ipv4_fragment(4, 0, true, &payload(8)), at(100));
+        assert_eq!(defrag.expire(at(105)), 0);
+        assert_eq!(defrag.expire(at(106)), 1);
+        assert_eq!(defrag.stats().timed_out, 1);
+
+        //later packets expire old datagrams on their own
+        defrag.push_ip(&frames::ipv4_fragment(5, 0, true, &payload(8)), at(200));
+        let late = frames::ipv4_fragment(5, 8, false, &payload(8));
+        assert!(defrag.push_ip(&late, at(210)).is_none());
+        assert_eq!(defrag.stats().timed_out, 2);
+    }
+
+    #[test]
+    fn evicts_the_oldest_datagrams() {
+        let mut defrag = Reassembler::new(DefragSettings {
+            max_datagrams: 2,
+            ..DefragSettings::default()
+        });
+        for id in 0..3 {
+            defrag.push_ip(
+                &frames::ipv4_fragment(id, 0, true, &payload(8)),
+                at(id as u64),
+            );
+        }
+        assert_eq!((defrag.pending(), defrag.stats().evicted), (2, 1));
+        //the datagram evicted first cannot be completed any more
+        let rest = frames::ipv4_fragment(0, 8, false, &payload(8));
+        assert!(defrag.push_ip(&rest, at(3)).is_none());
+        let rest = frames::ipv4_fragment(2, 8, false, &payload(8));
+        assert!(defrag.push_ip(&rest, at(3)).is_some());
+
+        let mut defrag = Reassembler::new(DefragSettings {
+            max_bytes: 60,
+            ..DefragSettings::default()
+        });
+        defrag.push_ip(&frames::ipv4_fragment(1, 0, true, &payload(16)), at(0));
+        defrag.push_ip(&frames::ipv4_fragment(2, 0, true, &payload(16)), at(1));
+        assert_eq!((defrag.pending(), defrag.buffered_bytes()), (1, 36));
+        //a datagram larger than the limit on its own is dropped too
+        defrag.push_ip(&frames::ipv4_fragment(2, 16, true, &payload(32)), at(1));
+        assert_eq!((defrag.pending(), defrag.buffered_bytes()), (0, 0));
+        assert_eq!(defrag.stats().evicted, 2);
+    }
+}
@@ -0,0 +1,338 @@
+//!Runs a ring on a thread of its own and hands what it receives to consumers through a bounded
+//!queue, decoupling capture from processing
+//!
+//!Packets and blocks are handed over without copying them, see `shared`: every block stays lent
+//!out of the ring until all of its packets are consumed and dropped, so the queue capacity
+//!should leave the ring enough blocks to capture into.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use crate::rx::Ring;
+use crate::shared::{Packet, SharedBlock};
+use crate::shutdown::ShutdownHandle;
+
+///What the dispatcher does with a packet or block when the queue is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Overflow {
+    ///Wait for room, leaving it to the ring to drop packets once its blocks run out
+    Block,
+    ///Drop the new item
+    DropNewest,
+    ///Drop the oldest queued item to make room for the new one
+    DropOldest,
+}
+
+///Settings of a dispatcher
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DispatchSettings {
+    ///Items the queue holds at most
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for DispatchSettings {
+    fn default() -> DispatchSettings {
+        DispatchSettings {
+            capacity: 1024,
+            overflow: Overflow::Block,
+        }
+    }
+}
+
+///Thread reading a ring into a queue, see `packets()` and `blocks()`
+///
+///Stopped and joined when dropped.
+pub struct Dispatcher<T> {
+    shutdown: ShutdownHandle,
+    queue: Arc<Queue<T>>,
+    handle: Option<JoinHandle<Result<Ring>>>,
+}
+
+///Consuming end of a dispatcher's queue; clones share the queue, each item goes to one of them
+pub struct Receiver<T> {
+    queue: Arc<Queue<T>>,
+}
+
+struct Queue<T> {
+    state: Mutex<State<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    settings: DispatchSettings,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    dropped: u64,
+    closed: bool,
+    receivers: usize,
+}
+
+///Dispatches every packet of `ring` as a shared `Packet`
+pub fn packets(
+    ring: Ring,
+    settings: DispatchSettings,
+) -> Result<(Dispatcher<Packet>, Receiver<Packet>)> {
+    spawn(ring, settings, |block| block.packets())
+}
+
+///Dispatches whole blocks of `ring`, for consumers that work in batches
+pub fn blocks(
+    ring: Ring,
+    settings: DispatchSettings,
+) -> Result<(Dispatcher<SharedBlock>, Receiver<SharedBlock>)> {
+    spawn(ring, settings, |block| vec![block])
+}
+
+fn spawn<T, F>(
+    mut ring: Ring,
+    settings: DispatchSettings,
+    split: F,
+) -> Result<(Dispatcher<T>, Receiver<T>)>
+where
+    T: Send + 'static,
+    F: Fn(SharedBlock) -> Vec<T> + Send + 'static,
+{
+    if settings.capacity == 0 {
+        return Err(Error::InvalidGeometry(String::from(
+            "a dispatch queue needs room for at least one item",
+        )));
+    }
+    let shutdown = ring.shutdown_handle()?;
+    let queue = Arc::new(Queue {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(settings.capacity),
+            dropped: 0,
+            closed: false,
+            receivers: 1,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        settings,
+    });
+    let producer = queue.clone();
+    let handle = thread::spawn(move || {
+        let res = run(&mut ring, &producer, split);
+        producer.close();
+        res.map(|()| ring)
+    });
+    let dispatcher = Dispatcher {
+        shutdown,
+        queue: queue.clone(),
+        handle: Some(handle),
+    };
+    Ok((dispatcher, Receiver { queue }))
+}
+
+fn run<T, F>(ring: &mut Ring, queue: &Queue<T>, split: F) -> Result<()>
+where
+    F: Fn(SharedBlock) -> Vec<T>,
+{
+    loop {
+        let block = match ring.recv_block() {
+            Ok(block) => block,
+            Err(Error::Shutdown) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if block.packet_count() == 0 {
+            let mut block = block;
+            block.mark_as_consumed();
+            continue;
+        }
+        for item in split(block.into_shared()) {
+            if !queue.push(item) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<T> Queue<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    //false once nobody will take the item: the queue was closed or all receivers are gone
+    fn push(&self, item: T) -> bool {
+        let mut state = self.lock();
+        loop {
+            if state.closed || state.receivers == 0 {
+                return false;
+            }
+            if state.items.len() < self.settings.capacity {
+                break;
+            }
+            match self.settings.overflow {
+                Overflow::Block => {
+                    state = self.not_full.wait(state).unwrap_or_else(|e| e.into_inner());
+                }
+                Overflow::DropNewest => {
+                    state.dropped += 1;
+                    return true;
+                }
+                Overflow::DropOldest => {
+                    state.items.pop_front();
+                    state.dropped += 1;
+                    break;
+                }
+            }
+        }
+        state.items.push_back(item);
+        self.not_empty.notify_one();
+        true
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+}
+
+impl<T> Dispatcher<T> {
+    ///Asks the thread to stop; items already queued can still be received
+    pub fn stop(&self) {
+        self.shutdown.signal();
+        self.queue.close();
+    }
+
+    ///Items dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.queue.lock().dropped
+    }
+
+    ///Stops the thread and returns the ring, or the error that stopped the thread early
+    ///
+    ///The ring's `ShutdownHandle` stays signaled, give it a new one with
+    ///`Ring::set_shutdown_handle()` before receiving from it again.
+    pub fn join(mut self) -> Result<Ring> {
+        self.stop();
+        match self.handle.take().map(JoinHandle::join) {
+            Some(Ok(res)) => res,
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            None => Err(Error::Shutdown),
+        }
+    }
+}
+
+impl<T> Drop for Dispatcher<T> {
+    fn drop(&mut self) {
+        self.stop();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Dispatcher<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Dispatcher")
+            .field("settings", &self.queue.settings)
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+impl<T> Receiver<T> {
+    ///Waits for the next item, `None` once the dispatcher stopped and the queue is empty
+    pub fn recv(&self) -> Option<T> {
+        self.recv_until(None)
+    }
+
+    ///Waits for the next item for at most `timeout`
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<T> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    ///Next item if one is queued
+    pub fn try_recv(&self) -> Option<T> {
+        self.take(&mut self.queue.lock())
+    }
+
+    ///Number of queued items
+    pub fn len(&self) -> usize {
+        self.queue.lock().items.len()
+    }
+
+    ///Whether no items are queued
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    ///Whether the dispatcher stopped; queued items can still be received
+    pub fn is_closed(&self) -> bool {
+        self.queue.lock().closed
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Option<T> {
+        let mut state = self.queue.lock();
+        loop {
+            if let Some(item) = self.take(&mut state) {
+                return Some(item);
+            }
+            if state.closed {
+                return None;
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.queue
+                        .not_empty
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self
+                    .queue
+                    .not_empty
+                    .wait(state)
+                    .unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+
+    fn take(&self, state: &mut State<T>) -> Option<T> {
+        let item = state.items.pop_front()?;
+        self.queue.not_full.notify_one();
+        Some(item)
+    }
+}
+
+impl<T> Iterator for Receiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.recv()
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Receiver<T> {
+        self.queue.lock().receivers += 1;
+        Receiver {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.queue.lock().receivers -= 1;
+        self.queue.not_full.notify_all();
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver")
+            .field("queued", &self.len())
+            .finish()
+    }
+}
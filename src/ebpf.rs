@@ -0,0 +1,87 @@
+//!Handles to loaded eBPF programs, for `Socket::set_ebpf_filter()`
+//!
+//!Loading programs from ELF objects is left to aya or libbpf; their program fds can be used
+//!directly, or the programs pinned to bpffs and opened here.
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+use libc::{c_int, close, syscall, SYS_bpf};
+
+use crate::error::{Error, Result};
+
+const BPF_OBJ_GET: c_int = 7;
+
+#[repr(C)]
+#[derive(Default)]
+struct BpfObjAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+///Loaded eBPF program, closed when dropped
+#[derive(Debug)]
+pub struct EbpfProgram {
+    fd: RawFd,
+}
+
+impl EbpfProgram {
+    ///Opens a program pinned to bpffs, e.g. under /sys/fs/bpf
+    pub fn open_pinned<P: AsRef<Path>>(path: P) -> Result<EbpfProgram> {
+        let path = path.as_ref();
+        let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| {
+            Error::os(
+                "bpf(BPF_OBJ_GET)",
+                io::Error::new(io::ErrorKind::InvalidInput, "path contains a nul byte"),
+            )
+        })?;
+        let attr = BpfObjAttr {
+            pathname: c_path.as_ptr() as u64,
+            ..BpfObjAttr::default()
+        };
+        let fd = unsafe {
+            syscall(
+                SYS_bpf,
+                BPF_OBJ_GET,
+                &attr as *const BpfObjAttr,
+                mem::size_of::<BpfObjAttr>(),
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error("bpf(BPF_OBJ_GET)"));
+        }
+        Ok(EbpfProgram { fd: fd as RawFd })
+    }
+
+    ///Takes ownership of a program fd obtained elsewhere
+    ///
+    ///# Safety
+    ///`fd` must be an open eBPF program descriptor that nothing else closes.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> EbpfProgram {
+        EbpfProgram { fd }
+    }
+
+    ///Program descriptor, to be passed to `Socket::set_ebpf_filter()`
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl AsRawFd for EbpfProgram {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for EbpfProgram {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.fd);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use libc::{EACCES, EPERM};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+///Errors returned by this crate, one variant per failure point
+#[derive(Debug)]
+pub enum Error {
+    ///The named interface does not exist
+    NoSuchInterface(String),
+    ///The interface name is too long or contains a NUL byte
+    InvalidInterfaceName(String),
+    ///The OS refused the operation with EPERM or EACCES and no missing privilege explains it:
+    ///the calling thread holds the capability `MissingPrivilege` would name, or the operation
+    ///is not one that is checked for one, e.g. opening a capture file or a network namespace.
+    ///Usually an LSM or seccomp policy is in the way.
+    PermissionDenied {
+        context: &'static str,
+        source: io::Error,
+    },
+    ///The ring geometry was rejected, either by validation or by the kernel
+    InvalidGeometry(String),
+    ///Mapping the ring into memory failed
+    Mmap(io::Error),
+    ///A BPF filter program is malformed
+    InvalidFilter(String),
+    ///A capture file could not be parsed
+    BadCaptureFile(String),
+    ///A block's descriptor or packet headers point outside of it
+    BlockCorrupt(String),
+    ///The ring was stopped through its `ShutdownHandle`
+    Shutdown,
+    ///The captured interface went down or was removed
+    InterfaceDown(String),
+    ///The process lacks a capability or resource limit the operation needs, the message says
+    ///how to grant it
+    ///
+    ///Returned instead of `PermissionDenied` when
+    ///- `socket()` fails with EPERM or EACCES and the thread lacks CAP_NET_RAW
+    ///- a socket level option (SO_*) or interface ioctl fails with EPERM or EACCES and the
+    ///  thread lacks CAP_NET_ADMIN
+    ///- mapping a ring fails with EAGAIN, EPERM or EACCES and the ring is larger than
+    ///  RLIMIT_MEMLOCK allows locking
+    MissingPrivilege {
+        context: &'static str,
+        privilege: Privilege,
+        source: io::Error,
+    },
+    ///Any other OS error, along with the operation that failed
+    Os {
+        context: &'static str,
+        source: io::Error,
+    },
+}
+
+///What an `Error::MissingPrivilege` is missing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Privilege {
+    ///CAP_NET_RAW, needed to open packet sockets
+    NetRaw,
+    ///CAP_NET_ADMIN, needed to force socket buffers and busy polling past their sysctl limits
+    ///and to change interface flags
+    NetAdmin,
+    ///Room under RLIMIT_MEMLOCK for `needed` bytes of locked memory
+    MemLock { needed: u64, limit: u64 },
+}
+
+impl Error {
+    ///Wraps an OS error, picking the most specific variant for it
+    pub fn os(context: &'static str, source: io::Error) -> Error {
+        match source.raw_os_error() {
+            Some(EPERM) | Some(EACCES) => Error::PermissionDenied { context, source },
+            _ => Error::Os { context, source },
+        }
+    }
+
+    ///Wraps the last OS error, see `Error::os()`
+    pub fn last_os_error(context: &'static str) -> Error {
+        Error::os(context, io::Error::last_os_error())
+    }
+
+    ///Returns the underlying OS error, if any
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            Error::PermissionDenied { source, .. }
+            | Error::MissingPrivilege { source, .. }
+            | Error::Os { source, .. } => Some(source),
+            Error::Mmap(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoSuchInterface(name) => write!(f, "no such interface: {}", name),
+            Error::InvalidInterfaceName(name) => write!(f, "invalid interface name: {:?}", name),
+            Error::PermissionDenied { context, source } => {
+                write!(f, "{}: permission denied: {}", context, source)
+            }
+            Error::InvalidGeometry(msg) => write!(f, "invalid ring geometry: {}", msg),
+            Error::Mmap(source) => write!(f, "mmap failed: {}", source),
+            Error::InvalidFilter(msg) => write!(f, "invalid filter: {}", msg),
+            Error::BadCaptureFile(msg) => write!(f, "bad capture file: {}", msg),
+            Error::BlockCorrupt(msg) => write!(f, "corrupt block: {}", msg),
+            Error::Shutdown => write!(f, "shut down"),
+            Error::InterfaceDown(name) => write!(f, "interface down: {}", name),
+            Error::MissingPrivilege {
+                context,
+                privilege: Privilege::NetRaw,
+                source,
+            } => write!(
+                f,
+                "{}: {}: CAP_NET_RAW is missing, grant cap_net_raw (setcap cap_net_raw+ep on \
+                 the binary) or run as root",
+                context, source
+            ),
+            Error::MissingPrivilege {
+                context,
+                privilege: Privilege::NetAdmin,
+                source,
+            } => write!(
+                f,
+                "{}: {}: CAP_NET_ADMIN is missing, grant cap_net_admin (setcap cap_net_admin+ep \
+                 on the binary) or run as root",
+                context, source
+            ),
+            Error::MissingPrivilege {
+                context,
+                privilege: Privilege::MemLock { needed, limit },
+                source,
+            } => write!(
+                f,
+                "{}: {}: locking {} bytes exceeds RLIMIT_MEMLOCK of {} bytes, raise the limit \
+                 (ulimit -l, LimitMEMLOCK= for systemd units), grant cap_ipc_lock or shrink \
+                 the ring",
+                context, source, needed, limit
+            ),
+            Error::Os { context, source } => write!(f, "{}: {}", context, source),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.io_error().map(|e| e as &(dyn error::Error + 'static))
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Error {
+        Error::os("io", source)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        let kind = match &err {
+            Error::NoSuchInterface(_) => io::ErrorKind::NotFound,
+            Error::InvalidInterfaceName(_)
+            | Error::InvalidGeometry(_)
+            | Error::InvalidFilter(_) => io::ErrorKind::InvalidInput,
+            Error::BadCaptureFile(_) | Error::BlockCorrupt(_) => io::ErrorKind::InvalidData,
+            Error::Shutdown => io::ErrorKind::Interrupted,
+            Error::InterfaceDown(_) => io::ErrorKind::NotConnected,
+            Error::PermissionDenied { .. } | Error::MissingPrivilege { .. } => {
+                io::ErrorKind::PermissionDenied
+            }
+            Error::Mmap(source) | Error::Os { source, .. } => source.kind(),
+        };
+        io::Error::new(kind, err)
+    }
+}
@@ -0,0 +1,407 @@
+//!NIC receive queues and ntuple flow steering rules, through the ethtool ioctl
+//!
+//!Multi-queue NICs spread flows over their receive queues with RSS. Rings in a
+//!PACKET_FANOUT_QM group receive the packets of queue `i % rings`, see
+//!`RingGroup::per_rx_queue()`, so with one ring per queue every ring reads its own queue and
+//!never contends with the others. Flow rules go further and pin chosen flows to a queue, and
+//!thereby to a ring. The NIC needs ntuple filtering enabled, e.g. `ethtool -K eth0 ntuple on`.
+//!Queries work unprivileged, changing rules needs CAP_NET_ADMIN.
+//!
+//!`driver_stats()` reads the driver's own counters, what `ethtool -S` shows.
+
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+
+use libc::{
+    c_char, c_int, c_ulong, c_void, close, ioctl, socket, AF_INET, IF_NAMESIZE, SOCK_DGRAM,
+};
+
+use crate::error::{Error, Result};
+use crate::filters::{IPPROTO_SCTP, IPPROTO_TCP, IPPROTO_UDP};
+
+const SIOCETHTOOL: c_ulong = 0x8946;
+
+const ETHTOOL_GDRVINFO: u32 = 0x03;
+const ETHTOOL_GSTRINGS: u32 = 0x1b;
+const ETHTOOL_GSTATS: u32 = 0x1d;
+const ETHTOOL_GRXRINGS: u32 = 0x2d;
+const ETHTOOL_GRXCLSRLCNT: u32 = 0x2e;
+const ETHTOOL_GRXCLSRULE: u32 = 0x2f;
+const ETHTOOL_GRXCLSRLALL: u32 = 0x30;
+const ETHTOOL_SRXCLSRLDEL: u32 = 0x31;
+const ETHTOOL_SRXCLSRLINS: u32 = 0x32;
+const ETHTOOL_GCHANNELS: u32 = 0x3c;
+
+const ETH_SS_STATS: u32 = 1;
+const ETH_GSTRING_LEN: usize = 32;
+//struct ethtool_drvinfo in u32 words, n_stats is word 45
+const DRVINFO_WORDS: usize = 49;
+const DRVINFO_N_STATS: usize = 45;
+
+const TCP_V4_FLOW: u32 = 0x01;
+const UDP_V4_FLOW: u32 = 0x02;
+const SCTP_V4_FLOW: u32 = 0x03;
+const TCP_V6_FLOW: u32 = 0x05;
+const UDP_V6_FLOW: u32 = 0x06;
+const SCTP_V6_FLOW: u32 = 0x07;
+
+//let the driver pick where a rule goes, if it says it can
+const RX_CLS_LOC_ANY: u32 = 0xffff_ffff;
+const RX_CLS_LOC_SPECIAL: u64 = 0x8000_0000;
+
+///Queue counts of a NIC, see `channels()`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Channels {
+    pub max_rx: u32,
+    pub max_tx: u32,
+    pub max_other: u32,
+    pub max_combined: u32,
+    ///Receive only queues
+    pub rx: u32,
+    ///Transmit only queues
+    pub tx: u32,
+    ///Queues for link interrupts and the like
+    pub other: u32,
+    ///Queue pairs serving both directions
+    pub combined: u32,
+}
+
+impl Channels {
+    ///Queues packets are received on
+    pub fn rx_queues(&self) -> u32 {
+        self.rx + self.combined
+    }
+}
+
+///ntuple rule steering a TCP, UDP or SCTP flow to a receive queue
+///
+///Fields left `None` match anything. Addresses must match `ipv6`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowRule {
+    ///IP protocol: 6 for TCP, 17 for UDP or 132 for SCTP
+    pub protocol: u8,
+    pub ipv6: bool,
+    pub src: Option<IpAddr>,
+    pub dst: Option<IpAddr>,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    ///Receive queue matching packets go to
+    pub queue: u32,
+}
+
+impl FlowRule {
+    ///Rule sending every IPv4 packet of `protocol` to `queue`, narrow it down with the fields
+    pub fn new(protocol: u8, queue: u32) -> FlowRule {
+        FlowRule {
+            protocol,
+            ipv6: false,
+            src: None,
+            dst: None,
+            src_port: None,
+            dst_port: None,
+            queue,
+        }
+    }
+
+    fn to_spec(self) -> Result<RxFlowSpec> {
+        let flow_type = match (self.protocol, self.ipv6) {
+            (IPPROTO_TCP, false) => TCP_V4_FLOW,
+            (IPPROTO_UDP, false) => UDP_V4_FLOW,
+            (IPPROTO_SCTP, false) => SCTP_V4_FLOW,
+            (IPPROTO_TCP, true) => TCP_V6_FLOW,
+            (IPPROTO_UDP, true) => UDP_V6_FLOW,
+            (IPPROTO_SCTP, true) => SCTP_V6_FLOW,
+            (protocol, _) => {
+                return Err(invalid(format!(
+                    "flow rules match TCP, UDP or SCTP, not IP protocol {}",
+                    protocol
+                )))
+            }
+        };
+        let mut spec = RxFlowSpec {
+            flow_type,
+            ring_cookie: self.queue as u64,
+            ..RxFlowSpec::default()
+        };
+        //offsets into ethtool_tcpip4_spec and ethtool_tcpip6_spec
+        let (addr_len, ports_at) = if self.ipv6 { (16, 32) } else { (4, 8) };
+        for (addr, at) in [(self.src, 0), (self.dst, addr_len)] {
+            let octets = match addr {
+                None => continue,
+                Some(IpAddr::V4(addr)) if !self.ipv6 => addr.octets().to_vec(),
+                Some(IpAddr::V6(addr)) if self.ipv6 => addr.octets().to_vec(),
+                Some(addr) => {
+                    return Err(invalid(format!(
+                        "{} does not match the rule's address family",
+                        addr
+                    )))
+                }
+            };
+            spec.h_u[at..at + addr_len].copy_from_slice(&octets);
+            spec.m_u[at..at + addr_len].fill(0xff);
+        }
+        for (port, at) in [(self.src_port, ports_at), (self.dst_port, ports_at + 2)] {
+            if let Some(port) = port {
+                spec.h_u[at..at + 2].copy_from_slice(&port.to_be_bytes());
+                spec.m_u[at..at + 2].fill(0xff);
+            }
+        }
+        Ok(spec)
+    }
+}
+
+///Queue counts of the NIC behind `if_name` (ETHTOOL_GCHANNELS)
+pub fn channels(if_name: &str) -> Result<Channels> {
+    let mut req = [0u32; 9];
+    req[0] = ETHTOOL_GCHANNELS;
+    ethtool(if_name, req.as_mut_ptr() as *mut c_void)?;
+    Ok(Channels {
+        max_rx: req[1],
+        max_tx: req[2],
+        max_other: req[3],
+        max_combined: req[4],
+        rx: req[5],
+        tx: req[6],
+        other: req[7],
+        combined: req[8],
+    })
+}
+
+///Number of receive queues of the NIC behind `if_name`, 1 for devices that do not report
+///any
+pub fn rx_queue_count(if_name: &str) -> Result<u32> {
+    let mut nfc = RxNfc::new(ETHTOOL_GRXRINGS);
+    let from_rings = match ethtool(if_name, &mut nfc as *mut RxNfc as *mut c_void) {
+        Ok(()) => nfc.data as u32,
+        Err(err) if is_unsupported(&err) => 0,
+        Err(err) => return Err(err),
+    };
+    if from_rings > 0 {
+        return Ok(from_rings);
+    }
+    match channels(if_name) {
+        Ok(channels) => Ok(channels.rx_queues().max(1)),
+        Err(err) if is_unsupported(&err) => Ok(1),
+        Err(err) => Err(err),
+    }
+}
+
+///Driver statistics of `if_name` by name, in the driver's order (`ethtool -S`); empty for
+///devices without any, such as lo
+///
+///Names and meanings differ between drivers, e.g. `rx_missed_errors` or `rx_queue_0_drops`;
+///`iface::stats()` has the counters every driver reports.
+pub fn driver_stats(if_name: &str) -> Result<Vec<(String, u64)>> {
+    let mut info = [0u32; DRVINFO_WORDS];
+    info[0] = ETHTOOL_GDRVINFO;
+    match ethtool(if_name, info.as_mut_ptr() as *mut c_void) {
+        Ok(()) => {}
+        Err(err) if is_unsupported(&err) => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    }
+    let count = info[DRVINFO_N_STATS] as usize;
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    //struct ethtool_gstrings: cmd, string_set and len, then the names
+    let mut strings = vec![0u8; 12 + count * ETH_GSTRING_LEN];
+    strings[0..4].copy_from_slice(&ETHTOOL_GSTRINGS.to_ne_bytes());
+    strings[4..8].copy_from_slice(&ETH_SS_STATS.to_ne_bytes());
+    strings[8..12].copy_from_slice(&(count as u32).to_ne_bytes());
+    ethtool(if_name, strings.as_mut_ptr() as *mut c_void)?;
+
+    //struct ethtool_stats: cmd and n_stats, then the values
+    let mut stats = vec![0u64; 1 + count];
+    stats[0] = u64::from_ne_bytes(header(ETHTOOL_GSTATS, count as u32));
+    ethtool(if_name, stats.as_mut_ptr() as *mut c_void)?;
+    let counted = stats[0].to_ne_bytes();
+    let counted = u32::from_ne_bytes([counted[4], counted[5], counted[6], counted[7]]);
+
+    Ok(strings[12..]
+        .chunks(ETH_GSTRING_LEN)
+        .zip(&stats[1..])
+        .take(counted as usize)
+        .map(|(name, &value)| {
+            let name = name.split(|&b| b == 0).next().unwrap_or_default();
+            (String::from_utf8_lossy(name).into_owned(), value)
+        })
+        .collect())
+}
+
+///Inserts a flow rule and returns its location, needed to remove it again
+pub fn add_flow_rule(if_name: &str, rule: &FlowRule) -> Result<u32> {
+    let mut nfc = RxNfc::new(ETHTOOL_SRXCLSRLINS);
+    nfc.fs = rule.to_spec()?;
+    nfc.fs.location = free_location(if_name)?;
+    ethtool(if_name, &mut nfc as *mut RxNfc as *mut c_void)?;
+    Ok(nfc.fs.location)
+}
+
+///Removes the flow rule at `location`
+pub fn remove_flow_rule(if_name: &str, location: u32) -> Result<()> {
+    let mut nfc = RxNfc::new(ETHTOOL_SRXCLSRLDEL);
+    nfc.fs.location = location;
+    ethtool(if_name, &mut nfc as *mut RxNfc as *mut c_void)
+}
+
+///Locations of the flow rules installed on the NIC behind `if_name`
+pub fn flow_rule_locations(if_name: &str) -> Result<Vec<u32>> {
+    let (count, _) = rule_count(if_name)?;
+    //the kernel writes the locations right after the fixed part of the request
+    let header = mem::offset_of!(RxNfc, rule_cnt) / 4 + 1;
+    let mut buf = vec![0u32; header + count as usize];
+    let mut nfc = RxNfc::new(ETHTOOL_GRXCLSRLALL);
+    nfc.rule_cnt = count;
+    unsafe {
+        std::ptr::copy_nonoverlapping(&nfc as *const RxNfc as *const u32, buf.as_mut_ptr(), header);
+    }
+    ethtool(if_name, buf.as_mut_ptr() as *mut c_void)?;
+    let count = buf[header - 1] as usize;
+    Ok(buf[header..header + count.min(buf.len() - header)].to_vec())
+}
+
+///Queue the flow rule at `location` steers to
+pub fn flow_rule_queue(if_name: &str, location: u32) -> Result<u32> {
+    let mut nfc = RxNfc::new(ETHTOOL_GRXCLSRULE);
+    nfc.fs.location = location;
+    ethtool(if_name, &mut nfc as *mut RxNfc as *mut c_void)?;
+    Ok(nfc.fs.ring_cookie as u32)
+}
+
+//rules installed and the size of the rule table, with RX_CLS_LOC_SPECIAL set if the driver
+//picks locations itself
+fn rule_count(if_name: &str) -> Result<(u32, u64)> {
+    let mut nfc = RxNfc::new(ETHTOOL_GRXCLSRLCNT);
+    ethtool(if_name, &mut nfc as *mut RxNfc as *mut c_void)?;
+    Ok((nfc.rule_cnt, nfc.data))
+}
+
+fn free_location(if_name: &str) -> Result<u32> {
+    let (_, table) = rule_count(if_name)?;
+    if table & RX_CLS_LOC_SPECIAL != 0 {
+        return Ok(RX_CLS_LOC_ANY);
+    }
+    let used = flow_rule_locations(if_name)?;
+    (0..table as u32)
+        .find(|loc| !used.contains(loc))
+        .ok_or_else(|| invalid(format!("all {} flow rule locations are in use", table)))
+}
+
+//two u32 fields in the layout of the u64 they share
+fn header(cmd: u32, count: u32) -> [u8; 8] {
+    let mut header = [0u8; 8];
+    header[..4].copy_from_slice(&cmd.to_ne_bytes());
+    header[4..].copy_from_slice(&count.to_ne_bytes());
+    header
+}
+
+fn is_unsupported(err: &Error) -> bool {
+    err.io_error()
+        .and_then(io::Error::raw_os_error)
+        .is_some_and(|errno| errno == libc::EOPNOTSUPP || errno == libc::EINVAL)
+}
+
+fn invalid(msg: String) -> Error {
+    Error::os("ethtool", io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
+
+#[repr(C)]
+struct EthtoolReq {
+    ifr_name: [c_char; IF_NAMESIZE],
+    ifr_data: *mut c_void,
+    //rest of the ifreq union
+    _pad: [u8; 16],
+}
+
+fn ethtool(if_name: &str, data: *mut c_void) -> Result<()> {
+    let mut req = EthtoolReq {
+        ifr_name: [0; IF_NAMESIZE],
+        ifr_data: data,
+        _pad: [0; 16],
+    };
+    if if_name.len() >= IF_NAMESIZE || if_name.contains('\0') {
+        return Err(Error::InvalidInterfaceName(String::from(if_name)));
+    }
+    for (a, c) in req.ifr_name.iter_mut().zip(if_name.bytes()) {
+        *a = c as c_char;
+    }
+    let fd = unsafe { socket(AF_INET, SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error("socket"));
+    }
+    let res: c_int = unsafe { ioctl(fd, SIOCETHTOOL, &mut req) };
+    let err = io::Error::last_os_error();
+    unsafe {
+        close(fd);
+    }
+    match res {
+        -1 if err.raw_os_error() == Some(libc::ENODEV) => {
+            Err(Error::NoSuchInterface(String::from(if_name)))
+        }
+        -1 => Err(Error::os("ethtool", err)),
+        _ => Ok(()),
+    }
+}
+
+//struct ethtool_flow_ext
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FlowExt {
+    padding: [u8; 2],
+    h_dest: [u8; 6],
+    vlan_etype: u16,
+    vlan_tci: u16,
+    data: [u32; 2],
+}
+
+//struct ethtool_rx_flow_spec
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RxFlowSpec {
+    flow_type: u32,
+    h_u: [u8; 52],
+    h_ext: FlowExt,
+    //bits set here are compared
+    m_u: [u8; 52],
+    m_ext: FlowExt,
+    ring_cookie: u64,
+    location: u32,
+}
+
+impl Default for RxFlowSpec {
+    fn default() -> RxFlowSpec {
+        RxFlowSpec {
+            flow_type: 0,
+            h_u: [0; 52],
+            h_ext: FlowExt::default(),
+            m_u: [0; 52],
+            m_ext: FlowExt::default(),
+            ring_cookie: 0,
+            location: 0,
+        }
+    }
+}
+
+//struct ethtool_rxnfc without the trailing rule_locs
+#[repr(C)]
+struct RxNfc {
+    cmd: u32,
+    flow_type: u32,
+    data: u64,
+    fs: RxFlowSpec,
+    rule_cnt: u32,
+}
+
+impl RxNfc {
+    fn new(cmd: u32) -> RxNfc {
+        RxNfc {
+            cmd,
+            flow_type: 0,
+            data: 0,
+            fs: RxFlowSpec::default(),
+            rule_cnt: 0,
+        }
+    }
+}
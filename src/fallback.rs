@@ -0,0 +1,364 @@
+//!Capture without PACKET_RX_RING, for kernels, containers or seccomp profiles that do not allow
+//!mapping a ring
+//!
+//!Packets are copied out of the kernel in batches with recvmmsg(), each one's metadata and
+//!timestamp taken from its own PACKET_AUXDATA and SO_TIMESTAMPNS control messages, and laid out
+//!in blocks like a live ring fills them, so consumers written against `PacketSource` work
+//!unchanged.
+
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::ptr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libc::{
+    c_int, c_void, iovec, mmsghdr, msghdr, poll, pollfd, recvmmsg, sockaddr_ll, timespec,
+    CMSG_DATA, CMSG_FIRSTHDR, CMSG_NXTHDR, EAGAIN, EINTR, MSG_DONTWAIT, MSG_TRUNC, POLLIN,
+    SOL_PACKET, SOL_SOCKET,
+};
+
+use crate::error::{Error, Result};
+use crate::offline::Packet;
+use crate::promisc::PromiscuousFlag;
+use crate::rx::{self, Block, Promiscuous, RingSettings};
+use crate::shutdown::ShutdownHandle;
+use crate::socket::Socket;
+use crate::source::PacketSource;
+use crate::tpacket3::{BlockBuilder, SockAddrLl, TpStatus, Tpacket3Hdr};
+
+pub(crate) const PACKET_AUXDATA: c_int = 8;
+const SO_TIMESTAMPNS: c_int = 35;
+//largest packet a packet socket hands out, GSO aside
+const MAX_PACKET: usize = 65536;
+//room for the auxdata and timestamp messages
+const CONTROL_LEN: usize = 16;
+///Packets read per recvmmsg() call unless changed with `FallbackRing::set_batch_size()`
+pub const DEFAULT_BATCH_SIZE: usize = 32;
+
+//struct tpacket_auxdata
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct TpacketAuxdata {
+    tp_status: u32,
+    tp_len: u32,
+    tp_snaplen: u32,
+    tp_mac: u16,
+    tp_net: u16,
+    tp_vlan_tci: u16,
+    tp_vlan_tpid: u16,
+}
+
+///Packet socket read with recvmmsg(), handing out packets in blocks like `rx::Ring`
+///
+///Takes the same settings as `Ring::new()`; the ring geometry only sets the block size, and
+///`frame_reserve` and `vnet_header` are ignored. Expect a fraction of the throughput of a
+///mapped ring.
+#[derive(Debug)]
+pub struct FallbackRing {
+    pub socket: Socket,
+    block_size: usize,
+    seq_num: u64,
+    pending: VecDeque<Packet>,
+    buf: Vec<u8>,
+    batch: Batch,
+    shutdown: Option<ShutdownHandle>,
+    promiscuous: Promiscuous,
+    //share of IFF_PROMISC with Promiscuous::InterfaceFlag
+    promiscuous_flag: Option<PromiscuousFlag>,
+}
+
+impl FallbackRing {
+    ///Opens and binds the socket, see `Ring::new()`
+    pub fn new(mut settings: RingSettings) -> Result<FallbackRing> {
+        if let Some(ns) = settings.netns.take() {
+            return crate::netns::run_in(&ns, || FallbackRing::new(settings));
+        }
+        let mut socket = rx::open_socket(&settings)?;
+        let promiscuous_flag = rx::join_memberships(&mut socket, &settings)?;
+        if settings.ignore_outgoing {
+            socket.setsockopt(rx::PACKET_IGNORE_OUTGOING, 1 as c_int)?;
+        }
+        if let Some(filter) = rx::socket_filter(&settings)? {
+            socket.attach_filter(&filter)?;
+        }
+        if let Some(busy_poll) = &settings.busy_poll {
+            rx::set_busy_poll(&mut socket, busy_poll)?;
+        }
+        rx::set_buffers(&mut socket, &settings)?;
+        socket.setsockopt(PACKET_AUXDATA, 1 as c_int)?;
+        set_timestamps(&socket)?;
+        rx::bind_socket(&socket, settings.protocol)?;
+        rx::join_fanout(&mut socket, &settings)?;
+        let block_size = settings.ring_settings.tp_block_size as usize;
+        Ok(FallbackRing {
+            socket,
+            block_size,
+            seq_num: 1,
+            pending: VecDeque::new(),
+            buf: Vec::new(),
+            batch: Batch::new(DEFAULT_BATCH_SIZE, block_size.min(MAX_PACKET)),
+            shutdown: None,
+            promiscuous: if settings.any_interface {
+                Promiscuous::Off
+            } else {
+                settings.promiscuous
+            },
+            promiscuous_flag,
+        })
+    }
+
+    ///Waits for at least one packet and returns it along with every other packet already
+    ///queued on the socket that fits in the block. Returns `Error::Shutdown` once the ring's
+    ///`ShutdownHandle` is signaled.
+    pub fn recv_block(&mut self) -> Result<Block<'_>> {
+        let mut builder = BlockBuilder::new(self.block_size, self.seq_num);
+        loop {
+            let mut packet = match self.pending.pop_front() {
+                Some(packet) => packet,
+                None => match self.batch.recv(self.socket.fd, &mut self.pending)? {
+                    0 if builder.is_empty() => {
+                        self.wait()?;
+                        continue;
+                    }
+                    0 => break,
+                    _ => continue,
+                },
+            };
+            if builder.push(&packet.hdr, &packet.sll, &packet.data) {
+                continue;
+            }
+            if builder.is_empty() {
+                packet.data.truncate(builder.remaining());
+                packet.hdr.tp_status |= TpStatus::COPY.bits();
+                builder.push(&packet.hdr, &packet.sll, &packet.data);
+            } else {
+                self.pending.push_front(packet);
+            }
+            break;
+        }
+        self.seq_num += 1;
+        self.buf = builder.finish();
+        Ok(Block::from_raw(&mut self.buf).expect("blocks are at least a descriptor long"))
+    }
+
+    ///Sets how many packets one recvmmsg() call reads at most, 1 reads them one at a time.
+    ///Packets that do not fit in the current block are kept for the next one.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        let slot_len = self.batch.slot_len;
+        self.batch = Batch::new(batch_size.max(1), slot_len);
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch.names.len()
+    }
+
+    ///Returns a handle that stops `recv_block()` from another thread, creating it on first use
+    pub fn shutdown_handle(&mut self) -> Result<ShutdownHandle> {
+        if let Some(handle) = &self.shutdown {
+            return Ok(handle.clone());
+        }
+        let handle = ShutdownHandle::new()?;
+        self.shutdown = Some(handle.clone());
+        Ok(handle)
+    }
+
+    ///Makes the ring listen to an existing handle, so that one handle can stop many rings
+    pub fn set_shutdown_handle(&mut self, handle: ShutdownHandle) {
+        self.shutdown = Some(handle);
+    }
+
+    ///Takes the interface out of promiscuous mode now, see `Ring::leave_promiscuous()`
+    pub fn leave_promiscuous(&mut self) -> Result<()> {
+        match self.promiscuous {
+            Promiscuous::Off => {}
+            Promiscuous::Membership => self.socket.set_promiscuous(false)?,
+            Promiscuous::InterfaceFlag => {
+                if let Some(flag) = self.promiscuous_flag.take() {
+                    flag.release()?;
+                }
+            }
+        }
+        self.promiscuous = Promiscuous::Off;
+        Ok(())
+    }
+
+    fn wait(&self) -> Result<()> {
+        let mut pfds = [
+            pollfd {
+                fd: self.socket.fd,
+                events: POLLIN,
+                revents: 0,
+            },
+            pollfd {
+                fd: self.shutdown.as_ref().map(|s| s.fd()).unwrap_or(-1),
+                events: POLLIN,
+                revents: 0,
+            },
+        ];
+        if unsafe { poll(pfds.as_mut_ptr(), 2, -1) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(EINTR) {
+                return Err(Error::os("poll", err));
+            }
+        }
+        if pfds[1].revents & POLLIN != 0 {
+            return Err(Error::Shutdown);
+        }
+        Ok(())
+    }
+}
+
+impl PacketSource for FallbackRing {
+    ///Returns `None` once the `ShutdownHandle` is signaled
+    fn next_block(&mut self) -> Result<Option<Block<'_>>> {
+        match self.recv_block() {
+            Ok(block) => Ok(Some(block)),
+            Err(Error::Shutdown) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn set_timestamps(socket: &Socket) -> Result<()> {
+    let on: c_int = 1;
+    match unsafe {
+        libc::setsockopt(
+            socket.fd,
+            SOL_SOCKET,
+            SO_TIMESTAMPNS,
+            &on as *const c_int as *const c_void,
+            mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error("setsockopt")),
+    }
+}
+
+//buffers recvmmsg() reads into, one slot per packet of a batch
+#[derive(Debug)]
+struct Batch {
+    slot_len: usize,
+    data: Vec<u8>,
+    names: Vec<sockaddr_ll>,
+    control: Vec<[u64; CONTROL_LEN]>,
+}
+
+impl Batch {
+    fn new(size: usize, slot_len: usize) -> Batch {
+        Batch {
+            slot_len,
+            data: vec![0; size * slot_len],
+            names: vec![unsafe { mem::zeroed() }; size],
+            control: vec![[0; CONTROL_LEN]; size],
+        }
+    }
+
+    //reads whatever is queued without blocking, up to a batch, returns how many packets were read
+    fn recv(&mut self, fd: c_int, out: &mut VecDeque<Packet>) -> Result<usize> {
+        let size = self.names.len();
+        let mut iovs: Vec<iovec> = self
+            .data
+            .chunks_mut(self.slot_len)
+            .map(|slot| iovec {
+                iov_base: slot.as_mut_ptr() as *mut c_void,
+                iov_len: slot.len(),
+            })
+            .collect();
+        let mut msgs: Vec<mmsghdr> = (0..size)
+            .map(|i| {
+                let mut hdr: msghdr = unsafe { mem::zeroed() };
+                hdr.msg_name = &mut self.names[i] as *mut sockaddr_ll as *mut c_void;
+                hdr.msg_namelen = mem::size_of::<sockaddr_ll>() as u32;
+                hdr.msg_iov = &mut iovs[i];
+                hdr.msg_iovlen = 1;
+                hdr.msg_control = self.control[i].as_mut_ptr() as *mut c_void;
+                hdr.msg_controllen = mem::size_of::<[u64; CONTROL_LEN]>();
+                mmsghdr {
+                    msg_hdr: hdr,
+                    msg_len: 0,
+                }
+            })
+            .collect();
+
+        let count = unsafe {
+            recvmmsg(
+                fd,
+                msgs.as_mut_ptr(),
+                size as u32,
+                MSG_DONTWAIT | MSG_TRUNC,
+                ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(EAGAIN) | Some(EINTR) => Ok(0),
+                _ => Err(Error::os("recvmmsg", err)),
+            };
+        }
+        for (i, msg) in msgs.iter().take(count as usize).enumerate() {
+            //msg_len is the length on the wire with MSG_TRUNC
+            let len = (msg.msg_len as usize).min(self.slot_len);
+            let start = i * self.slot_len;
+            out.push_back(to_packet(
+                &msg.msg_hdr,
+                &self.names[i],
+                &self.data[start..start + len],
+            ));
+        }
+        Ok(count as usize)
+    }
+}
+
+//builds a packet from what recvmmsg() filled in for it
+fn to_packet(msg: &msghdr, sa: &sockaddr_ll, data: &[u8]) -> Packet {
+    let mut aux = None;
+    let mut ts = None;
+    let mut cmsg = unsafe { CMSG_FIRSTHDR(msg) };
+    while !cmsg.is_null() {
+        let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+        if level == SOL_PACKET && kind == PACKET_AUXDATA {
+            aux = Some(unsafe { (CMSG_DATA(cmsg) as *const TpacketAuxdata).read_unaligned() });
+        } else if level == SOL_SOCKET && kind == SO_TIMESTAMPNS {
+            ts = Some(unsafe { (CMSG_DATA(cmsg) as *const timespec).read_unaligned() });
+        }
+        cmsg = unsafe { CMSG_NXTHDR(msg, cmsg) };
+    }
+
+    let (sec, nsec) = match ts {
+        Some(ts) => (ts.tv_sec as u32, ts.tv_nsec as u32),
+        None => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            (now.as_secs() as u32, now.subsec_nanos())
+        }
+    };
+    let aux = aux.unwrap_or_default();
+    let mut hdr = Tpacket3Hdr {
+        tp_sec: sec,
+        tp_nsec: nsec,
+        tp_len: aux.tp_len,
+        tp_status: aux.tp_status | TpStatus::USER.bits(),
+        tp_net: aux.tp_net,
+        ..Tpacket3Hdr::default()
+    };
+    hdr.hv1.tp_vlan_tci = aux.tp_vlan_tci as u32;
+    hdr.hv1.tp_vlan_tpid = aux.tp_vlan_tpid;
+    let sll = SockAddrLl {
+        sll_family: sa.sll_family,
+        sll_protocol: u16::from_be(sa.sll_protocol),
+        sll_ifindex: sa.sll_ifindex,
+        sll_hatype: sa.sll_hatype,
+        sll_pkttype: sa.sll_pkttype,
+        sll_halen: sa.sll_halen,
+        sll_addr: sa.sll_addr,
+    };
+    Packet {
+        hdr,
+        sll,
+        data: data.to_vec(),
+    }
+}
@@ -0,0 +1,99 @@
+//!Classic BPF programs, attached to sockets with SO_ATTACH_FILTER or used for fanout steering
+
+use libc::sock_fprog;
+
+use crate::bpf::{self, BPF_K, BPF_RET};
+use crate::error::{Error, Result};
+
+///Most instructions the kernel accepts in one program (BPF_MAXINSNS)
+pub const MAX_INSTRUCTIONS: usize = 4096;
+
+///One classic BPF instruction, laid out like `struct sock_filter`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Instruction {
+    pub code: u16,
+    ///Instructions to skip if the condition holds
+    pub jt: u8,
+    ///Instructions to skip if it does not
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl Instruction {
+    pub fn new(code: u16, jt: u8, jf: u8, k: u32) -> Instruction {
+        Instruction { code, jt, jf, k }
+    }
+}
+
+///Classic BPF program, e.g. the output of `tcpdump -dd`
+///
+///A socket filter returns how many bytes of the packet to keep, 0 drops it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FilterProgram {
+    insns: Vec<Instruction>,
+}
+
+impl FilterProgram {
+    ///Wraps a program, checking only that its length is acceptable to the kernel
+    pub fn new(insns: Vec<Instruction>) -> Result<FilterProgram> {
+        if insns.is_empty() || insns.len() > MAX_INSTRUCTIONS {
+            return Err(Error::InvalidFilter(format!(
+                "{} instructions, must be 1 to {}",
+                insns.len(),
+                MAX_INSTRUCTIONS
+            )));
+        }
+        Ok(FilterProgram { insns })
+    }
+
+    ///Compiles a tcpdump filter expression such as `tcp and port 443` for packets of the given
+    ///pcap link type: `DLT_EN10MB` for normal rings, `DLT_LINUX_SLL` for cooked ones, see
+    ///`Ring::link_type()`
+    ///
+    ///This is a compiler for the common subset of the syntax, see `pcap_filter` for what it
+    ///understands; an empty expression accepts everything.
+    #[cfg(feature = "pcap-filter")]
+    pub fn compile(expr: &str, link_type: u16) -> Result<FilterProgram> {
+        crate::pcap_filter::compile(expr, link_type)
+    }
+
+    ///Program that keeps every packet whole
+    pub fn accept_all() -> FilterProgram {
+        FilterProgram {
+            insns: vec![Instruction::new(BPF_RET | BPF_K, 0, 0, u32::MAX)],
+        }
+    }
+
+    ///Program that drops every packet
+    pub fn drop_all() -> FilterProgram {
+        FilterProgram {
+            insns: vec![Instruction::new(BPF_RET | BPF_K, 0, 0, 0)],
+        }
+    }
+
+    ///Checks the program the way the kernel will when it is attached, see `bpf::validate()`
+    pub fn validate(&self) -> Result<()> {
+        bpf::validate(&self.insns)
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.insns
+    }
+
+    pub fn len(&self) -> usize {
+        self.insns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.insns.is_empty()
+    }
+
+    ///Kernel view of the program, only valid while `self` is borrowed
+    pub(crate) fn as_fprog(&self) -> sock_fprog {
+        sock_fprog {
+            len: self.insns.len() as u16,
+            filter: self.insns.as_ptr() as *mut libc::sock_filter,
+        }
+    }
+}
@@ -0,0 +1,652 @@
+//!Ready-made filters for the most common tcpdump expressions, for Ethernet captures
+//!
+//!Like tcpdump, port filters look at the first fragment of IPv4 packets and at IPv6 packets
+//!without extension headers.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+
+use crate::bpf::{
+    AluOp, Assembler, Label, Size, BPF_A, BPF_JA, BPF_JGT, BPF_JMP, BPF_K, BPF_RET, NEXT,
+};
+use crate::error::Result;
+use crate::filter::{FilterProgram, Instruction};
+use crate::socket::EtherType;
+
+const ETH_P_IP: u32 = 0x0800;
+const ETH_P_IPV6: u32 = 0x86dd;
+const ETH_P_8021Q: u32 = 0x8100;
+const ETH_P_8021AD: u32 = 0x88a8;
+const ETH_P_QINQ1: u32 = 0x9100;
+
+pub(crate) const IPPROTO_ICMP: u8 = 1;
+pub(crate) const IPPROTO_TCP: u8 = 6;
+pub(crate) const IPPROTO_UDP: u8 = 17;
+pub(crate) const IPPROTO_SCTP: u8 = 132;
+pub(crate) const IPPROTO_ICMPV6: u8 = 58;
+
+//protocols `port` and `portrange` apply to
+pub(crate) const PORT_PROTOCOLS: &[u8] = &[IPPROTO_TCP, IPPROTO_UDP, IPPROTO_SCTP];
+
+//offsets from the start of the network header
+const IP4_FRAG: u32 = 6;
+const IP4_PROTO: u32 = 9;
+const IP4_SRC: u32 = 12;
+const IP4_DST: u32 = 16;
+const IP6_NEXT_HDR: u32 = 6;
+const IP6_SRC: u32 = 8;
+const IP6_DST: u32 = 24;
+const IP6_SPORT: u32 = 40;
+const IP6_DPORT: u32 = 42;
+
+const ETH_TYPE: u32 = 12;
+
+//ancillary loads, offsets from SKF_AD_OFF
+const SKF_AD_OFF: u32 = 0xffff_f000;
+const SKF_AD_PROTOCOL: u32 = 0;
+const SKF_AD_VLAN_TAG: u32 = 44;
+const SKF_AD_VLAN_TAG_PRESENT: u32 = 48;
+const SKF_AD_RANDOM: u32 = 56;
+
+///Where the filtered packets start
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Link {
+    ///Length of the link-layer header in front of the network header
+    l3: u32,
+    ///No link-layer header to read the ethertype from, as on SOCK_DGRAM sockets
+    cooked: bool,
+}
+
+pub(crate) const ETHERNET: Link = Link {
+    l3: 14,
+    cooked: false,
+};
+
+#[cfg(feature = "pcap-filter")]
+pub(crate) const COOKED: Link = Link {
+    l3: 0,
+    cooked: true,
+};
+
+///Which address or port of a packet a filter looks at
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Src,
+    Dst,
+    ///Either one, like tcpdump's default
+    Any,
+}
+
+///`ether proto <ethertype>`
+pub fn ether_type(ethertype: EtherType) -> FilterProgram {
+    if ethertype == EtherType::All {
+        return FilterProgram::accept_all();
+    }
+    build(|asm, t, f| emit_ether_type(asm, ETHERNET, ethertype.to_raw(), t, f))
+}
+
+///`ip`
+pub fn ipv4() -> FilterProgram {
+    ether_type(EtherType::Ipv4)
+}
+
+///`ip6`
+pub fn ipv6() -> FilterProgram {
+    ether_type(EtherType::Ipv6)
+}
+
+///`arp`
+pub fn arp() -> FilterProgram {
+    ether_type(EtherType::Arp)
+}
+
+///`tcp`
+pub fn tcp() -> FilterProgram {
+    build(|asm, t, f| emit_ip_proto(asm, ETHERNET, &[IPPROTO_TCP], &[IPPROTO_TCP], t, f))
+}
+
+///`udp`
+pub fn udp() -> FilterProgram {
+    build(|asm, t, f| emit_ip_proto(asm, ETHERNET, &[IPPROTO_UDP], &[IPPROTO_UDP], t, f))
+}
+
+///`icmp or icmp6`
+pub fn icmp() -> FilterProgram {
+    build(|asm, t, f| emit_ip_proto(asm, ETHERNET, &[IPPROTO_ICMP], &[IPPROTO_ICMPV6], t, f))
+}
+
+///`host <addr>`
+pub fn ip_host(addr: IpAddr) -> FilterProgram {
+    ip_net(addr, max_prefix(addr), Direction::Any)
+}
+
+///`src host <addr>`
+pub fn ip_src(addr: IpAddr) -> FilterProgram {
+    ip_net(addr, max_prefix(addr), Direction::Src)
+}
+
+///`dst host <addr>`
+pub fn ip_dst(addr: IpAddr) -> FilterProgram {
+    ip_net(addr, max_prefix(addr), Direction::Dst)
+}
+
+///`[src|dst] net <addr>/<prefix_len>`
+pub fn ip_net(addr: IpAddr, prefix_len: u8, dir: Direction) -> FilterProgram {
+    build(|asm, t, f| emit_ip_net(asm, ETHERNET, addr, prefix_len, dir, t, f))
+}
+
+///`tcp port <port>`
+pub fn tcp_port(port: u16) -> FilterProgram {
+    ports(&[IPPROTO_TCP], port..=port, Direction::Any)
+}
+
+///`udp port <port>`
+pub fn udp_port(port: u16) -> FilterProgram {
+    ports(&[IPPROTO_UDP], port..=port, Direction::Any)
+}
+
+///`port <port>`, on TCP, UDP or SCTP
+pub fn port(port: u16) -> FilterProgram {
+    ports(PORT_PROTOCOLS, port..=port, Direction::Any)
+}
+
+///`tcp portrange <lo>-<hi>`
+pub fn tcp_port_range(range: RangeInclusive<u16>) -> FilterProgram {
+    ports(&[IPPROTO_TCP], range, Direction::Any)
+}
+
+///`udp portrange <lo>-<hi>`
+pub fn udp_port_range(range: RangeInclusive<u16>) -> FilterProgram {
+    ports(&[IPPROTO_UDP], range, Direction::Any)
+}
+
+///`portrange <lo>-<hi>`, on TCP, UDP or SCTP
+pub fn port_range(range: RangeInclusive<u16>) -> FilterProgram {
+    ports(PORT_PROTOCOLS, range, Direction::Any)
+}
+
+///`[tcp|udp|sctp] [src|dst] portrange <lo>-<hi>` for any set of IP protocols
+pub fn ports(protocols: &[u8], range: RangeInclusive<u16>, dir: Direction) -> FilterProgram {
+    build(|asm, t, f| emit_ports(asm, ETHERNET, protocols, range.clone(), dir, t, f))
+}
+
+///`vlan [id]`, matching tags the NIC stripped as well as ones still in the frame
+pub fn vlan(id: Option<u16>) -> FilterProgram {
+    build(|asm, t, f| emit_vlan(asm, ETHERNET, id, t, f))
+}
+
+///Keeps on average one in `one_in` packets, picked at random by the kernel
+///
+///For statistical visibility of links too fast to capture whole; 0 and 1 keep everything.
+pub fn sample(one_in: u32) -> FilterProgram {
+    build(|asm, t, f| emit_sample(asm, one_in, t, f))
+}
+
+///Runs `filter` on a random sample of one in `one_in` packets, dropping the rest up front
+pub fn sampled(filter: &FilterProgram, one_in: u32) -> Result<FilterProgram> {
+    if one_in <= 1 {
+        return Ok(filter.clone());
+    }
+    let mut asm = Assembler::new();
+    let (run, reject) = (asm.label(), asm.label());
+    emit_sample(&mut asm, one_in, run, reject);
+    asm.bind(run);
+    //jumps are relative, so the program runs unchanged after the sampling check
+    for insn in filter.instructions() {
+        asm.insn(*insn);
+    }
+    asm.bind(reject);
+    asm.ret(0);
+    asm.assemble()
+}
+
+///Keeps at most `snaplen` bytes of every packet, like the snap length of tcpdump
+pub fn snaplen(snaplen: u32) -> FilterProgram {
+    FilterProgram::new(vec![Instruction::new(BPF_RET | BPF_K, 0, 0, snaplen)])
+        .expect("a single return is always valid")
+}
+
+///Runs `filter` and keeps at most `snaplen` bytes of every packet it accepts
+pub fn truncated(filter: &FilterProgram, snaplen: u32) -> Result<FilterProgram> {
+    let insns = filter.instructions();
+    let clamp = insns.len();
+    let mut returns_a = false;
+    let mut out: Vec<Instruction> = insns
+        .iter()
+        .enumerate()
+        .map(|(pc, insn)| {
+            if insn.code == BPF_RET | BPF_K {
+                Instruction::new(insn.code, 0, 0, insn.k.min(snaplen))
+            } else if insn.code == BPF_RET | BPF_A {
+                //the accumulator is clamped after the program, jumps are relative so nothing
+                //else moves
+                returns_a = true;
+                Instruction::new(BPF_JMP | BPF_JA, 0, 0, (clamp - pc - 1) as u32)
+            } else {
+                *insn
+            }
+        })
+        .collect();
+    if returns_a {
+        out.push(Instruction::new(BPF_JMP | BPF_JGT | BPF_K, 0, 1, snaplen));
+        out.push(Instruction::new(BPF_RET | BPF_K, 0, 0, snaplen));
+        out.push(Instruction::new(BPF_RET | BPF_A, 0, 0, 0));
+    }
+    FilterProgram::new(out)
+}
+
+//the emitters below jump to `t` if the packet matches and to `f` if it does not, so that
+//they can be chained into larger expressions
+
+pub(crate) fn emit_sample(asm: &mut Assembler, one_in: u32, t: Label, f: Label) {
+    if one_in <= 1 {
+        asm.ja(t);
+        return;
+    }
+    asm.ld_abs(Size::Word, SKF_AD_OFF + SKF_AD_RANDOM);
+    asm.alu(AluOp::Mod, one_in);
+    asm.jeq(0, t, f);
+}
+
+pub(crate) fn emit_ether_type(asm: &mut Assembler, link: Link, ethertype: u16, t: Label, f: Label) {
+    load_ethertype(asm, link);
+    asm.jeq(ethertype as u32, t, f);
+}
+
+pub(crate) fn emit_ip_proto(
+    asm: &mut Assembler,
+    link: Link,
+    v4: &[u8],
+    v6: &[u8],
+    t: Label,
+    f: Label,
+) {
+    let is_v4 = asm.label();
+    load_ethertype(asm, link);
+    asm.jeq(ETH_P_IPV6, NEXT, is_v4);
+    asm.ld_abs(Size::Byte, link.l3 + IP6_NEXT_HDR);
+    one_of(asm, v6, f);
+    asm.ja(t);
+    asm.bind(is_v4);
+    asm.jeq(ETH_P_IP, NEXT, f);
+    asm.ld_abs(Size::Byte, link.l3 + IP4_PROTO);
+    one_of(asm, v4, f);
+    asm.ja(t);
+}
+
+pub(crate) fn emit_ip_net(
+    asm: &mut Assembler,
+    link: Link,
+    addr: IpAddr,
+    prefix_len: u8,
+    dir: Direction,
+    t: Label,
+    f: Label,
+) {
+    let (ethertype, words, src, dst) = match addr {
+        IpAddr::V4(v4) => (ETH_P_IP, v4_words(v4), IP4_SRC, IP4_DST),
+        IpAddr::V6(v6) => (ETH_P_IPV6, v6_words(v6), IP6_SRC, IP6_DST),
+    };
+    let prefix_len = prefix_len.min(max_prefix(addr)) as u32;
+    let (src, dst) = (link.l3 + src, link.l3 + dst);
+    load_ethertype(asm, link);
+    asm.jeq(ethertype, NEXT, f);
+    match dir {
+        Direction::Src => addr_match(asm, src, &words, prefix_len, t, f),
+        Direction::Dst => addr_match(asm, dst, &words, prefix_len, t, f),
+        Direction::Any => {
+            let try_dst = asm.label();
+            addr_match(asm, src, &words, prefix_len, t, try_dst);
+            asm.bind(try_dst);
+            addr_match(asm, dst, &words, prefix_len, t, f);
+        }
+    }
+}
+
+pub(crate) fn emit_ports(
+    asm: &mut Assembler,
+    link: Link,
+    protocols: &[u8],
+    range: RangeInclusive<u16>,
+    dir: Direction,
+    t: Label,
+    f: Label,
+) {
+    let v4 = asm.label();
+    let (lo, hi) = (*range.start() as u32, *range.end() as u32);
+
+    load_ethertype(asm, link);
+    asm.jeq(ETH_P_IPV6, NEXT, v4);
+    asm.ld_abs(Size::Byte, link.l3 + IP6_NEXT_HDR);
+    one_of(asm, protocols, f);
+    let (sport, dport) = (link.l3 + IP6_SPORT, link.l3 + IP6_DPORT);
+    port_match(asm, false, sport, dport, lo, hi, dir, t, f);
+
+    asm.bind(v4);
+    asm.jeq(ETH_P_IP, NEXT, f);
+    asm.ld_abs(Size::Byte, link.l3 + IP4_PROTO);
+    one_of(asm, protocols, f);
+    //only the first fragment carries the ports
+    asm.ld_abs(Size::Half, link.l3 + IP4_FRAG);
+    asm.jset(0x1fff, f, NEXT);
+    asm.ldx_msh(link.l3);
+    port_match(asm, true, link.l3, link.l3 + 2, lo, hi, dir, t, f);
+}
+
+pub(crate) fn emit_vlan(asm: &mut Assembler, link: Link, id: Option<u16>, t: Label, f: Label) {
+    let (inline, tagged) = (asm.label(), asm.label());
+
+    asm.ld_abs(Size::Word, SKF_AD_OFF + SKF_AD_VLAN_TAG_PRESENT);
+    asm.jeq(0, inline, NEXT);
+    match id {
+        Some(id) => {
+            asm.ld_abs(Size::Word, SKF_AD_OFF + SKF_AD_VLAN_TAG);
+            asm.alu(AluOp::And, 0x0fff);
+            asm.jeq(id as u32 & 0x0fff, t, f);
+        }
+        None => {
+            asm.ja(t);
+        }
+    }
+
+    asm.bind(inline);
+    if link.cooked {
+        //without a link-layer header only stripped tags can be seen
+        asm.ja(f);
+        asm.bind(tagged);
+        return;
+    }
+    asm.ld_abs(Size::Half, ETH_TYPE);
+    asm.jeq(ETH_P_8021Q, tagged, NEXT);
+    asm.jeq(ETH_P_8021AD, tagged, NEXT);
+    asm.jeq(ETH_P_QINQ1, tagged, f);
+    asm.bind(tagged);
+    match id {
+        Some(id) => {
+            asm.ld_abs(Size::Half, ETH_TYPE + 2);
+            asm.alu(AluOp::And, 0x0fff);
+            asm.jeq(id as u32 & 0x0fff, t, f);
+        }
+        None => {
+            asm.ja(t);
+        }
+    }
+}
+
+fn load_ethertype(asm: &mut Assembler, link: Link) {
+    if link.cooked {
+        asm.ld_abs(Size::Word, SKF_AD_OFF + SKF_AD_PROTOCOL);
+    } else {
+        asm.ld_abs(Size::Half, ETH_TYPE);
+    }
+}
+
+//falls through if A is one of `values`, jumps to `fail` otherwise
+fn one_of(asm: &mut Assembler, values: &[u8], fail: Label) {
+    let ok = asm.label();
+    for (i, &value) in values.iter().enumerate() {
+        if i + 1 < values.len() {
+            asm.jeq(value as u32, ok, NEXT);
+        } else {
+            asm.jeq(value as u32, NEXT, fail);
+        }
+    }
+    asm.bind(ok);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn port_match(
+    asm: &mut Assembler,
+    indirect: bool,
+    src: u32,
+    dst: u32,
+    lo: u32,
+    hi: u32,
+    dir: Direction,
+    accept: Label,
+    reject: Label,
+) {
+    let load = |asm: &mut Assembler, offset: u32| {
+        if indirect {
+            asm.ld_ind(Size::Half, offset);
+        } else {
+            asm.ld_abs(Size::Half, offset);
+        }
+    };
+    let check = |asm: &mut Assembler, fail: Label| {
+        if lo == hi {
+            asm.jeq(lo, accept, fail);
+        } else {
+            asm.jge(lo, NEXT, fail);
+            asm.jgt(hi, fail, accept);
+        }
+    };
+    match dir {
+        Direction::Src => {
+            load(asm, src);
+            check(asm, reject);
+        }
+        Direction::Dst => {
+            load(asm, dst);
+            check(asm, reject);
+        }
+        Direction::Any => {
+            let try_dst = asm.label();
+            load(asm, src);
+            check(asm, try_dst);
+            asm.bind(try_dst);
+            load(asm, dst);
+            check(asm, reject);
+        }
+    }
+}
+
+//compares the first `prefix_len` bits of the address at `offset` against `words`
+fn addr_match(
+    asm: &mut Assembler,
+    offset: u32,
+    words: &[u32],
+    prefix_len: u32,
+    accept: Label,
+    fail: Label,
+) {
+    let mut remaining = prefix_len;
+    for (i, &word) in words.iter().enumerate() {
+        if remaining == 0 {
+            break;
+        }
+        let bits = remaining.min(32);
+        remaining -= bits;
+        let mask = if bits == 32 {
+            u32::MAX
+        } else {
+            !(u32::MAX >> bits)
+        };
+        asm.ld_abs(Size::Word, offset + 4 * i as u32);
+        if mask != u32::MAX {
+            asm.alu(AluOp::And, mask);
+        }
+        asm.jeq(word & mask, NEXT, fail);
+    }
+    asm.ja(accept);
+}
+
+fn build<F: FnOnce(&mut Assembler, Label, Label)>(emit: F) -> FilterProgram {
+    let mut asm = Assembler::new();
+    let (accept, reject) = (asm.label(), asm.label());
+    emit(&mut asm, accept, reject);
+    asm.bind(accept);
+    asm.ret(u32::MAX);
+    asm.bind(reject);
+    asm.ret(0);
+    asm.assemble().expect("built-in filters are always valid")
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn v4_words(addr: Ipv4Addr) -> Vec<u32> {
+    vec![u32::from(addr)]
+}
+
+fn v6_words(addr: Ipv6Addr) -> Vec<u32> {
+    addr.octets()
+        .chunks(4)
+        .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bpf::tests::{run, run_with, Meta};
+    use crate::testing::frames::{self, ETH_P_ARP};
+
+    const A: [u8; 4] = [10, 0, 0, 1];
+    const B: [u8; 4] = [192, 168, 1, 7];
+
+    fn accepts(filter: &FilterProgram, frame: &[u8]) -> bool {
+        run(filter, frame) != 0
+    }
+
+    fn v6(last: u16) -> Ipv6Addr {
+        Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, last)
+    }
+
+    #[test]
+    fn protocols() {
+        let tcp4 = frames::tcp4(A, B, 40000, 80);
+        let udp4 = frames::udp4(A, B, 40000, 53);
+        let udp6 = frames::udp6(v6(1), v6(2), 40000, 53);
+        let arp_frame = frames::ethernet(ETH_P_ARP, &[0; 28]);
+
+        assert!(accepts(&ipv4(), &tcp4) && !accepts(&ipv4(), &udp6));
+        assert!(accepts(&ipv6(), &udp6) && !accepts(&ipv6(), &arp_frame));
+        assert!(accepts(&arp(), &arp_frame) && !accepts(&arp(), &tcp4));
+        assert!(accepts(&tcp(), &tcp4) && !accepts(&tcp(), &udp4));
+        assert!(accepts(&udp(), &udp4) && accepts(&udp(), &udp6));
+        assert!(!accepts(&udp(), &arp_frame));
+        assert!(accepts(&ether_type(EtherType::All), &arp_frame));
+    }
+
+    #[test]
+    fn hosts_and_nets() {
+        let frame = frames::udp4(A, B, 40000, 53);
+        assert!(accepts(&ip_host(Ipv4Addr::from(A).into()), &frame));
+        assert!(accepts(&ip_src(Ipv4Addr::from(A).into()), &frame));
+        assert!(!accepts(&ip_dst(Ipv4Addr::from(A).into()), &frame));
+        assert!(accepts(&ip_dst(Ipv4Addr::from(B).into()), &frame));
+
+        let net = |addr: [u8; 4], len, dir| ip_net(Ipv4Addr::from(addr).into(), len, dir);
+        assert!(accepts(&net([192, 168, 0, 0], 16, Direction::Dst), &frame));
+        assert!(!accepts(&net([192, 168, 0, 0], 24, Direction::Dst), &frame));
+        assert!(!accepts(&net([192, 168, 0, 0], 16, Direction::Src), &frame));
+        assert!(accepts(&net([0, 0, 0, 0], 0, Direction::Any), &frame));
+
+        let frame = frames::udp6(v6(1), v6(5), 40000, 53);
+        assert!(accepts(&ip_host(v6(1).into()), &frame));
+        assert!(!accepts(&ip_host(v6(3).into()), &frame));
+        let prefix = IpAddr::from(v6(0));
+        assert!(accepts(&ip_net(prefix, 64, Direction::Dst), &frame));
+        assert!(!accepts(&ip_net(prefix, 127, Direction::Dst), &frame));
+        //an IPv4 host never matches IPv6 packets
+        assert!(!accepts(&ip_host(Ipv4Addr::from(A).into()), &frame));
+    }
+
+    #[test]
+    fn ports_and_ranges() {
+        let tcp = frames::tcp4(A, B, 40000, 80);
+        let udp = frames::udp4(A, B, 40000, 53);
+        let udp6 = frames::udp6(v6(1), v6(2), 40000, 53);
+
+        assert!(accepts(&tcp_port(80), &tcp) && !accepts(&tcp_port(53), &udp));
+        assert!(accepts(&udp_port(53), &udp) && accepts(&udp_port(53), &udp6));
+        assert!(accepts(&port(40000), &tcp) && accepts(&port(40000), &udp));
+        assert!(!accepts(&port(443), &tcp));
+        assert!(accepts(&tcp_port_range(79..=81), &tcp));
+        assert!(!accepts(&udp_port_range(79..=81), &tcp));
+        assert!(accepts(&port_range(50..=60), &udp6));
+        assert!(!accepts(&port_range(81..=1000), &tcp));
+
+        let dst = |range| ports(PORT_PROTOCOLS, range, Direction::Dst);
+        let src = |range| ports(PORT_PROTOCOLS, range, Direction::Src);
+        assert!(accepts(&dst(80..=80), &tcp) && !accepts(&src(80..=80), &tcp));
+        assert!(accepts(&src(40000..=40000), &udp6));
+    }
+
+    #[test]
+    fn ports_skip_later_fragments() {
+        //the first fragment has the UDP header, the rest do not
+        let udp = frames::udp(40000, 53, &[0; 16]);
+        let first = frames::ethernet(0x0800, &frames::ipv4_fragment(7, 0, true, &udp));
+        let rest = frames::ethernet(0x0800, &frames::ipv4_fragment(7, 24, false, &udp));
+        assert!(accepts(&udp_port(53), &first));
+        assert!(!accepts(&udp_port(53), &rest));
+        //the header length comes from the packet, not a fixed 20 bytes
+        let mut options = frames::udp4(A, B, 40000, 53);
+        options[14] = 0x46;
+        options.splice(34..34, [1, 1, 1, 0]);
+        assert!(accepts(&udp_port(53), &options));
+    }
+
+    #[test]
+    fn vlans() {
+        let inline = frames::tagged(42, 0x0800, &[0; 20]);
+        let untagged = frames::udp4(A, B, 40000, 53);
+        assert!(accepts(&vlan(None), &inline) && accepts(&vlan(Some(42)), &inline));
+        assert!(!accepts(&vlan(Some(43)), &inline));
+        assert!(!accepts(&vlan(None), &untagged));
+
+        //a tag the NIC stripped is only visible through the ancillary data
+        let stripped = Meta {
+            protocol: 0x0800,
+            vlan_tci: Some(0x2000 | 42),
+            random: 0,
+        };
+        assert_ne!(run_with(&vlan(Some(42)), &untagged, stripped), 0);
+        assert_eq!(run_with(&vlan(Some(7)), &untagged, stripped), 0);
+    }
+
+    #[test]
+    fn sampling() {
+        let frame = frames::udp4(A, B, 40000, 53);
+        let with = |random| Meta {
+            protocol: 0x0800,
+            vlan_tci: None,
+            random,
+        };
+        assert_ne!(run_with(&sample(4), &frame, with(8)), 0);
+        assert_eq!(run_with(&sample(4), &frame, with(9)), 0);
+        assert_ne!(run_with(&sample(1), &frame, with(9)), 0);
+
+        let udp_sample = sampled(&udp_port(53), 4).unwrap();
+        assert_ne!(run_with(&udp_sample, &frame, with(4)), 0);
+        assert_eq!(run_with(&udp_sample, &frame, with(5)), 0);
+        let other = frames::udp4(A, B, 40000, 54);
+        assert_eq!(run_with(&udp_sample, &other, with(4)), 0);
+    }
+
+    #[test]
+    fn truncation() {
+        let frame = frames::udp4(A, B, 40000, 53);
+        assert_eq!(run(&snaplen(64), &frame), 64);
+        let udp_64 = truncated(&udp_port(53), 64).unwrap();
+        assert_eq!(run(&udp_64, &frame), 64);
+        assert_eq!(run(&udp_64, &frames::udp4(A, B, 40000, 54)), 0);
+
+        //programs returning the accumulator are clamped after they finish
+        let mut asm = Assembler::new();
+        asm.ld_len();
+        asm.ret_a();
+        let len = asm.assemble().unwrap();
+        assert_eq!(run(&truncated(&len, 16).unwrap(), &frame), 16);
+        assert_eq!(
+            run(&truncated(&len, 1000).unwrap(), &frame),
+            frame.len() as u32
+        );
+    }
+}
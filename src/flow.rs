@@ -0,0 +1,438 @@
+//!Per-flow state indexed by the flow hash the kernel already computed for every packet
+//!
+//!Rings fill in the RX hash by default (TP_FT_REQ_FILL_RXHASH), see `RawPacket::rx_hash()`.
+//!`FlowTable` uses it as is to find the bucket of a flow and only compares the 5-tuple to
+//!tell apart flows whose hashes collide, so tracking state costs no hashing in userspace.
+//!Packets without a hash, e.g. read from a file, are hashed from their 5-tuple instead.
+
+use std::collections::hash_map::{DefaultHasher, HashMap};
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime};
+
+use crate::filters::PORT_PROTOCOLS;
+use crate::rx::RawPacket;
+
+///Addresses, ports and IP protocol of a packet
+///
+///Ports are 0 for protocols without them and for IPv4 fragments other than the first one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: u8,
+}
+
+impl FlowKey {
+    ///5-tuple of an IPv4 or IPv6 packet, IPv6 extension headers are not followed
+    pub fn from_packet(packet: &RawPacket) -> Option<FlowKey> {
+        let l3 = packet.l3_payload();
+        let (src, dst, protocol, l4) = match l3.first()? >> 4 {
+            4 if l3.len() >= 20 => {
+                let ihl = (l3[0] & 0x0f) as usize * 4;
+                let first_fragment = u16::from_be_bytes([l3[6], l3[7]]) & 0x1fff == 0;
+                let l4 = if first_fragment { l3.get(ihl..) } else { None };
+                (
+                    IpAddr::V4(Ipv4Addr::new(l3[12], l3[13], l3[14], l3[15])),
+                    IpAddr::V4(Ipv4Addr::new(l3[16], l3[17], l3[18], l3[19])),
+                    l3[9],
+                    l4,
+                )
+            }
+            6 if l3.len() >= 40 => {
+                let addr = |at: usize| {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(&l3[at..at + 16]);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                };
+                (addr(8), addr(24), l3[6], l3.get(40..))
+            }
+            _ => return None,
+        };
+        let (src_port, dst_port) = match l4 {
+            Some(l4) if l4.len() >= 4 && PORT_PROTOCOLS.contains(&protocol) => (
+                u16::from_be_bytes([l4[0], l4[1]]),
+                u16::from_be_bytes([l4[2], l4[3]]),
+            ),
+            _ => (0, 0),
+        };
+        Some(FlowKey {
+            src,
+            dst,
+            src_port,
+            dst_port,
+            protocol,
+        })
+    }
+
+    ///Key of the opposite direction of the flow
+    pub fn reversed(&self) -> FlowKey {
+        FlowKey {
+            src: self.dst,
+            dst: self.src,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            protocol: self.protocol,
+        }
+    }
+
+    ///Hash standing in for the RX hash of packets that came without one
+    pub fn hash32(&self) -> u32 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish() as u32
+    }
+}
+
+///Flows that were idle for too long, removed by `FlowTable::expire()`
+#[derive(Debug)]
+pub struct Expired<V> {
+    pub key: FlowKey,
+    pub value: V,
+    pub last_seen: SystemTime,
+}
+
+#[derive(Debug)]
+struct Entry<V> {
+    key: FlowKey,
+    value: V,
+    last_seen: SystemTime,
+}
+
+//the RX hash is already well distributed, use it as the bucket index as it is
+#[derive(Default)]
+struct RxHashHasher(u64);
+
+impl Hasher for RxHashHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 << 8) | b as u64;
+        }
+    }
+
+    fn write_u32(&mut self, hash: u32) {
+        self.0 = hash as u64;
+    }
+}
+
+///Table of per-flow values keyed by RX hash and 5-tuple
+///
+///Both directions of a connection are separate flows, use `FlowKey::reversed()` to find the
+///other one. Flows are timed by packet timestamps, so offline captures expire like live ones.
+#[derive(Debug)]
+pub struct FlowTable<V> {
+    buckets: HashMap<u32, Vec<Entry<V>>, BuildHasherDefault<RxHashHasher>>,
+    len: usize,
+    idle_timeout: Duration,
+}
+
+impl<V> FlowTable<V> {
+    ///Table whose flows expire after `idle_timeout` without packets
+    pub fn new(idle_timeout: Duration) -> FlowTable<V> {
+        FlowTable {
+            buckets: HashMap::default(),
+            len: 0,
+            idle_timeout,
+        }
+    }
+
+    ///Value of the flow `packet` belongs to, inserted with `init` if the flow is new
+    ///
+    ///Refreshes the flow's last seen time with the packet timestamp. None if the packet is not
+    ///IPv4 or IPv6.
+    pub fn get_or_insert_with<F>(&mut self, packet: &RawPacket, init: F) -> Option<&mut V>
+    where
+        F: FnOnce() -> V,
+    {
+        let key = FlowKey::from_packet(packet)?;
+        let hash = packet.rx_hash().unwrap_or_else(|| key.hash32());
+        let now = packet.timestamp();
+        let bucket = self.buckets.entry(hash).or_default();
+        let index = match bucket.iter().position(|entry| entry.key == key) {
+            Some(index) => index,
+            None => {
+                bucket.push(Entry {
+                    key,
+                    value: init(),
+                    last_seen: now,
+                });
+                self.len += 1;
+                bucket.len() - 1
+            }
+        };
+        let entry = &mut bucket[index];
+        if now > entry.last_seen {
+            entry.last_seen = now;
+        }
+        Some(&mut entry.value)
+    }
+
+    ///Inserts or replaces the value of a flow, returning the old one
+    pub fn insert(&mut self, hash: u32, key: FlowKey, value: V, now: SystemTime) -> Option<V> {
+        let bucket = self.buckets.entry(hash).or_default();
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.key == key) {
+            entry.last_seen = now;
+            return Some(std::mem::replace(&mut entry.value, value));
+        }
+        bucket.push(Entry {
+            key,
+            value,
+            last_seen: now,
+        });
+        self.len += 1;
+        None
+    }
+
+    ///Value of a flow
+    pub fn get(&self, hash: u32, key: &FlowKey) -> Option<&V> {
+        self.buckets
+            .get(&hash)?
+            .iter()
+            .find(|entry| entry.key == *key)
+            .map(|entry| &entry.value)
+    }
+
+    ///Value of a flow, for updating it
+    pub fn get_mut(&mut self, hash: u32, key: &FlowKey) -> Option<&mut V> {
+        self.buckets
+            .get_mut(&hash)?
+            .iter_mut()
+            .find(|entry| entry.key == *key)
+            .map(|entry| &mut entry.value)
+    }
+
+    ///Value of the flow `packet` belongs to
+    pub fn get_for(&self, packet: &RawPacket) -> Option<&V> {
+        let key = FlowKey::from_packet(packet)?;
+        self.get(packet.rx_hash().unwrap_or_else(|| key.hash32()), &key)
+    }
+
+    ///Removes a flow and returns its value
+    pub fn remove(&mut self, hash: u32, key: &FlowKey) -> Option<V> {
+        let bucket = self.buckets.get_mut(&hash)?;
+        let index = bucket.iter().position(|entry| entry.key == *key)?;
+        let entry = bucket.swap_remove(index);
+        if bucket.is_empty() {
+            self.buckets.remove(&hash);
+        }
+        self.len -= 1;
+        Some(entry.value)
+    }
+
+    ///Removes the flows idle for longer than the table's timeout as of `now`
+    ///
+    ///For live captures pass `SystemTime::now()`, for offline ones the latest packet timestamp.
+    pub fn expire(&mut self, now: SystemTime) -> Vec<Expired<V>> {
+        let idle_timeout = self.idle_timeout;
+        let is_idle = |entry: &Entry<V>| {
+            now.duration_since(entry.last_seen)
+                .is_ok_and(|idle| idle > idle_timeout)
+        };
+        let mut expired = Vec::new();
+        self.buckets.retain(|_, bucket| {
+            let mut i = 0;
+            while i < bucket.len() {
+                if is_idle(&bucket[i]) {
+                    let entry = bucket.swap_remove(i);
+                    expired.push(Expired {
+                        key: entry.key,
+                        value: entry.value,
+                        last_seen: entry.last_seen,
+                    });
+                } else {
+                    i += 1;
+                }
+            }
+            !bucket.is_empty()
+        });
+        self.len -= expired.len();
+        expired
+    }
+
+    ///Flows with their values
+    pub fn iter(&self) -> impl Iterator<Item = (&FlowKey, &V)> {
+        self.buckets
+            .values()
+            .flatten()
+            .map(|entry| (&entry.key, &entry.value))
+    }
+
+    ///Number of flows
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    ///Whether the table holds no flows
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    ///Removes every flow
+    pub fn clear(&mut self) {
+        self.buckets.clear();
+        self.len = 0;
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::offline;
+    use crate::pcapng::DLT_EN10MB;
+    use crate::testing::frames::{self, IPPROTO_TCP, IPPROTO_UDP};
+    use crate::testing::MockRing;
+
+    const A: [u8; 4] = [10, 0, 0, 1];
+    const B: [u8; 4] = [10, 0, 0, 2];
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    //queues `frame` received at `secs` with the given RX hash, 0 for none
+    fn push(ring: &mut MockRing, frame: &[u8], secs: u32, rx_hash: u32) {
+        let mut packet = offline::make_packet(DLT_EN10MB, 1, secs, 0, 0, frame.to_vec());
+        packet.hdr.hv1.tp_rxhash = rx_hash;
+        ring.push_packet(packet.hdr, packet.sll, &packet.data);
+    }
+
+    fn keys(frames: &[Vec<u8>]) -> Vec<Option<FlowKey>> {
+        let mut ring = MockRing::new(frames);
+        let block = ring.get_block().unwrap();
+        block
+            .get_raw_packets()
+            .iter()
+            .map(FlowKey::from_packet)
+            .collect()
+    }
+
+    #[test]
+    fn keys_of_packets() {
+        let v6 = |last| Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, last);
+        let fragment = frames::ethernet(0x0800, &frames::ipv4_fragment(1, 8, false, &[0; 8]));
+        let icmp = frames::ethernet(0x0800, &frames::ipv4(1, A, B, &[8, 0, 0, 0]));
+        let keys = keys(&[
+            frames::tcp4(A, B, 40000, 443),
+            frames::udp6(v6(1), v6(2), 5353, 53),
+            fragment,
+            icmp,
+            frames::ethernet(0x0806, &[0; 28]),
+        ]);
+        let tcp = FlowKey {
+            src: IpAddr::from(A),
+            dst: IpAddr::from(B),
+            src_port: 40000,
+            dst_port: 443,
+            protocol: IPPROTO_TCP,
+        };
+        assert_eq!(keys[0], Some(tcp));
+        assert_eq!(
+            keys[1],
+            Some(FlowKey {
+                src: IpAddr::from(v6(1)),
+                dst: IpAddr::from(v6(2)),
+                src_port: 5353,
+                dst_port: 53,
+                protocol: IPPROTO_UDP,
+            })
+        );
+        //no ports in later fragments and in protocols without them
+        assert_eq!(keys[2].map(|k| (k.src_port, k.dst_port)), Some((0, 0)));
+        assert_eq!(keys[3].map(|k| (k.protocol, k.dst_port)), Some((1, 0)));
+        assert_eq!(keys[4], None);
+
+        let reply = tcp.reversed();
+        assert_eq!((reply.src, reply.dst_port), (IpAddr::from(B), 40000));
+        assert_eq!(reply.reversed(), tcp);
+        assert_eq!(tcp.hash32(), tcp.hash32());
+        assert_ne!(tcp.hash32(), reply.hash32());
+    }
+
+    #[test]
+    fn tracks_flows_across_blocks() {
+        let mut ring = MockRing::new(Vec::<Vec<u8>>::new());
+        ring.set_max_packets_per_block(2);
+        for (i, port) in [53, 53, 54, 53, 54].iter().enumerate() {
+            push(&mut ring, &frames::udp4(A, B, 40000, *port), i as u32, 0);
+        }
+        let mut table = FlowTable::new(Duration::from_secs(10));
+        while let Some(block) = ring.get_block() {
+            for packet in block.get_raw_packets() {
+                *table.get_or_insert_with(&packet, || 0).unwrap() += 1;
+            }
+        }
+        assert_eq!(table.len(), 2);
+        let mut counts: Vec<(u16, i32)> = table.iter().map(|(k, v)| (k.dst_port, *v)).collect();
+        counts.sort_unstable();
+        assert_eq!(counts, vec![(53, 3), (54, 2)]);
+    }
+
+    #[test]
+    fn tells_apart_flows_with_the_same_rx_hash() {
+        let mut ring = MockRing::new(Vec::<Vec<u8>>::new());
+        push(&mut ring, &frames::udp4(A, B, 1, 53), 0, 7);
+        push(&mut ring, &frames::tcp4(A, B, 1, 80), 0, 7);
+        push(&mut ring, &frames::udp4(A, B, 1, 53), 0, 7);
+        let mut table = FlowTable::new(Duration::from_secs(10));
+        let block = ring.get_block().unwrap();
+        let packets = block.get_raw_packets();
+        for packet in &packets {
+            table
+                .get_or_insert_with(packet, Vec::new)
+                .unwrap()
+                .push(packet.payload().len());
+        }
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get_for(&packets[0]).map(Vec::len), Some(2));
+        assert_eq!(table.get_for(&packets[1]).map(Vec::len), Some(1));
+
+        let key = FlowKey::from_packet(&packets[1]).unwrap();
+        assert!(table.get(key.hash32(), &key).is_none());
+        assert!(table.get_mut(7, &key).is_some());
+        assert_eq!(table.remove(7, &key).map(|v| v.len()), Some(1));
+        assert!(table.remove(7, &key).is_none());
+        assert!(table.get_for(&packets[0]).is_some());
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn expires_idle_flows_by_packet_time() {
+        let mut ring = MockRing::new(Vec::<Vec<u8>>::new());
+        push(&mut ring, &frames::udp4(A, B, 1, 53), 100, 0);
+        push(&mut ring, &frames::udp4(A, B, 1, 54), 100, 0);
+        push(&mut ring, &frames::udp4(A, B, 1, 53), 108, 0);
+        let mut table = FlowTable::new(Duration::from_secs(10));
+        let block = ring.get_block().unwrap();
+        for packet in block.get_raw_packets() {
+            table.get_or_insert_with(&packet, || ()).unwrap();
+        }
+
+        assert!(table.expire(at(110)).is_empty());
+        let expired = table.expire(at(111));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].key.dst_port, 54);
+        assert_eq!(expired[0].last_seen, at(100));
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.expire(at(119)).len(), 1);
+        assert!(table.is_empty());
+
+        let key = expired[0].key;
+        assert_eq!(table.insert(1, key, (), at(0)), None);
+        assert_eq!(table.insert(1, key, (), at(200)), Some(()));
+        assert_eq!(table.expire(at(205)).len(), 0);
+        table.clear();
+        assert!(table.is_empty() && table.iter().next().is_none());
+    }
+}
@@ -0,0 +1,408 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use libc::{c_int, getpid};
+
+use crate::affinity;
+use crate::error::{Error, Result};
+use crate::ethtool;
+use crate::filter::FilterProgram;
+use crate::rx::{Block, FanoutMethod, RawPacket, Ring, RingSettings};
+use crate::shutdown::ShutdownHandle;
+use crate::source::PacketSource;
+use crate::stats::RingStats;
+
+static NEXT_GROUP: AtomicU16 = AtomicU16::new(0);
+
+///Returns a fanout group id that is not used by any other group created by this process
+pub fn unique_fanout_group() -> u16 {
+    let pid = unsafe { getpid() } as u16;
+    pid.wrapping_add(
+        NEXT_GROUP
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_mul(7919),
+    )
+}
+
+///A set of rings on one interface that share a fanout group, normally one per thread
+///
+///Dropping the group drops its rings; use `into_rings()` to keep them.
+#[derive(Debug)]
+pub struct RingGroup {
+    rings: Vec<Ring>,
+    cpus: Vec<Option<usize>>,
+    fanout_group: u16,
+}
+
+impl RingGroup {
+    ///Creates `n_rings` rings on `if_name` with default settings and the given fanout method
+    pub fn new(if_name: &str, n_rings: usize, fanout_method: FanoutMethod) -> Result<RingGroup> {
+        RingGroup::with_settings(
+            RingSettings {
+                if_name: String::from(if_name),
+                fanout_method,
+                ..RingSettings::default()
+            },
+            n_rings,
+        )
+    }
+
+    ///Creates `n_rings` rings from the same settings; unless `settings.fanout_group` is set
+    ///the group gets a fresh fanout group id
+    pub fn with_settings(mut settings: RingSettings, n_rings: usize) -> Result<RingGroup> {
+        if n_rings == 0 {
+            return Err(Error::InvalidGeometry(String::from(
+                "a ring group needs at least one ring",
+            )));
+        }
+        let fanout_group = *settings
+            .fanout_group
+            .get_or_insert_with(unique_fanout_group);
+        let mut rings = Vec::with_capacity(n_rings);
+        for _ in 0..n_rings {
+            rings.push(Ring::new(settings.clone())?);
+        }
+        Ok(RingGroup {
+            cpus: vec![None; n_rings],
+            rings,
+            fanout_group,
+        })
+    }
+
+    ///Creates one ring per receive queue of the interface in a PACKET_FANOUT_QM group, so that
+    ///ring `i` receives the packets of hardware queue `i`; see `ethtool` to steer flows to
+    ///queues and `affinity::align_irqs()` to keep each queue on the CPU of its ring
+    pub fn per_rx_queue(mut settings: RingSettings) -> Result<RingGroup> {
+        let queues = ethtool::rx_queue_count(&settings.if_name)?;
+        settings.fanout_method = FanoutMethod::Qm;
+        RingGroup::with_settings(settings, queues as usize)
+    }
+
+    ///Assigns ring `i` to CPU `i` modulo the number of online CPUs, see `pin_thread()`
+    pub fn pin_cpus(&mut self) {
+        let online = affinity::online_cpus();
+        for (i, cpu) in self.cpus.iter_mut().enumerate() {
+            *cpu = Some(i % online);
+        }
+    }
+
+    ///Assigns rings to the given CPUs, ring `i` gets `cpus[i % cpus.len()]`
+    pub fn set_cpus(&mut self, cpus: &[usize]) {
+        if cpus.is_empty() {
+            return;
+        }
+        for (i, cpu) in self.cpus.iter_mut().enumerate() {
+            *cpu = Some(cpus[i % cpus.len()]);
+        }
+    }
+
+    ///CPU assigned to a ring, if any
+    pub fn cpu(&self, ring_idx: usize) -> Option<usize> {
+        self.cpus.get(ring_idx).cloned().flatten()
+    }
+
+    ///Pins the calling thread to the CPU assigned to a ring, does nothing if there is none
+    pub fn pin_thread(&self, ring_idx: usize) -> Result<()> {
+        match self.cpu(ring_idx) {
+            Some(cpu) => pin_current_thread(cpu),
+            None => Ok(()),
+        }
+    }
+
+    ///Installs the steering program of a PACKET_FANOUT_CBPF group
+    pub fn set_fanout_cbpf(&mut self, program: &FilterProgram) -> Result<()> {
+        match self.rings.first_mut() {
+            Some(ring) => ring.set_fanout_cbpf(program),
+            None => Ok(()),
+        }
+    }
+
+    ///Installs the steering program of a PACKET_FANOUT_EBPF group
+    pub fn set_fanout_ebpf(&mut self, prog_fd: c_int) -> Result<()> {
+        match self.rings.first_mut() {
+            Some(ring) => ring.set_fanout_ebpf(prog_fd),
+            None => Ok(()),
+        }
+    }
+
+    ///Fanout group id shared by all rings
+    pub fn fanout_group(&self) -> u16 {
+        self.fanout_group
+    }
+
+    ///Number of rings
+    pub fn len(&self) -> usize {
+        self.rings.len()
+    }
+
+    ///Whether the group has no rings, which never happens for a group built by this crate
+    pub fn is_empty(&self) -> bool {
+        self.rings.is_empty()
+    }
+
+    ///Rings of the group
+    pub fn rings(&mut self) -> &mut [Ring] {
+        &mut self.rings
+    }
+
+    ///Gives up the group and returns the rings along with their assigned CPUs
+    pub fn into_rings(self) -> Vec<(Ring, Option<usize>)> {
+        self.rings.into_iter().zip(self.cpus).collect()
+    }
+
+    ///Statistics summed over all rings
+    pub fn statistics(&mut self) -> Result<RingStats> {
+        let mut total = RingStats::default();
+        for ring in &mut self.rings {
+            total.accumulate(&ring.statistics()?);
+        }
+        Ok(total)
+    }
+
+    ///Spawns one thread per ring, pinned to the ring's CPU if one was assigned, that calls
+    ///`f` with the ring index and every packet received
+    ///
+    ///The workers stop on the `ShutdownHandle` of the first ring if it has one, on a new one
+    ///otherwise.
+    ///
+    ///```no_run
+    ///use af_packet::prelude::*;
+    ///
+    ///let mut group = RingGroup::new("eth0", 4, FanoutMethod::Hash)?;
+    ///group.pin_cpus();
+    ///let workers = group.spawn(|ring_idx, packet| {
+    ///    //process frame data here
+    ///})?;
+    ///workers.join();
+    ///# Ok::<(), af_packet::Error>(())
+    ///```
+    pub fn spawn<F>(self, f: F) -> Result<Workers>
+    where
+        F: Fn(usize, &RawPacket) + Send + Sync + 'static,
+    {
+        let shutdown = match self.rings.first().and_then(Ring::get_shutdown_handle) {
+            Some(handle) => handle.clone(),
+            None => ShutdownHandle::new()?,
+        };
+        let f = Arc::new(f);
+        let handles = self
+            .into_rings()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (mut ring, cpu))| {
+                let shutdown = shutdown.clone();
+                let f = f.clone();
+                ring.set_shutdown_handle(shutdown.clone());
+                thread::spawn(move || {
+                    if let Some(cpu) = cpu {
+                        let _ = pin_current_thread(cpu);
+                    }
+                    while let Ok(mut block) = ring.recv_block() {
+                        for packet in block.get_raw_packets() {
+                            f(idx, &packet);
+                        }
+                        block.mark_as_consumed();
+                    }
+                })
+            })
+            .collect();
+        Ok(Workers { shutdown, handles })
+    }
+}
+
+///Threads started by `RingGroup::spawn()`, they are stopped and joined when this is dropped
+#[derive(Debug)]
+pub struct Workers {
+    shutdown: ShutdownHandle,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    ///Asks all workers to stop after the block they are processing
+    pub fn shutdown(&self) {
+        self.shutdown.signal();
+    }
+
+    ///Handle that stops the workers, e.g. to pass to a signal handler thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    ///Waits for all workers to finish; they only finish after `shutdown()` or a panic
+    pub fn join(mut self) {
+        self.join_all();
+    }
+
+    fn join_all(&mut self) {
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.shutdown();
+        self.join_all();
+    }
+}
+
+///Fanout group on one interface that consumers join and leave at any time, e.g. workers
+///scaled up and down with the load
+///
+///Every `subscribe()` opens a ring in the group, which the kernel starts sharing the traffic
+///with; dropping the `Subscription` closes it and the others take over its share. Cloning the
+///set gives another handle to the same group.
+///
+///Hash based fanout spreads flows by the number of members, so most flows move to another
+///ring whenever one joins or leaves. Once the last subscription is gone the kernel removes the
+///group, taking a steering program set with `Ring::set_fanout_cbpf()` with it; the next
+///subscriber starts a new group under the same id.
+///
+///```no_run
+///use af_packet::group::FanoutSet;
+///use af_packet::rx::RingSettings;
+///
+///let set = FanoutSet::new(RingSettings {
+///    if_name: String::from("eth0"),
+///    ..RingSettings::default()
+///})?;
+///let mut worker = set.subscribe()?;
+///let block = worker.ring_mut().recv_block();
+///# Ok::<(), af_packet::Error>(())
+///```
+#[derive(Clone)]
+pub struct FanoutSet {
+    shared: Arc<FanoutShared>,
+}
+
+struct FanoutShared {
+    settings: RingSettings,
+    subscribers: AtomicUsize,
+    shutdown: ShutdownHandle,
+}
+
+///Ring of one consumer of a `FanoutSet`, leaves the group when dropped
+#[derive(Debug)]
+pub struct Subscription {
+    ring: Ring,
+    set: Arc<FanoutShared>,
+}
+
+impl FanoutSet {
+    ///Creates the set without opening any ring; unless `settings.fanout_group` is set it gets
+    ///a fresh fanout group id
+    pub fn new(mut settings: RingSettings) -> Result<FanoutSet> {
+        settings
+            .fanout_group
+            .get_or_insert_with(unique_fanout_group);
+        Ok(FanoutSet {
+            shared: Arc::new(FanoutShared {
+                settings,
+                subscribers: AtomicUsize::new(0),
+                shutdown: ShutdownHandle::new()?,
+            }),
+        })
+    }
+
+    ///Opens a ring in the group for a new consumer
+    pub fn subscribe(&self) -> Result<Subscription> {
+        let mut ring = Ring::new(self.shared.settings.clone())?;
+        ring.set_shutdown_handle(self.shared.shutdown.clone());
+        self.shared.subscribers.fetch_add(1, Ordering::AcqRel);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            group = self.fanout_group(),
+            subscribers = self.len(),
+            "fanout subscriber joined"
+        );
+        Ok(Subscription {
+            ring,
+            set: self.shared.clone(),
+        })
+    }
+
+    ///Fanout group id shared by all subscriptions
+    pub fn fanout_group(&self) -> u16 {
+        self.shared.settings.fanout_group.unwrap_or_default()
+    }
+
+    ///Number of live subscriptions
+    pub fn len(&self) -> usize {
+        self.shared.subscribers.load(Ordering::Acquire)
+    }
+
+    ///Whether no consumer is subscribed
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    ///Makes `recv_block()` return `Error::Shutdown` on every subscription, current and future
+    pub fn shutdown(&self) {
+        self.shared.shutdown.signal();
+    }
+
+    ///Handle that stops all subscriptions, see `shutdown()`
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shared.shutdown.clone()
+    }
+}
+
+impl fmt::Debug for FanoutSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FanoutSet")
+            .field("if_name", &self.shared.settings.if_name)
+            .field("fanout_group", &self.fanout_group())
+            .field("subscribers", &self.len())
+            .finish()
+    }
+}
+
+impl fmt::Debug for FanoutShared {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FanoutShared")
+            .field("fanout_group", &self.settings.fanout_group)
+            .finish()
+    }
+}
+
+impl Subscription {
+    ///Ring of the subscription
+    pub fn ring(&self) -> &Ring {
+        &self.ring
+    }
+
+    ///Ring of the subscription
+    pub fn ring_mut(&mut self) -> &mut Ring {
+        &mut self.ring
+    }
+}
+
+impl PacketSource for Subscription {
+    ///Returns `None` once the set is shut down
+    fn next_block(&mut self) -> Result<Option<Block<'_>>> {
+        match self.ring.recv_block() {
+            Ok(block) => Ok(Some(block)),
+            Err(Error::Shutdown) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.set.subscribers.fetch_sub(1, Ordering::AcqRel);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            group = self.set.settings.fanout_group,
+            "fanout subscriber left"
+        );
+    }
+}
+
+///Pins the calling thread to a single CPU
+pub fn pin_current_thread(cpu: usize) -> Result<()> {
+    affinity::set_current_thread_cpus(&[cpu])
+}
@@ -0,0 +1,519 @@
+//!Network interfaces and their state, to validate or pick capture targets before opening rings,
+//!and `LinkWatcher` to follow them while capturing
+//!
+//!`stats()` reads the interface counters, which include drops in the NIC and its driver
+//!that ring statistics never see.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use libc::{poll, pollfd, IFF_LOOPBACK, IFF_PROMISC, IFF_RUNNING, IFF_UP, POLLIN};
+
+use crate::error::{Error, Result};
+use crate::netlink::{
+    self, Message, NetlinkSocket, Reply, IFINFOMSG_LEN, IFLA_ADDRESS, IFLA_IFNAME, IFLA_MTU,
+    IFLA_OPERSTATE, IFLA_STATS64, NLM_F_DUMP, NLM_F_REQUEST, RTMGRP_LINK, RTM_DELLINK, RTM_GETLINK,
+    RTM_NEWLINK,
+};
+
+///RFC 2863 operational state of an interface (IFLA_OPERSTATE)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OperState {
+    Unknown,
+    NotPresent,
+    Down,
+    LowerLayerDown,
+    Testing,
+    Dormant,
+    Up,
+}
+
+impl From<u8> for OperState {
+    fn from(state: u8) -> OperState {
+        match state {
+            1 => OperState::NotPresent,
+            2 => OperState::Down,
+            3 => OperState::LowerLayerDown,
+            4 => OperState::Testing,
+            5 => OperState::Dormant,
+            6 => OperState::Up,
+            _ => OperState::Unknown,
+        }
+    }
+}
+
+///Snapshot of one interface
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Interface {
+    pub index: i32,
+    pub name: String,
+    pub mtu: u32,
+    ///Hardware address, None for interfaces without an Ethernet address such as tun
+    pub mac: Option<[u8; 6]>,
+    ///IFF_* flags
+    pub flags: u32,
+    pub oper_state: OperState,
+    ///Link speed in Mbit/s, None if the driver does not report one or the link is down
+    pub speed: Option<u32>,
+}
+
+impl Interface {
+    ///Administratively up (IFF_UP)
+    pub fn is_up(&self) -> bool {
+        self.flags & IFF_UP as u32 != 0
+    }
+
+    ///Has a carrier (IFF_RUNNING)
+    pub fn is_running(&self) -> bool {
+        self.flags & IFF_RUNNING as u32 != 0
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        self.flags & IFF_LOOPBACK as u32 != 0
+    }
+
+    ///In promiscuous mode, whether set by flag or held by a socket
+    pub fn is_promiscuous(&self) -> bool {
+        self.flags & IFF_PROMISC as u32 != 0
+    }
+
+    ///Parses an RTM_NEWLINK payload, speed is left unset
+    pub(crate) fn from_message(payload: &[u8]) -> Option<Interface> {
+        if payload.len() < IFINFOMSG_LEN {
+            return None;
+        }
+        let index = i32::from_ne_bytes([payload[4], payload[5], payload[6], payload[7]]);
+        let flags = u32::from_ne_bytes([payload[8], payload[9], payload[10], payload[11]]);
+        let mut iface = Interface {
+            index,
+            name: String::new(),
+            mtu: 0,
+            mac: None,
+            flags,
+            oper_state: OperState::Unknown,
+            speed: None,
+        };
+        for (attr_type, data) in netlink::attrs(&payload[IFINFOMSG_LEN..]) {
+            match attr_type {
+                IFLA_IFNAME => {
+                    let name = data.split(|&b| b == 0).next().unwrap_or_default();
+                    iface.name = String::from_utf8_lossy(name).into_owned();
+                }
+                IFLA_MTU if data.len() == 4 => {
+                    iface.mtu = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]);
+                }
+                IFLA_ADDRESS if data.len() == 6 => {
+                    let mut mac = [0u8; 6];
+                    mac.copy_from_slice(data);
+                    iface.mac = Some(mac);
+                }
+                IFLA_OPERSTATE if !data.is_empty() => iface.oper_state = data[0].into(),
+                _ => {}
+            }
+        }
+        Some(iface)
+    }
+}
+
+///Lists every interface in the calling thread's network namespace
+pub fn list() -> Result<Vec<Interface>> {
+    let mut nl = NetlinkSocket::open(0)?;
+    let mut msg = Message::new(RTM_GETLINK, NLM_F_REQUEST | NLM_F_DUMP);
+    msg.ifinfomsg(0, 0, 0);
+    let mut interfaces: Vec<Interface> = nl
+        .dump(msg)?
+        .iter()
+        .filter_map(|reply| Interface::from_message(&reply.payload))
+        .collect();
+    for iface in interfaces.iter_mut() {
+        iface.speed = speed(&iface.name);
+    }
+    Ok(interfaces)
+}
+
+///Looks up one interface by name
+pub fn by_name(name: &str) -> Result<Interface> {
+    list()?
+        .into_iter()
+        .find(|iface| iface.name == name)
+        .ok_or_else(|| Error::NoSuchInterface(String::from(name)))
+}
+
+///Looks up one interface by index
+pub fn by_index(index: i32) -> Result<Interface> {
+    list()?
+        .into_iter()
+        .find(|iface| iface.index == index)
+        .ok_or_else(|| Error::NoSuchInterface(format!("ifindex {}", index)))
+}
+
+///Interface counters since it was created (struct rtnl_link_stats64), see `stats()`
+///
+///Counters the kernel does not have are 0, e.g. `rx_otherhost_dropped` before Linux 6.0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LinkStats {
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    ///Received but not processed, e.g. for lack of memory or an unknown protocol
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+    pub multicast: u64,
+    pub collisions: u64,
+    pub rx_length_errors: u64,
+    ///Receive ring overflows
+    pub rx_over_errors: u64,
+    pub rx_crc_errors: u64,
+    pub rx_frame_errors: u64,
+    ///Receive FIFO overflows
+    pub rx_fifo_errors: u64,
+    ///Dropped by the NIC for lack of receive buffers, the usual sign of a host too slow to
+    ///keep up
+    pub rx_missed_errors: u64,
+    pub tx_aborted_errors: u64,
+    pub tx_carrier_errors: u64,
+    pub tx_fifo_errors: u64,
+    pub tx_heartbeat_errors: u64,
+    pub tx_window_errors: u64,
+    pub rx_compressed: u64,
+    pub tx_compressed: u64,
+    ///Dropped because no protocol handled them
+    pub rx_nohandler: u64,
+    ///Dropped because they were addressed to another host
+    pub rx_otherhost_dropped: u64,
+}
+
+impl LinkStats {
+    fn from_counters(c: &[u64; 25]) -> LinkStats {
+        LinkStats {
+            rx_packets: c[0],
+            tx_packets: c[1],
+            rx_bytes: c[2],
+            tx_bytes: c[3],
+            rx_errors: c[4],
+            tx_errors: c[5],
+            rx_dropped: c[6],
+            tx_dropped: c[7],
+            multicast: c[8],
+            collisions: c[9],
+            rx_length_errors: c[10],
+            rx_over_errors: c[11],
+            rx_crc_errors: c[12],
+            rx_frame_errors: c[13],
+            rx_fifo_errors: c[14],
+            rx_missed_errors: c[15],
+            tx_aborted_errors: c[16],
+            tx_carrier_errors: c[17],
+            tx_fifo_errors: c[18],
+            tx_heartbeat_errors: c[19],
+            tx_window_errors: c[20],
+            rx_compressed: c[21],
+            tx_compressed: c[22],
+            rx_nohandler: c[23],
+            rx_otherhost_dropped: c[24],
+        }
+    }
+
+    fn counters(&self) -> [u64; 25] {
+        [
+            self.rx_packets,
+            self.tx_packets,
+            self.rx_bytes,
+            self.tx_bytes,
+            self.rx_errors,
+            self.tx_errors,
+            self.rx_dropped,
+            self.tx_dropped,
+            self.multicast,
+            self.collisions,
+            self.rx_length_errors,
+            self.rx_over_errors,
+            self.rx_crc_errors,
+            self.rx_frame_errors,
+            self.rx_fifo_errors,
+            self.rx_missed_errors,
+            self.tx_aborted_errors,
+            self.tx_carrier_errors,
+            self.tx_fifo_errors,
+            self.tx_heartbeat_errors,
+            self.tx_window_errors,
+            self.rx_compressed,
+            self.tx_compressed,
+            self.rx_nohandler,
+            self.rx_otherhost_dropped,
+        ]
+    }
+
+    ///Counters accumulated since `earlier`, a previous snapshot of the same interface
+    ///
+    ///Interface counters only ever grow, unlike ring statistics which are reset when read.
+    pub fn since(&self, earlier: &LinkStats) -> LinkStats {
+        let (now, then) = (self.counters(), earlier.counters());
+        let mut delta = [0; 25];
+        for (i, d) in delta.iter_mut().enumerate() {
+            *d = now[i].saturating_sub(then[i]);
+        }
+        LinkStats::from_counters(&delta)
+    }
+
+    ///Packets lost before they reached any socket: dropped by the stack or missed by the NIC
+    pub fn rx_drops(&self) -> u64 {
+        self.rx_dropped + self.rx_missed_errors
+    }
+}
+
+///Counters of the interface `if_name`, to put the drops of its rings next to those of the NIC
+///and its driver; `ethtool::driver_stats()` has the driver specific ones
+pub fn stats(if_name: &str) -> Result<LinkStats> {
+    let mut nl = NetlinkSocket::open(0)?;
+    let mut msg = Message::new(RTM_GETLINK, NLM_F_REQUEST);
+    msg.ifinfomsg(0, 0, 0);
+    msg.attr_str(IFLA_IFNAME, if_name);
+    let reply = match nl.get(msg) {
+        Ok(reply) => reply,
+        Err(err) if err.io_error().and_then(|e| e.raw_os_error()) == Some(libc::ENODEV) => {
+            return Err(Error::NoSuchInterface(String::from(if_name)))
+        }
+        Err(err) => return Err(err),
+    };
+    let attrs = reply.payload.get(IFINFOMSG_LEN..).unwrap_or_default();
+    let mut counters = [0u64; 25];
+    for (attr_type, data) in netlink::attrs(attrs) {
+        if attr_type == IFLA_STATS64 {
+            for (counter, raw) in counters.iter_mut().zip(data.chunks_exact(8)) {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(raw);
+                *counter = u64::from_ne_bytes(bytes);
+            }
+        }
+    }
+    Ok(LinkStats::from_counters(&counters))
+}
+
+///Change to an interface reported by `LinkWatcher`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkEvent {
+    ///A new interface appeared, possibly one that was removed earlier under the same name
+    Added(Interface),
+    ///The interface is up and has a carrier
+    Up(Interface),
+    ///The interface was taken down or lost its carrier
+    Down(Interface),
+    ///The interface was renamed from `from`
+    Renamed { from: String, iface: Interface },
+    ///The interface is gone
+    Removed(Interface),
+}
+
+impl LinkEvent {
+    ///Interface the event is about, with its state after the change
+    pub fn interface(&self) -> &Interface {
+        match self {
+            LinkEvent::Added(iface)
+            | LinkEvent::Up(iface)
+            | LinkEvent::Down(iface)
+            | LinkEvent::Renamed { iface, .. }
+            | LinkEvent::Removed(iface) => iface,
+        }
+    }
+}
+
+///Follows interfaces coming, going, going up or down and being renamed (RTM_NEWLINK and
+///RTM_DELLINK)
+///
+///The watcher is a plain netlink socket, so besides the blocking `next_event()` it can be
+///driven by any event loop: wait for `as_raw_fd()` to become readable (e.g. with tokio's
+///`AsyncFd`) and call `try_next_event()` until it returns None. With the `async-io` feature
+///`AsyncLinkWatcher` does this for you.
+#[derive(Debug)]
+pub struct LinkWatcher {
+    nl: NetlinkSocket,
+    known: HashMap<i32, Interface>,
+    pending: VecDeque<LinkEvent>,
+    only: Option<String>,
+}
+
+impl LinkWatcher {
+    ///Watches every interface in the calling thread's network namespace
+    pub fn new() -> Result<LinkWatcher> {
+        //subscribe before taking the snapshot so that no change falls in between
+        let nl = NetlinkSocket::open(RTMGRP_LINK)?;
+        let known = list()?
+            .into_iter()
+            .map(|iface| (iface.index, iface))
+            .collect();
+        Ok(LinkWatcher {
+            nl,
+            known,
+            pending: VecDeque::new(),
+            only: None,
+        })
+    }
+
+    ///Only reports events about the interface called `if_name`, including when it is removed
+    ///and a new one with the same name shows up
+    pub fn for_interface(if_name: &str) -> Result<LinkWatcher> {
+        let mut watcher = LinkWatcher::new()?;
+        watcher.only = Some(String::from(if_name));
+        Ok(watcher)
+    }
+
+    ///Last known state of every interface
+    pub fn interfaces(&self) -> impl Iterator<Item = &Interface> {
+        self.known.values()
+    }
+
+    ///Waits for the next event
+    pub fn next_event(&mut self) -> Result<LinkEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+            let replies = self.nl.recv()?;
+            self.process(replies);
+        }
+    }
+
+    ///Like `next_event()`, but gives up and returns None once `timeout` has passed
+    pub fn next_event_timeout(&mut self, timeout: Duration) -> Result<Option<LinkEvent>> {
+        if let Some(event) = self.try_next_event()? {
+            return Ok(Some(event));
+        }
+        let mut pfd = pollfd {
+            fd: self.nl.fd,
+            events: POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        match unsafe { poll(&mut pfd, 1, timeout_ms) } {
+            -1 => Err(Error::last_os_error("poll")),
+            _ => self.try_next_event(),
+        }
+    }
+
+    ///Returns an event if one is queued, without waiting
+    pub fn try_next_event(&mut self) -> Result<Option<LinkEvent>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+            match self.nl.try_recv()? {
+                Some(replies) => self.process(replies),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn process(&mut self, replies: Vec<Reply>) {
+        for reply in replies {
+            let iface = match Interface::from_message(&reply.payload) {
+                Some(iface) => iface,
+                None => continue,
+            };
+            let mut events = Vec::new();
+            match reply.msg_type {
+                RTM_NEWLINK => {
+                    let old = self.known.insert(iface.index, iface.clone());
+                    match old {
+                        None => {
+                            events.push(LinkEvent::Added(iface.clone()));
+                            if is_active(&iface) {
+                                events.push(LinkEvent::Up(iface));
+                            }
+                        }
+                        Some(old) => {
+                            if old.name != iface.name {
+                                events.push(LinkEvent::Renamed {
+                                    from: old.name.clone(),
+                                    iface: iface.clone(),
+                                });
+                            }
+                            match (is_active(&old), is_active(&iface)) {
+                                (false, true) => events.push(LinkEvent::Up(iface)),
+                                (true, false) => events.push(LinkEvent::Down(iface)),
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+                RTM_DELLINK => {
+                    let iface = self.known.remove(&iface.index).unwrap_or(iface);
+                    events.push(LinkEvent::Removed(iface));
+                }
+                _ => {}
+            }
+            let only = self.only.as_deref();
+            self.pending
+                .extend(events.into_iter().filter(|event| match (only, event) {
+                    (None, _) => true,
+                    (Some(name), LinkEvent::Renamed { from, iface }) => {
+                        from == name || iface.name == name
+                    }
+                    (Some(name), event) => event.interface().name == name,
+                }));
+        }
+    }
+}
+
+impl AsRawFd for LinkWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.nl.fd
+    }
+}
+
+///`LinkWatcher` awaited through the `async-io` reactor
+#[cfg(feature = "async-io")]
+#[derive(Debug)]
+pub struct AsyncLinkWatcher {
+    watcher: LinkWatcher,
+    io: async_io::Async<crate::async_ring::RawFdRef>,
+}
+
+#[cfg(feature = "async-io")]
+impl AsyncLinkWatcher {
+    pub fn new(watcher: LinkWatcher) -> Result<AsyncLinkWatcher> {
+        let fd = crate::async_ring::RawFdRef(watcher.as_raw_fd());
+        let io = async_io::Async::new(fd).map_err(|e| Error::os("async-io", e))?;
+        Ok(AsyncLinkWatcher { watcher, io })
+    }
+
+    ///Waits for the next event
+    pub async fn next_event(&mut self) -> Result<LinkEvent> {
+        loop {
+            if let Some(event) = self.watcher.try_next_event()? {
+                return Ok(event);
+            }
+            self.io
+                .readable()
+                .await
+                .map_err(|e| Error::os("async-io", e))?;
+        }
+    }
+
+    pub fn get_ref(&self) -> &LinkWatcher {
+        &self.watcher
+    }
+
+    pub fn into_inner(self) -> LinkWatcher {
+        self.watcher
+    }
+}
+
+//up with a carrier, the state a ring needs to receive anything
+fn is_active(iface: &Interface) -> bool {
+    iface.is_up() && iface.is_running()
+}
+
+//what ethtool reports, -1 or an error when unknown
+pub(crate) fn speed(name: &str) -> Option<u32> {
+    let speed = fs::read_to_string(format!("/sys/class/net/{}/speed", name)).ok()?;
+    match speed.trim().parse::<i64>() {
+        Ok(speed) if speed > 0 && speed < u32::MAX as i64 => Some(speed as u32),
+        _ => None,
+    }
+}
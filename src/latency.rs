@@ -0,0 +1,129 @@
+//!Histograms of how long blocks wait in the ring and how long they take to process, enabled
+//!with the `latency` feature
+//!
+//!Every ring records two durations for each block it hands out, once the block is marked as
+//!consumed: the time since the last packet of the block arrived, and the time since the ring
+//!handed the block out. When the first one keeps growing while the second one stays flat, the
+//!consumer is not called often enough; when both grow, processing the blocks is too slow.
+//!Blocks turned into `SharedBlock`s are not recorded.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use hdrhistogram::Histogram;
+
+//one minute in nanoseconds, longer latencies are recorded as one minute
+const MAX_NANOS: u64 = 60_000_000_000;
+const SIGNIFICANT_DIGITS: u8 = 3;
+
+///Latencies in nanoseconds of the blocks a ring handed out, see `Ring::latency_stats()`
+#[derive(Clone, Debug)]
+pub struct LatencyStats {
+    ///Time from the last packet of a block to the block being consumed
+    pub retire: Histogram<u64>,
+    ///Time from the ring handing a block out to the block being consumed
+    pub processing: Histogram<u64>,
+}
+
+impl LatencyStats {
+    ///Number of blocks recorded
+    pub fn len(&self) -> u64 {
+        self.processing.len()
+    }
+
+    ///Whether no block was recorded yet
+    pub fn is_empty(&self) -> bool {
+        self.processing.is_empty()
+    }
+
+    ///Adds the blocks recorded in `other`, e.g. to sum up several rings
+    pub fn accumulate(&mut self, other: &LatencyStats) {
+        //both have the same bounds, so adding cannot fail
+        let _ = self.retire.add(&other.retire);
+        let _ = self.processing.add(&other.processing);
+    }
+}
+
+impl Default for LatencyStats {
+    fn default() -> LatencyStats {
+        LatencyStats {
+            retire: histogram(),
+            processing: histogram(),
+        }
+    }
+}
+
+impl fmt::Display for LatencyStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} blocks, retire ", self.len())?;
+        percentiles(f, &self.retire)?;
+        f.write_str(", processing ")?;
+        percentiles(f, &self.processing)
+    }
+}
+
+fn histogram() -> Histogram<u64> {
+    Histogram::new_with_max(MAX_NANOS, SIGNIFICANT_DIGITS).expect("valid histogram bounds")
+}
+
+fn percentiles(f: &mut fmt::Formatter, histogram: &Histogram<u64>) -> fmt::Result {
+    write!(
+        f,
+        "p50 {}us p99 {}us max {}us",
+        histogram.value_at_quantile(0.5) / 1000,
+        histogram.value_at_quantile(0.99) / 1000,
+        histogram.max() / 1000
+    )
+}
+
+fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+//histograms shared by a ring, its clones and the blocks it hands out
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LatencyRecorder(Arc<Mutex<LatencyStats>>);
+
+impl LatencyRecorder {
+    //starts timing a block being handed out
+    pub(crate) fn start(&self, last_packet: SystemTime) -> BlockTiming {
+        BlockTiming {
+            recorder: self.clone(),
+            last_packet,
+            handed_out: Instant::now(),
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> LatencyStats {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub(crate) fn reset(&self) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = LatencyStats::default();
+    }
+}
+
+//a block handed out and not consumed yet
+#[derive(Debug)]
+pub(crate) struct BlockTiming {
+    recorder: LatencyRecorder,
+    last_packet: SystemTime,
+    handed_out: Instant,
+}
+
+impl BlockTiming {
+    pub(crate) fn consumed(self) {
+        let processing = nanos(self.handed_out.elapsed());
+        //timestamps taken by the NIC may run ahead of the system clock
+        let retire = nanos(
+            SystemTime::now()
+                .duration_since(self.last_packet)
+                .unwrap_or_default(),
+        );
+        let mut stats = self.recorder.0.lock().unwrap_or_else(|e| e.into_inner());
+        stats.retire.saturating_record(retire);
+        stats.processing.saturating_record(processing);
+    }
+}
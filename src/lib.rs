@@ -2,7 +2,68 @@ extern crate libc;
 #[macro_use]
 extern crate nom;
 
+pub mod adaptive;
+pub mod affinity;
+#[cfg(feature = "async-io")]
+pub mod async_ring;
+pub mod bpf;
+pub mod capture;
+#[cfg(feature = "codec")]
+pub mod codec;
+#[cfg(feature = "defrag")]
+pub mod defrag;
+pub mod dispatch;
+pub mod ebpf;
+mod error;
+pub mod ethtool;
+pub mod fallback;
+pub mod filter;
+pub mod filters;
+pub mod flow;
+pub mod group;
+pub mod iface;
+#[cfg(feature = "latency")]
+pub mod latency;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod monitor;
+mod netlink;
+pub mod netns;
+pub mod numa;
+pub mod offline;
+pub mod pacer;
+#[cfg(feature = "rayon")]
+mod parallel;
+#[cfg(feature = "pcap-filter")]
+pub mod pcap_filter;
+pub mod pcapng;
+#[cfg(feature = "pnet")]
+pub mod pnet;
+pub mod prelude;
+pub mod probe;
+mod promisc;
+pub mod radiotap;
+pub mod reactor;
+pub mod replay;
+pub mod resilient;
 pub mod rx;
+pub mod shared;
+pub mod shutdown;
+pub mod sll;
+pub mod sniffer;
 pub mod socket;
+pub mod source;
+pub mod stats;
+#[cfg(feature = "test_util")]
+pub mod test_util;
+pub mod testing;
+pub mod tpacket2;
 pub mod tpacket3;
+pub mod tunnel;
 pub mod tx;
+#[cfg(feature = "io-uring")]
+pub mod uring;
+pub mod util;
+
+pub use crate::capture::Capture;
+pub use crate::error::{Error, Privilege, Result};
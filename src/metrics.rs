@@ -0,0 +1,100 @@
+//!Ring counters and gauges reported through the `metrics` facade, enabled with the `metrics`
+//!feature
+//!
+//!Every ring registers the metrics below, labelled with `interface`, and updates them at most
+//!once a second as it hands out blocks as well as whenever `Ring::statistics()` is called.
+//!Reading the kernel counters resets them; the ring keeps what it read for the next
+//!`statistics()` call, so both can be used side by side. Install a recorder, e.g.
+//!`metrics-exporter-prometheus`, to export them.
+
+use std::time::{Duration, Instant};
+
+use ::metrics::{counter, gauge, Counter, Gauge};
+
+use crate::tpacket3::TpacketStatsV3;
+
+///Packets seen by the kernel, including dropped ones
+pub const PACKETS: &str = "af_packet_packets_total";
+///Packets dropped by the kernel because the ring was full
+pub const DROPS: &str = "af_packet_drops_total";
+///Times the kernel froze the queue
+pub const FREEZES: &str = "af_packet_freezes_total";
+///Blocks handed out to userspace
+pub const BLOCKS: &str = "af_packet_blocks_total";
+///Blocks skipped according to their sequence numbers
+pub const BLOCKS_LOST: &str = "af_packet_blocks_lost_total";
+///Blocks retired by the kernel and not yet consumed
+pub const READY_BLOCKS: &str = "af_packet_ready_blocks";
+///Fraction of blocks retired by the kernel and not yet consumed, between 0 and 1
+pub const SATURATION: &str = "af_packet_ring_saturation";
+///Blocks handed out per second over the last update interval
+pub const BLOCKS_PER_SECOND: &str = "af_packet_blocks_per_second";
+
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub(crate) struct RingMetrics {
+    packets: Counter,
+    drops: Counter,
+    freezes: Counter,
+    blocks: Counter,
+    blocks_lost: Counter,
+    ready_blocks: Gauge,
+    saturation: Gauge,
+    blocks_per_second: Gauge,
+    last_update: Instant,
+    blocks_since_update: u64,
+    blocks_lost_seen: u64,
+}
+
+impl RingMetrics {
+    pub(crate) fn new(if_name: &str) -> RingMetrics {
+        let label = || ("interface", if_name.to_owned());
+        RingMetrics {
+            packets: counter!(PACKETS, &[label()]),
+            drops: counter!(DROPS, &[label()]),
+            freezes: counter!(FREEZES, &[label()]),
+            blocks: counter!(BLOCKS, &[label()]),
+            blocks_lost: counter!(BLOCKS_LOST, &[label()]),
+            ready_blocks: gauge!(READY_BLOCKS, &[label()]),
+            saturation: gauge!(SATURATION, &[label()]),
+            blocks_per_second: gauge!(BLOCKS_PER_SECOND, &[label()]),
+            last_update: Instant::now(),
+            blocks_since_update: 0,
+            blocks_lost_seen: 0,
+        }
+    }
+
+    ///Counts a block handed out, `blocks_lost` is the ring's running total; true if the kernel
+    ///counters are due to be read
+    pub(crate) fn block(&mut self, blocks_lost: u64) -> bool {
+        self.blocks.increment(1);
+        if blocks_lost > self.blocks_lost_seen {
+            self.blocks_lost
+                .increment(blocks_lost - self.blocks_lost_seen);
+            self.blocks_lost_seen = blocks_lost;
+        }
+        self.blocks_since_update += 1;
+        self.last_update.elapsed() >= UPDATE_INTERVAL
+    }
+
+    ///Records kernel counters read by the ring
+    pub(crate) fn poll(&mut self, kstats: &TpacketStatsV3, ready_blocks: u32, total_blocks: u32) {
+        self.packets.increment(kstats.tp_packets as u64);
+        self.drops.increment(kstats.tp_drops as u64);
+        self.freezes.increment(kstats.tp_freeze_q_cnt as u64);
+        self.ready_blocks.set(ready_blocks);
+        if total_blocks > 0 {
+            self.saturation
+                .set(ready_blocks as f64 / total_blocks as f64);
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+        if elapsed > 0.0 {
+            self.blocks_per_second
+                .set(self.blocks_since_update as f64 / elapsed);
+        }
+        self.blocks_since_update = 0;
+        self.last_update = now;
+    }
+}
@@ -0,0 +1,214 @@
+//!Background thread sampling ring statistics and raising alerts on drops and saturation
+//!
+//!The monitor works on clones of the rings, which share their sockets and memory with the
+//!originals. Kernel counters are reset every time they are read, so once a ring is monitored
+//!the monitor owns its counters: `Ring::statistics()` on the original only covers what was
+//!counted since the monitor's last sample.
+
+use std::fmt;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::rx::Ring;
+use crate::stats::RingStats;
+
+///Settings of a stats monitor
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MonitorSettings {
+    ///Time between two samples
+    pub interval: Duration,
+    ///Drop rate, between 0 and 1, above which an alert is raised; None to not watch drops
+    pub drop_rate: Option<f64>,
+    ///Saturation, between 0 and 1, above which an alert is raised; None to not watch it
+    pub saturation: Option<f64>,
+}
+
+impl Default for MonitorSettings {
+    fn default() -> MonitorSettings {
+        MonitorSettings {
+            interval: Duration::from_secs(1),
+            drop_rate: Some(0.01),
+            saturation: Some(0.9),
+        }
+    }
+}
+
+///Threshold an alert is about
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    DropRate,
+    Saturation,
+}
+
+///A ring's drop rate or saturation crossed its threshold
+#[derive(Clone, Debug)]
+pub struct Alert {
+    ///Index of the ring in the monitored rings
+    pub ring: usize,
+    pub kind: AlertKind,
+    ///True when the value went above the threshold, false when it went back below it
+    pub raised: bool,
+    ///Sample that crossed the threshold
+    pub stats: RingStats,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (what, value) = match self.kind {
+            AlertKind::DropRate => ("drop rate", self.stats.drop_rate()),
+            AlertKind::Saturation => ("saturation", self.stats.saturation()),
+        };
+        write!(
+            f,
+            "ring {}: {} {} at {:.2}%",
+            self.ring,
+            what,
+            if self.raised {
+                "above threshold"
+            } else {
+                "back to normal"
+            },
+            value * 100.0
+        )
+    }
+}
+
+///Thread sampling the statistics of a set of rings, see `spawn()` and `channel()`
+///
+///Stopped and joined when dropped.
+pub struct StatsMonitor {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<Vec<Ring>>>,
+}
+
+impl StatsMonitor {
+    ///Samples `rings` every `settings.interval` and calls `on_alert` whenever a ring's drop
+    ///rate or saturation crosses its threshold in either direction
+    ///
+    ///The rings are usually clones of the ones being read.
+    pub fn spawn<F>(
+        rings: Vec<Ring>,
+        settings: MonitorSettings,
+        on_alert: F,
+    ) -> Result<StatsMonitor>
+    where
+        F: FnMut(Alert) + Send + 'static,
+    {
+        if rings.is_empty() {
+            return Err(Error::InvalidGeometry(String::from(
+                "a stats monitor needs at least one ring",
+            )));
+        }
+        if settings.interval.is_zero() {
+            return Err(Error::InvalidGeometry(String::from(
+                "a stats monitor needs a non-zero interval",
+            )));
+        }
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        let handle = thread::spawn(move || run(rings, settings, &thread_stop, on_alert));
+        Ok(StatsMonitor {
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    ///Like `spawn()`, sending alerts on a channel instead
+    pub fn channel(
+        rings: Vec<Ring>,
+        settings: MonitorSettings,
+    ) -> Result<(StatsMonitor, Receiver<Alert>)> {
+        let (tx, rx) = mpsc::channel();
+        let monitor = StatsMonitor::spawn(rings, settings, move |alert| {
+            let _ = tx.send(alert);
+        })?;
+        Ok((monitor, rx))
+    }
+
+    ///Stops the thread and gives the rings back
+    pub fn stop(mut self) -> Vec<Ring> {
+        self.shutdown().unwrap_or_default()
+    }
+
+    fn shutdown(&mut self) -> Option<Vec<Ring>> {
+        let (stopped, wakeup) = &*self.stop;
+        *stopped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        wakeup.notify_all();
+        self.handle.take()?.join().ok()
+    }
+}
+
+impl Drop for StatsMonitor {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl fmt::Debug for StatsMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StatsMonitor")
+            .field("running", &self.handle.is_some())
+            .finish()
+    }
+}
+
+fn run<F>(
+    mut rings: Vec<Ring>,
+    settings: MonitorSettings,
+    stop: &(Mutex<bool>, Condvar),
+    mut on_alert: F,
+) -> Vec<Ring>
+where
+    F: FnMut(Alert),
+{
+    //which alerts are currently raised, per ring
+    let mut raised = vec![[false; 2]; rings.len()];
+    //the first read only resets whatever the counters held before
+    for ring in rings.iter_mut() {
+        let _ = ring.statistics();
+    }
+    let (stopped, wakeup) = stop;
+    loop {
+        let guard = stopped.lock().unwrap_or_else(|e| e.into_inner());
+        let (guard, _) = wakeup
+            .wait_timeout_while(guard, settings.interval, |stopped| !*stopped)
+            .unwrap_or_else(|e| e.into_inner());
+        if *guard {
+            return rings;
+        }
+        drop(guard);
+        for (i, ring) in rings.iter_mut().enumerate() {
+            let stats = match ring.statistics() {
+                Ok(stats) => stats,
+                Err(_) => continue,
+            };
+            let checks = [
+                (AlertKind::DropRate, settings.drop_rate, stats.drop_rate()),
+                (
+                    AlertKind::Saturation,
+                    settings.saturation,
+                    stats.saturation(),
+                ),
+            ];
+            for (j, &(kind, threshold, value)) in checks.iter().enumerate() {
+                let threshold = match threshold {
+                    Some(threshold) => threshold,
+                    None => continue,
+                };
+                let above = value > threshold;
+                if above != raised[i][j] {
+                    raised[i][j] = above;
+                    on_alert(Alert {
+                        ring: i,
+                        kind,
+                        raised: above,
+                        stats: stats.clone(),
+                    });
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//!Minimal rtnetlink client, just enough to query and manage links
+
+//the link management half is only used by `test_util`
+#![cfg_attr(not(feature = "test_util"), allow(dead_code))]
+
+use std::io;
+use std::mem;
+
+use libc::{bind, c_int, c_void, close, recv, send, sockaddr, sockaddr_nl, socket, AF_NETLINK};
+use libc::{EAGAIN, MSG_DONTWAIT, SOCK_CLOEXEC, SOCK_RAW};
+
+use crate::error::{Error, Result};
+
+const NETLINK_ROUTE: c_int = 0;
+
+pub const NLMSG_HDRLEN: usize = 16;
+pub const NLMSG_ERROR: u16 = 2;
+pub const NLMSG_DONE: u16 = 3;
+
+pub const NLM_F_REQUEST: u16 = 1;
+pub const NLM_F_ACK: u16 = 4;
+pub const NLM_F_DUMP: u16 = 0x300;
+pub const NLM_F_EXCL: u16 = 0x200;
+pub const NLM_F_CREATE: u16 = 0x400;
+
+pub const RTM_NEWLINK: u16 = 16;
+pub const RTM_DELLINK: u16 = 17;
+pub const RTM_GETLINK: u16 = 18;
+
+pub const RTMGRP_LINK: u32 = 1;
+
+pub const IFLA_ADDRESS: u16 = 1;
+
+pub const IFLA_IFNAME: u16 = 3;
+pub const IFLA_MTU: u16 = 4;
+pub const IFLA_OPERSTATE: u16 = 16;
+pub const IFLA_STATS64: u16 = 23;
+pub const IFLA_LINKINFO: u16 = 18;
+pub const IFLA_INFO_KIND: u16 = 1;
+pub const IFLA_INFO_DATA: u16 = 2;
+
+pub const IFINFOMSG_LEN: usize = 16;
+
+const NLA_F_NESTED: u16 = 1 << 15;
+const RECV_BUF_LEN: usize = 32768;
+
+#[inline]
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+///Builds a netlink request: header, fixed payload and attributes
+#[derive(Clone, Debug)]
+pub struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(msg_type: u16, flags: u16) -> Message {
+        let mut buf = vec![0; NLMSG_HDRLEN];
+        buf[4..6].copy_from_slice(&msg_type.to_ne_bytes());
+        buf[6..8].copy_from_slice(&flags.to_ne_bytes());
+        Message { buf }
+    }
+
+    ///Appends a struct ifinfomsg
+    pub fn ifinfomsg(&mut self, index: i32, flags: u32, change: u32) {
+        let mut info = [0u8; IFINFOMSG_LEN];
+        info[4..8].copy_from_slice(&index.to_ne_bytes());
+        info[8..12].copy_from_slice(&flags.to_ne_bytes());
+        info[12..16].copy_from_slice(&change.to_ne_bytes());
+        self.raw(&info);
+    }
+
+    ///Appends an attribute
+    pub fn attr(&mut self, attr_type: u16, data: &[u8]) {
+        self.push_attr(attr_type, data);
+    }
+
+    ///Appends a NUL terminated string attribute
+    pub fn attr_str(&mut self, attr_type: u16, value: &str) {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.attr(attr_type, &data);
+    }
+
+    ///Starts a nested attribute, close it with `end_nested()`
+    pub fn begin_nested(&mut self, attr_type: u16) -> usize {
+        let start = self.buf.len();
+        self.push_attr(attr_type | NLA_F_NESTED, &[]);
+        start
+    }
+
+    ///Fixes up the length of a nested attribute started with `begin_nested()`
+    pub fn end_nested(&mut self, start: usize) {
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    ///Appends raw bytes, e.g. a struct ifinfomsg inside a nested attribute
+    pub fn raw(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align4(self.buf.len()), 0);
+    }
+
+    fn push_attr(&mut self, attr_type: u16, data: &[u8]) {
+        let len = (4 + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&attr_type.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align4(self.buf.len()), 0);
+    }
+
+    fn finish(&mut self, seq: u32) -> &[u8] {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        &self.buf
+    }
+}
+
+///A message received from the kernel
+#[derive(Clone, Debug)]
+pub struct Reply {
+    pub msg_type: u16,
+    pub payload: Vec<u8>,
+}
+
+///NETLINK_ROUTE socket, closed when dropped
+#[derive(Debug)]
+pub struct NetlinkSocket {
+    pub fd: c_int,
+    seq: u32,
+}
+
+impl NetlinkSocket {
+    ///Opens a socket subscribed to the given multicast groups (RTMGRP_*), 0 for none
+    pub fn open(groups: u32) -> Result<NetlinkSocket> {
+        let fd = unsafe { socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, NETLINK_ROUTE) };
+        if fd < 0 {
+            return Err(Error::last_os_error("netlink socket"));
+        }
+        let sock = NetlinkSocket { fd, seq: 0 };
+        let mut sa: sockaddr_nl = unsafe { mem::zeroed() };
+        sa.nl_family = AF_NETLINK as u16;
+        sa.nl_groups = groups;
+        let addr_ptr = &sa as *const sockaddr_nl as *const sockaddr;
+        match unsafe { bind(fd, addr_ptr, mem::size_of_val(&sa) as u32) } {
+            0 => Ok(sock),
+            _ => Err(Error::last_os_error("netlink bind")),
+        }
+    }
+
+    ///Sends a request and waits for the kernel to acknowledge it
+    pub fn request(&mut self, mut msg: Message) -> Result<()> {
+        self.seq += 1;
+        let seq = self.seq;
+        self.send(msg.finish(seq))?;
+        loop {
+            for reply in self.recv()? {
+                if reply.msg_type == NLMSG_ERROR {
+                    return check_ack(&reply.payload);
+                }
+            }
+        }
+    }
+
+    ///Sends a request for a single object and returns the kernel's answer
+    pub fn get(&mut self, mut msg: Message) -> Result<Reply> {
+        self.seq += 1;
+        let seq = self.seq;
+        self.send(msg.finish(seq))?;
+        loop {
+            for reply in self.recv()? {
+                match reply.msg_type {
+                    NLMSG_ERROR => check_ack(&reply.payload)?,
+                    _ => return Ok(reply),
+                }
+            }
+        }
+    }
+
+    ///Sends a dump request and collects every reply until the kernel is done
+    pub fn dump(&mut self, mut msg: Message) -> Result<Vec<Reply>> {
+        self.seq += 1;
+        let seq = self.seq;
+        self.send(msg.finish(seq))?;
+        let mut replies = Vec::new();
+        loop {
+            for reply in self.recv()? {
+                match reply.msg_type {
+                    NLMSG_DONE => return Ok(replies),
+                    NLMSG_ERROR => check_ack(&reply.payload)?,
+                    _ => replies.push(reply),
+                }
+            }
+        }
+    }
+
+    ///Receives one datagram worth of messages
+    pub fn recv(&mut self) -> Result<Vec<Reply>> {
+        self.recv_with_flags(0)
+    }
+
+    ///Like `recv()`, but returns None instead of blocking when nothing is queued
+    pub fn try_recv(&mut self) -> Result<Option<Vec<Reply>>> {
+        match self.recv_with_flags(MSG_DONTWAIT) {
+            Ok(replies) => Ok(Some(replies)),
+            Err(err) if err.io_error().and_then(|e| e.raw_os_error()) == Some(EAGAIN) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn recv_with_flags(&mut self, flags: c_int) -> Result<Vec<Reply>> {
+        let mut buf = vec![0u8; RECV_BUF_LEN];
+        let len = unsafe { recv(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len(), flags) };
+        if len < 0 {
+            return Err(Error::last_os_error("netlink recv"));
+        }
+        let mut data = &buf[..len as usize];
+        let mut replies = Vec::new();
+        while data.len() >= NLMSG_HDRLEN {
+            let msg_len = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            if msg_len < NLMSG_HDRLEN || msg_len > data.len() {
+                break;
+            }
+            replies.push(Reply {
+                msg_type: u16::from_ne_bytes([data[4], data[5]]),
+                payload: data[NLMSG_HDRLEN..msg_len].to_vec(),
+            });
+            data = &data[align4(msg_len).min(data.len())..];
+        }
+        Ok(replies)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> Result<()> {
+        match unsafe { send(self.fd, buf.as_ptr() as *const c_void, buf.len(), 0) } {
+            -1 => Err(Error::last_os_error("netlink send")),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.fd);
+        }
+    }
+}
+
+///Walks the attributes in `data`, yielding their type (without flags) and payload
+pub fn attrs(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < 4 {
+            return None;
+        }
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let attr_type = u16::from_ne_bytes([data[2], data[3]]) & !NLA_F_NESTED;
+        if len < 4 || len > data.len() {
+            return None;
+        }
+        let payload = &data[4..len];
+        data = &data[align4(len).min(data.len())..];
+        Some((attr_type, payload))
+    })
+}
+
+fn check_ack(payload: &[u8]) -> Result<()> {
+    if payload.len() < 4 {
+        return Ok(());
+    }
+    match i32::from_ne_bytes([payload[0], payload[1], payload[2], payload[3]]) {
+        0 => Ok(()),
+        errno => Err(Error::os("netlink", io::Error::from_raw_os_error(-errno))),
+    }
+}
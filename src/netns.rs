@@ -0,0 +1,67 @@
+//!Network namespaces, so rings can be opened on interfaces that live in other namespaces, e.g.
+//!inside containers
+//!
+//!A packet socket stays in the namespace it was created in, so only the calling thread switches
+//!namespaces, and only while the socket is being set up.
+
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+
+use libc::{setns, CLONE_NEWNET};
+
+use crate::error::{Error, Result};
+
+///Network namespace to open a ring in
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NetNs {
+    ///Namespace file such as `/proc/<pid>/ns/net` or `/var/run/netns/<name>`
+    Path(PathBuf),
+    ///Open namespace descriptor, owned by the caller
+    Fd(RawFd),
+}
+
+impl NetNs {
+    ///Namespace of a running process, e.g. a container's init
+    pub fn from_pid(pid: i32) -> NetNs {
+        NetNs::Path(PathBuf::from(format!("/proc/{}/ns/net", pid)))
+    }
+
+    ///Namespace created with `ip netns add <name>`
+    pub fn named(name: &str) -> NetNs {
+        NetNs::Path(PathBuf::from("/var/run/netns").join(name))
+    }
+}
+
+///Runs `f` on the calling thread inside `ns`, then switches the thread back to its original
+///namespace
+///
+///Needs CAP_SYS_ADMIN. If switching back fails the thread is left in `ns` and an error is
+///returned, even if `f` succeeded.
+pub fn run_in<T, F>(ns: &NetNs, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    let original = File::open("/proc/thread-self/ns/net").map_err(|e| Error::os("open", e))?;
+    //keeps the namespace file open until the switch is done
+    let (_file, target_fd) = match ns {
+        NetNs::Path(path) => {
+            let file = File::open(path).map_err(|e| Error::os("open", e))?;
+            let fd = file.as_raw_fd();
+            (Some(file), fd)
+        }
+        NetNs::Fd(fd) => (None, *fd),
+    };
+
+    enter(target_fd)?;
+    let result = f();
+    enter(original.as_raw_fd())?;
+    result
+}
+
+fn enter(fd: RawFd) -> Result<()> {
+    match unsafe { setns(fd, CLONE_NEWNET) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error("setns")),
+    }
+}
@@ -0,0 +1,86 @@
+//!NUMA placement of rings and of the threads reading them
+//!
+//!A ring's blocks are allocated by the kernel when the ring is set up, from the node of the
+//!CPU doing the setup. `RingSettings::numa_node` therefore runs the setup on the node's CPUs and
+//!additionally binds the mapping to the node, and `pin_current_thread_to_node()` keeps the
+//!consuming thread next to it.
+
+use std::fs;
+use std::io;
+use std::mem;
+
+use libc::{c_ulong, c_void, cpu_set_t, sched_getaffinity, sched_setaffinity, syscall, SYS_mbind};
+
+use crate::affinity::{parse_cpu_list, set_current_thread_cpus};
+use crate::error::{Error, Result};
+
+const MPOL_BIND: c_ulong = 2;
+const MPOL_MF_MOVE: c_ulong = 1 << 1;
+
+///CPUs of a NUMA node
+pub fn node_cpus(node: u32) -> Result<Vec<usize>> {
+    let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    let list = fs::read_to_string(path).map_err(|e| Error::os("read cpulist", e))?;
+    parse_cpu_list(list.trim()).ok_or_else(|| {
+        Error::os(
+            "read cpulist",
+            io::Error::new(io::ErrorKind::InvalidData, list.trim().to_string()),
+        )
+    })
+}
+
+///NUMA node the NIC behind `if_name` is attached to, None for virtual interfaces and machines
+///with a single node
+pub fn interface_node(if_name: &str) -> Option<u32> {
+    let path = format!("/sys/class/net/{}/device/numa_node", if_name);
+    //-1 when the platform does not say
+    let node: i32 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    if node < 0 {
+        return None;
+    }
+    Some(node as u32)
+}
+
+///Pins the calling thread to the CPUs of a NUMA node
+pub fn pin_current_thread_to_node(node: u32) -> Result<()> {
+    set_current_thread_cpus(&node_cpus(node)?)
+}
+
+///Runs `f` with the calling thread pinned to the CPUs of `node`, then restores its affinity
+pub(crate) fn run_on_node<T, F>(node: u32, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    let mut original: cpu_set_t = unsafe { mem::zeroed() };
+    if unsafe { sched_getaffinity(0, mem::size_of::<cpu_set_t>(), &mut original) } != 0 {
+        return Err(Error::last_os_error("sched_getaffinity"));
+    }
+    pin_current_thread_to_node(node)?;
+    let result = f();
+    if unsafe { sched_setaffinity(0, mem::size_of::<cpu_set_t>(), &original) } != 0 {
+        return Err(Error::last_os_error("sched_setaffinity"));
+    }
+    result
+}
+
+///Binds `len` bytes of memory at `addr` to `node` (MPOL_BIND), moving pages that can be moved
+pub(crate) fn bind_memory(addr: *mut u8, len: usize, node: u32) -> Result<()> {
+    let bits = 8 * mem::size_of::<c_ulong>();
+    let mut mask = vec![0 as c_ulong; node as usize / bits + 1];
+    mask[node as usize / bits] |= 1 << (node as usize % bits);
+    match unsafe {
+        syscall(
+            SYS_mbind,
+            addr as *mut c_void,
+            len,
+            MPOL_BIND,
+            mask.as_ptr(),
+            //maxnode counts one past the highest bit
+            mask.len() * bits + 1,
+            MPOL_MF_MOVE,
+        )
+    } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error("mbind")),
+    }
+}
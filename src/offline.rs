@@ -0,0 +1,589 @@
+//!Offline packet source reading pcap and pcapng files
+//!
+//!Packets are laid out in TPACKET_V3 blocks exactly like a live ring would hand them out, so
+//!code written against `Block` and `RawPacket` works unchanged on recorded traffic.
+
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::pcapng::DLT_EN10MB;
+use crate::radiotap::ARPHRD_IEEE80211_RADIOTAP;
+use crate::rx::Block;
+use crate::sll::{DLT_LINUX_SLL, SLL_HDR_LEN};
+use crate::tpacket3::{BlockBuilder, SockAddrLl, TpStatus, Tpacket3Hdr};
+
+///pcap link type of captures without a link-layer header
+pub const DLT_RAW: u16 = 101;
+///pcap link type of 802.11 captures with a radiotap header
+pub const DLT_IEEE802_11_RADIOTAP: u16 = 127;
+
+const PCAP_MAGIC_USEC: u32 = 0xA1B2_C3D4;
+const PCAP_MAGIC_NSEC: u32 = 0xA1B2_3C4D;
+const PCAPNG_SHB: u32 = 0x0A0D_0D0A;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const PCAPNG_IDB: u32 = 1;
+const PCAPNG_SPB: u32 = 3;
+const PCAPNG_EPB: u32 = 6;
+const PCAPNG_OPT_IF_TSRESOL: u16 = 9;
+
+const ARPHRD_ETHER: u16 = 1;
+const ARPHRD_NONE: u16 = 0xFFFE;
+
+///Default size of the blocks packets are laid out in
+pub const DEFAULT_BLOCK_SIZE: usize = 1 << 20;
+
+#[derive(Clone, Debug)]
+struct Interface {
+    link_type: u16,
+    units_per_sec: u64,
+    snaplen: u32,
+}
+
+#[derive(Clone, Debug)]
+enum Format {
+    Pcap { nanos: bool },
+    Pcapng { interfaces: Vec<Interface> },
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Packet {
+    pub(crate) hdr: Tpacket3Hdr,
+    pub(crate) sll: SockAddrLl,
+    pub(crate) data: Vec<u8>,
+}
+
+///Reads packets from a pcap or pcapng file and hands them out block by block
+pub struct Ring {
+    reader: Box<dyn Read + Send>,
+    format: Format,
+    swapped: bool,
+    link_type: u16,
+    snaplen: u32,
+    block_size: usize,
+    seq_num: u64,
+    pending: Option<Packet>,
+    buf: Vec<u8>,
+}
+
+impl Ring {
+    ///Opens a pcap or pcapng file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Ring> {
+        let file = File::open(path).map_err(|e| Error::os("open", e))?;
+        Ring::from_reader(BufReader::new(file))
+    }
+
+    ///Reads a pcap or pcapng stream, the format is detected from its first bytes
+    pub fn from_reader<R: Read + Send + 'static>(reader: R) -> Result<Ring> {
+        let mut ring = Ring {
+            reader: Box::new(reader),
+            format: Format::Pcap { nanos: false },
+            swapped: false,
+            link_type: DLT_EN10MB,
+            snaplen: 0,
+            block_size: DEFAULT_BLOCK_SIZE,
+            seq_num: 1,
+            pending: None,
+            buf: Vec::new(),
+        };
+        let magic = ring.read_bytes(4)?.ok_or_else(|| bad("empty file"))?;
+        let magic = u32::from_le_bytes([magic[0], magic[1], magic[2], magic[3]]);
+        match magic {
+            PCAP_MAGIC_USEC | PCAP_MAGIC_NSEC => ring.read_pcap_header(magic, false)?,
+            m if m.swap_bytes() == PCAP_MAGIC_USEC || m.swap_bytes() == PCAP_MAGIC_NSEC => {
+                ring.read_pcap_header(m.swap_bytes(), true)?
+            }
+            PCAPNG_SHB => {
+                ring.format = Format::Pcapng {
+                    interfaces: Vec::new(),
+                };
+                ring.read_pcapng_shb()?;
+                //the link type is in the interface descriptions, read up to the first packet
+                ring.pending = ring.read_pcapng_packet()?;
+            }
+            _ => return Err(bad("not a pcap or pcapng file")),
+        }
+        Ok(ring)
+    }
+
+    ///Size of the blocks packets are laid out in, packets larger than a block are truncated
+    pub fn set_block_size(&mut self, block_size: usize) {
+        self.block_size = block_size;
+    }
+
+    ///pcap link type of the file, for pcapng files the one of the first interface
+    pub fn link_type(&self) -> u16 {
+        self.link_type
+    }
+
+    ///Snapshot length recorded in the file
+    pub fn snaplen(&self) -> u32 {
+        self.snaplen
+    }
+
+    ///Returns the next block of packets, or `None` once the whole file was read
+    pub fn get_block(&mut self) -> Result<Option<Block<'_>>> {
+        let mut builder = BlockBuilder::new(self.block_size, self.seq_num);
+        loop {
+            let mut packet = match self.pending.take() {
+                Some(packet) => packet,
+                None => match self.read_packet()? {
+                    Some(packet) => packet,
+                    None => break,
+                },
+            };
+            if builder.push(&packet.hdr, &packet.sll, &packet.data) {
+                continue;
+            }
+            if builder.is_empty() {
+                packet.data.truncate(builder.remaining());
+                packet.hdr.tp_status |= TpStatus::COPY.bits();
+                builder.push(&packet.hdr, &packet.sll, &packet.data);
+            } else {
+                self.pending = Some(packet);
+            }
+            break;
+        }
+        if builder.is_empty() {
+            return Ok(None);
+        }
+        self.seq_num += 1;
+        self.buf = builder.finish();
+        Ok(Block::from_raw(&mut self.buf))
+    }
+
+    fn read_pcap_header(&mut self, magic: u32, swapped: bool) -> Result<()> {
+        self.swapped = swapped;
+        self.format = Format::Pcap {
+            nanos: magic == PCAP_MAGIC_NSEC,
+        };
+        let hdr = self
+            .read_bytes(20)?
+            .ok_or_else(|| bad("truncated header"))?;
+        self.snaplen = self.u32_at(&hdr, 12);
+        self.link_type = self.u32_at(&hdr, 16) as u16;
+        Ok(())
+    }
+
+    fn read_pcapng_shb(&mut self) -> Result<()> {
+        let head = self
+            .read_bytes(8)?
+            .ok_or_else(|| bad("truncated section header"))?;
+        let bom = u32::from_le_bytes([head[4], head[5], head[6], head[7]]);
+        self.swapped = match bom {
+            PCAPNG_BYTE_ORDER_MAGIC => false,
+            m if m.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => true,
+            _ => return Err(bad("bad byte order magic")),
+        };
+        let total_len = self.u32_at(&head, 0) as usize;
+        if total_len < 12 {
+            return Err(bad("bad section header length"));
+        }
+        self.read_bytes(total_len - 12)?
+            .ok_or_else(|| bad("truncated section header"))?;
+        Ok(())
+    }
+
+    fn read_packet(&mut self) -> Result<Option<Packet>> {
+        match self.format {
+            Format::Pcap { nanos } => self.read_pcap_packet(nanos),
+            Format::Pcapng { .. } => self.read_pcapng_packet(),
+        }
+    }
+
+    fn read_pcap_packet(&mut self, nanos: bool) -> Result<Option<Packet>> {
+        let rec = match self.read_bytes(16)? {
+            Some(rec) => rec,
+            None => return Ok(None),
+        };
+        let sec = self.u32_at(&rec, 0);
+        let frac = self.u32_at(&rec, 4);
+        let caplen = self.u32_at(&rec, 8);
+        let len = self.u32_at(&rec, 12);
+        let data = self
+            .read_bytes(caplen as usize)?
+            .ok_or_else(|| bad("truncated packet"))?;
+        let nsec = if nanos { frac } else { frac * 1000 };
+        Ok(Some(make_packet(self.link_type, 0, sec, nsec, len, data)))
+    }
+
+    fn read_pcapng_packet(&mut self) -> Result<Option<Packet>> {
+        loop {
+            let head = match self.read_bytes(8)? {
+                Some(head) => head,
+                None => return Ok(None),
+            };
+            let block_type = self.u32_at(&head, 0);
+            let total_len = self.u32_at(&head, 4) as usize;
+            if total_len < 12 || !total_len.is_multiple_of(4) {
+                return Err(bad("bad block length"));
+            }
+            let body = self
+                .read_bytes(total_len - 8)?
+                .ok_or_else(|| bad("truncated block"))?;
+            let body = &body[..total_len - 12];
+            match block_type {
+                PCAPNG_SHB => {
+                    //a new section starts over with its own interfaces
+                    let bom = self.u32_at(body, 0);
+                    self.swapped = bom != PCAPNG_BYTE_ORDER_MAGIC;
+                    self.format = Format::Pcapng {
+                        interfaces: Vec::new(),
+                    };
+                }
+                PCAPNG_IDB => self.read_pcapng_idb(body)?,
+                PCAPNG_EPB => return self.read_pcapng_epb(body).map(Some),
+                PCAPNG_SPB => return self.read_pcapng_spb(body).map(Some),
+                _ => {}
+            }
+        }
+    }
+
+    fn read_pcapng_idb(&mut self, body: &[u8]) -> Result<()> {
+        if body.len() < 8 {
+            return Err(bad("truncated interface description"));
+        }
+        let link_type = self.u16_at(body, 0);
+        let snaplen = self.u32_at(body, 4);
+        let mut units_per_sec = 1_000_000;
+        let mut opts = &body[8..];
+        while opts.len() >= 4 {
+            let code = self.u16_at(opts, 0);
+            let len = self.u16_at(opts, 2) as usize;
+            if code == 0 || opts.len() < 4 + len {
+                break;
+            }
+            if code == PCAPNG_OPT_IF_TSRESOL && len >= 1 {
+                let res = opts[4];
+                units_per_sec = if res & 0x80 != 0 {
+                    1u64 << (res & 0x7f).min(63)
+                } else {
+                    10u64.saturating_pow(res as u32)
+                };
+            }
+            opts = &opts[(4 + len + 3) & !3..];
+        }
+        if let Format::Pcapng { interfaces } = &mut self.format {
+            if interfaces.is_empty() {
+                self.link_type = link_type;
+                self.snaplen = snaplen;
+            }
+            interfaces.push(Interface {
+                link_type,
+                units_per_sec,
+                snaplen,
+            });
+        }
+        Ok(())
+    }
+
+    fn interface(&self, id: u32) -> Result<Interface> {
+        match &self.format {
+            Format::Pcapng { interfaces } => interfaces
+                .get(id as usize)
+                .cloned()
+                .ok_or_else(|| bad("packet refers to an unknown interface")),
+            Format::Pcap { .. } => Err(bad("not a pcapng file")),
+        }
+    }
+
+    fn read_pcapng_epb(&mut self, body: &[u8]) -> Result<Packet> {
+        if body.len() < 20 {
+            return Err(bad("truncated packet block"));
+        }
+        let iface = self.interface(self.u32_at(body, 0))?;
+        let ts = ((self.u32_at(body, 4) as u64) << 32) | self.u32_at(body, 8) as u64;
+        let caplen = self.u32_at(body, 12) as usize;
+        let len = self.u32_at(body, 16);
+        let data = body
+            .get(20..20 + caplen)
+            .ok_or_else(|| bad("truncated packet block"))?;
+        let nanos = ts as u128 * 1_000_000_000 / iface.units_per_sec as u128;
+        Ok(make_packet(
+            iface.link_type,
+            self.u32_at(body, 0) as i32,
+            (nanos / 1_000_000_000) as u32,
+            (nanos % 1_000_000_000) as u32,
+            len,
+            data.to_vec(),
+        ))
+    }
+
+    fn read_pcapng_spb(&mut self, body: &[u8]) -> Result<Packet> {
+        let iface = self.interface(0)?;
+        if body.len() < 4 {
+            return Err(bad("truncated simple packet block"));
+        }
+        let len = self.u32_at(body, 0);
+        let mut caplen = (len as usize).min(body.len() - 4);
+        if iface.snaplen > 0 {
+            caplen = caplen.min(iface.snaplen as usize);
+        }
+        let data = body[4..4 + caplen].to_vec();
+        Ok(make_packet(iface.link_type, 0, 0, 0, len, data))
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; len];
+        match self.reader.read_exact(&mut buf) {
+            Ok(()) => Ok(Some(buf)),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(Error::os("read", e)),
+        }
+    }
+
+    fn u32_at(&self, buf: &[u8], offset: usize) -> u32 {
+        let v = u32::from_ne_bytes([
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ]);
+        if self.swapped {
+            v.swap_bytes()
+        } else {
+            v
+        }
+    }
+
+    fn u16_at(&self, buf: &[u8], offset: usize) -> u16 {
+        let v = u16::from_ne_bytes([buf[offset], buf[offset + 1]]);
+        if self.swapped {
+            v.swap_bytes()
+        } else {
+            v
+        }
+    }
+}
+
+///Recovers the metadata a live ring would have reported from the link-layer header
+pub(crate) fn make_packet(
+    link_type: u16,
+    ifindex: i32,
+    sec: u32,
+    nsec: u32,
+    len: u32,
+    data: Vec<u8>,
+) -> Packet {
+    let mut hdr = Tpacket3Hdr {
+        tp_sec: sec,
+        tp_nsec: nsec,
+        tp_len: len,
+        tp_status: TpStatus::USER.bits(),
+        ..Tpacket3Hdr::default()
+    };
+    let mut sll = SockAddrLl {
+        sll_family: libc::AF_PACKET as u16,
+        sll_ifindex: ifindex,
+        ..SockAddrLl::default()
+    };
+    match link_type {
+        DLT_EN10MB => {
+            sll.sll_hatype = ARPHRD_ETHER;
+            sll.sll_halen = 6;
+            if data.len() >= 14 {
+                sll.sll_addr[..6].copy_from_slice(&data[6..12]);
+                sll.sll_protocol = u16::from_be_bytes([data[12], data[13]]);
+                hdr.tp_net = 14;
+            }
+        }
+        DLT_LINUX_SLL if data.len() >= SLL_HDR_LEN => {
+            sll.sll_pkttype = data[1];
+            sll.sll_hatype = u16::from_be_bytes([data[2], data[3]]);
+            sll.sll_halen = data[5].min(8);
+            sll.sll_addr.copy_from_slice(&data[6..14]);
+            sll.sll_protocol = u16::from_be_bytes([data[14], data[15]]);
+            hdr.tp_len = len.saturating_sub(SLL_HDR_LEN as u32);
+            return Packet {
+                hdr,
+                sll,
+                data: data[SLL_HDR_LEN..].to_vec(),
+            };
+        }
+        DLT_RAW => sll.sll_hatype = ARPHRD_NONE,
+        DLT_IEEE802_11_RADIOTAP => sll.sll_hatype = ARPHRD_IEEE80211_RADIOTAP,
+        _ => {}
+    }
+    Packet { hdr, sll, data }
+}
+
+fn bad(msg: &str) -> Error {
+    Error::BadCaptureFile(String::from(msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::pcapng::PcapngWriter;
+    use crate::testing::frames;
+
+    //(seconds, fraction, original length, captured bytes)
+    type Record<'a> = (u32, u32, u32, &'a [u8]);
+
+    fn pcap(magic: u32, swapped: bool, link_type: u32, records: &[Record]) -> Vec<u8> {
+        let word = |v: u32| {
+            if swapped {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            }
+        };
+        let mut file = word(magic).to_vec();
+        file.extend_from_slice(&[2, 0, 4, 0]);
+        if swapped {
+            file[4..8].copy_from_slice(&[0, 2, 0, 4]);
+        }
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&word(65535));
+        file.extend_from_slice(&word(link_type));
+        for (sec, frac, len, data) in records {
+            file.extend_from_slice(&word(*sec));
+            file.extend_from_slice(&word(*frac));
+            file.extend_from_slice(&word(data.len() as u32));
+            file.extend_from_slice(&word(*len));
+            file.extend_from_slice(data);
+        }
+        file
+    }
+
+    fn open(file: Vec<u8>) -> Ring {
+        Ring::from_reader(Cursor::new(file)).unwrap()
+    }
+
+    #[test]
+    fn reads_pcap_in_either_byte_order() {
+        let frame = frames::udp4([10, 0, 0, 1], [10, 0, 0, 2], 40000, 53);
+        let records = [(1_600_000_000, 250_000, frame.len() as u32, &frame[..])];
+        for &swapped in &[false, true] {
+            let mut ring = open(pcap(PCAP_MAGIC_USEC, swapped, 1, &records));
+            assert_eq!((ring.link_type(), ring.snaplen()), (DLT_EN10MB, 65535));
+            let block = ring.get_block().unwrap().unwrap();
+            let packets = block.get_raw_packets();
+            assert_eq!(packets.len(), 1);
+            assert_eq!(packets[0].payload(), &frame[..]);
+            assert_eq!(packets[0].l3_payload(), &frame[14..]);
+            assert_eq!(
+                packets[0].timestamp(),
+                UNIX_EPOCH + Duration::new(1_600_000_000, 250_000_000)
+            );
+            let info = packets[0].link_info().unwrap();
+            assert_eq!(
+                (info.protocol, info.source_mac()),
+                (0x0800, Some([2, 0, 0, 0, 0, 1]))
+            );
+            drop(block);
+            assert!(ring.get_block().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn nanosecond_timestamps_and_truncated_records() {
+        let frame = frames::udp4([10, 0, 0, 1], [10, 0, 0, 2], 40000, 53);
+        let records = [(7, 123_456_789, 1500, &frame[..])];
+        let mut ring = open(pcap(PCAP_MAGIC_NSEC, false, 1, &records));
+        let block = ring.get_block().unwrap().unwrap();
+        let packets = block.get_raw_packets();
+        let packet = &packets[0];
+        assert_eq!(
+            packet.timestamp(),
+            UNIX_EPOCH + Duration::new(7, 123_456_789)
+        );
+        //captured short by the snap length, not by the reader
+        assert!(packet.truncated());
+        assert_eq!(packet.tpacket3_hdr.tp_len, 1500);
+        assert!(!packet.status().contains(TpStatus::COPY));
+    }
+
+    #[test]
+    fn splits_packets_into_blocks() {
+        let frame = [0xab; 200];
+        let records: Vec<Record> = (0..10).map(|i| (i, 0, 200, &frame[..])).collect();
+        let mut ring = open(pcap(PCAP_MAGIC_USEC, false, 1, &records));
+        //room for three packets per block
+        ring.set_block_size(48 + 3 * 288);
+        let mut sizes = Vec::new();
+        let mut seconds = Vec::new();
+        let mut seq = Vec::new();
+        while let Some(block) = ring.get_block().unwrap() {
+            let packets = block.get_raw_packets();
+            sizes.push(packets.len());
+            seconds.extend(packets.iter().map(|p| p.tpacket3_hdr.tp_sec));
+            seq.push(block.seq_num());
+        }
+        assert_eq!(sizes, vec![3, 3, 3, 1]);
+        assert_eq!(seconds, (0..10).collect::<Vec<_>>());
+        assert_eq!(seq, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn truncates_packets_larger_than_a_block() {
+        let frame = [0xcd; 1000];
+        let records = [(0, 0, 1000, &frame[..]), (1, 0, 10, &frame[..10])];
+        let mut ring = open(pcap(PCAP_MAGIC_USEC, false, 1, &records));
+        ring.set_block_size(512);
+        let block = ring.get_block().unwrap().unwrap();
+        let packets = block.get_raw_packets();
+        let packet = &packets[0];
+        assert!(packet.status().contains(TpStatus::COPY));
+        assert_eq!(packet.payload().len(), 512 - 48 - 80);
+        assert_eq!(packet.tpacket3_hdr.tp_len, 1000);
+        drop(block);
+        let block = ring.get_block().unwrap().unwrap();
+        assert_eq!(block.get_raw_packets()[0].payload(), &frame[..10]);
+    }
+
+    #[test]
+    fn strips_linux_sll_headers() {
+        let ip = frames::ipv4(17, [10, 0, 0, 1], [10, 0, 0, 2], &frames::udp(1, 2, b""));
+        let mut sll = vec![0, 4, 0, 1, 0, 6, 2, 0, 0, 0, 0, 9, 0, 0, 0x08, 0x00];
+        sll.extend_from_slice(&ip);
+        let records = [(0, 0, sll.len() as u32, &sll[..])];
+        let mut ring = open(pcap(PCAP_MAGIC_USEC, false, DLT_LINUX_SLL as u32, &records));
+        let block = ring.get_block().unwrap().unwrap();
+        let packets = block.get_raw_packets();
+        let packet = &packets[0];
+        assert_eq!(packet.payload(), &ip[..]);
+        assert_eq!(packet.tpacket3_hdr.tp_len, ip.len() as u32);
+        let info = packet.link_info().unwrap();
+        assert_eq!((info.protocol, info.hatype), (0x0800, ARPHRD_ETHER));
+        assert_eq!(info.source_mac(), Some([2, 0, 0, 0, 0, 9]));
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let is_bad = |file: Vec<u8>| match Ring::from_reader(Cursor::new(file)) {
+            Err(Error::BadCaptureFile(_)) => true,
+            Err(_) => false,
+            Ok(mut ring) => matches!(ring.get_block(), Err(Error::BadCaptureFile(_))),
+        };
+        assert!(is_bad(Vec::new()));
+        assert!(is_bad(b"not a capture file".to_vec()));
+        assert!(is_bad(PCAP_MAGIC_USEC.to_le_bytes().to_vec()));
+        let mut truncated = pcap(PCAP_MAGIC_USEC, false, 1, &[(0, 0, 64, &[0; 64])]);
+        truncated.truncate(truncated.len() - 1);
+        assert!(is_bad(truncated));
+        let mut bad_order = PCAPNG_SHB.to_le_bytes().to_vec();
+        bad_order.extend_from_slice(&[28, 0, 0, 0, 1, 2, 3, 4]);
+        assert!(is_bad(bad_order));
+    }
+
+    #[test]
+    fn pcapng_link_type_is_known_before_the_first_block() {
+        let mut pcap = PcapngWriter::new(Vec::new()).unwrap();
+        pcap.add_interface("tun0", DLT_RAW, 1500).unwrap();
+        pcap.write_packet(0, UNIX_EPOCH, &[0x45; 20], 20, None)
+            .unwrap();
+
+        //the interface description follows the section header, so this used to
+        //report the DLT_EN10MB default and a snaplen of 0 until get_block
+        let mut ring = Ring::from_reader(Cursor::new(pcap.into_inner())).unwrap();
+        assert_eq!((ring.link_type(), ring.snaplen()), (DLT_RAW, 1500));
+        let block = ring.get_block().unwrap().unwrap();
+        assert_eq!(block.get_raw_packets().len(), 1);
+        assert!(ring.get_block().unwrap().is_none());
+    }
+}
@@ -0,0 +1,115 @@
+//!Token bucket pacing for transmits
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::Result;
+use crate::socket::Socket;
+
+//largest Ethernet frame without FCS, the default burst for bit rates
+const MAX_FRAME_BITS: f64 = 1514.0 * 8.0;
+
+///Transmit rate a `Pacer` holds to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rate {
+    ///No pacing at all
+    Unlimited,
+    ///Frames per second, whatever their size
+    PacketsPerSec(f64),
+    ///Frame bits per second, counting the bytes handed to the socket
+    BitsPerSec(f64),
+}
+
+///Spaces out frames so that they leave at a configured rate
+///
+///Sending early builds up debt that the next frames wait off, so the long-run rate stays exact
+///even when the sleeps overshoot.
+#[derive(Clone, Debug)]
+pub struct Pacer {
+    rate: Rate,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Pacer {
+    ///Creates a pacer that allows a burst of one frame
+    pub fn new(rate: Rate) -> Pacer {
+        let burst = match rate {
+            Rate::BitsPerSec(_) => MAX_FRAME_BITS,
+            _ => 1.0,
+        };
+        Pacer {
+            rate,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    ///Rate the pacer holds to
+    pub fn rate(&self) -> Rate {
+        self.rate
+    }
+
+    ///Changes the rate, keeping the current burst allowance
+    pub fn set_rate(&mut self, rate: Rate) {
+        self.refill();
+        self.rate = rate;
+    }
+
+    ///Lets up to `burst` frames (for `PacketsPerSec`) or bits (for `BitsPerSec`) go out
+    ///back to back after an idle period
+    pub fn set_burst(&mut self, burst: f64) {
+        self.burst = burst;
+        self.tokens = self.tokens.min(burst);
+    }
+
+    ///Takes a frame of `frame_len` bytes out of the bucket and returns how long to wait
+    ///before sending it
+    pub fn delay(&mut self, frame_len: usize) -> Duration {
+        let per_sec = match self.rate {
+            Rate::Unlimited => return Duration::from_secs(0),
+            Rate::PacketsPerSec(pps) => {
+                self.refill();
+                self.tokens -= 1.0;
+                pps
+            }
+            Rate::BitsPerSec(bps) => {
+                self.refill();
+                self.tokens -= frame_len as f64 * 8.0;
+                bps
+            }
+        };
+        if self.tokens >= 0.0 || per_sec <= 0.0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_secs_f64(-self.tokens / per_sec)
+    }
+
+    ///Sleeps until a frame of `frame_len` bytes may go out
+    pub fn wait(&mut self, frame_len: usize) {
+        let delay = self.delay(frame_len);
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+        }
+    }
+
+    ///Waits for the frame's turn and sends it with `Socket::send_frame()`
+    pub fn send(&mut self, sock: &Socket, frame: &[u8]) -> Result<usize> {
+        self.wait(frame.len());
+        sock.send_frame(frame)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        let per_sec = match self.rate {
+            Rate::Unlimited => return,
+            Rate::PacketsPerSec(pps) => pps,
+            Rate::BitsPerSec(bps) => bps,
+        };
+        self.tokens = (self.tokens + elapsed * per_sec).min(self.burst);
+    }
+}
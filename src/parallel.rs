@@ -0,0 +1,23 @@
+//!Spreads the packets of a block over a rayon thread pool, enabled with the `rayon` feature
+
+use rayon::prelude::*;
+
+use crate::rx::{Block, RawPacket};
+
+impl<'a> Block<'a> {
+    ///Parallel iterator over the packets of the block, run on the current rayon pool
+    ///
+    ///Worth it for CPU heavy work per packet; for light work handing packets to other threads
+    ///costs more than it saves.
+    pub fn par_packets(&self) -> rayon::vec::IntoIter<RawPacket<'_>> {
+        self.get_raw_packets().into_par_iter()
+    }
+
+    ///Calls `f` for every packet of the block, in parallel, and returns once all calls did
+    pub fn process_parallel<F>(&self, f: F)
+    where
+        F: Fn(&RawPacket) + Sync + Send,
+    {
+        self.par_packets().for_each(|packet| f(&packet));
+    }
+}
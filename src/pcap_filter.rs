@@ -0,0 +1,433 @@
+//!tcpdump filter expressions compiled to classic BPF without libpcap, enabled with the
+//!`pcap-filter` feature
+//!
+//!Understands the primitives people actually type: `ip`, `ip6`, `arp`, `rarp`, `tcp`, `udp`,
+//!`sctp`, `icmp`, `icmp6`, `ip proto N`, `ether proto N`, `vlan [id]`,
+//!`[src|dst] host ADDR`, `[src|dst] net ADDR[/LEN]`, `[tcp|udp|sctp] [src|dst] port N` and
+//!`[tcp|udp|sctp] [src|dst] portrange LO-HI`, combined with `and`/`&&`, `or`/`||`,
+//!`not`/`!` and parentheses. Offsets after a `vlan` are not shifted like tcpdump does, which
+//!only matters if the NIC does not strip VLAN tags.
+
+use std::net::IpAddr;
+
+use crate::bpf::{Assembler, Label};
+use crate::error::{Error, Result};
+use crate::filter::FilterProgram;
+use crate::filters::{
+    self, Direction, Link, COOKED, ETHERNET, IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_SCTP,
+    IPPROTO_TCP, IPPROTO_UDP, PORT_PROTOCOLS,
+};
+use crate::offline::DLT_RAW;
+use crate::pcapng::DLT_EN10MB;
+use crate::sll::DLT_LINUX_SLL;
+
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_IPV6: u16 = 0x86dd;
+const ETH_P_ARP: u16 = 0x0806;
+const ETH_P_RARP: u16 = 0x8035;
+
+#[derive(Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    EtherType(u16),
+    IpProto {
+        v4: Vec<u8>,
+        v6: Vec<u8>,
+    },
+    Net {
+        addr: IpAddr,
+        prefix_len: u8,
+        dir: Direction,
+    },
+    Ports {
+        protocols: Vec<u8>,
+        lo: u16,
+        hi: u16,
+        dir: Direction,
+    },
+    Vlan(Option<u16>),
+}
+
+///Compiles `expr` for packets of the given pcap link type, see `FilterProgram::compile()`
+pub(crate) fn compile(expr: &str, link_type: u16) -> Result<FilterProgram> {
+    let link = match link_type {
+        DLT_EN10MB => ETHERNET,
+        DLT_LINUX_SLL | DLT_RAW => COOKED,
+        other => return Err(invalid(format!("unsupported link type {}", other))),
+    };
+    let tokens = tokenize(expr);
+    if tokens.is_empty() {
+        return Ok(FilterProgram::accept_all());
+    }
+    let mut parser = Parser { tokens, pos: 0 };
+    let ast = parser.or()?;
+    if let Some(tok) = parser.peek() {
+        return Err(invalid(format!("unexpected '{}'", tok)));
+    }
+
+    let mut asm = Assembler::new();
+    let (accept, reject) = (asm.label(), asm.label());
+    emit(&mut asm, link, &ast, accept, reject);
+    asm.bind(accept);
+    asm.ret(u32::MAX);
+    asm.bind(reject);
+    asm.ret(0);
+    asm.assemble()
+}
+
+fn emit(asm: &mut Assembler, link: Link, expr: &Expr, t: Label, f: Label) {
+    match expr {
+        Expr::And(a, b) => {
+            let mid = asm.label();
+            emit(asm, link, a, mid, f);
+            asm.bind(mid);
+            emit(asm, link, b, t, f);
+        }
+        Expr::Or(a, b) => {
+            let mid = asm.label();
+            emit(asm, link, a, t, mid);
+            asm.bind(mid);
+            emit(asm, link, b, t, f);
+        }
+        Expr::Not(a) => emit(asm, link, a, f, t),
+        Expr::EtherType(ethertype) => filters::emit_ether_type(asm, link, *ethertype, t, f),
+        Expr::IpProto { v4, v6 } => filters::emit_ip_proto(asm, link, v4, v6, t, f),
+        Expr::Net {
+            addr,
+            prefix_len,
+            dir,
+        } => filters::emit_ip_net(asm, link, *addr, *prefix_len, *dir, t, f),
+        Expr::Ports {
+            protocols,
+            lo,
+            hi,
+            dir,
+        } => filters::emit_ports(asm, link, protocols, *lo..=*hi, *dir, t, f),
+        Expr::Vlan(id) => filters::emit_vlan(asm, link, *id, t, f),
+    }
+}
+
+fn tokenize(expr: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(c) = chars.next() {
+        let op = match c {
+            '(' | ')' => Some(c.to_string()),
+            '!' if chars.peek() != Some(&'=') => Some(String::from("!")),
+            '&' | '|' if chars.peek() == Some(&c) => {
+                chars.next();
+                Some(format!("{}{}", c, c))
+            }
+            c if c.is_whitespace() => Some(String::new()),
+            _ => None,
+        };
+        match op {
+            Some(op) => {
+                if !word.is_empty() {
+                    tokens.push(word.to_lowercase());
+                    word.clear();
+                }
+                if !op.is_empty() {
+                    tokens.push(op);
+                }
+            }
+            None => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word.to_lowercase());
+    }
+    tokens
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.as_str())
+    }
+
+    fn next(&mut self) -> Result<String> {
+        let tok = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| invalid(String::from("unexpected end of expression")))?;
+        self.pos += 1;
+        Ok(tok)
+    }
+
+    fn eat(&mut self, tok: &str) -> bool {
+        if self.peek() == Some(tok) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut lhs = self.and()?;
+        while self.eat("or") || self.eat("||") {
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut lhs = self.not()?;
+        while self.eat("and") || self.eat("&&") {
+            lhs = Expr::And(Box::new(lhs), Box::new(self.not()?));
+        }
+        Ok(lhs)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.eat("not") || self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err(invalid(String::from("missing ')'")));
+            }
+            return Ok(expr);
+        }
+        self.primitive()
+    }
+
+    fn primitive(&mut self) -> Result<Expr> {
+        let tok = self.next()?;
+        Ok(match tok.as_str() {
+            "ip" if self.eat("proto") => {
+                let proto = self.number(u8::MAX as u32)? as u8;
+                Expr::IpProto {
+                    v4: vec![proto],
+                    v6: vec![proto],
+                }
+            }
+            "ip" => Expr::EtherType(ETH_P_IP),
+            "ip6" => Expr::EtherType(ETH_P_IPV6),
+            "arp" => Expr::EtherType(ETH_P_ARP),
+            "rarp" => Expr::EtherType(ETH_P_RARP),
+            "icmp" => Expr::IpProto {
+                v4: vec![IPPROTO_ICMP],
+                v6: vec![],
+            },
+            "icmp6" => Expr::IpProto {
+                v4: vec![],
+                v6: vec![IPPROTO_ICMPV6],
+            },
+            "tcp" | "udp" | "sctp" => {
+                let proto = match tok.as_str() {
+                    "tcp" => IPPROTO_TCP,
+                    "udp" => IPPROTO_UDP,
+                    _ => IPPROTO_SCTP,
+                };
+                match self.peek() {
+                    Some("src") | Some("dst") | Some("port") | Some("portrange") => {
+                        let dir = self.direction();
+                        self.port(vec![proto], dir)?
+                    }
+                    _ => Expr::IpProto {
+                        v4: vec![proto],
+                        v6: vec![proto],
+                    },
+                }
+            }
+            "ether" => {
+                if !self.eat("proto") {
+                    return Err(invalid(String::from("expected 'proto' after 'ether'")));
+                }
+                Expr::EtherType(self.number(u16::MAX as u32)? as u16)
+            }
+            "vlan" => match self.peek() {
+                Some(t) if t.starts_with(|c: char| c.is_ascii_digit()) => {
+                    Expr::Vlan(Some(self.number(0x0fff)? as u16))
+                }
+                _ => Expr::Vlan(None),
+            },
+            "src" | "dst" => {
+                self.pos -= 1;
+                let dir = self.direction();
+                self.qualified(dir)?
+            }
+            "host" | "net" | "port" | "portrange" => {
+                self.pos -= 1;
+                self.qualified(Direction::Any)?
+            }
+            _ => return Err(invalid(format!("unknown primitive '{}'", tok))),
+        })
+    }
+
+    fn direction(&mut self) -> Direction {
+        if self.eat("src") {
+            Direction::Src
+        } else if self.eat("dst") {
+            Direction::Dst
+        } else {
+            Direction::Any
+        }
+    }
+
+    fn qualified(&mut self, dir: Direction) -> Result<Expr> {
+        match self.peek() {
+            Some("host") | Some("net") => {
+                let net = self.next()? == "net";
+                let arg = self.next()?;
+                let (addr, prefix_len) = match arg.split_once('/') {
+                    Some((addr, len)) if net => (addr, Some(len)),
+                    _ => (arg.as_str(), None),
+                };
+                let addr: IpAddr = addr
+                    .parse()
+                    .map_err(|_| invalid(format!("bad address '{}'", arg)))?;
+                let max = if addr.is_ipv4() { 32 } else { 128 };
+                let prefix_len = match prefix_len {
+                    Some(len) => parse_number(len, max)? as u8,
+                    None => max as u8,
+                };
+                Ok(Expr::Net {
+                    addr,
+                    prefix_len,
+                    dir,
+                })
+            }
+            Some("port") | Some("portrange") => self.port(PORT_PROTOCOLS.to_vec(), dir),
+            _ => Err(invalid(String::from(
+                "expected 'host', 'net', 'port' or 'portrange'",
+            ))),
+        }
+    }
+
+    fn port(&mut self, protocols: Vec<u8>, dir: Direction) -> Result<Expr> {
+        let (lo, hi) = match self.next()?.as_str() {
+            "port" => {
+                let port = self.number(u16::MAX as u32)? as u16;
+                (port, port)
+            }
+            "portrange" => {
+                let arg = self.next()?;
+                let (lo, hi) = arg
+                    .split_once('-')
+                    .ok_or_else(|| invalid(format!("bad port range '{}'", arg)))?;
+                let lo = parse_number(lo, u16::MAX as u32)? as u16;
+                let hi = parse_number(hi, u16::MAX as u32)? as u16;
+                (lo.min(hi), lo.max(hi))
+            }
+            other => return Err(invalid(format!("expected 'port', got '{}'", other))),
+        };
+        Ok(Expr::Ports {
+            protocols,
+            lo,
+            hi,
+            dir,
+        })
+    }
+
+    fn number(&mut self, max: u32) -> Result<u32> {
+        let tok = self.next()?;
+        parse_number(&tok, max)
+    }
+}
+
+fn parse_number(tok: &str, max: u32) -> Result<u32> {
+    let value = match tok.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => tok.parse(),
+    }
+    .map_err(|_| invalid(format!("bad number '{}'", tok)))?;
+    if value > max {
+        return Err(invalid(format!("{} is larger than {}", value, max)));
+    }
+    Ok(value)
+}
+
+fn invalid(msg: String) -> Error {
+    Error::InvalidFilter(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bpf::tests::{run, run_with, Meta};
+    use crate::testing::frames;
+
+    const A: [u8; 4] = [10, 0, 0, 1];
+    const B: [u8; 4] = [192, 168, 1, 7];
+
+    fn accepts(expr: &str, frame: &[u8]) -> bool {
+        run(&compile(expr, DLT_EN10MB).unwrap(), frame) != 0
+    }
+
+    #[test]
+    fn primitives() {
+        let tcp = frames::tcp4(A, B, 40000, 80);
+        let udp = frames::udp4(A, B, 40000, 53);
+        assert!(accepts("", &tcp));
+        assert!(accepts("ip", &tcp) && !accepts("ip6", &tcp));
+        assert!(accepts("tcp", &tcp) && !accepts("tcp", &udp));
+        assert!(accepts("ip proto 17", &udp) && accepts("ether proto 0x800", &udp));
+        assert!(accepts("host 10.0.0.1", &udp) && !accepts("dst host 10.0.0.1", &udp));
+        assert!(accepts("src net 10.0.0.0/8", &udp) && !accepts("net 172.16.0.0/12", &udp));
+        assert!(accepts("dst port 80", &tcp) && !accepts("src port 80", &tcp));
+        assert!(accepts("udp portrange 50-60", &udp) && !accepts("tcp portrange 50-60", &udp));
+        assert!(accepts("vlan 42", &frames::tagged(42, 0x0800, &[0; 20])));
+    }
+
+    #[test]
+    fn operators_and_precedence() {
+        let tcp = frames::tcp4(A, B, 40000, 80);
+        let udp = frames::udp4(A, B, 40000, 53);
+        assert!(accepts("tcp and port 80", &tcp) && !accepts("tcp && port 53", &tcp));
+        assert!(accepts("udp or tcp", &tcp) && accepts("udp || tcp", &udp));
+        assert!(!accepts("not tcp", &tcp) && accepts("! tcp", &udp));
+        //`and` binds tighter than `or`
+        assert!(accepts("udp or tcp and port 53", &udp));
+        assert!(!accepts("(udp or tcp) and port 53", &tcp));
+        assert!(accepts("not (udp and port 80)", &udp));
+        assert!(accepts("tcp and (port 80 or port 443)", &tcp));
+    }
+
+    #[test]
+    fn cooked_packets_have_no_ethernet_header() {
+        let frame = frames::udp4(A, B, 40000, 53);
+        let ip = Meta {
+            protocol: 0x0800,
+            ..Meta::default()
+        };
+        for link_type in &[DLT_LINUX_SLL, DLT_RAW] {
+            let program = compile("udp and dst port 53 and src host 10.0.0.1", *link_type).unwrap();
+            assert_ne!(run_with(&program, &frame[14..], ip), 0);
+            let program = compile("ip6", *link_type).unwrap();
+            assert_eq!(run_with(&program, &frame[14..], ip), 0);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        for expr in &[
+            "bogus",
+            "tcp and",
+            "(tcp",
+            "tcp)",
+            "port",
+            "port 70000",
+            "net 10.0.0.0/33",
+            "host not-an-address",
+            "portrange 10",
+        ] {
+            assert!(
+                matches!(compile(expr, DLT_EN10MB), Err(Error::InvalidFilter(_))),
+                "{}",
+                expr
+            );
+        }
+        assert!(compile("tcp", 105).is_err());
+    }
+}
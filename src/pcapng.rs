@@ -0,0 +1,281 @@
+//!Minimal pcapng writer with nanosecond timestamps
+//!
+//!Every interface gets an Interface Description Block with its name, link type and
+//!`if_tsresol` set to nanoseconds, packets are written as Enhanced Packet Blocks. Kernel drop
+//!counters can be attached to packets (`epb_dropcount`) and written as Interface Statistics
+//!Blocks.
+
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Result;
+use crate::offline::{DLT_IEEE802_11_RADIOTAP, DLT_RAW};
+use crate::radiotap::ARPHRD_IEEE80211_RADIOTAP;
+use crate::rx::RawPacket;
+use crate::sll::SLL_HDR_LEN;
+use crate::stats::RingStats;
+
+///pcap link type of Ethernet captures
+pub const DLT_EN10MB: u16 = 1;
+
+const ARPHRD_ETHER: u16 = 1;
+const ARPHRD_RAWIP: u16 = 519;
+const ARPHRD_TUNNEL: u16 = 768;
+const ARPHRD_TUNNEL6: u16 = 769;
+const ARPHRD_LOOPBACK: u16 = 772;
+const ARPHRD_SIT: u16 = 776;
+const ARPHRD_NONE: u16 = 0xFFFE;
+
+///pcap link type of packets read from a raw (not cooked) socket on an interface of the given
+///ARPHRD_* hardware type, None for types whose link-layer header has no pcap equivalent here
+pub fn link_type_of(hardware_type: u16) -> Option<u16> {
+    match hardware_type {
+        ARPHRD_ETHER | ARPHRD_LOOPBACK => Some(DLT_EN10MB),
+        ARPHRD_IEEE80211_RADIOTAP => Some(DLT_IEEE802_11_RADIOTAP),
+        //interfaces without a link-layer header hand out bare IP packets
+        ARPHRD_NONE | ARPHRD_RAWIP | ARPHRD_TUNNEL | ARPHRD_TUNNEL6 | ARPHRD_SIT => Some(DLT_RAW),
+        _ => None,
+    }
+}
+
+const BLOCK_SHB: u32 = 0x0A0D_0D0A;
+const BLOCK_IDB: u32 = 1;
+const BLOCK_ISB: u32 = 5;
+const BLOCK_EPB: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+const OPT_EPB_DROPCOUNT: u16 = 4;
+const OPT_ISB_IFRECV: u16 = 4;
+const OPT_ISB_IFDROP: u16 = 5;
+
+///Writes a pcapng section to any `Write` implementation
+#[derive(Debug)]
+pub struct PcapngWriter<W: Write> {
+    writer: W,
+    interfaces: u32,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    ///Starts a new section by writing the Section Header Block
+    pub fn new(writer: W) -> Result<PcapngWriter<W>> {
+        let mut pcap = PcapngWriter {
+            writer,
+            interfaces: 0,
+        };
+        let mut body = Vec::with_capacity(16);
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_ne_bytes());
+        body.extend_from_slice(&1u16.to_ne_bytes());
+        body.extend_from_slice(&0u16.to_ne_bytes());
+        body.extend_from_slice(&(-1i64).to_ne_bytes());
+        pcap.write_block(BLOCK_SHB, &body)?;
+        Ok(pcap)
+    }
+
+    ///Describes an interface and returns its id to be used with the other methods
+    ///
+    ///`Ring::link_type()` gives the link type of a live capture.
+    pub fn add_interface(&mut self, if_name: &str, link_type: u16, snaplen: u32) -> Result<u32> {
+        let mut body = Vec::with_capacity(32 + if_name.len());
+        body.extend_from_slice(&link_type.to_ne_bytes());
+        body.extend_from_slice(&0u16.to_ne_bytes());
+        body.extend_from_slice(&snaplen.to_ne_bytes());
+        push_option(&mut body, OPT_IF_NAME, if_name.as_bytes());
+        push_option(&mut body, OPT_IF_TSRESOL, &[9]);
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        self.write_block(BLOCK_IDB, &body)?;
+        self.interfaces += 1;
+        Ok(self.interfaces - 1)
+    }
+
+    ///Writes a packet, `dropcount` is the number of packets lost since the previous one
+    pub fn write_packet(
+        &mut self,
+        if_id: u32,
+        timestamp: SystemTime,
+        data: &[u8],
+        orig_len: u32,
+        dropcount: Option<u64>,
+    ) -> Result<()> {
+        let mut body = Vec::with_capacity(40 + data.len());
+        body.extend_from_slice(&if_id.to_ne_bytes());
+        push_timestamp(&mut body, timestamp);
+        body.extend_from_slice(&(data.len() as u32).to_ne_bytes());
+        body.extend_from_slice(&orig_len.to_ne_bytes());
+        body.extend_from_slice(data);
+        pad(&mut body);
+        if let Some(dropcount) = dropcount {
+            push_option(&mut body, OPT_EPB_DROPCOUNT, &dropcount.to_ne_bytes());
+            push_option(&mut body, OPT_ENDOFOPT, &[]);
+        }
+        self.write_block(BLOCK_EPB, &body)
+    }
+
+    ///Writes a packet captured by a ring, starting at its link-layer header
+    pub fn write_raw_packet(
+        &mut self,
+        if_id: u32,
+        packet: &RawPacket,
+        dropcount: Option<u64>,
+    ) -> Result<()> {
+        self.write_packet(
+            if_id,
+            packet.timestamp(),
+            packet.payload(),
+            packet.tpacket3_hdr.tp_len,
+            dropcount,
+        )
+    }
+
+    ///Writes a packet captured in cooked mode, prefixed with its LINUX_SLL pseudo-header; the
+    ///interface must have been added with link type `sll::DLT_LINUX_SLL`
+    pub fn write_cooked_packet(
+        &mut self,
+        if_id: u32,
+        packet: &RawPacket,
+        dropcount: Option<u64>,
+    ) -> Result<()> {
+        let payload = packet.payload();
+        let mut data = Vec::with_capacity(SLL_HDR_LEN + payload.len());
+        if let Some(sll) = packet.sll_header() {
+            data.extend_from_slice(&sll.to_bytes());
+        }
+        data.extend_from_slice(payload);
+        let orig_len = packet.tpacket3_hdr.tp_len + (data.len() - payload.len()) as u32;
+        self.write_packet(if_id, packet.timestamp(), &data, orig_len, dropcount)
+    }
+
+    ///Writes an Interface Statistics Block with the counters from a ring statistics snapshot
+    pub fn write_statistics(&mut self, if_id: u32, stats: &RingStats) -> Result<()> {
+        let mut body = Vec::with_capacity(48);
+        body.extend_from_slice(&if_id.to_ne_bytes());
+        push_timestamp(&mut body, SystemTime::now());
+        push_option(&mut body, OPT_ISB_IFRECV, &stats.packets.to_ne_bytes());
+        push_option(&mut body, OPT_ISB_IFDROP, &stats.drops.to_ne_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        self.write_block(BLOCK_ISB, &body)
+    }
+
+    ///Flushes the underlying writer
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    ///Returns the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> Result<()> {
+        let total_len = (12 + body.len()) as u32;
+        self.writer.write_all(&block_type.to_ne_bytes())?;
+        self.writer.write_all(&total_len.to_ne_bytes())?;
+        self.writer.write_all(body)?;
+        self.writer.write_all(&total_len.to_ne_bytes())?;
+        Ok(())
+    }
+}
+
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_ne_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_ne_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn push_timestamp(body: &mut Vec<u8>, timestamp: SystemTime) {
+    let nanos = timestamp
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    body.extend_from_slice(&((nanos >> 32) as u32).to_ne_bytes());
+    body.extend_from_slice(&(nanos as u32).to_ne_bytes());
+}
+
+fn pad(body: &mut Vec<u8>) {
+    while !body.len().is_multiple_of(4) {
+        body.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::offline::Ring;
+    use crate::testing::frames;
+
+    #[test]
+    fn round_trips_through_the_offline_reader() {
+        let frame = frames::udp4([10, 0, 0, 1], [10, 0, 0, 2], 40000, 53);
+        let first = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let second = first + Duration::from_micros(1500);
+
+        let mut pcap = PcapngWriter::new(Vec::new()).unwrap();
+        let eth = pcap.add_interface("eth0", DLT_EN10MB, 65535).unwrap();
+        let raw = pcap.add_interface("tun0", DLT_RAW, 1500).unwrap();
+        assert_eq!((eth, raw), (0, 1));
+        pcap.write_packet(eth, first, &frame, frame.len() as u32, None)
+            .unwrap();
+        pcap.write_statistics(eth, &RingStats::default()).unwrap();
+        //a truncated packet with options after its odd-sized data
+        pcap.write_packet(raw, second, &frame[14..47], 1400, Some(3))
+            .unwrap();
+
+        let mut ring = Ring::from_reader(Cursor::new(pcap.into_inner())).unwrap();
+        assert_eq!((ring.link_type(), ring.snaplen()), (DLT_EN10MB, 65535));
+        let block = ring.get_block().unwrap().unwrap();
+        let packets = block.get_raw_packets();
+        assert_eq!(packets.len(), 2);
+
+        assert_eq!(packets[0].payload(), &frame[..]);
+        assert_eq!(packets[0].timestamp(), first);
+        assert_eq!(packets[0].ifindex(), Some(0));
+        assert!(!packets[0].truncated());
+
+        assert_eq!(packets[1].payload(), &frame[14..47]);
+        assert_eq!(packets[1].timestamp(), second);
+        assert_eq!(packets[1].ifindex(), Some(1));
+        assert_eq!(packets[1].tpacket3_hdr.tp_len, 1400);
+        assert!(packets[1].truncated());
+        assert_eq!(packets[1].link_info().unwrap().hatype, ARPHRD_NONE);
+    }
+
+    #[test]
+    fn blocks_are_padded_to_32_bits() {
+        let mut pcap = PcapngWriter::new(Vec::new()).unwrap();
+        pcap.add_interface("a", DLT_EN10MB, 0).unwrap();
+        pcap.write_packet(0, UNIX_EPOCH, &[1, 2, 3], 3, Some(1))
+            .unwrap();
+        let file = pcap.into_inner();
+        assert_eq!(file.len() % 4, 0);
+        //every block repeats its length at the end
+        let mut at = 0;
+        while at < file.len() {
+            let len = u32::from_ne_bytes([file[at + 4], file[at + 5], file[at + 6], file[at + 7]]);
+            let trailer = &file[at + len as usize - 4..at + len as usize];
+            assert_eq!(trailer, &len.to_ne_bytes());
+            at += len as usize;
+        }
+        assert_eq!(at, file.len());
+    }
+
+    #[test]
+    fn link_types_of_hardware_types() {
+        assert_eq!(link_type_of(ARPHRD_ETHER), Some(DLT_EN10MB));
+        assert_eq!(link_type_of(ARPHRD_LOOPBACK), Some(DLT_EN10MB));
+        assert_eq!(link_type_of(ARPHRD_RAWIP), Some(DLT_RAW));
+        assert_eq!(link_type_of(ARPHRD_NONE), Some(DLT_RAW));
+        assert_eq!(
+            link_type_of(ARPHRD_IEEE80211_RADIOTAP),
+            Some(DLT_IEEE802_11_RADIOTAP)
+        );
+        //ARPHRD_INFINIBAND
+        assert_eq!(link_type_of(32), None);
+    }
+}
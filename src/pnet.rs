@@ -0,0 +1,61 @@
+//!Typed `pnet_packet` views over packets in the ring, enabled with the `pnet` feature
+//!
+//!The views borrow the ring memory, nothing is copied.
+
+use pnet_packet::ethernet::EthernetPacket;
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::ipv6::Ipv6Packet;
+
+use crate::rx::RawPacket;
+
+const ARPHRD_ETHER: u16 = 1;
+const ARPHRD_LOOPBACK: u16 = 772;
+
+///Network layer of a packet, see `RawPacket::parse()`
+#[derive(Debug)]
+pub enum NetworkPacket<'a> {
+    Ipv4(Ipv4Packet<'a>),
+    Ipv6(Ipv6Packet<'a>),
+}
+
+///Typed views of the layers of a packet, see `RawPacket::parse()`
+#[derive(Debug)]
+pub struct ParsedPacket<'a> {
+    ///Ethernet header and everything after it, None in cooked mode and on non-Ethernet links
+    pub ethernet: Option<EthernetPacket<'a>>,
+    ///IPv4 or IPv6 header and everything after it, None for other protocols
+    pub network: Option<NetworkPacket<'a>>,
+}
+
+impl<'a> RawPacket<'a> {
+    ///Ethernet view of the packet, None in cooked mode and on non-Ethernet links
+    pub fn ethernet(&self) -> Option<EthernetPacket<'a>> {
+        let hatype = self.link_info()?.hatype;
+        if hatype != ARPHRD_ETHER && hatype != ARPHRD_LOOPBACK {
+            return None;
+        }
+        //cooked mode strips the link layer header
+        if self.l2_header().is_empty() {
+            return None;
+        }
+        EthernetPacket::new(self.payload())
+    }
+
+    ///IPv4 or IPv6 view starting at the network header, picked by the IP version field
+    pub fn network(&self) -> Option<NetworkPacket<'a>> {
+        let l3 = self.l3_payload();
+        match l3.first()? >> 4 {
+            4 => Ipv4Packet::new(l3).map(NetworkPacket::Ipv4),
+            6 => Ipv6Packet::new(l3).map(NetworkPacket::Ipv6),
+            _ => None,
+        }
+    }
+
+    ///Ethernet and IP views of the packet at once
+    pub fn parse(&self) -> ParsedPacket<'a> {
+        ParsedPacket {
+            ethernet: self.ethernet(),
+            network: self.network(),
+        }
+    }
+}
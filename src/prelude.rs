@@ -0,0 +1,16 @@
+//!Commonly used types, `use af_packet::prelude::*;` to import them all
+
+pub use crate::capture::{Capture, CaptureBuilder};
+pub use crate::error::{Error, Result};
+pub use crate::filter::FilterProgram;
+pub use crate::group::{FanoutSet, RingGroup};
+pub use crate::reactor::Reactor;
+pub use crate::rx::{
+    Block, FanoutFlags, FanoutMethod, LinkInfo, PacketDirection, Promiscuous, RawPacket, Ring,
+    RingSettings, TpacketVersion, VlanTag,
+};
+pub use crate::shutdown::ShutdownHandle;
+pub use crate::socket::{EtherType, MembershipKind, SocketBuilder};
+pub use crate::source::{AsyncPacketSource, PacketSource};
+pub use crate::stats::RingStats;
+pub use crate::tpacket3::{TpStatus, TpacketReq3};
@@ -0,0 +1,245 @@
+//!Detects which packet socket features the running kernel supports, so applications can pick
+//!settings that work or explain why a capture cannot be set up
+//!
+//!Every feature is tried on a throwaway socket bound to an unused protocol, so probing neither
+//!receives traffic nor touches any interface. Needs CAP_NET_RAW like opening a ring does.
+
+use std::ffi::CStr;
+use std::fmt;
+use std::mem;
+
+use libc::{
+    c_int, c_uint, close, mmap, munmap, sysconf, uname, _SC_PAGESIZE, MAP_FAILED, MAP_SHARED,
+    PROT_READ, PROT_WRITE,
+};
+
+use crate::error::Result;
+use crate::fallback::PACKET_AUXDATA;
+use crate::group;
+use crate::rx::{
+    FanoutFlags, FanoutMethod, TpacketVersion, PACKET_IGNORE_OUTGOING, PACKET_RX_RING,
+    PACKET_VERSION, PACKET_VNET_HDR,
+};
+use crate::socket::{self, EtherType, Socket, PACKET_FANOUT};
+use crate::tpacket2::TpacketReq;
+use crate::tpacket3::TpacketReq3;
+
+const PACKET_TIMESTAMP: c_int = 17;
+const PACKET_QDISC_BYPASS: c_int = 20;
+//IEEE 802 local experimental ethertype, nothing should arrive on it
+const PROBE_PROTOCOL: u16 = 0x88b5;
+
+const FANOUT_METHODS: [FanoutMethod; 8] = [
+    FanoutMethod::Hash,
+    FanoutMethod::Lb,
+    FanoutMethod::Cpu,
+    FanoutMethod::Rollover,
+    FanoutMethod::Rnd,
+    FanoutMethod::Qm,
+    FanoutMethod::Cbpf,
+    FanoutMethod::Ebpf,
+];
+
+///Packet socket features of the running kernel, see `capabilities()`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    ///Kernel release as reported by uname(2)
+    pub kernel: String,
+    ///TPACKET_V2 frame rings
+    pub tpacket_v2: bool,
+    ///TPACKET_V3 block rings
+    pub tpacket_v3: bool,
+    ///PACKET_RX_RING can be set up and mapped; without it only `fallback::FallbackRing` works
+    pub mmap_ring: bool,
+    ///Fanout methods that can be joined
+    pub fanout_methods: Vec<FanoutMethod>,
+    ///`FanoutFlags::DEFRAG`
+    pub fanout_defrag: bool,
+    ///PACKET_QDISC_BYPASS for transmit
+    pub qdisc_bypass: bool,
+    ///PACKET_VNET_HDR, see `RingSettings::vnet_header`
+    pub vnet_hdr: bool,
+    ///PACKET_IGNORE_OUTGOING, see `RingSettings::ignore_outgoing`
+    pub ignore_outgoing: bool,
+    ///PACKET_AUXDATA, used by `fallback::FallbackRing`
+    pub auxdata: bool,
+    ///PACKET_TIMESTAMP to pick hardware timestamps
+    pub timestamp_source: bool,
+}
+
+impl Capabilities {
+    ///Whether rings can join fanout groups using `method`
+    pub fn supports_fanout(&self, method: FanoutMethod) -> bool {
+        self.fanout_methods.contains(&method)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let yes_no = |supported: bool| if supported { "yes" } else { "no" };
+        let fanout: Vec<String> = self
+            .fanout_methods
+            .iter()
+            .map(FanoutMethod::to_string)
+            .collect();
+        write!(
+            f,
+            "kernel {}: TPACKET_V3 {}, TPACKET_V2 {}, mmap ring {}, fanout [{}], fanout defrag {}, \
+             qdisc bypass {}, vnet hdr {}, ignore outgoing {}, auxdata {}, timestamp source {}",
+            self.kernel,
+            yes_no(self.tpacket_v3),
+            yes_no(self.tpacket_v2),
+            yes_no(self.mmap_ring),
+            fanout.join(" "),
+            yes_no(self.fanout_defrag),
+            yes_no(self.qdisc_bypass),
+            yes_no(self.vnet_hdr),
+            yes_no(self.ignore_outgoing),
+            yes_no(self.auxdata),
+            yes_no(self.timestamp_source)
+        )
+    }
+}
+
+///Probes the running kernel
+///
+///Fails only if no packet socket can be opened at all, typically for lack of CAP_NET_RAW.
+pub fn capabilities() -> Result<Capabilities> {
+    //fail early with the error opening a ring would give
+    let probe = ProbeSocket::open()?;
+    let mut caps = Capabilities {
+        kernel: kernel_release(),
+        ..Capabilities::default()
+    };
+    caps.tpacket_v3 = probe.supports(PACKET_VERSION, TpacketVersion::V3.as_raw());
+    caps.vnet_hdr = probe.supports(PACKET_VNET_HDR, 1 as c_int);
+    caps.qdisc_bypass = probe.supports(PACKET_QDISC_BYPASS, 1 as c_int);
+    caps.ignore_outgoing = probe.supports(PACKET_IGNORE_OUTGOING, 1 as c_int);
+    caps.auxdata = probe.supports(PACKET_AUXDATA, 1 as c_int);
+    caps.timestamp_source = probe.supports(PACKET_TIMESTAMP, 0 as c_int);
+    drop(probe);
+
+    let probe = ProbeSocket::open()?;
+    caps.tpacket_v2 = probe.supports(PACKET_VERSION, TpacketVersion::V2.as_raw());
+    drop(probe);
+
+    caps.mmap_ring = if caps.tpacket_v3 {
+        probe_ring(TpacketVersion::V3.as_raw(), true)
+    } else if caps.tpacket_v2 {
+        probe_ring(TpacketVersion::V2.as_raw(), false)
+    } else {
+        false
+    };
+
+    //a socket cannot leave a fanout group, so every join gets a fresh one
+    for method in FANOUT_METHODS.iter() {
+        if probe_fanout(method.as_raw()) {
+            caps.fanout_methods.push(*method);
+        }
+    }
+    caps.fanout_defrag = probe_fanout(FanoutMethod::Hash.as_raw() | FanoutFlags::DEFRAG.bits());
+    Ok(caps)
+}
+
+//packet socket closed when dropped
+struct ProbeSocket(Socket);
+
+impl ProbeSocket {
+    fn open() -> Result<ProbeSocket> {
+        let socket = Socket::open_any(
+            socket::PF_PACKET,
+            libc::SOCK_RAW,
+            EtherType::Other(PROBE_PROTOCOL),
+        )?;
+        Ok(ProbeSocket(socket))
+    }
+
+    fn supports<T>(&self, opt: c_int, value: T) -> bool {
+        self.0.clone().setsockopt(opt, value).is_ok()
+    }
+}
+
+impl Drop for ProbeSocket {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.0.fd);
+        }
+    }
+}
+
+fn probe_fanout(fanout: c_int) -> bool {
+    match ProbeSocket::open() {
+        Ok(probe) => {
+            let group = c_int::from(group::unique_fanout_group());
+            probe.supports(PACKET_FANOUT, group | (fanout << 16))
+        }
+        Err(_) => false,
+    }
+}
+
+//sets up and maps the smallest possible ring
+fn probe_ring(version: c_int, v3: bool) -> bool {
+    let probe = match ProbeSocket::open() {
+        Ok(probe) => probe,
+        Err(_) => return false,
+    };
+    if !probe.supports(PACKET_VERSION, version) {
+        return false;
+    }
+    let block_size = unsafe { sysconf(_SC_PAGESIZE) } as c_uint;
+    let set = if v3 {
+        probe.supports(
+            PACKET_RX_RING,
+            TpacketReq3 {
+                tp_block_size: block_size,
+                tp_block_nr: 1,
+                tp_frame_size: block_size,
+                tp_frame_nr: 1,
+                tp_retire_blk_tov: 1,
+                tp_sizeof_priv: 0,
+                tp_feature_req_word: 0,
+            },
+        )
+    } else {
+        probe.supports(
+            PACKET_RX_RING,
+            TpacketReq {
+                tp_block_size: block_size,
+                tp_block_nr: 1,
+                tp_frame_size: block_size,
+                tp_frame_nr: 1,
+            },
+        )
+    };
+    if !set {
+        return false;
+    }
+    let len = block_size as usize;
+    let map = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            len,
+            PROT_READ | PROT_WRITE,
+            MAP_SHARED,
+            probe.0.fd,
+            0,
+        )
+    };
+    if map == MAP_FAILED {
+        return false;
+    }
+    unsafe {
+        munmap(map, len);
+    }
+    true
+}
+
+fn kernel_release() -> String {
+    let mut uts: libc::utsname = unsafe { mem::zeroed() };
+    if unsafe { uname(&mut uts) } != 0 {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(uts.release.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
@@ -0,0 +1,109 @@
+//!Reference counts of IFF_PROMISC set by rings
+//!
+//!The flag belongs to the interface rather than to a socket, so the kernel never clears it.
+//!Rings setting it share one count per interface and network namespace, and the last one to
+//!let go puts the flag back the way it found it.
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use libc::{c_ulong, close, dup};
+
+use crate::error::{Error, Result};
+use crate::socket::{Socket, IFF_PROMISC};
+
+//network namespace inode and interface index
+type Key = (u64, u32);
+
+struct Hold {
+    key: Key,
+    count: usize,
+    //IFF_PROMISC was set before the first ring set it
+    was_set: bool,
+}
+
+static HOLDS: Mutex<Vec<Hold>> = Mutex::new(Vec::new());
+
+//one ring's share of IFF_PROMISC, released when dropped
+#[derive(Debug)]
+pub(crate) struct PromiscuousFlag {
+    key: Key,
+    //duplicate of the ring's socket, which may be closed first
+    socket: Socket,
+    released: AtomicBool,
+}
+
+impl PromiscuousFlag {
+    //sets IFF_PROMISC on the interface of `socket` unless a ring already did
+    pub(crate) fn set(socket: &Socket) -> Result<PromiscuousFlag> {
+        let ns = fs::metadata("/proc/thread-self/ns/net")
+            .map_err(|e| Error::os("stat netns", e))?
+            .ino();
+        let key = (ns, socket.if_index);
+        let mut holds = HOLDS.lock().unwrap_or_else(|e| e.into_inner());
+        match holds.iter_mut().find(|hold| hold.key == key) {
+            Some(hold) => hold.count += 1,
+            None => {
+                let mut socket = socket.clone();
+                let was_set = socket.has_flag(IFF_PROMISC as c_ulong)?;
+                if !was_set {
+                    socket.set_flag(IFF_PROMISC as c_ulong)?;
+                }
+                holds.push(Hold {
+                    key,
+                    count: 1,
+                    was_set,
+                });
+            }
+        }
+        let mut dup_socket = socket.clone();
+        dup_socket.fd = unsafe { dup(socket.fd) };
+        if dup_socket.fd < 0 {
+            let err = Error::last_os_error("dup");
+            let _ = release(&mut holds, key, socket);
+            return Err(err);
+        }
+        Ok(PromiscuousFlag {
+            key,
+            socket: dup_socket,
+            released: AtomicBool::new(false),
+        })
+    }
+
+    //gives up this share, clearing the flag if it was the last one and the flag was not set
+    //before; only the first call counts
+    pub(crate) fn release(&self) -> Result<()> {
+        if self.released.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let mut holds = HOLDS.lock().unwrap_or_else(|e| e.into_inner());
+        release(&mut holds, self.key, &self.socket)
+    }
+}
+
+impl Drop for PromiscuousFlag {
+    fn drop(&mut self) {
+        let _ = self.release();
+        unsafe {
+            close(self.socket.fd);
+        }
+    }
+}
+
+fn release(holds: &mut Vec<Hold>, key: Key, socket: &Socket) -> Result<()> {
+    let i = match holds.iter().position(|hold| hold.key == key) {
+        Some(i) => i,
+        None => return Ok(()),
+    };
+    holds[i].count -= 1;
+    if holds[i].count > 0 {
+        return Ok(());
+    }
+    let hold = holds.swap_remove(i);
+    if hold.was_set {
+        return Ok(());
+    }
+    socket.clone().clear_flag(IFF_PROMISC as c_ulong)
+}
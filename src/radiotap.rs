@@ -0,0 +1,294 @@
+//!Radiotap headers in front of 802.11 frames captured on wlan interfaces in monitor mode
+//!
+//!Such interfaces have the hardware type `ARPHRD_IEEE80211_RADIOTAP`, see
+//!`Ring::hardware_type()`. Every frame starts with a radiotap header of variable length
+//!carrying the radio metadata, followed by the 802.11 frame itself.
+
+use crate::rx::RawPacket;
+
+///ARPHRD_* hardware type of 802.11 interfaces that prepend radiotap headers
+pub const ARPHRD_IEEE80211_RADIOTAP: u16 = 803;
+
+const TSFT: u32 = 0;
+const FLAGS: u32 = 1;
+const RATE: u32 = 2;
+const CHANNEL: u32 = 3;
+const ANTENNA_SIGNAL: u32 = 5;
+const ANTENNA_NOISE: u32 = 6;
+const ANTENNA: u32 = 11;
+const EXT: u32 = 31;
+
+///The frame ends with its 4 byte FCS
+pub const FLAG_FCS: u8 = 0x10;
+///The frame failed the FCS check
+pub const FLAG_BAD_FCS: u8 = 0x40;
+
+//alignment and size of the fields of the default namespace, by presence bit; parsing stops at
+//the first present field not in here as its size is unknown
+const FIELDS: [(usize, usize); 28] = [
+    (8, 8),  //TSFT
+    (1, 1),  //flags
+    (1, 1),  //rate
+    (2, 4),  //channel
+    (1, 2),  //FHSS
+    (1, 1),  //antenna signal, dBm
+    (1, 1),  //antenna noise, dBm
+    (2, 2),  //lock quality
+    (2, 2),  //TX attenuation
+    (2, 2),  //TX attenuation, dB
+    (1, 1),  //TX power, dBm
+    (1, 1),  //antenna
+    (1, 1),  //antenna signal, dB
+    (1, 1),  //antenna noise, dB
+    (2, 2),  //RX flags
+    (2, 2),  //TX flags
+    (1, 1),  //RTS retries
+    (1, 1),  //data retries
+    (4, 8),  //XChannel
+    (1, 3),  //MCS
+    (4, 8),  //A-MPDU status
+    (2, 12), //VHT
+    (8, 12), //timestamp
+    (2, 12), //HE
+    (2, 12), //HE-MU
+    (2, 6),  //HE-MU-other-user
+    (1, 1),  //0-length PSDU
+    (2, 4),  //L-SIG
+];
+
+///Radio metadata of a frame, the commonly used fields of its radiotap header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Radiotap {
+    ///Length of the whole header, the 802.11 frame starts right after it
+    pub len: usize,
+    ///Bitmap of the fields present, from the first presence word
+    pub present: u32,
+    ///Value of the TSF timer when the frame arrived, in microseconds
+    pub tsft: Option<u64>,
+    ///`FLAG_*` bits
+    pub flags: Option<u8>,
+    ///Data rate in units of 500 kbit/s
+    pub rate: Option<u8>,
+    ///Channel frequency in MHz
+    pub channel_freq: Option<u16>,
+    pub channel_flags: Option<u16>,
+    ///Signal power at the antenna in dBm
+    pub antenna_signal: Option<i8>,
+    ///Noise power at the antenna in dBm
+    pub antenna_noise: Option<i8>,
+    ///Antenna index the frame was received on
+    pub antenna: Option<u8>,
+}
+
+impl Radiotap {
+    ///Parses the radiotap header at the start of `frame`
+    ///
+    ///Fields after the first one of unknown size are left unset; None if the header is
+    ///malformed.
+    pub fn parse(frame: &[u8]) -> Option<Radiotap> {
+        let hdr = frame.get(..8)?;
+        if hdr[0] != 0 {
+            return None;
+        }
+        let len = u16::from_le_bytes([hdr[2], hdr[3]]) as usize;
+        if len < 8 {
+            return None;
+        }
+        let hdr = frame.get(..len)?;
+        let present = u32::from_le_bytes([hdr[4], hdr[5], hdr[6], hdr[7]]);
+        let mut radiotap = Radiotap {
+            len,
+            present,
+            ..Radiotap::default()
+        };
+        //extended presence words come before the fields
+        let mut at = 8;
+        let mut word = present;
+        while word & (1 << EXT) != 0 {
+            let next = hdr.get(at..at + 4)?;
+            word = u32::from_le_bytes([next[0], next[1], next[2], next[3]]);
+            at += 4;
+        }
+        for bit in 0..EXT {
+            if present & (1 << bit) == 0 {
+                continue;
+            }
+            let (align, size) = match FIELDS.get(bit as usize) {
+                Some(&field) => field,
+                None => break,
+            };
+            at = at.next_multiple_of(align);
+            let field = match hdr.get(at..at + size) {
+                Some(field) => field,
+                None => break,
+            };
+            at += size;
+            match bit {
+                TSFT => {
+                    let mut tsft = [0; 8];
+                    tsft.copy_from_slice(field);
+                    radiotap.tsft = Some(u64::from_le_bytes(tsft));
+                }
+                FLAGS => radiotap.flags = Some(field[0]),
+                RATE => radiotap.rate = Some(field[0]),
+                CHANNEL => {
+                    radiotap.channel_freq = Some(u16::from_le_bytes([field[0], field[1]]));
+                    radiotap.channel_flags = Some(u16::from_le_bytes([field[2], field[3]]));
+                }
+                ANTENNA_SIGNAL => radiotap.antenna_signal = Some(field[0] as i8),
+                ANTENNA_NOISE => radiotap.antenna_noise = Some(field[0] as i8),
+                ANTENNA => radiotap.antenna = Some(field[0]),
+                _ => {}
+            }
+        }
+        Some(radiotap)
+    }
+
+    ///Whether the 802.11 frame still ends with its FCS
+    pub fn has_fcs(&self) -> bool {
+        self.flags.is_some_and(|flags| flags & FLAG_FCS != 0)
+    }
+}
+
+///802.11 frame after the radiotap header at the start of `frame`, without its FCS
+pub fn ieee80211_frame(frame: &[u8]) -> Option<&[u8]> {
+    let radiotap = Radiotap::parse(frame)?;
+    let mut end = frame.len();
+    if radiotap.has_fcs() {
+        end = end.checked_sub(4)?.max(radiotap.len);
+    }
+    frame.get(radiotap.len..end)
+}
+
+impl<'a> RawPacket<'a> {
+    ///Radiotap header of a frame captured on a monitor mode wlan interface
+    pub fn radiotap(&self) -> Option<Radiotap> {
+        if self.link_info()?.hatype != ARPHRD_IEEE80211_RADIOTAP {
+            return None;
+        }
+        Radiotap::parse(self.payload())
+    }
+
+    ///802.11 frame of a packet captured on a monitor mode wlan interface, with the radiotap
+    ///header and FCS stripped
+    pub fn ieee80211_frame(&self) -> Option<&'a [u8]> {
+        if self.link_info()?.hatype != ARPHRD_IEEE80211_RADIOTAP {
+            return None;
+        }
+        ieee80211_frame(self.payload())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockRing;
+    use crate::tpacket3::{SockAddrLl, Tpacket3Hdr};
+
+    fn header(present: &[u32], fields: &[u8]) -> Vec<u8> {
+        let mut hdr = vec![0, 0, 0, 0];
+        for word in present {
+            hdr.extend_from_slice(&word.to_le_bytes());
+        }
+        hdr.extend_from_slice(fields);
+        let len = hdr.len() as u16;
+        hdr[2..4].copy_from_slice(&len.to_le_bytes());
+        hdr
+    }
+
+    //TSFT, flags, rate, channel, signal, noise and antenna
+    fn full_header(flags: u8) -> Vec<u8> {
+        let mut fields = 0x0102_0304_0506_0708u64.to_le_bytes().to_vec();
+        fields.extend_from_slice(&[flags, 12]);
+        fields.extend_from_slice(&2437u16.to_le_bytes());
+        fields.extend_from_slice(&0x00a0u16.to_le_bytes());
+        fields.extend_from_slice(&[(-42i8) as u8, (-95i8) as u8, 1]);
+        header(&[0x086f], &fields)
+    }
+
+    #[test]
+    fn parses_common_fields() {
+        let hdr = full_header(0);
+        let radiotap = Radiotap::parse(&hdr).unwrap();
+        assert_eq!(
+            radiotap,
+            Radiotap {
+                len: 25,
+                present: 0x086f,
+                tsft: Some(0x0102_0304_0506_0708),
+                flags: Some(0),
+                rate: Some(12),
+                channel_freq: Some(2437),
+                channel_flags: Some(0x00a0),
+                antenna_signal: Some(-42),
+                antenna_noise: Some(-95),
+                antenna: Some(1),
+            }
+        );
+        assert!(!radiotap.has_fcs());
+    }
+
+    #[test]
+    fn aligns_fields_from_the_start_of_the_header() {
+        //flags at 8, then the channel aligned to 2 bytes at 10
+        let hdr = header(&[0x000a], &[0x10, 0xff, 0x6c, 0x09, 0, 0]);
+        let radiotap = Radiotap::parse(&hdr).unwrap();
+        assert_eq!(radiotap.channel_freq, Some(2412));
+        assert!(radiotap.has_fcs());
+
+        //an extended presence word moves the TSFT to 16
+        let mut fields = vec![0; 4];
+        fields.extend_from_slice(&7u64.to_le_bytes());
+        let hdr = header(&[0x8000_0001, 0], &fields);
+        assert_eq!(Radiotap::parse(&hdr).unwrap().tsft, Some(7));
+    }
+
+    #[test]
+    fn strips_the_header_and_fcs() {
+        let body = [0x80, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef];
+        let mut frame = full_header(FLAG_FCS);
+        frame.extend_from_slice(&body);
+        assert_eq!(ieee80211_frame(&frame), Some(&body[..4]));
+
+        let mut frame = full_header(0);
+        frame.extend_from_slice(&body);
+        assert_eq!(ieee80211_frame(&frame), Some(&body[..]));
+        //an FCS flag on a frame too short to hold one leaves it empty
+        let frame = full_header(FLAG_FCS);
+        assert_eq!(ieee80211_frame(&frame), Some(&[][..]));
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        assert!(Radiotap::parse(&[0, 0, 8]).is_none());
+        //unknown version
+        assert!(Radiotap::parse(&[1, 0, 8, 0, 0, 0, 0, 0]).is_none());
+        assert!(Radiotap::parse(&[0, 0, 4, 0, 0, 0, 0, 0]).is_none());
+        //longer than the frame
+        assert!(Radiotap::parse(&[0, 0, 16, 0, 0, 0, 0, 0]).is_none());
+        //extended presence word missing
+        assert!(Radiotap::parse(&header(&[0x8000_0000], &[])).is_none());
+        //fields cut short are left unset
+        let hdr = header(&[0x0001], &[0; 4]);
+        assert_eq!(Radiotap::parse(&hdr).unwrap().tsft, None);
+    }
+
+    #[test]
+    fn only_on_radiotap_interfaces() {
+        let mut frame = full_header(0);
+        frame.extend_from_slice(&[0x80, 0, 0, 0]);
+        let mut ring = MockRing::new(vec![frame.clone()]);
+        let sll = SockAddrLl {
+            sll_hatype: ARPHRD_IEEE80211_RADIOTAP,
+            ..SockAddrLl::default()
+        };
+        ring.push_packet(Tpacket3Hdr::default(), sll, &frame);
+
+        let block = ring.get_block().unwrap();
+        let packets = block.get_raw_packets();
+        assert_eq!(packets[0].radiotap(), None);
+        assert_eq!(packets[0].ieee80211_frame(), None);
+        assert_eq!(packets[1].radiotap().unwrap().antenna, Some(1));
+        assert_eq!(packets[1].ieee80211_frame(), Some(&[0x80, 0, 0, 0][..]));
+    }
+}
@@ -0,0 +1,209 @@
+//!Single-threaded capture from many rings through one epoll instance
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use libc::{
+    c_int, close, epoll_create1, epoll_ctl, epoll_event, epoll_wait, EPOLLERR, EPOLLIN,
+    EPOLL_CLOEXEC, EPOLL_CTL_ADD, EPOLL_CTL_DEL,
+};
+
+use crate::error::{Error, Result};
+use crate::rx::{Block, Ring};
+use crate::shutdown::ShutdownHandle;
+
+const SHUTDOWN_TOKEN: u64 = u64::MAX;
+const MAX_EVENTS: usize = 64;
+
+///Owns a set of rings and yields their blocks as they are retired, in the order their sockets
+///become readable
+///
+///Rings are identified by the id `add()` returned; ids of removed rings are not reused. Rings
+///on different interfaces need their own `fanout_group`, e.g. from
+///`group::unique_fanout_group()`.
+#[derive(Debug)]
+pub struct Reactor {
+    epfd: c_int,
+    rings: Vec<Option<Ring>>,
+    //rings that may have a block ready, each listed at most once
+    ready: VecDeque<usize>,
+    queued: Vec<bool>,
+    shutdown: Option<ShutdownHandle>,
+}
+
+impl Reactor {
+    ///Creates a reactor without any rings
+    pub fn new() -> Result<Reactor> {
+        let epfd = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+        if epfd < 0 {
+            return Err(Error::last_os_error("epoll_create1"));
+        }
+        Ok(Reactor {
+            epfd,
+            rings: Vec::new(),
+            ready: VecDeque::new(),
+            queued: Vec::new(),
+            shutdown: None,
+        })
+    }
+
+    ///Starts watching a ring and returns its id
+    pub fn add(&mut self, ring: Ring) -> Result<usize> {
+        let id = self.rings.len();
+        self.register(ring.socket.fd, (EPOLLIN | EPOLLERR) as u32, id as u64)?;
+        self.rings.push(Some(ring));
+        self.queued.push(false);
+        //blocks may have been retired before the ring was added
+        self.enqueue(id);
+        Ok(id)
+    }
+
+    ///Stops watching a ring and hands it back
+    pub fn remove(&mut self, id: usize) -> Option<Ring> {
+        let ring = self.rings.get_mut(id)?.take()?;
+        unsafe {
+            epoll_ctl(
+                self.epfd,
+                EPOLL_CTL_DEL,
+                ring.socket.fd,
+                std::ptr::null_mut(),
+            );
+        }
+        self.ready.retain(|&i| i != id);
+        self.queued[id] = false;
+        Some(ring)
+    }
+
+    ///Ring with the given id, e.g. for statistics
+    pub fn ring(&self, id: usize) -> Option<&Ring> {
+        self.rings.get(id).and_then(|r| r.as_ref())
+    }
+
+    ///Ring with the given id, e.g. for statistics
+    pub fn ring_mut(&mut self, id: usize) -> Option<&mut Ring> {
+        self.rings.get_mut(id).and_then(|r| r.as_mut())
+    }
+
+    ///Ids of the rings currently watched
+    pub fn ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.rings
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.is_some())
+            .map(|(id, _)| id)
+    }
+
+    ///Number of rings currently watched
+    pub fn len(&self) -> usize {
+        self.rings.iter().filter(|r| r.is_some()).count()
+    }
+
+    ///Whether no rings are watched
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    ///Makes `next_block()` return `Error::Shutdown` once the handle is signaled
+    pub fn set_shutdown_handle(&mut self, handle: ShutdownHandle) -> Result<()> {
+        if let Some(old) = self.shutdown.take() {
+            unsafe {
+                epoll_ctl(self.epfd, EPOLL_CTL_DEL, old.fd(), std::ptr::null_mut());
+            }
+        }
+        self.register(handle.fd(), EPOLLIN as u32, SHUTDOWN_TOKEN)?;
+        self.shutdown = Some(handle);
+        Ok(())
+    }
+
+    ///Waits for the next block from any ring, returns `Error::Shutdown` once the shutdown
+    ///handle is signaled
+    pub fn next_block(&mut self) -> Result<(usize, Block<'_>)> {
+        loop {
+            if let Some(found) = self.next_queued_block() {
+                return Ok(found);
+            }
+            self.wait(-1)?;
+        }
+    }
+
+    ///Like `next_block()`, but gives up and returns `None` once `timeout` has passed
+    pub fn next_block_timeout(&mut self, timeout: Duration) -> Result<Option<(usize, Block<'_>)>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(found) = self.next_queued_block() {
+                return Ok(Some(found));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let remaining = deadline - now;
+            self.wait(remaining.as_millis().clamp(1, c_int::MAX as u128) as c_int)?;
+        }
+    }
+
+    fn next_queued_block<'a>(&mut self) -> Option<(usize, Block<'a>)> {
+        while let Some(id) = self.ready.pop_front() {
+            self.queued[id] = false;
+            let ring = match self.rings[id].as_mut() {
+                Some(ring) => ring,
+                None => continue,
+            };
+            if let Some(block) = ring.next_ready_block() {
+                //requeue behind the others so one busy ring can't starve the rest
+                self.enqueue(id);
+                return Some((id, block));
+            }
+        }
+        None
+    }
+
+    fn wait(&mut self, timeout_ms: c_int) -> Result<()> {
+        let mut events = [epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let n = unsafe {
+            epoll_wait(
+                self.epfd,
+                events.as_mut_ptr(),
+                MAX_EVENTS as c_int,
+                timeout_ms,
+            )
+        };
+        if n < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EINTR) {
+                return Ok(());
+            }
+            return Err(Error::os("epoll_wait", err));
+        }
+        for event in &events[..n as usize] {
+            match event.u64 {
+                SHUTDOWN_TOKEN => return Err(Error::Shutdown),
+                id => self.enqueue(id as usize),
+            }
+        }
+        Ok(())
+    }
+
+    fn enqueue(&mut self, id: usize) {
+        if !self.queued[id] {
+            self.queued[id] = true;
+            self.ready.push_back(id);
+        }
+    }
+
+    fn register(&self, fd: c_int, events: u32, token: u64) -> Result<()> {
+        let mut event = epoll_event { events, u64: token };
+        match unsafe { epoll_ctl(self.epfd, EPOLL_CTL_ADD, fd, &mut event) } {
+            0 => Ok(()),
+            _ => Err(Error::last_os_error("epoll_ctl")),
+        }
+    }
+}
+
+impl Drop for Reactor {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.epfd);
+        }
+    }
+}
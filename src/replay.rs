@@ -0,0 +1,128 @@
+//!Transmitting capture files onto an interface, a small tcpreplay
+
+use std::path::Path;
+use std::thread;
+use std::time::{Instant, SystemTime};
+
+use crate::error::{Error, Result};
+use crate::offline;
+use crate::pacer::{Pacer, Rate};
+use crate::pcapng::DLT_EN10MB;
+use crate::shutdown::ShutdownHandle;
+use crate::socket::{Socket, AF_PACKET};
+
+///How fast packets are sent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timing {
+    ///Keep the gaps between packets recorded in the file
+    Original,
+    ///Keep the recorded gaps divided by a factor, 2.0 replays twice as fast
+    Scaled(f64),
+    ///Send packets back to back
+    AsFastAsPossible,
+    ///Ignore the recorded gaps and send at a fixed rate
+    Paced(Rate),
+}
+
+///Options for `replay_file()`
+#[derive(Clone, Debug)]
+pub struct ReplayOptions {
+    pub timing: Timing,
+    ///How many times to send the file, 0 to loop until the shutdown handle is signaled
+    pub loops: u32,
+    ///Stops the replay early, checked once per block and before every wait
+    pub shutdown: Option<ShutdownHandle>,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> ReplayOptions {
+        ReplayOptions {
+            timing: Timing::Original,
+            loops: 1,
+            shutdown: None,
+        }
+    }
+}
+
+///What a replay sent
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub packets: u64,
+    pub bytes: u64,
+    ///Passes over the file that ran to the end
+    pub loops: u32,
+}
+
+///Sends every packet of a pcap or pcapng file out of `if_name`
+///
+///Only Ethernet captures can be replayed. Packets are sent as captured, so ones cut short by
+///the snapshot length go out truncated.
+pub fn replay_file<P: AsRef<Path>>(
+    path: P,
+    if_name: &str,
+    opts: ReplayOptions,
+) -> Result<ReplayStats> {
+    let path = path.as_ref();
+    let sock = Socket::from_if_name(if_name, AF_PACKET)?;
+    let mut pacer = match opts.timing {
+        Timing::Paced(rate) => Some(Pacer::new(rate)),
+        _ => None,
+    };
+    let speed = match opts.timing {
+        Timing::Original => Some(1.0),
+        Timing::Scaled(factor) if factor > 0.0 => Some(factor),
+        _ => None,
+    };
+    let mut stats = ReplayStats::default();
+
+    while opts.loops == 0 || stats.loops < opts.loops {
+        let mut file = offline::Ring::open(path)?;
+        if file.link_type() != DLT_EN10MB {
+            return Err(Error::BadCaptureFile(format!(
+                "cannot replay link type {}, only Ethernet",
+                file.link_type()
+            )));
+        }
+        //the first packet of every pass is sent right away
+        let mut origin: Option<(SystemTime, Instant)> = None;
+
+        while let Some(block) = file.get_block()? {
+            if is_shut_down(&opts) {
+                return Ok(stats);
+            }
+            for packet in block.get_raw_packets() {
+                let frame = packet.payload();
+                if let Some(speed) = speed {
+                    let ts = packet.timestamp();
+                    match origin {
+                        None => origin = Some((ts, Instant::now())),
+                        Some((first_ts, start)) => {
+                            //out of order timestamps are sent immediately
+                            let offset = ts.duration_since(first_ts).unwrap_or_default();
+                            let due = start + offset.div_f64(speed);
+                            let now = Instant::now();
+                            if due > now {
+                                if is_shut_down(&opts) {
+                                    return Ok(stats);
+                                }
+                                thread::sleep(due - now);
+                            }
+                        }
+                    }
+                }
+                if let Some(pacer) = pacer.as_mut() {
+                    pacer.wait(frame.len());
+                }
+                sock.send_frame(frame)?;
+                stats.packets += 1;
+                stats.bytes += frame.len() as u64;
+            }
+        }
+        stats.loops += 1;
+    }
+    Ok(stats)
+}
+
+fn is_shut_down(opts: &ReplayOptions) -> bool {
+    opts.shutdown.as_ref().is_some_and(|s| s.is_signaled())
+}
@@ -0,0 +1,183 @@
+//!Ring that survives its interface going away, e.g. USB NICs being replugged or container
+//!interfaces being recreated
+
+use std::os::unix::io::AsRawFd;
+
+use libc::{poll, pollfd, POLLIN};
+
+use crate::error::{Error, Result};
+use crate::filter::FilterProgram;
+use crate::iface::LinkWatcher;
+use crate::rx::{Block, Ring, RingSettings};
+use crate::shutdown::ShutdownHandle;
+use crate::source::PacketSource;
+
+///Wraps a `Ring` and rebuilds it from the same settings, filter and fanout group whenever its
+///interface is removed and a new one with the same name shows up
+///
+///While the interface is down or missing `recv_block()` simply keeps waiting. Packets are only
+///lost for as long as there is no interface to capture on.
+#[derive(Debug)]
+pub struct ResilientRing {
+    settings: RingSettings,
+    ring: Option<Ring>,
+    watcher: LinkWatcher,
+    shutdown: Option<ShutdownHandle>,
+    rebuilds: u64,
+}
+
+impl ResilientRing {
+    ///Opens the ring right away if the interface exists, otherwise on the first `recv_block()`
+    ///once it appears
+    pub fn new(mut settings: RingSettings) -> Result<ResilientRing> {
+        settings.report_link_down = true;
+        let watcher = LinkWatcher::for_interface(&settings.if_name)?;
+        let ring = match Ring::new(settings.clone()) {
+            Ok(ring) => Some(ring),
+            Err(Error::NoSuchInterface(_)) => None,
+            Err(err) => return Err(err),
+        };
+        Ok(ResilientRing {
+            settings,
+            ring,
+            watcher,
+            shutdown: None,
+            rebuilds: 0,
+        })
+    }
+
+    ///Waits for the next block, through any number of interface flaps. Returns
+    ///`Error::Shutdown` once the `ShutdownHandle` is signaled.
+    pub fn recv_block(&mut self) -> Result<Block<'_>> {
+        loop {
+            let ring = match self.ring.as_mut() {
+                Some(ring) => ring,
+                None => {
+                    self.reopen()?;
+                    continue;
+                }
+            };
+            if let Some(block) = ring.next_ready_block() {
+                return Ok(block);
+            }
+            if ring.wait_for_block(-1) {
+                return Err(Error::Shutdown);
+            }
+            if let Err(Error::InterfaceDown(_)) = ring.check_link_down() {
+                let index = self.wait_until_up()?;
+                let ring = self.ring.as_mut().expect("ring checked above");
+                if index != ring.socket.if_index as i32 {
+                    //removed and added again, the old socket is bound to a dead ifindex
+                    self.ring = None;
+                }
+            }
+        }
+    }
+
+    ///Returns a handle that stops `recv_block()` from another thread, also while waiting for the
+    ///interface to come back
+    pub fn shutdown_handle(&mut self) -> Result<ShutdownHandle> {
+        if let Some(handle) = &self.shutdown {
+            return Ok(handle.clone());
+        }
+        let handle = ShutdownHandle::new()?;
+        if let Some(ring) = self.ring.as_mut() {
+            ring.set_shutdown_handle(handle.clone());
+        }
+        self.shutdown = Some(handle.clone());
+        Ok(handle)
+    }
+
+    ///Replaces the filter on the current ring and on every ring built after it
+    pub fn set_filter(&mut self, filter: FilterProgram) -> Result<()> {
+        if let Some(ring) = self.ring.as_mut() {
+            ring.set_filter(&filter)?;
+        }
+        self.settings.filter = Some(filter);
+        Ok(())
+    }
+
+    ///Current ring, None while waiting for the interface to come back
+    pub fn ring(&self) -> Option<&Ring> {
+        self.ring.as_ref()
+    }
+
+    ///Current ring, e.g. for statistics
+    pub fn ring_mut(&mut self) -> Option<&mut Ring> {
+        self.ring.as_mut()
+    }
+
+    ///Settings every ring is built from
+    pub fn settings(&self) -> &RingSettings {
+        &self.settings
+    }
+
+    ///How many times the ring was rebuilt
+    pub fn rebuilds(&self) -> u64 {
+        self.rebuilds
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        loop {
+            self.wait_until_up()?;
+            match Ring::new(self.settings.clone()) {
+                Ok(mut ring) => {
+                    if let Some(handle) = &self.shutdown {
+                        ring.set_shutdown_handle(handle.clone());
+                    }
+                    self.ring = Some(ring);
+                    self.rebuilds += 1;
+                    return Ok(());
+                }
+                //gone again before the socket was bound
+                Err(Error::NoSuchInterface(_)) | Err(Error::InterfaceDown(_)) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    //waits until an interface with the ring's name is up and running, returns its index
+    fn wait_until_up(&mut self) -> Result<i32> {
+        loop {
+            while self.watcher.try_next_event()?.is_some() {}
+            let name = &self.settings.if_name;
+            let up = self
+                .watcher
+                .interfaces()
+                .find(|iface| &iface.name == name && iface.is_up() && iface.is_running());
+            if let Some(iface) = up {
+                return Ok(iface.index);
+            }
+
+            let mut pfds = [
+                pollfd {
+                    fd: self.watcher.as_raw_fd(),
+                    events: POLLIN,
+                    revents: 0,
+                },
+                pollfd {
+                    fd: self.shutdown.as_ref().map(|s| s.fd()).unwrap_or(-1),
+                    events: POLLIN,
+                    revents: 0,
+                },
+            ];
+            if unsafe { poll(pfds.as_mut_ptr(), 2, -1) } < 0 {
+                return Err(Error::last_os_error("poll"));
+            }
+            if pfds[1].revents & POLLIN != 0 {
+                return Err(Error::Shutdown);
+            }
+        }
+    }
+}
+
+impl PacketSource for ResilientRing {
+    ///Returns `None` once the `ShutdownHandle` is signaled
+    fn next_block(&mut self) -> Result<Option<Block<'_>>> {
+        match self.recv_block() {
+            Ok(block) => Ok(Some(block)),
+            Err(Error::Shutdown) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
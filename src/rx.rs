@@ -1,28 +1,71 @@
 use std;
-use std::io::{self, Error};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
 use std::mem;
+use std::ops::BitOr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use libc::{
-    bind, c_int, c_void, getpid, mmap, poll, pollfd, sockaddr, sockaddr_ll, socklen_t, AF_PACKET,
-    ETH_ALEN, ETH_P_IP, MAP_LOCKED, MAP_NORESERVE, MAP_SHARED, POLLERR, POLLIN, PROT_READ,
-    PROT_WRITE,
+    bind, c_int, c_uint, c_void, close, geteuid, getpid, getrlimit, mmap, munmap, poll, pollfd,
+    rlimit, sockaddr, sockaddr_ll, socklen_t, sysconf, _SC_PAGESIZE, AF_PACKET, EACCES, EAGAIN,
+    EINVAL, ENETDOWN, ENOENT, ENOMEM, EPERM, ETH_ALEN, ETH_P_8021Q, MAP_HUGETLB, MAP_HUGE_1GB,
+    MAP_HUGE_2MB, MAP_LOCKED, MAP_NORESERVE, MAP_POPULATE, MAP_SHARED, POLLERR, POLLIN, PROT_READ,
+    PROT_WRITE, RLIMIT_MEMLOCK, RLIM_INFINITY, SOCK_DGRAM, SOCK_RAW,
 };
 
-use crate::socket::{self, Socket, IFF_PROMISC};
+use crate::error::{Error, Privilege, Result};
+use crate::filter::FilterProgram;
+use crate::filters;
+use crate::iface;
+use crate::netns::{self, NetNs};
+use crate::numa;
+use crate::pcapng;
+use crate::promisc::PromiscuousFlag;
+use crate::shared::{BlockMemory, Lease, Leases, Packet, SharedBlock};
+use crate::shutdown::ShutdownHandle;
+use crate::sll::{LinuxSllHeader, DLT_LINUX_SLL};
+use crate::socket::{self, EtherType, Socket};
+use crate::stats::{CloseReport, MemoryInfo, RingStats, RolloverStats};
 
-use crate::tpacket3;
+use crate::tpacket2::{self, TPACKET_V2};
+use crate::tpacket3::{self, BlockBuilder, TpStatus};
 
 //Used digits for these consts, if they were defined differently in C headers I have added that definition in the comments beside them
 
-const PACKET_RX_RING: c_int = 5;
+pub(crate) const PACKET_RX_RING: c_int = 5;
 const PACKET_STATISTICS: c_int = 6;
-const PACKET_VERSION: c_int = 10;
+const PACKET_COPY_THRESH: c_int = 7;
+pub(crate) const PACKET_VERSION: c_int = 10;
+const PACKET_RESERVE: c_int = 12;
+pub(crate) const PACKET_VNET_HDR: c_int = 15;
+pub(crate) const PACKET_IGNORE_OUTGOING: c_int = 23;
 const PACKET_FANOUT: c_int = 18;
 
 /* https://stackoverflow.com/questions/43193889/sending-data-with-packet-mmap-and-packet-tx-ring-is-slower-than-normal-withou */
 
 pub const PACKET_FANOUT_HASH: c_int = 0;
 pub const PACKET_FANOUT_LB: c_int = 1;
+pub const PACKET_FANOUT_CPU: c_int = 2;
+pub const PACKET_FANOUT_ROLLOVER: c_int = 3;
+pub const PACKET_FANOUT_RND: c_int = 4;
+pub const PACKET_FANOUT_QM: c_int = 5;
+///Steers packets with a classic BPF program, see `Ring::set_fanout_cbpf()`
+pub const PACKET_FANOUT_CBPF: c_int = 6;
+///Steers packets with an eBPF program, see `Ring::set_fanout_ebpf()`
+pub const PACKET_FANOUT_EBPF: c_int = 7;
+///A full ring passes packets on to another ring of the group instead of dropping them, see
+///`FanoutFlags::ROLLOVER`
+pub const PACKET_FANOUT_FLAG_ROLLOVER: c_int = 0x1000;
+///The kernel reassembles IPv4 fragments before fanout, see `FanoutFlags::DEFRAG`
+pub const PACKET_FANOUT_FLAG_DEFRAG: c_int = 0x8000;
+
+const PACKET_ROLLOVER_STATS: c_int = 21;
+const PACKET_FANOUT_DATA: c_int = 22;
+
+//block timeout assumed by `Ring::drain()` when the kernel picked it
+const DRAIN_DEFAULT_TOV_MS: c_int = 100;
 
 const PACKET_HOST: u8 = 0;
 const PACKET_BROADCAST: u8 = 1;
@@ -30,42 +73,667 @@ const PACKET_MULTICAST: u8 = 2;
 const PACKET_OTHERHOST: u8 = 3;
 const PACKET_OUTGOING: u8 = 4;
 
+///Direction of a packet relative to this host, as reported by the kernel (sll_pkttype)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PacketDirection {
+    ///Addressed to this host
+    Host,
+    ///Link-layer broadcast
+    Broadcast,
+    ///Link-layer multicast
+    Multicast,
+    ///Addressed to another host, seen in promiscuous mode
+    OtherHost,
+    ///Transmitted by this host
+    Outgoing,
+    ///Any other value
+    Other(u8),
+}
+
+impl From<u8> for PacketDirection {
+    fn from(pkttype: u8) -> PacketDirection {
+        match pkttype {
+            PACKET_HOST => PacketDirection::Host,
+            PACKET_BROADCAST => PacketDirection::Broadcast,
+            PACKET_MULTICAST => PacketDirection::Multicast,
+            PACKET_OTHERHOST => PacketDirection::OtherHost,
+            PACKET_OUTGOING => PacketDirection::Outgoing,
+            other => PacketDirection::Other(other),
+        }
+    }
+}
+
+///How a ring puts its interface into promiscuous mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Promiscuous {
+    ///Leave the interface alone, only traffic addressed to the host is captured
+    Off,
+    ///Hold a PACKET_MR_PROMISC membership on the ring's socket, dropped by the kernel when the
+    ///ring is closed
+    Membership,
+    ///Set IFF_PROMISC on the interface, cleared again when the last ring of the process that
+    ///set it is dropped unless it was set before; left set if the process dies
+    InterfaceFlag,
+}
+
+///TPACKET version of a ring's memory layout
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TpacketVersion {
+    ///Fixed size frames, each handed back to the kernel on its own
+    V2,
+    ///Variable sized frames packed into blocks, Linux 3.2+
+    V3,
+}
+
+impl TpacketVersion {
+    ///PACKET_VERSION value, TPACKET_V2 or TPACKET_V3
+    pub fn as_raw(self) -> c_int {
+        match self {
+            TpacketVersion::V2 => TPACKET_V2,
+            TpacketVersion::V3 => tpacket3::TPACKET_V3,
+        }
+    }
+
+    ///Version for a PACKET_VERSION value, None for TPACKET_V1 and unknown ones
+    pub fn from_raw(version: c_int) -> Option<TpacketVersion> {
+        match version {
+            TPACKET_V2 => Some(TpacketVersion::V2),
+            tpacket3::TPACKET_V3 => Some(TpacketVersion::V3),
+            _ => None,
+        }
+    }
+}
+
+///How a fanout group spreads packets over its rings, see `RingSettings::fanout_method`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum FanoutMethod {
+    ///By flow hash, pinning every flow to one ring (PACKET_FANOUT_HASH)
+    #[default]
+    Hash,
+    ///Round robin (PACKET_FANOUT_LB)
+    Lb,
+    ///To the ring of the CPU that received the packet (PACKET_FANOUT_CPU)
+    Cpu,
+    ///To the first ring with room, moving on when it fills up (PACKET_FANOUT_ROLLOVER)
+    Rollover,
+    ///To a random ring (PACKET_FANOUT_RND)
+    Rnd,
+    ///By receive queue of the NIC (PACKET_FANOUT_QM)
+    Qm,
+    ///By a classic BPF program, see `Ring::set_fanout_cbpf()` (PACKET_FANOUT_CBPF)
+    Cbpf,
+    ///By an eBPF program, see `Ring::set_fanout_ebpf()` (PACKET_FANOUT_EBPF)
+    Ebpf,
+    ///PACKET_FANOUT_* value passed to the kernel as is, for methods or flag combinations this
+    ///crate does not know
+    Raw(c_int),
+}
+
+impl FanoutMethod {
+    ///PACKET_FANOUT_* value
+    pub fn as_raw(self) -> c_int {
+        match self {
+            FanoutMethod::Hash => PACKET_FANOUT_HASH,
+            FanoutMethod::Lb => PACKET_FANOUT_LB,
+            FanoutMethod::Cpu => PACKET_FANOUT_CPU,
+            FanoutMethod::Rollover => PACKET_FANOUT_ROLLOVER,
+            FanoutMethod::Rnd => PACKET_FANOUT_RND,
+            FanoutMethod::Qm => PACKET_FANOUT_QM,
+            FanoutMethod::Cbpf => PACKET_FANOUT_CBPF,
+            FanoutMethod::Ebpf => PACKET_FANOUT_EBPF,
+            FanoutMethod::Raw(method) => method,
+        }
+    }
+
+    ///Method for a PACKET_FANOUT_* value, `Raw` for unknown ones
+    pub fn from_raw(method: c_int) -> FanoutMethod {
+        match method {
+            PACKET_FANOUT_HASH => FanoutMethod::Hash,
+            PACKET_FANOUT_LB => FanoutMethod::Lb,
+            PACKET_FANOUT_CPU => FanoutMethod::Cpu,
+            PACKET_FANOUT_ROLLOVER => FanoutMethod::Rollover,
+            PACKET_FANOUT_RND => FanoutMethod::Rnd,
+            PACKET_FANOUT_QM => FanoutMethod::Qm,
+            PACKET_FANOUT_CBPF => FanoutMethod::Cbpf,
+            PACKET_FANOUT_EBPF => FanoutMethod::Ebpf,
+            method => FanoutMethod::Raw(method),
+        }
+    }
+}
+
+impl fmt::Display for FanoutMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FanoutMethod::Hash => f.write_str("hash"),
+            FanoutMethod::Lb => f.write_str("lb"),
+            FanoutMethod::Cpu => f.write_str("cpu"),
+            FanoutMethod::Rollover => f.write_str("rollover"),
+            FanoutMethod::Rnd => f.write_str("rnd"),
+            FanoutMethod::Qm => f.write_str("qm"),
+            FanoutMethod::Cbpf => f.write_str("cbpf"),
+            FanoutMethod::Ebpf => f.write_str("ebpf"),
+            FanoutMethod::Raw(method) => write!(f, "0x{:x}", method),
+        }
+    }
+}
+
+///PACKET_FANOUT_FLAG_* options of a ring's fanout group, see `RingSettings::fanout_flags`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FanoutFlags(pub c_int);
+
+impl FanoutFlags {
+    ///No options
+    pub const NONE: FanoutFlags = FanoutFlags(0);
+    ///A full ring passes packets on to another ring of the group instead of dropping them,
+    ///see `RingStats::rollover`
+    pub const ROLLOVER: FanoutFlags = FanoutFlags(PACKET_FANOUT_FLAG_ROLLOVER);
+    ///The kernel reassembles IPv4 fragments before picking a ring, so that all fragments of a
+    ///datagram reach the same one
+    pub const DEFRAG: FanoutFlags = FanoutFlags(PACKET_FANOUT_FLAG_DEFRAG);
+
+    ///Raw flag bits
+    pub fn bits(self) -> c_int {
+        self.0
+    }
+
+    ///Whether all flags in `other` are set
+    pub fn contains(self, other: FanoutFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for FanoutFlags {
+    type Output = FanoutFlags;
+
+    fn bitor(self, other: FanoutFlags) -> FanoutFlags {
+        FanoutFlags(self.0 | other.0)
+    }
+}
+
+///Huge page size to map a ring with, see `RingSettings::hugepages`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HugepageSize {
+    ///The system's default huge page size
+    Default,
+    Size2MB,
+    Size1GB,
+}
+
+impl HugepageSize {
+    ///Size in bytes, None for the system default
+    pub fn bytes(self) -> Option<c_uint> {
+        match self {
+            HugepageSize::Default => None,
+            HugepageSize::Size2MB => Some(2 << 20),
+            HugepageSize::Size1GB => Some(1 << 30),
+        }
+    }
+
+    fn mmap_flags(self) -> c_int {
+        MAP_HUGETLB
+            | match self {
+                HugepageSize::Default => 0,
+                HugepageSize::Size2MB => MAP_HUGE_2MB,
+                HugepageSize::Size1GB => MAP_HUGE_1GB,
+            }
+    }
+}
+
+///Kernel busy polling for a ring's socket, see `RingSettings::busy_poll`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BusyPoll {
+    ///How long to busy poll before sleeping, in microseconds
+    pub micros: u32,
+    ///Keep device interrupts off while busy polling (Linux 5.11+)
+    pub prefer: bool,
+    ///Packets per busy poll, the kernel default of 8 if None (Linux 5.11+)
+    pub budget: Option<u16>,
+}
+
+impl BusyPoll {
+    ///Busy polls for `micros` microseconds with interrupts deferred
+    pub fn new(micros: u32) -> BusyPoll {
+        BusyPoll {
+            micros,
+            prefer: true,
+            budget: None,
+        }
+    }
+}
+
+///How a ring waits for the kernel to retire a block: spin, then yield the CPU, then sleep in
+///poll()
+///
+///Spinning and yielding check the ring memory directly, so a block is picked up without a
+///wakeup at the cost of keeping a core busy. The default goes straight to poll().
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct WaitStrategy {
+    ///Busy loop iterations before yielding
+    pub spins: u32,
+    ///sched_yield() rounds before sleeping
+    pub yields: u32,
+    ///Longest a single poll() sleeps before spinning again, None to sleep until woken up
+    pub poll_timeout: Option<Duration>,
+}
+
+impl WaitStrategy {
+    ///Spins `spins` times, yields `yields` times, then sleeps for at most `poll_timeout`
+    pub fn hybrid(spins: u32, yields: u32, poll_timeout: Option<Duration>) -> WaitStrategy {
+        WaitStrategy {
+            spins,
+            yields,
+            poll_timeout,
+        }
+    }
+}
+
+///Blocks skipped between two blocks a ring received, see `RingSettings::on_seq_gap`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SeqGap {
+    ///Sequence number the next block should have had
+    pub expected: u64,
+    ///Sequence number of the block received instead
+    pub received: u64,
+}
+
+impl SeqGap {
+    ///Number of blocks lost
+    pub fn lost(&self) -> u64 {
+        self.received - self.expected
+    }
+}
+
+///Function called with every `SeqGap` a ring sees, see `RingSettings::on_seq_gap`
+#[derive(Clone)]
+pub struct SeqGapCallback(Arc<dyn Fn(SeqGap) + Send + Sync>);
+
+impl SeqGapCallback {
+    ///Wraps `f`
+    pub fn new<F>(f: F) -> SeqGapCallback
+    where
+        F: Fn(SeqGap) + Send + Sync + 'static,
+    {
+        SeqGapCallback(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for SeqGapCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SeqGapCallback")
+    }
+}
+
+///A stretch of time the kernel held the queue frozen, see `RingSettings::on_freeze`
+///
+///The kernel freezes a TPACKET_V3 queue when the block it would fill next has not been
+///consumed yet, and drops packets until the block comes back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FreezeEvent {
+    ///Timestamp of the last packet captured before the freeze
+    pub started: SystemTime,
+    ///Time from the last packet before the freeze to the first packet after it
+    pub duration: Duration,
+    ///Packets the kernel dropped in the meantime
+    pub drops: u64,
+}
+
+///Function called with every `FreezeEvent` a ring sees, see `RingSettings::on_freeze`
+#[derive(Clone)]
+pub struct FreezeCallback(Arc<dyn Fn(FreezeEvent) + Send + Sync>);
+
+impl FreezeCallback {
+    ///Wraps `f`
+    pub fn new<F>(f: F) -> FreezeCallback
+    where
+        F: Fn(FreezeEvent) + Send + Sync + 'static,
+    {
+        FreezeCallback(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for FreezeCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("FreezeCallback")
+    }
+}
+
+//queue frozen after the kernel retired block `last_seq`
+#[derive(Clone, Copy, Debug)]
+struct Freeze {
+    last_seq: u64,
+    started: SystemTime,
+    //kernel drop count before the freeze was noticed
+    drops: u64,
+}
+
 ///Settings to be used to bring up each ring
 #[derive(Clone, Debug)]
 pub struct RingSettings {
     ///Interface name
     pub if_name: String,
-    ///PACKET_FANOUT_HASH will pin flows to individual threads, PACKET_FANOUT_LB will distribute
-    ///them across multiple threads
-    pub fanout_method: c_int,
+    ///`FanoutMethod::Hash` will pin flows to individual threads, `FanoutMethod::Lb` will
+    ///distribute them across multiple threads
+    pub fanout_method: FanoutMethod,
+    ///Options of the fanout group, e.g. `FanoutFlags::ROLLOVER`
+    pub fanout_flags: FanoutFlags,
+    ///Fanout group id to join, rings only share packets with rings in the same group.
+    ///Defaults to the process id, see `group::unique_fanout_group()` for separate groups.
+    pub fanout_group: Option<u16>,
     ///Lower-level settings including block size, also enable/disable filling RXHASH in packet data
     pub ring_settings: tpacket3::TpacketReq3,
+    ///Memory budget in bytes to size the ring from the interface speed instead of taking the
+    ///block and frame counts of `ring_settings`, see `TpacketReq3::auto_size()`
+    pub auto_size: Option<u64>,
+    ///Extra headroom in bytes the kernel leaves in front of every packet (PACKET_RESERVE)
+    ///
+    ///TPACKET_V3 packs packets back to back in a block, each one starting on a
+    ///`TPACKET_ALIGNMENT` (16 byte) boundary, so with 64 byte packets up to four of them share
+    ///a cache line. Padding every frame with headroom spreads small packets over more cache
+    ///lines, at the cost of fewer packets per block. See `tpacket3::frame_reserve_for()`.
+    pub frame_reserve: c_uint,
+    ///Only capture frames of this protocol, filtering is done by the kernel before frames reach
+    ///the ring
+    pub protocol: EtherType,
+    ///Open the socket with SOCK_DGRAM so the kernel strips link-layer headers, needed for
+    ///interfaces without Ethernet headers such as tun or ppp. Use `RawPacket::sll_header()` to
+    ///recover the link-layer metadata.
+    pub cooked: bool,
+    ///Capture from all interfaces at once by binding to ifindex 0, `if_name` is ignored and no
+    ///interface is put into promiscuous mode. Use `RawPacket::ifindex()` to tell sources apart.
+    pub any_interface: bool,
+    ///Promiscuous mode for the interface, a socket membership by default
+    pub promiscuous: Promiscuous,
+    ///Also receive all multicast traffic (PACKET_MR_ALLMULTI), useful when promiscuous mode is
+    ///off
+    pub all_multicast: bool,
+    ///Do not deliver packets transmitted by this host (PACKET_IGNORE_OUTGOING, Linux 4.20+)
+    pub ignore_outgoing: bool,
+    ///Have the kernel write a virtio_net_hdr in front of every packet (PACKET_VNET_HDR), read
+    ///it with `RawPacket::vnet_header()`. Segmentation offloaded frames then arrive whole,
+    ///with the GSO type and size needed to tell them apart.
+    pub vnet_header: bool,
+    ///Classic BPF filter attached before the ring starts receiving, so that it never sees
+    ///packets the filter would drop
+    pub filter: Option<FilterProgram>,
+    ///Deliver on average one in this many packets, picked at random by the kernel ahead of
+    ///`filter` so that dropped packets cost next to nothing; see `filters::sample()`
+    pub sample: Option<u32>,
+    ///Keep at most this many bytes of every packet, cut by the socket filter so the rest is
+    ///never copied into the ring; see `filters::truncated()`
+    pub snaplen: Option<u32>,
+    ///Network namespace `if_name` lives in, entered only while the socket is set up. Defaults
+    ///to the namespace of the calling thread.
+    pub netns: Option<NetNs>,
+    ///Make `recv_block()` return `Error::InterfaceDown` when the interface goes down or is
+    ///removed, instead of waiting for packets that will not come. See `iface::LinkWatcher` to
+    ///find out when it is back.
+    pub report_link_down: bool,
+    ///TPACKET version to use; by default TPACKET_V3 is tried first and TPACKET_V2 used if the
+    ///kernel refuses it. With V2 every frame is copied into a block before it is handed out,
+    ///`vnet_header` is not carried over and `ring_settings.tp_frame_size` sets the largest
+    ///packet captured whole.
+    pub tpacket_version: Option<TpacketVersion>,
+    ///Map the ring with huge pages (MAP_HUGETLB) and prefault it (MAP_POPULATE) to cut TLB
+    ///misses on rings of hundreds of MB. `tp_block_size` must be a multiple of the huge page
+    ///size. Kernels that cannot map packet rings with huge pages refuse MAP_HUGETLB, the ring
+    ///is then mapped with normal, still prefaulted pages; see `Ring::is_hugepage_backed()`.
+    pub hugepages: Option<HugepageSize>,
+    ///Lock the ring in RAM (MAP_LOCKED) so it is never swapped out, on by default. Locking
+    ///counts against RLIMIT_MEMLOCK unless the process has CAP_IPC_LOCK; when the limit is
+    ///too low the ring is mapped unlocked, see `Ring::is_memory_locked()`.
+    pub lock_memory: bool,
+    ///Prefault the whole ring when it is mapped (MAP_POPULATE). Current kernels already
+    ///insert every page of a packet ring into the mapping up front, so this only guards
+    ///against page faults on kernels that would not. Always done with `hugepages`.
+    pub populate: bool,
+    ///NUMA node to place the ring memory on: the ring is set up from the node's CPUs so the
+    ///kernel allocates its blocks there, and the mapping is bound to the node. See
+    ///`numa::pin_current_thread_to_node()` to keep the consuming thread on the same node.
+    pub numa_node: Option<u32>,
+    ///Have the kernel busy poll the device queue instead of sleeping right away when the ring
+    ///waits for packets, trading CPU for wakeup latency. Only drivers using NAPI support it.
+    pub busy_poll: Option<BusyPoll>,
+    ///How the ring waits for blocks, see `WaitStrategy`
+    pub wait_strategy: WaitStrategy,
+    ///Socket receive buffer in bytes (SO_RCVBUF), the queue `fallback::FallbackRing` reads from
+    ///and where a ring with `copy_thresh` puts packets too large for a frame
+    pub rcvbuf: Option<u32>,
+    ///Set `rcvbuf` past net.core.rmem_max (SO_RCVBUFFORCE), needs CAP_NET_ADMIN
+    pub rcvbuf_force: bool,
+    ///Queue a full copy of packets that are truncated to fit a TPACKET_V2 frame on the socket,
+    ///as long as the receive buffer has room (PACKET_COPY_THRESH). TPACKET_V3 rings ignore it.
+    pub copy_thresh: Option<u32>,
+    ///Called from the receiving thread as soon as a block arrives out of sequence, i.e. blocks
+    ///were retired but never handed out; `Ring::blocks_lost()` keeps the total
+    pub on_seq_gap: Option<SeqGapCallback>,
+    ///Called from the receiving thread with every freeze of a TPACKET_V3 queue, once the first
+    ///block after it arrives; `Ring::freezes()` keeps the total
+    pub on_freeze: Option<FreezeCallback>,
 }
 
 impl Default for RingSettings {
     fn default() -> RingSettings {
         RingSettings {
             if_name: String::from("eth0"),
-            fanout_method: PACKET_FANOUT_HASH,
+            fanout_method: FanoutMethod::Hash,
+            fanout_flags: FanoutFlags::NONE,
+            fanout_group: None,
             ring_settings: tpacket3::TpacketReq3::default(),
+            auto_size: None,
+            frame_reserve: 0,
+            protocol: EtherType::All,
+            cooked: false,
+            any_interface: false,
+            promiscuous: Promiscuous::Membership,
+            all_multicast: false,
+            ignore_outgoing: false,
+            vnet_header: false,
+            filter: None,
+            sample: None,
+            snaplen: None,
+            netns: None,
+            report_link_down: false,
+            tpacket_version: None,
+            hugepages: None,
+            lock_memory: true,
+            populate: false,
+            numa_node: None,
+            busy_poll: None,
+            wait_strategy: WaitStrategy::default(),
+            rcvbuf: None,
+            rcvbuf_force: false,
+            copy_thresh: None,
+            on_seq_gap: None,
+            on_freeze: None,
         }
     }
 }
 
 ///References a single mmaped ring buffer. Normally one per thread.
+///
+///Clones share the socket and the mapping, which are closed when the last of them is dropped.
 #[derive(Clone, Debug)]
 pub struct Ring {
     pub socket: Socket,
     mmap: Option<*mut u8>,
     opts: tpacket3::TpacketReq3,
+    next_block: u32,
+    last_seq: Option<u64>,
+    seq_gaps: u64,
+    blocks_lost: u64,
+    blocks_received: u64,
+    //kernel counters summed over every `statistics()` call, for `close()`
+    totals: RingStats,
+    //the kernel keeps rollover counters only for rings that can roll over
+    rollover: bool,
+    //rollover counters as of the last `statistics()` call, the kernel never resets them
+    last_rollover: RolloverStats,
+    opened: Instant,
+    on_seq_gap: Option<SeqGapCallback>,
+    on_freeze: Option<FreezeCallback>,
+    //kernel counters read by the ring itself, returned by the next `statistics()` call
+    unread: tpacket3::TpacketStatsV3,
+    //freezes and drops counted by the kernel since the ring was set up
+    freezes: u64,
+    drops: u64,
+    //`freezes` when the ring last looked for a freeze
+    freezes_seen: u64,
+    //sequence number of the newest block when the ring was last found full
+    full_at_seq: Option<u64>,
+    frozen: Option<Freeze>,
+    filter: Option<FilterProgram>,
+    sample: Option<u32>,
+    snaplen: Option<u32>,
+    paused: bool,
+    last_stats: Option<Instant>,
+    shutdown: Option<ShutdownHandle>,
+    report_link_down: bool,
+    link_down: bool,
+    //set for TPACKET_V2 rings
+    frames: Option<FrameRing>,
+    //packets of the last block `poll_burst()` took that did not fit
+    burst: Burst,
+    hugepages: Option<HugepageSize>,
+    hugepage_backed: bool,
+    lock_memory: bool,
+    populate: bool,
+    memory_locked: bool,
+    wait_strategy: WaitStrategy,
+    //ARPHRD_* type of the interface, None on rings bound to all interfaces
+    hardware_type: Option<u16>,
+    cooked: bool,
+    #[cfg(feature = "metrics")]
+    metrics: crate::metrics::RingMetrics,
+    #[cfg(feature = "latency")]
+    latency: crate::latency::LatencyRecorder,
+    //blocks lent out as SharedBlocks
+    leases: Arc<Leases>,
+    //socket and mapping, released by the last clone
+    mapping: Arc<Mapping>,
+    promiscuous: Promiscuous,
+    //share of IFF_PROMISC with Promiscuous::InterfaceFlag, released by the last clone
+    promiscuous_flag: Option<Arc<PromiscuousFlag>>,
+}
+
+//socket and mapping of a ring shared by its clones, the last one closes the socket and unmaps
+//the ring, or leaves unmapping to the last block lent out of it
+#[derive(Debug)]
+struct Mapping {
+    fd: c_int,
+    map: Option<*mut u8>,
+    len: usize,
+    leases: Arc<Leases>,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if let Some(map) = self.map {
+            if !self.leases.defer_unmap(map, self.len) {
+                unsafe {
+                    munmap(map as *mut c_void, self.len);
+                }
+            }
+        }
+        unsafe {
+            close(self.fd);
+        }
+    }
+}
+
+//the pointer is only used to unmap the ring once
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+//TPACKET_V2 ring state, ready frames are copied into blocks of their own and released
+#[derive(Clone, Debug)]
+struct FrameRing {
+    req: tpacket2::TpacketReq,
+    next_frame: u32,
+    seq_num: u64,
+}
+
+//a clone starts without the leftover packets, which would otherwise be handed out twice
+#[derive(Debug, Default)]
+struct Burst(VecDeque<Packet>);
+
+impl Clone for Burst {
+    fn clone(&self) -> Burst {
+        Burst::default()
+    }
+}
+
+///virtio_net_hdr describing checksum and segmentation offload state of a packet, see
+///`RingSettings::vnet_header`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VirtioNetHdr {
+    ///VIRTIO_NET_HDR_F_* flags
+    pub flags: u8,
+    ///VIRTIO_NET_HDR_GSO_* type, possibly or'ed with VIRTIO_NET_HDR_GSO_ECN
+    pub gso_type: u8,
+    ///Length of the headers repeated in front of every segment
+    pub hdr_len: u16,
+    ///Payload bytes per segment
+    pub gso_size: u16,
+    ///Where checksumming starts, from the link-layer header
+    pub csum_start: u16,
+    ///Where the checksum goes, from `csum_start`
+    pub csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    pub const F_NEEDS_CSUM: u8 = 1;
+    pub const F_DATA_VALID: u8 = 2;
+    pub const GSO_NONE: u8 = 0;
+    pub const GSO_TCPV4: u8 = 1;
+    pub const GSO_UDP: u8 = 3;
+    pub const GSO_TCPV6: u8 = 4;
+    pub const GSO_UDP_L4: u8 = 5;
+    pub const GSO_ECN: u8 = 0x80;
+
+    ///Size of the header in front of the packet
+    pub const LEN: usize = 10;
+
+    fn from_bytes(raw: &[u8]) -> VirtioNetHdr {
+        let u16_at = |i: usize| u16::from_ne_bytes([raw[i], raw[i + 1]]);
+        VirtioNetHdr {
+            flags: raw[0],
+            gso_type: raw[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        }
+    }
+
+    ///Whether the packet is a segmentation offloaded superframe
+    pub fn is_gso(&self) -> bool {
+        self.gso_type & !VirtioNetHdr::GSO_ECN != VirtioNetHdr::GSO_NONE
+    }
+
+    ///Whether the checksum is still to be filled in, so the one in the packet is not valid
+    pub fn needs_csum(&self) -> bool {
+        self.flags & VirtioNetHdr::F_NEEDS_CSUM != 0
+    }
 }
 
 ///Contains a reference to a block as it exists in the ring buffer, its block descriptor, and a Vec of individual packets in that block.
 #[derive(Debug)]
 pub struct Block<'a> {
     block_desc: tpacket3::TpacketBlockDesc,
-    packets: Vec<RawPacket<'a>>,
     raw_data: &'a mut [u8],
+    //set for blocks in a ring's memory, which can be lent out by `into_shared()`
+    lease: Option<Lease>,
+    //set for blocks of a TPACKET_V2 ring, which are copied out of it; `raw_data` points here
+    owned: Option<BlockMemory>,
+    #[cfg(feature = "latency")]
+    timing: Option<crate::latency::BlockTiming>,
+}
+
+///Blocks left in a stopped ring, see `Ring::drain()`
+#[derive(Debug)]
+pub struct Drain<'a> {
+    ring: &'a mut Ring,
+    waited: bool,
 }
 
 ///Contains a reference to an individual packet in a block, as well as details about that packet
@@ -75,12 +743,77 @@ pub struct RawPacket<'a> {
     pub tpacket3_hdr: tpacket3::Tpacket3Hdr,
     ///Raw packet data including any encapsulations
     pub data: &'a [u8],
+    //block the packet is in when it is shared, see `to_shared()`
+    pub(crate) shared: Option<&'a Arc<BlockMemory>>,
+}
+
+///Link-layer metadata of a packet (the sockaddr_ll stored after its header), see
+///`RawPacket::link_info()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LinkInfo {
+    ///Interface the packet was captured on
+    pub ifindex: i32,
+    ///ARPHRD_* hardware type of the interface
+    pub hatype: u16,
+    pub direction: PacketDirection,
+    ///Ethertype in host byte order
+    pub protocol: u16,
+    ///Link-layer source address, `addr_len` bytes of it are meaningful
+    pub addr: [u8; 8],
+    pub addr_len: u8,
+}
+
+impl LinkInfo {
+    ///Link-layer source address
+    pub fn address(&self) -> &[u8] {
+        &self.addr[..self.addr_len as usize]
+    }
+
+    ///Source MAC address, None for interfaces without 6 byte addresses such as tun
+    pub fn source_mac(&self) -> Option<[u8; 6]> {
+        if self.addr_len != 6 {
+            return None;
+        }
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&self.addr[..6]);
+        Some(mac)
+    }
+}
+
+///802.1Q tag stripped from a packet by the NIC or the kernel
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VlanTag {
+    ///Tag control information: priority, drop eligible indicator and VLAN id
+    pub tci: u16,
+    ///Tag protocol identifier, e.g. 0x8100 or 0x88a8
+    pub tpid: u16,
+}
+
+impl VlanTag {
+    ///VLAN id (lower 12 bits of the TCI)
+    pub fn id(&self) -> u16 {
+        self.tci & 0x0fff
+    }
+
+    ///Priority code point
+    pub fn priority(&self) -> u8 {
+        (self.tci >> 13) as u8
+    }
+
+    ///Drop eligible indicator
+    pub fn dei(&self) -> bool {
+        self.tci & 0x1000 != 0
+    }
 }
 
 impl<'a> Block<'a> {
     ///Marks a block as free to be destroyed by the kernel
     #[inline]
     pub fn mark_as_consumed(&mut self) {
+        #[cfg(feature = "latency")]
+        if let Some(timing) = self.timing.take() {
+            timing.consumed();
+        }
         self.raw_data[tpacket3::TP_BLK_STATUS_OFFSET] = tpacket3::TP_STATUS_KERNEL;
         self.raw_data[tpacket3::TP_BLK_STATUS_OFFSET + 1] = 0;
         self.raw_data[tpacket3::TP_BLK_STATUS_OFFSET + 2] = 0;
@@ -92,204 +825,1919 @@ impl<'a> Block<'a> {
         (self.raw_data[tpacket3::TP_BLK_STATUS_OFFSET] & tpacket3::TP_STATUS_USER) != 0
     }
 
-    ///Returns a `Vec` of details and references to raw packets that can be read from the ring buffer
-    #[inline]
-    pub fn get_raw_packets(&self) -> Vec<RawPacket> {
-        //standard block header is 48b
+    ///Wraps a buffer laid out as a TPACKET_V3 block, see `tpacket3::BlockBuilder`
+    pub(crate) fn from_raw(raw_data: &'a mut [u8]) -> Option<Block<'a>> {
+        let block_desc = match tpacket3::get_tpacket_block_desc(&raw_data[..]) {
+            Ok(x) => x,
+            Err(_) => {
+                return None;
+            }
+        };
 
-        let mut packets = Vec::<RawPacket>::new();
-        let mut next_offset = 48;
+        Some(Block {
+            block_desc: block_desc.1,
+            raw_data,
+            lease: None,
+            owned: None,
+            #[cfg(feature = "latency")]
+            timing: None,
+        })
+    }
 
-        let count = self.block_desc.hdr.num_pkts;
-        for x in 0..count {
-            let this_offset = next_offset;
+    //wraps a buffer like `from_raw()`, taking it over so that the block is valid however long
+    //it is kept
+    fn from_owned(buf: Vec<u8>) -> Option<Block<'a>> {
+        let mut memory = BlockMemory::owned(buf);
+        let mut block = Block::from_raw(unsafe { memory.bytes_mut() })?;
+        block.owned = Some(memory);
+        Some(block)
+    }
 
-            let mut tpacket3_hdr = match tpacket3::get_tpacket3_hdr(&self.raw_data[next_offset..]) {
-                Ok(x) => x,
-                Err(_) => {
-                    continue;
-                }
-            };
+    ///Status flags of the block as retired by the kernel
+    #[inline]
+    pub fn status(&self) -> TpStatus {
+        TpStatus(self.block_desc.hdr.block_status)
+    }
 
-            if x < count - 1 {
-                next_offset = this_offset + tpacket3_hdr.1.tp_next_offset as usize;
-            } else {
-                next_offset = self.raw_data.len();
-                tpacket3_hdr.1.tp_next_offset = 0;
+    ///Number of packets in the block
+    #[inline]
+    pub fn packet_count(&self) -> u32 {
+        self.block_desc.hdr.num_pkts
+    }
+
+    ///Sequence number the kernel gave the block; it counts every retired block, so a jump
+    ///means blocks were skipped
+    #[inline]
+    pub fn seq_num(&self) -> u64 {
+        self.block_desc.hdr.seq_num
+    }
+
+    ///Timestamp of the first packet in the block
+    ///
+    ///An empty block has none; this is then when the kernel opened it.
+    pub fn first_packet_ts(&self) -> SystemTime {
+        //the descriptor holds when the block was opened, the packet may come later
+        let first = self.block_desc.hdr.offset_to_first_pkt as usize;
+        match self.raw_data.get(first..).map(tpacket3::get_tpacket3_hdr) {
+            Some(Ok((_, hdr))) if self.packet_count() > 0 => {
+                UNIX_EPOCH + Duration::new(hdr.tp_sec as u64, hdr.tp_nsec)
             }
-            packets.push(RawPacket {
-                tpacket3_hdr: tpacket3_hdr.1,
-                data: &self.raw_data[this_offset..next_offset],
-            });
+            _ => self.block_desc.hdr.ts_first_pkt.to_system_time(),
         }
+    }
 
-        packets
+    ///Timestamp of the last packet in the block
+    ///
+    ///An empty block has none; this is then when the kernel retired it.
+    pub fn last_packet_ts(&self) -> SystemTime {
+        self.block_desc.hdr.ts_last_pkt.to_system_time()
     }
-}
 
-impl Ring {
-    ///Creates a new ring buffer on the specified interface name and puts the interface into promiscuous mode
-    pub fn from_if_name(if_name: &str) -> io::Result<Ring> {
-        let mut ring = Ring {
-            socket: Socket::from_if_name(if_name, socket::PF_PACKET)?,
-            mmap: None,
-            opts: tpacket3::TpacketReq3::default(),
-        };
+    ///Whether the kernel reported dropping packets around the time this block was filled
+    #[inline]
+    pub fn lost_packets_hint(&self) -> bool {
+        self.status().contains(TpStatus::LOSING)
+    }
 
-        ring.socket.set_flag(IFF_PROMISC as u64)?;
-        ring.socket
-            .setsockopt(PACKET_VERSION, tpacket3::TPACKET_V3)?;
-        ring.socket.setsockopt(PACKET_RX_RING, ring.opts.clone())?;
-        ring.mmap_rx_ring()?;
-        ring.bind_rx_ring()?;
-        let fanout = (unsafe { getpid() } & 0xFFFF) | (PACKET_FANOUT_HASH << 16);
-        ring.socket.setsockopt(PACKET_FANOUT, fanout)?;
-        Ok(ring)
+    ///Per-block private area reserved with `TpacketReq3::tp_sizeof_priv`, rounded up to
+    ///`TPACKET_ALIGNMENT`
+    ///
+    ///The kernel never writes to it, so whatever is stored there stays with the block while it
+    ///cycles through the ring. Empty for blocks that are not read straight from a TPACKET_V3 ring.
+    pub fn private_data(&self) -> &[u8] {
+        &self.raw_data[self.private_range()]
     }
 
-    ///Creates a new ring buffer from the supplied RingSettings struct
-    pub fn new(settings: RingSettings) -> io::Result<Ring> {
-        //this typecasting sucks :(
-        let mut ring = Ring {
-            socket: Socket::from_if_name(&settings.if_name, socket::PF_PACKET)?,
-            mmap: None,
-            opts: settings.ring_settings,
-        };
+    ///Mutable `private_data()`
+    pub fn private_data_mut(&mut self) -> &mut [u8] {
+        let range = self.private_range();
+        &mut self.raw_data[range]
+    }
 
-        ring.socket.set_flag(IFF_PROMISC as u64)?;
-        ring.socket
-            .setsockopt(PACKET_VERSION, tpacket3::TPACKET_V3)?;
-        ring.socket.setsockopt(PACKET_RX_RING, ring.opts.clone())?;
-        ring.mmap_rx_ring()?;
-        ring.bind_rx_ring()?;
-        let fanout = (unsafe { getpid() } & 0xFFFF) | (settings.fanout_method << 16);
-        ring.socket.setsockopt(PACKET_FANOUT, fanout)?;
-        Ok(ring)
+    fn private_range(&self) -> std::ops::Range<usize> {
+        let len = self.raw_data.len();
+        let start = (self.block_desc.offset_to_priv as usize).min(len);
+        let end = (self.block_desc.hdr.offset_to_first_pkt as usize).clamp(start, len);
+        start..end
     }
 
-    ///Waits for a block to be added to the ring buffer and returns it
-    //We're allowing unused_mut here because apps that include this crate may need to control
-    //marking blocks as consumed for performance reasons to avoid copies
-    #[allow(unused_mut)]
+    ///Returns a `Vec` of details and references to raw packets that can be read from the ring buffer
     #[inline]
-    pub fn get_block(&mut self) -> Block {
-        loop {
-            self.wait_for_block();
-            //check all blocks in memory space
-            for i in 0..self.opts.tp_block_nr {
-                if let Some(mut block) = self.get_single_block(i) {
-                    if block.is_ready() {
-                        return block;
-                    }
-                }
-            }
+    pub fn get_raw_packets(&self) -> Vec<RawPacket<'_>> {
+        get_raw_packets(&self.block_desc, self.raw_data)
+    }
+
+    ///Like `get_raw_packets()`, but checks every offset the kernel wrote against the block
+    ///length and fails with `Error::BlockCorrupt` on the first one that is out of bounds
+    ///
+    ///`get_raw_packets()` never panics either, it stops at the first bad offset and returns
+    ///the packets before it.
+    pub fn try_get_raw_packets(&self) -> Result<Vec<RawPacket<'_>>> {
+        try_get_raw_packets(&self.block_desc, self.raw_data)
+    }
+
+    ///Copies the bytes of every packet in the block into `out`, one `Vec` per packet, and
+    ///returns how many there were
+    ///
+    ///The `Vec`s already in `out` are reused, so passing the same one for every block avoids
+    ///allocating once it has grown to the size of a block.
+    pub fn copy_packets_into(&self, out: &mut Vec<Vec<u8>>) -> usize {
+        let packets = self.get_raw_packets();
+        out.resize_with(packets.len(), Vec::new);
+        for (packet, buf) in packets.iter().zip(out.iter_mut()) {
+            buf.clear();
+            buf.extend_from_slice(packet.payload());
         }
+        packets.len()
     }
 
-    fn mmap_rx_ring(&mut self) -> io::Result<()> {
-        match unsafe {
-            mmap(
-                std::ptr::null_mut(),
-                (self.opts.tp_block_size * self.opts.tp_block_nr) as usize,
-                PROT_READ | PROT_WRITE,
-                MAP_SHARED | MAP_LOCKED | MAP_NORESERVE,
-                self.socket.fd,
-                0,
-            )
-        } as isize
-        {
-            -1 => Err(io::Error::last_os_error()),
-            map => {
-                self.mmap = Some(map as *mut u8);
-                Ok(())
-            }
+    ///Turns the block into one that outlives the borrow of the ring, so its packets can be
+    ///kept and shared with `RawPacket::to_shared()` without copying them
+    ///
+    ///A block of a TPACKET_V3 ring is lent out of the ring until the `SharedBlock` and all its
+    ///packets are dropped, and then handed back to the kernel; it must not be marked as consumed
+    ///in the meantime. Other blocks are copied.
+    pub fn into_shared(self) -> SharedBlock {
+        match self.owned {
+            Some(memory) => SharedBlock::from_memory(self.block_desc, memory),
+            None => SharedBlock::new(self.block_desc, self.raw_data, self.lease),
         }
     }
+}
 
-    fn bind_rx_ring(&mut self) -> io::Result<()> {
-        let mut sa = sockaddr_ll {
-            sll_family: AF_PACKET as u16,
-            sll_protocol: ETH_P_IP.to_be() as u16,
-            sll_ifindex: self.socket.if_index as c_int,
-            sll_hatype: 519,
-            sll_pkttype: (PACKET_HOST //can we just use 255 here lol
-                | PACKET_BROADCAST
-                | PACKET_MULTICAST
-                | PACKET_OTHERHOST
-                | PACKET_OUTGOING),
-            sll_halen: ETH_ALEN as u8,
-            sll_addr: [0; 8],
-        };
+///Details and references to the packets of the block `raw_data` described by `block_desc`
+#[inline]
+pub(crate) fn get_raw_packets<'a>(
+    block_desc: &tpacket3::TpacketBlockDesc,
+    raw_data: &'a [u8],
+) -> Vec<RawPacket<'a>> {
+    //packets start after the block descriptor and the private area, if any
+    let mut packets = Vec::<RawPacket>::new();
+    let mut next_offset = block_desc.hdr.offset_to_first_pkt as usize;
 
-        //get the size before we change the pointer type
-        let size = mem::size_of_val(&sa);
-        //we have to do this transmute or similar because Linux uses multiple sockaddr_ 
-        //family structs and casts them to sockaddr after populating them
-        let addr_ptr = unsafe { mem::transmute::<*mut sockaddr_ll, *mut sockaddr>(&mut sa) };
+    let count = block_desc.hdr.num_pkts;
+    for x in 0..count {
+        let this_offset = next_offset;
 
-        match unsafe { bind(self.socket.fd, addr_ptr, size as socklen_t) } {
-            0 => Ok(()),
-            _ => Err(io::Error::last_os_error()),
+        let mut tpacket3_hdr = match raw_data.get(next_offset..).map(tpacket3::get_tpacket3_hdr) {
+            Some(Ok(x)) => x,
+            _ => break,
+        };
+
+        if x < count - 1 {
+            next_offset = this_offset + tpacket3_hdr.1.tp_next_offset as usize;
+        } else {
+            next_offset = raw_data.len();
+            tpacket3_hdr.1.tp_next_offset = 0;
         }
+        let data = match raw_data.get(this_offset..next_offset) {
+            Some(data) => data,
+            None => break,
+        };
+        packets.push(RawPacket {
+            tpacket3_hdr: tpacket3_hdr.1,
+            data,
+            shared: None,
+        });
     }
 
-    #[inline]
-    fn wait_for_block(&self) {
-        let mut pfd = pollfd {
-            fd: self.socket.fd,
-            events: POLLIN | POLLERR,
-            revents: 0,
+    packets
+}
+
+///Checked `get_raw_packets()`, see `Block::try_get_raw_packets()`
+pub(crate) fn try_get_raw_packets<'a>(
+    block_desc: &tpacket3::TpacketBlockDesc,
+    raw_data: &'a [u8],
+) -> Result<Vec<RawPacket<'a>>> {
+    let corrupt = |msg: String| Err(Error::BlockCorrupt(msg));
+    let hdr = &block_desc.hdr;
+    let blk_len = hdr.blk_len as usize;
+    if blk_len > raw_data.len() {
+        return corrupt(format!(
+            "blk_len {} past the end of the {} byte block",
+            blk_len,
+            raw_data.len()
+        ));
+    }
+    let mut packets = Vec::<RawPacket>::new();
+    let mut next_offset = hdr.offset_to_first_pkt as usize;
+    for x in 0..hdr.num_pkts {
+        let this_offset = next_offset;
+        let tpacket3_hdr = match raw_data[..blk_len]
+            .get(this_offset..)
+            .map(tpacket3::get_tpacket3_hdr)
+        {
+            Some(Ok((_, hdr))) => hdr,
+            _ => {
+                return corrupt(format!(
+                    "header of packet {} at offset {} past blk_len {}",
+                    x, this_offset, blk_len
+                ))
+            }
+        };
+        next_offset = if x < hdr.num_pkts - 1 {
+            if tpacket3_hdr.tp_next_offset == 0 {
+                return corrupt(format!("packet {} has no tp_next_offset", x));
+            }
+            this_offset + tpacket3_hdr.tp_next_offset as usize
+        } else {
+            blk_len
         };
+        if next_offset > blk_len {
+            return corrupt(format!(
+                "tp_next_offset {} of packet {} past blk_len {}",
+                tpacket3_hdr.tp_next_offset, x, blk_len
+            ));
+        }
+        let frame_len = next_offset - this_offset;
+        let end = tpacket3_hdr.tp_mac as usize + tpacket3_hdr.tp_snaplen as usize;
+        if end > frame_len || tpacket3_hdr.tp_net as usize > frame_len {
+            return corrupt(format!(
+                "tp_mac {} + tp_snaplen {} of packet {} past its {} byte frame",
+                tpacket3_hdr.tp_mac, tpacket3_hdr.tp_snaplen, x, frame_len
+            ));
+        }
+        packets.push(RawPacket {
+            tpacket3_hdr,
+            data: &raw_data[this_offset..next_offset],
+            shared: None,
+        });
+    }
+    Ok(packets)
+}
 
-        unsafe {
-            poll(&mut pfd, 1, -1);
+impl<'a> RawPacket<'a> {
+    ///Cheap to clone handle to the packet that can be kept after the block is gone
+    ///
+    ///Shares the block's memory for packets of a `SharedBlock` and copies the packet otherwise.
+    pub fn to_shared(&self) -> Packet {
+        match self.shared {
+            Some(block) => Packet::shared(block, self),
+            None => Packet::copied(self),
         }
     }
 
+    ///Copy of the packet bytes, see `payload()`
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.payload().to_vec()
+    }
+
+    ///Copies the packet bytes into the start of `buf`, returns how many were copied
+    ///
+    ///Packets longer than `buf` are cut short; `payload().len()` tells whether it was.
+    pub fn copy_into(&self, buf: &mut [u8]) -> usize {
+        let payload = self.payload();
+        let len = payload.len().min(buf.len());
+        buf[..len].copy_from_slice(&payload[..len]);
+        len
+    }
+
+    ///Packet bytes starting at the link-layer header, or at the network header in cooked mode
     #[inline]
-    fn get_single_block<'a>(&mut self, count: u32) -> Option<Block<'a>> {
-        //TODO: clean up all this typecasting
-        let offset = count as isize * self.opts.tp_block_size as isize;
+    pub fn payload(&self) -> &'a [u8] {
+        let start = (self.tpacket3_hdr.tp_mac as usize).min(self.data.len());
+        let end = (start + self.tpacket3_hdr.tp_snaplen as usize).min(self.data.len());
+        &self.data[start..end]
+    }
 
-        let block = unsafe {
-            std::slice::from_raw_parts_mut(
-                self.mmap?.offset(offset),
-                self.opts.tp_block_size as usize,
-            )
-        };
+    ///Link-layer header, from `tp_mac` up to `tp_net`; empty in cooked mode
+    #[inline]
+    pub fn l2_header(&self) -> &'a [u8] {
+        let (mac, net, _) = self.layer_offsets();
+        &self.data[mac..net]
+    }
 
-        let block_desc = match tpacket3::get_tpacket_block_desc(&block[..]) {
-            Ok(x) => x,
-            Err(_) => {
-                return None;
-            }
-        };
+    ///Network header and everything after it that was captured
+    #[inline]
+    pub fn l3_payload(&self) -> &'a [u8] {
+        let (_, net, end) = self.layer_offsets();
+        &self.data[net..end]
+    }
 
-        let blk = Block {
-            block_desc: block_desc.1,
-            packets: Vec::new(),
-            raw_data: &mut block[..],
-        };
+    ///Whether the packet was cut short to fit its frame or the snap length
+    #[inline]
+    pub fn truncated(&self) -> bool {
+        self.tpacket3_hdr.tp_snaplen < self.tpacket3_hdr.tp_len
+    }
 
-        Some(blk)
+    //start of the link-layer header, start of the network header and end of the packet,
+    //clamped to the frame
+    #[inline]
+    fn layer_offsets(&self) -> (usize, usize, usize) {
+        let len = self.data.len();
+        let mac = (self.tpacket3_hdr.tp_mac as usize).min(len);
+        let end = (mac + self.tpacket3_hdr.tp_snaplen as usize).min(len);
+        let net = (self.tpacket3_hdr.tp_net as usize).clamp(mac, end);
+        (mac, net, end)
     }
-}
 
-unsafe impl Send for Ring {}
+    ///Status flags of the packet, e.g. to check whether the NIC verified its checksum
+    #[inline]
+    pub fn status(&self) -> TpStatus {
+        TpStatus(self.tpacket3_hdr.tp_status)
+    }
+
+    ///VLAN tag stripped from the packet, if the kernel reported one
+    #[inline]
+    pub fn vlan(&self) -> Option<VlanTag> {
+        let hdr = &self.tpacket3_hdr;
+        let status = self.status();
+        if !status.contains(TpStatus::VLAN_VALID) {
+            return None;
+        }
+        let tpid = if status.contains(TpStatus::VLAN_TPID_VALID) {
+            hdr.hv1.tp_vlan_tpid
+        } else {
+            ETH_P_8021Q as u16
+        };
+        Some(VlanTag {
+            tci: hdr.hv1.tp_vlan_tci as u16,
+            tpid,
+        })
+    }
+
+    ///Flow hash computed by the NIC or the kernel
+    ///
+    ///Only filled in when TP_FT_REQ_FILL_RXHASH is requested (the default), there is no status
+    ///bit for it so a zero hash is reported as `None`
+    #[inline]
+    pub fn rx_hash(&self) -> Option<u32> {
+        match self.tpacket3_hdr.hv1.tp_rxhash {
+            0 => None,
+            hash => Some(hash),
+        }
+    }
+
+    ///virtio_net_hdr the kernel wrote right before the link-layer header
+    ///
+    ///Only meaningful on rings opened with `RingSettings::vnet_header`, otherwise it reads
+    ///whatever padding precedes the packet.
+    pub fn vnet_header(&self) -> Option<VirtioNetHdr> {
+        let mac = self.tpacket3_hdr.tp_mac as usize;
+        if mac < tpacket3::TPACKET3_HDRLEN as usize + VirtioNetHdr::LEN {
+            return None;
+        }
+        let raw = self.data.get(mac - VirtioNetHdr::LEN..mac)?;
+        Some(VirtioNetHdr::from_bytes(raw))
+    }
+
+    ///Time the packet was received
+    #[inline]
+    pub fn timestamp(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::new(self.tpacket3_hdr.tp_sec as u64, self.tpacket3_hdr.tp_nsec)
+    }
+
+    ///Whether the packet was received or transmitted by this host
+    pub fn direction(&self) -> Option<PacketDirection> {
+        Some(PacketDirection::from(self.sockaddr_ll()?.sll_pkttype))
+    }
+
+    ///Index of the interface the packet was captured on
+    pub fn ifindex(&self) -> Option<i32> {
+        Some(self.sockaddr_ll()?.sll_ifindex)
+    }
+
+    ///Link-layer metadata the kernel stored with the packet, the only way to tell where a
+    ///packet came from on `any_interface` or cooked rings
+    pub fn link_info(&self) -> Option<LinkInfo> {
+        let sll = self.sockaddr_ll()?;
+        Some(LinkInfo {
+            ifindex: sll.sll_ifindex,
+            hatype: sll.sll_hatype,
+            direction: PacketDirection::from(sll.sll_pkttype),
+            protocol: sll.sll_protocol,
+            addr: sll.sll_addr,
+            addr_len: sll.sll_halen.min(8),
+        })
+    }
+
+    ///DLT_LINUX_SLL pseudo-header describing this packet, to be written in front of
+    ///`payload()` when exporting cooked captures
+    pub fn sll_header(&self) -> Option<LinuxSllHeader> {
+        Some(LinuxSllHeader::from(&self.sockaddr_ll()?))
+    }
+
+    fn sockaddr_ll(&self) -> Option<tpacket3::SockAddrLl> {
+        let sll =
+            tpacket3::get_sockaddr_ll(self.data.get(tpacket3::TPACKET3_SLL_OFFSET..)?).ok()?;
+        Some(sll.1)
+    }
+}
+
+impl Ring {
+    ///Creates a new ring buffer on the specified interface name and puts the interface into promiscuous mode
+    pub fn from_if_name(if_name: &str) -> Result<Ring> {
+        Ring::new(RingSettings {
+            if_name: String::from(if_name),
+            ..RingSettings::default()
+        })
+    }
+
+    ///Creates a new ring buffer from the supplied RingSettings struct
+    ///
+    ///The ring geometry is validated before anything is allocated, see `TpacketReq3::validate()`
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(if_name = %settings.if_name))
+    )]
+    pub fn new(mut settings: RingSettings) -> Result<Ring> {
+        if let Some(ns) = settings.netns.take() {
+            return netns::run_in(&ns, || Ring::new(settings));
+        }
+        if let Some(node) = settings.numa_node.take() {
+            return numa::run_on_node(node, || {
+                let ring = Ring::new(settings)?;
+                let map = ring.mmap.expect("ring is mapped");
+                numa::bind_memory(map, ring.mapped_len(), node)?;
+                Ok(ring)
+            });
+        }
+        if let Some(max_memory) = settings.auto_size {
+            let speed = match settings.any_interface {
+                true => None,
+                false => iface::speed(&settings.if_name),
+            };
+            settings.ring_settings = settings.ring_settings.auto_size(speed, max_memory);
+        }
+        settings.ring_settings.validate()?;
+        if let Some(size) = settings.hugepages.and_then(HugepageSize::bytes) {
+            if !settings.ring_settings.tp_block_size.is_multiple_of(size) {
+                return Err(Error::InvalidGeometry(format!(
+                    "tp_block_size ({}) must be a multiple of the huge page size ({})",
+                    settings.ring_settings.tp_block_size, size
+                )));
+            }
+        }
+        let socket = open_socket(&settings)?;
+        //from here on dropping the ring on an error closes the socket
+        let leases = Arc::new(Leases::new(settings.ring_settings.tp_block_nr));
+        let mapping = Arc::new(Mapping {
+            fd: socket.fd,
+            map: None,
+            len: 0,
+            leases: leases.clone(),
+        });
+        #[cfg(feature = "metrics")]
+        let metrics = crate::metrics::RingMetrics::new(&socket.if_name);
+        let mut ring = Ring {
+            socket,
+            mmap: None,
+            opts: settings.ring_settings.clone(),
+            next_block: 0,
+            last_seq: None,
+            seq_gaps: 0,
+            blocks_lost: 0,
+            blocks_received: 0,
+            totals: RingStats::default(),
+            rollover: fanout_arg(&settings) & 0xff == PACKET_FANOUT_ROLLOVER
+                || fanout_arg(&settings) & PACKET_FANOUT_FLAG_ROLLOVER != 0,
+            last_rollover: RolloverStats::default(),
+            opened: Instant::now(),
+            on_seq_gap: settings.on_seq_gap.clone(),
+            on_freeze: settings.on_freeze.clone(),
+            unread: tpacket3::TpacketStatsV3::default(),
+            freezes: 0,
+            drops: 0,
+            freezes_seen: 0,
+            full_at_seq: None,
+            frozen: None,
+            filter: settings.filter.clone(),
+            sample: settings.sample,
+            snaplen: settings.snaplen,
+            paused: false,
+            last_stats: None,
+            shutdown: None,
+            report_link_down: settings.report_link_down,
+            link_down: false,
+            frames: None,
+            burst: Burst::default(),
+            hugepages: settings.hugepages,
+            lock_memory: settings.lock_memory,
+            populate: settings.populate,
+            hugepage_backed: false,
+            memory_locked: false,
+            wait_strategy: settings.wait_strategy,
+            hardware_type: None,
+            cooked: settings.cooked,
+            #[cfg(feature = "metrics")]
+            metrics,
+            #[cfg(feature = "latency")]
+            latency: crate::latency::LatencyRecorder::default(),
+            leases,
+            mapping,
+            promiscuous: Promiscuous::Off,
+            promiscuous_flag: None,
+        };
+        if !settings.any_interface {
+            ring.hardware_type = Some(ring.socket.hardware_type()?);
+        }
+        ring.promiscuous_flag = join_memberships(&mut ring.socket, &settings)?.map(Arc::new);
+        if !settings.any_interface {
+            ring.promiscuous = settings.promiscuous;
+        }
+        let version = match settings.tpacket_version {
+            Some(TpacketVersion::V2) => TpacketVersion::V2,
+            Some(TpacketVersion::V3) => {
+                ring.socket
+                    .setsockopt(PACKET_VERSION, TpacketVersion::V3.as_raw())?;
+                TpacketVersion::V3
+            }
+            None => match ring
+                .socket
+                .setsockopt(PACKET_VERSION, TpacketVersion::V3.as_raw())
+            {
+                Ok(()) => TpacketVersion::V3,
+                Err(_) => TpacketVersion::V2,
+            },
+        };
+        if version == TpacketVersion::V2 {
+            ring.socket
+                .setsockopt(PACKET_VERSION, TpacketVersion::V2.as_raw())?;
+            ring.frames = Some(FrameRing {
+                req: tpacket2::TpacketReq::from(&ring.opts),
+                next_frame: 0,
+                seq_num: 1,
+            });
+        }
+        if settings.frame_reserve > 0 {
+            ring.socket
+                .setsockopt(PACKET_RESERVE, settings.frame_reserve)?;
+        }
+        if settings.ignore_outgoing {
+            ring.socket.setsockopt(PACKET_IGNORE_OUTGOING, 1 as c_int)?;
+        }
+        if settings.vnet_header {
+            ring.socket.setsockopt(PACKET_VNET_HDR, 1 as c_int)?;
+        }
+        if let Some(filter) = socket_filter(&settings)? {
+            ring.socket.attach_filter(&filter)?;
+        }
+        if let Some(busy_poll) = &settings.busy_poll {
+            set_busy_poll(&mut ring.socket, busy_poll)?;
+        }
+        set_buffers(&mut ring.socket, &settings)?;
+        let rx_ring = match &ring.frames {
+            Some(frames) => ring.socket.setsockopt(PACKET_RX_RING, frames.req.clone()),
+            None => ring.socket.setsockopt(PACKET_RX_RING, ring.opts.clone()),
+        };
+        rx_ring.map_err(|err| match err.io_error().and_then(|e| e.raw_os_error()) {
+            Some(EINVAL) => Error::InvalidGeometry(String::from("rejected by the kernel")),
+            _ => err,
+        })?;
+        ring.mmap_rx_ring()?;
+        bind_socket(&ring.socket, settings.protocol)?;
+        join_fanout(&mut ring.socket, &settings)?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            version = ?version,
+            blocks = ring.opts.tp_block_nr,
+            block_size = ring.opts.tp_block_size,
+            "ring created"
+        );
+        Ok(ring)
+    }
+
+    ///Waits for a block to be added to the ring buffer and returns it
+    //We're allowing unused_mut here because apps that include this crate may need to control
+    //marking blocks as consumed for performance reasons to avoid copies
+    #[allow(unused_mut)]
+    #[inline]
+    pub fn get_block(&mut self) -> Block<'_> {
+        loop {
+            self.wait_for_block(-1);
+            if let Some(mut block) = self.next_ready_block() {
+                return block;
+            }
+        }
+    }
+
+    ///Waits for a block like `get_block()`, but returns `Error::Shutdown` as soon as the
+    ///ring's `ShutdownHandle` is signaled
+    #[allow(unused_mut)]
+    pub fn recv_block(&mut self) -> Result<Block<'_>> {
+        loop {
+            if let Some(mut block) = self.next_ready_block() {
+                return Ok(block);
+            }
+            if self.wait_for_block(-1) {
+                return Err(Error::Shutdown);
+            }
+            self.check_link_down()?;
+        }
+    }
+
+    ///Returns a handle that stops `recv_block()` from another thread, creating it on first use
+    pub fn shutdown_handle(&mut self) -> Result<ShutdownHandle> {
+        if let Some(handle) = &self.shutdown {
+            return Ok(handle.clone());
+        }
+        let handle = ShutdownHandle::new()?;
+        self.shutdown = Some(handle.clone());
+        Ok(handle)
+    }
+
+    ///Blocks lost to sequence gaps since the ring was set up, unlike `RingStats::seq_gaps` it
+    ///is not reset by `statistics()`
+    pub fn blocks_lost(&self) -> u64 {
+        self.blocks_lost
+    }
+
+    ///Times the kernel froze the queue since the ring was set up, unlike
+    ///`RingStats::freeze_q_cnt` it is not reset by `statistics()`
+    ///
+    ///Covers every read of the kernel counters made through this ring value, including the
+    ///ones the ring makes itself to time freezes.
+    pub fn freezes(&self) -> u64 {
+        self.freezes
+    }
+
+    ///Latencies of the blocks consumed since the ring was set up or the last
+    ///`reset_latency_stats()`, shared with its clones
+    ///
+    ///A block counts once it is marked as consumed, see `latency` for what is recorded.
+    #[cfg(feature = "latency")]
+    pub fn latency_stats(&self) -> crate::latency::LatencyStats {
+        self.latency.snapshot()
+    }
+
+    ///Starts recording latencies afresh
+    #[cfg(feature = "latency")]
+    pub fn reset_latency_stats(&self) {
+        self.latency.reset()
+    }
+
+    ///Closes the ring and reports what it saw since it was set up
+    ///
+    ///Reads the kernel counters one last time, then drops the ring like going out of scope
+    ///would: the socket and mapping it shares with its clones are closed once the last of them
+    ///is gone. The counts cover what this ring value read through `statistics()`, not reads
+    ///made through its clones. The ring is dropped even when reading the counters fails.
+    pub fn close(mut self) -> Result<CloseReport> {
+        self.statistics()?;
+        Ok(CloseReport {
+            packets: self.totals.packets,
+            drops: self.totals.drops,
+            freeze_q_cnt: self.totals.freeze_q_cnt,
+            blocks: self.blocks_received,
+            blocks_lost: self.blocks_lost,
+            duration: self.opened.elapsed(),
+        })
+    }
+
+    ///Calls `f` with every sequence gap from now on, see `RingSettings::on_seq_gap`
+    pub fn on_seq_gap<F>(&mut self, f: F)
+    where
+        F: Fn(SeqGap) + Send + Sync + 'static,
+    {
+        self.on_seq_gap = Some(SeqGapCallback::new(f));
+    }
+
+    ///Calls `f` with every queue freeze from now on, see `RingSettings::on_freeze`
+    pub fn on_freeze<F>(&mut self, f: F)
+    where
+        F: Fn(FreezeEvent) + Send + Sync + 'static,
+    {
+        self.on_freeze = Some(FreezeCallback::new(f));
+    }
+
+    ///TPACKET version the ring was set up with
+    pub fn version(&self) -> TpacketVersion {
+        match self.frames {
+            Some(_) => TpacketVersion::V2,
+            None => TpacketVersion::V3,
+        }
+    }
+
+    ///ARPHRD_* hardware type of the interface, e.g. `radiotap::ARPHRD_IEEE80211_RADIOTAP` for
+    ///wlan interfaces in monitor mode; None when capturing from all interfaces
+    pub fn hardware_type(&self) -> Option<u16> {
+        self.hardware_type
+    }
+
+    ///pcap link type of the packets, as `payload()` hands them out, for writing captures and
+    ///compiling filters
+    ///
+    ///`sll::DLT_LINUX_SLL` for cooked rings, rings bound to all interfaces and interfaces whose
+    ///link-layer header pcap has no type for here; write those with the packets'
+    ///`sll_header()` in front of `l3_payload()`.
+    pub fn link_type(&self) -> u16 {
+        match self.hardware_type {
+            Some(hardware_type) if !self.cooked => {
+                pcapng::link_type_of(hardware_type).unwrap_or(DLT_LINUX_SLL)
+            }
+            _ => DLT_LINUX_SLL,
+        }
+    }
+
+    ///Whether the ring memory is mapped with huge pages, see `RingSettings::hugepages`
+    pub fn is_hugepage_backed(&self) -> bool {
+        self.hugepage_backed
+    }
+
+    ///Whether the ring memory is locked in RAM (MAP_LOCKED); it is mapped unlocked when
+    ///RLIMIT_MEMLOCK is too low for it
+    pub fn is_memory_locked(&self) -> bool {
+        self.memory_locked
+    }
+
+    ///Memory the ring takes and how it is laid out
+    pub fn memory_info(&self) -> MemoryInfo {
+        let mapped_bytes = match self.mmap {
+            Some(_) => self.mapped_len() as u64,
+            None => 0,
+        };
+        let page_size = match self.hugepages {
+            Some(size) if self.hugepage_backed => {
+                size.bytes().map(u64::from).or_else(default_hugepage_size)
+            }
+            _ => None,
+        };
+        MemoryInfo {
+            mapped_bytes,
+            locked_bytes: if self.memory_locked { mapped_bytes } else { 0 },
+            block_size: self.opts.tp_block_size,
+            block_nr: self.opts.tp_block_nr,
+            frame_size: self.opts.tp_frame_size,
+            frame_nr: self.opts.tp_frame_nr,
+            page_size: page_size.unwrap_or_else(|| unsafe { sysconf(_SC_PAGESIZE) } as u64),
+            hugepages: self.hugepage_backed,
+        }
+    }
+
+    ///CPU stored in the socket's SO_INCOMING_CPU, see `Socket::incoming_cpu()`
+    pub fn incoming_cpu(&self) -> Result<Option<usize>> {
+        self.socket.incoming_cpu()
+    }
+
+    ///Takes the interface out of promiscuous mode now instead of when the ring is dropped
+    ///
+    ///Drops the membership of `Promiscuous::Membership`, or gives up the ring's share of
+    ///`Promiscuous::InterfaceFlag`, clearing the flag if no other ring holds it and it was not
+    ///set before. Clones share the socket and are affected too. Other sockets keep the
+    ///interface promiscuous for as long as they hold their own memberships.
+    pub fn leave_promiscuous(&mut self) -> Result<()> {
+        match self.promiscuous {
+            Promiscuous::Off => {}
+            Promiscuous::Membership => self.socket.set_promiscuous(false)?,
+            Promiscuous::InterfaceFlag => {
+                if let Some(flag) = self.promiscuous_flag.take() {
+                    flag.release()?;
+                }
+            }
+        }
+        self.promiscuous = Promiscuous::Off;
+        Ok(())
+    }
+
+    ///Changes how the ring waits for blocks
+    pub fn set_wait_strategy(&mut self, wait_strategy: WaitStrategy) {
+        self.wait_strategy = wait_strategy;
+    }
+
+    pub fn wait_strategy(&self) -> WaitStrategy {
+        self.wait_strategy
+    }
+
+    ///Handle the ring listens to, if any
+    pub fn get_shutdown_handle(&self) -> Option<&ShutdownHandle> {
+        self.shutdown.as_ref()
+    }
+
+    ///Makes the ring listen to an existing handle, so that one handle can stop many rings
+    pub fn set_shutdown_handle(&mut self, handle: ShutdownHandle) {
+        self.shutdown = Some(handle);
+    }
+
+    ///Like `get_block()`, but gives up and returns `None` once `timeout` has passed
+    #[allow(unused_mut)]
+    pub fn get_block_timeout(&mut self, timeout: Duration) -> Option<Block<'_>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(mut block) = self.next_ready_block() {
+                return Some(block);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            let remaining = deadline - now;
+            self.wait_for_block(remaining.as_millis().clamp(1, c_int::MAX as u128) as c_int);
+        }
+    }
+
+    ///Returns a block if one is ready, without waiting
+    #[allow(unused_mut)]
+    pub fn try_get_block(&mut self) -> Option<Block<'_>> {
+        self.next_ready_block()
+    }
+
+    ///Moves up to `max` packets of the ready blocks into `packets` without waiting, returns how
+    ///many
+    ///
+    ///Styled after DPDK's rx_burst for busy poll loops: no system call is made as long as blocks
+    ///are ready. Blocks are lent out like `Block::into_shared()` and go back to the kernel once
+    ///all their packets are dropped, so drop them soon; packets of a block that did not fit are
+    ///handed out first by the next call.
+    pub fn poll_burst(&mut self, packets: &mut Vec<Packet>, max: usize) -> usize {
+        let mut taken = 0;
+        while taken < max {
+            if let Some(packet) = self.burst.0.pop_front() {
+                packets.push(packet);
+                taken += 1;
+                continue;
+            }
+            match self.next_ready_block() {
+                Some(block) => self.burst.0.extend(block.into_shared().packets()),
+                None => break,
+            }
+        }
+        taken
+    }
+
+    ///Waits for a block like `recv_block()`, then returns every block ready by then, at most
+    ///`max`
+    ///
+    ///Handing out a whole burst per wakeup saves the poll() and the ring walk for each block
+    ///under load. Every block still has to be marked as consumed.
+    pub fn recv_blocks(&mut self, max: usize) -> Result<Vec<Block<'_>>> {
+        if max == 0 {
+            return Ok(Vec::new());
+        }
+        loop {
+            let blocks = self.next_ready_blocks(max);
+            if !blocks.is_empty() {
+                return Ok(blocks);
+            }
+            if self.wait_for_block(-1) {
+                return Err(Error::Shutdown);
+            }
+            self.check_link_down()?;
+        }
+    }
+
+    ///Returns every block that is ready, at most `max`, without waiting; see `recv_blocks()`
+    pub fn try_get_blocks(&mut self, max: usize) -> Vec<Block<'_>> {
+        self.next_ready_blocks(max)
+    }
+
+    #[inline]
+    pub(crate) fn next_ready_block<'a>(&mut self) -> Option<Block<'a>> {
+        if self.frames.is_some() {
+            #[allow(unused_mut)]
+            let mut block = self.next_ready_frames()?;
+            self.blocks_received += 1;
+            #[cfg(feature = "latency")]
+            {
+                block.timing = Some(self.latency.start(block.last_packet_ts()));
+            }
+            return Some(block);
+        }
+        self.next_ready_block_within(self.opts.tp_block_nr)
+    }
+
+    //hands out blocks in ring order, without going round to those handed out first; they stay
+    //ready until they are consumed
+    pub(crate) fn next_ready_blocks<'a>(&mut self, max: usize) -> Vec<Block<'a>> {
+        let mut blocks = Vec::new();
+        if max == 0 {
+            return blocks;
+        }
+        if self.frames.is_some() {
+            //every block is a copy of its own, taken from the frames in order
+            while blocks.len() < max {
+                match self.next_ready_block() {
+                    Some(block) => blocks.push(block),
+                    None => break,
+                }
+            }
+            return blocks;
+        }
+        let nr = self.opts.tp_block_nr;
+        let mut left = nr;
+        while blocks.len() < max && left > 0 {
+            let start = self.next_block;
+            match self.next_ready_block_within(left) {
+                Some(block) => blocks.push(block),
+                None => break,
+            }
+            let passed = match (self.next_block + nr - start) % nr {
+                0 => nr,
+                passed => passed,
+            };
+            left = left.saturating_sub(passed);
+        }
+        blocks
+    }
+
+    //first ready block among the `span` blocks from where the kernel will retire the next one
+    #[inline]
+    fn next_ready_block_within<'a>(&mut self, span: u32) -> Option<Block<'a>> {
+        for n in 0..span {
+            let i = (self.next_block + n) % self.opts.tp_block_nr;
+            if self.leases.is_lent(i) {
+                continue;
+            }
+            if let Some(mut block) = self.get_single_block(i) {
+                if block.is_ready() {
+                    self.next_block = (i + 1) % self.opts.tp_block_nr;
+                    self.blocks_received += 1;
+                    self.track_seq(block.seq_num());
+                    self.track_freeze(i, &block);
+                    #[cfg(feature = "tracing")]
+                    trace_block(&block);
+                    block.lease = Some(Lease::new(&self.leases, i));
+                    #[cfg(feature = "latency")]
+                    {
+                        block.timing = Some(self.latency.start(block.last_packet_ts()));
+                    }
+                    return Some(block);
+                }
+            }
+        }
+        None
+    }
+
+    ///Replaces the ring's filter on a live capture
+    ///
+    ///Packets that arrive while the filters are swapped could have been checked against
+    ///either one, so the ring first drops everything, discards the blocks already retired and
+    ///only then attaches `filter`. The block the kernel is filling at that moment may still hold
+    ///packets accepted by the old filter. `RingSettings::sample` and `RingSettings::snaplen`
+    ///still apply. A paused ring takes the filter on `resume()`.
+    pub fn set_filter(&mut self, filter: &FilterProgram) -> Result<()> {
+        let combined = combine_filter(Some(filter), self.sample, self.snaplen)?
+            .unwrap_or_else(|| filter.clone());
+        self.filter = Some(filter.clone());
+        if self.paused {
+            return Ok(());
+        }
+        self.socket.attach_filter(&FilterProgram::drop_all())?;
+        self.discard_ready();
+        self.socket.attach_filter(&combined)
+    }
+
+    ///Removes the ring's filter so that it receives everything again, or everything
+    ///`RingSettings::sample` lets through, cut to `RingSettings::snaplen`
+    pub fn clear_filter(&mut self) -> Result<()> {
+        self.filter = None;
+        if self.paused {
+            return Ok(());
+        }
+        self.apply_filter()
+    }
+
+    ///Stops receiving packets while keeping the ring, its memory and its settings
+    ///
+    ///The kernel offers no way to leave a fanout group short of closing the socket, so the
+    ///ring stays in its group behind a filter dropping everything: its share of the group's
+    ///traffic is dropped, not handed to the other members. Blocks retired before the pause can
+    ///still be read, `discard_ready()` discards them. Packets dropped by the filter do not show up in
+    ///`statistics()`.
+    pub fn pause(&mut self) -> Result<()> {
+        self.socket.attach_filter(&FilterProgram::drop_all())?;
+        self.paused = true;
+        Ok(())
+    }
+
+    ///Receives packets again after `pause()`, through the filter set last
+    ///
+    ///Filters attached to `Ring::socket` directly, e.g. eBPF programs, are not restored.
+    pub fn resume(&mut self) -> Result<()> {
+        if !self.paused {
+            return Ok(());
+        }
+        self.apply_filter()?;
+        self.paused = false;
+        Ok(())
+    }
+
+    ///Whether the ring is paused, see `pause()`
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    //attaches the filter, sample and snap length of the ring, or detaches any filter
+    fn apply_filter(&mut self) -> Result<()> {
+        if let Some(filter) = combine_filter(self.filter.as_ref(), self.sample, self.snaplen)? {
+            return self.socket.attach_filter(&filter);
+        }
+        match self.socket.detach_filter() {
+            Err(ref err) if err.io_error().and_then(|e| e.raw_os_error()) == Some(ENOENT) => Ok(()),
+            res => res,
+        }
+    }
+
+    ///Stops the feed like `pause()` and returns the blocks still in the ring, for a shutdown
+    ///that keeps the traffic already captured
+    ///
+    ///The block the kernel is filling is retired by its timeout, so `Drain` waits for that
+    ///once before ending. Blocks have to be marked as consumed as usual, or they come up
+    ///again. The ring stays paused afterwards, `resume()` restarts it.
+    pub fn drain(&mut self) -> Result<Drain<'_>> {
+        self.pause()?;
+        Ok(Drain {
+            ring: self,
+            waited: false,
+        })
+    }
+
+    //attaches a filter without discarding the blocks already captured, which may be in use
+    //elsewhere
+    #[cfg(feature = "async-io")]
+    pub(crate) fn replace_filter(&mut self, filter: Option<FilterProgram>) -> Result<()> {
+        self.filter = filter;
+        self.apply_filter()
+    }
+
+    //records a filter attached through an observer
+    #[cfg(feature = "async-io")]
+    pub(crate) fn note_filter(&mut self, filter: Option<FilterProgram>) {
+        self.filter = filter;
+    }
+
+    ///Hands every retired block back to the kernel unread, returns how many there were
+    pub fn discard_ready(&mut self) -> usize {
+        let mut drained = 0;
+        while let Some(mut block) = self.next_ready_block() {
+            block.mark_as_consumed();
+            drained += 1;
+        }
+        drained
+    }
+
+    ///Installs the classic BPF program of a PACKET_FANOUT_CBPF group; its return value picks
+    ///the ring, modulo the number of rings in the group
+    ///
+    ///The program is shared by the whole group, so it only needs to be set through one ring.
+    pub fn set_fanout_cbpf(&mut self, program: &FilterProgram) -> Result<()> {
+        self.socket
+            .setsockopt(PACKET_FANOUT_DATA, program.as_fprog())
+    }
+
+    ///Installs the eBPF program of a PACKET_FANOUT_EBPF group, a loaded
+    ///BPF_PROG_TYPE_SOCKET_FILTER program whose return value picks the ring
+    ///
+    ///The program is shared by the whole group, so it only needs to be set through one ring.
+    pub fn set_fanout_ebpf(&mut self, prog_fd: c_int) -> Result<()> {
+        self.socket.setsockopt(PACKET_FANOUT_DATA, prog_fd)
+    }
+
+    ///Returns kernel counters since the last call along with the current ring saturation
+    ///and any block sequence gaps seen by `get_block()` in the meantime
+    pub fn statistics(&mut self) -> Result<RingStats> {
+        let mut kstats = self.read_kernel_stats()?;
+        let unread = mem::take(&mut self.unread);
+        kstats.tp_packets += unread.tp_packets;
+        kstats.tp_drops += unread.tp_drops;
+        kstats.tp_freeze_q_cnt += unread.tp_freeze_q_cnt;
+        let now = Instant::now();
+        let stats = RingStats {
+            packets: kstats.tp_packets as u64,
+            drops: kstats.tp_drops as u64,
+            freeze_q_cnt: kstats.tp_freeze_q_cnt as u64,
+            ready_blocks: self.ready_blocks(),
+            total_blocks: self.opts.tp_block_nr,
+            seq_gaps: self.seq_gaps,
+            rollover: self.take_rollover()?,
+            interval: self.last_stats.map(|last| now.duration_since(last)),
+        };
+        self.seq_gaps = 0;
+        self.last_stats = Some(now);
+        self.totals.accumulate(&stats);
+        #[cfg(feature = "tracing")]
+        if stats.drops > 0 {
+            tracing::warn!(
+                if_name = %self.socket.if_name,
+                drops = stats.drops,
+                packets = stats.packets,
+                "kernel dropped packets"
+            );
+        }
+        Ok(stats)
+    }
+
+    ///Rollover counters since the ring joined its group, None unless it uses
+    ///PACKET_FANOUT_ROLLOVER or PACKET_FANOUT_FLAG_ROLLOVER
+    pub fn rollover_statistics(&self) -> Result<Option<RolloverStats>> {
+        if !self.rollover {
+            return Ok(None);
+        }
+        let mut optval = tpacket3::TpacketRolloverStats::default();
+        socket::get_sock_opt(self.socket.fd, PACKET_ROLLOVER_STATS, &mut optval)?;
+        Ok(Some(RolloverStats {
+            all: optval.tp_all,
+            huge: optval.tp_huge,
+            failed: optval.tp_failed,
+        }))
+    }
+
+    //rollover counters since the previous call
+    fn take_rollover(&mut self) -> Result<Option<RolloverStats>> {
+        let totals = match self.rollover_statistics()? {
+            Some(totals) => totals,
+            None => return Ok(None),
+        };
+        let last = mem::replace(&mut self.last_rollover, totals);
+        Ok(Some(RolloverStats {
+            all: totals.all.wrapping_sub(last.all),
+            huge: totals.huge.wrapping_sub(last.huge),
+            failed: totals.failed.wrapping_sub(last.failed),
+        }))
+    }
+
+    #[inline]
+    fn track_seq(&mut self, seq: u64) {
+        if let Some(last) = self.last_seq {
+            if seq > last + 1 {
+                let gap = SeqGap {
+                    expected: last + 1,
+                    received: seq,
+                };
+                self.seq_gaps += gap.lost();
+                self.blocks_lost += gap.lost();
+                #[cfg(feature = "tracing")]
+                tracing::warn!(
+                    if_name = %self.socket.if_name,
+                    expected = gap.expected,
+                    received = gap.received,
+                    lost = gap.lost(),
+                    "blocks lost"
+                );
+                if let Some(callback) = &self.on_seq_gap {
+                    (callback.0)(gap);
+                }
+            }
+        }
+        self.last_seq = Some(seq);
+        #[cfg(feature = "metrics")]
+        self.record_metrics();
+    }
+
+    #[cfg(feature = "metrics")]
+    fn record_metrics(&mut self) {
+        if !self.metrics.block(self.blocks_lost) {
+            return;
+        }
+        let _ = self.read_unread_stats();
+    }
+
+    //reads the kernel counters, which resets them, and adds them to the lifetime totals
+    fn read_kernel_stats(&mut self) -> Result<tpacket3::TpacketStatsV3> {
+        let kstats = get_rx_statistics(self.socket.fd)?;
+        self.freezes += kstats.tp_freeze_q_cnt as u64;
+        self.drops += kstats.tp_drops as u64;
+        #[cfg(feature = "metrics")]
+        {
+            let ready = self.ready_blocks();
+            self.metrics.poll(&kstats, ready, self.opts.tp_block_nr);
+        }
+        Ok(kstats)
+    }
+
+    //reads the kernel counters on the ring's own account, keeping them for `statistics()`
+    fn read_unread_stats(&mut self) -> Result<()> {
+        let kstats = self.read_kernel_stats()?;
+        self.unread.tp_packets += kstats.tp_packets;
+        self.unread.tp_drops += kstats.tp_drops;
+        self.unread.tp_freeze_q_cnt += kstats.tp_freeze_q_cnt;
+        Ok(())
+    }
+
+    //`block` was just handed out from index `i`. Once every block is waiting for userspace the
+    //kernel has nowhere to put packets and freezes the queue, the counters tell whether it did.
+    //The freeze ends with the first block retired after it.
+    #[inline]
+    fn track_freeze(&mut self, i: u32, block: &Block) {
+        let seq = block.seq_num();
+        if let Some(freeze) = self.frozen {
+            if seq > freeze.last_seq {
+                self.frozen = None;
+                self.thaw(freeze, block.first_packet_ts());
+            }
+            return;
+        }
+        //blocks are retired in order, so the ring is full when the one before is not back yet
+        let nr = self.opts.tp_block_nr;
+        let map = match self.mmap {
+            Some(map) if nr > 1 => map,
+            _ => return,
+        };
+        let prev = (i + nr - 1) % nr;
+        if !self.unit_ready(map, prev) {
+            return;
+        }
+        //the block may be lent out, so only its descriptor is read
+        let raw = unsafe {
+            std::slice::from_raw_parts(
+                map.add(prev as usize * self.opts.tp_block_size as usize),
+                self.opts.tp_block_size as usize,
+            )
+        };
+        let newest = match tpacket3::get_tpacket_block_desc(raw) {
+            Ok((_, desc)) => desc.hdr,
+            Err(_) => return,
+        };
+        if self.full_at_seq == Some(newest.seq_num) {
+            return;
+        }
+        self.full_at_seq = Some(newest.seq_num);
+        let drops = self.drops;
+        if self.read_unread_stats().is_err() || self.freezes == self.freezes_seen {
+            return;
+        }
+        self.freezes_seen = self.freezes;
+        self.frozen = Some(Freeze {
+            last_seq: newest.seq_num,
+            started: newest.ts_last_pkt.to_system_time(),
+            drops,
+        });
+    }
+
+    #[cold]
+    fn thaw(&mut self, freeze: Freeze, resumed: SystemTime) {
+        let _ = self.read_unread_stats();
+        self.freezes_seen = self.freezes;
+        let event = FreezeEvent {
+            started: freeze.started,
+            duration: resumed.duration_since(freeze.started).unwrap_or_default(),
+            drops: self.drops - freeze.drops,
+        };
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            if_name = %self.socket.if_name,
+            duration_ms = event.duration.as_millis() as u64,
+            drops = event.drops,
+            "queue frozen"
+        );
+        if let Some(callback) = &self.on_freeze {
+            (callback.0)(event);
+        }
+    }
+
+    //copies the ready V2 frames, as many as fit, into a block
+    fn next_ready_frames<'a>(&mut self) -> Option<Block<'a>> {
+        let map = self.mmap?;
+        let frames = self.frames.as_mut()?;
+        let frame_size = frames.req.tp_frame_size as usize;
+        let frames_per_block = frames.req.frames_per_block();
+        let mut builder = BlockBuilder::new(frames.req.tp_block_size as usize, frames.seq_num);
+        for _ in 0..frames.req.tp_frame_nr {
+            //frames do not cross block boundaries, the tail of a block may be unused
+            let block = frames.next_frame / frames_per_block;
+            let offset = block as usize * frames.req.tp_block_size as usize
+                + (frames.next_frame % frames_per_block) as usize * frame_size;
+            let frame = unsafe { std::slice::from_raw_parts_mut(map.add(offset), frame_size) };
+            if unsafe { std::ptr::read_volatile(frame.as_ptr()) } & tpacket3::TP_STATUS_USER == 0 {
+                break;
+            }
+            if !tpacket2::push_frame(&mut builder, frame) {
+                break;
+            }
+            //tp_status is the first field of the frame
+            unsafe {
+                std::ptr::write_volatile(frame.as_mut_ptr() as *mut u32, 0);
+            }
+            frames.next_frame = (frames.next_frame + 1) % frames.req.tp_frame_nr;
+        }
+        if builder.is_empty() {
+            return None;
+        }
+        let seq_num = frames.seq_num;
+        frames.seq_num += 1;
+        self.track_seq(seq_num);
+        Block::from_owned(builder.finish())
+    }
+
+    //whether the block, or V2 frame, the ring reads next is ready, without taking it
+    #[inline]
+    fn is_next_ready(&self) -> bool {
+        let map = match self.mmap {
+            Some(map) => map,
+            None => return false,
+        };
+        match &self.frames {
+            Some(frames) => self.unit_ready(map, frames.next_frame),
+            None if self.leases.is_lent(self.next_block) => false,
+            None => self.unit_ready(map, self.next_block),
+        }
+    }
+
+    //status of block `i`, or of V2 frame `i`
+    #[inline]
+    fn unit_ready(&self, map: *mut u8, i: u32) -> bool {
+        let offset = match &self.frames {
+            Some(frames) => {
+                let frames_per_block = frames.req.frames_per_block();
+                (i / frames_per_block) as usize * frames.req.tp_block_size as usize
+                    + (i % frames_per_block) as usize * frames.req.tp_frame_size as usize
+            }
+            None => i as usize * self.opts.tp_block_size as usize + tpacket3::TP_BLK_STATUS_OFFSET,
+        };
+        unsafe { std::ptr::read_volatile(map.add(offset)) & tpacket3::TP_STATUS_USER != 0 }
+    }
+
+    ///Blocks retired by the kernel and not yet released, counted one by one
+    ///
+    ///Blocks lent out and not yet dropped count as ready. On a V2 ring ready frames are
+    ///counted and rounded up to blocks.
+    pub fn ready_blocks(&self) -> u32 {
+        let map = match self.mmap {
+            Some(map) => map,
+            None => return 0,
+        };
+        match &self.frames {
+            Some(frames) => {
+                let ready = (0..frames.req.tp_frame_nr)
+                    .filter(|&i| self.unit_ready(map, i))
+                    .count() as u32;
+                ready.div_ceil(frames.req.frames_per_block())
+            }
+            None => (0..self.opts.tp_block_nr)
+                .filter(|&i| self.unit_ready(map, i))
+                .count() as u32,
+        }
+    }
+
+    ///Fraction of blocks retired by the kernel and not yet released, between 0 and 1, see
+    ///`ready_blocks()`
+    pub fn saturation(&self) -> f64 {
+        if self.opts.tp_block_nr == 0 {
+            return 0.0;
+        }
+        self.ready_blocks() as f64 / self.opts.tp_block_nr as f64
+    }
+
+    ///Saturation in percent rounded down to a multiple of `step_percent`, for cheap sampling
+    ///
+    ///The kernel fills the ring in order from where it is read, so instead of counting every
+    ///block only one block per step is looked at, at most `100 / step_percent` of them.
+    ///`step_percent` is clamped to 1..=100.
+    pub fn buffer_saturation_threshold(&self, step_percent: u8) -> u8 {
+        let map = match self.mmap {
+            Some(map) => map,
+            None => return 0,
+        };
+        let (units, next) = match &self.frames {
+            Some(frames) => (frames.req.tp_frame_nr, frames.next_frame),
+            None => (self.opts.tp_block_nr, self.next_block),
+        };
+        if units == 0 {
+            return 0;
+        }
+        let step = step_percent.clamp(1, 100) as u64;
+        let mut saturation = 0;
+        let mut percent = step;
+        while percent <= 100 {
+            //the last unit that has to be ready for the ring to be `percent` full
+            let needed = (percent * units as u64).div_ceil(100).max(1) as u32;
+            if !self.unit_ready(map, (next + needed - 1) % units) {
+                break;
+            }
+            saturation = percent as u8;
+            percent += step;
+        }
+        saturation
+    }
+
+    fn mmap_rx_ring(&mut self) -> Result<()> {
+        let mut flags = MAP_SHARED | MAP_NORESERVE;
+        if self.populate {
+            flags |= MAP_POPULATE;
+        }
+        if self.lock_memory {
+            if memlock_allows(self.mapped_len()) {
+                flags |= MAP_LOCKED;
+            } else {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(
+                    len = self.mapped_len(),
+                    "RLIMIT_MEMLOCK too low to lock the ring, mapping it unlocked"
+                );
+            }
+        }
+        if let Some(size) = self.hugepages {
+            flags |= MAP_POPULATE;
+            match self.map_ring(flags | size.mmap_flags()) {
+                Ok(()) => {
+                    self.hugepage_backed = true;
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(len = self.mapped_len(), "ring mapped with huge pages");
+                    return Ok(());
+                }
+                Err(err) if err.raw_os_error() == Some(EINVAL) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!("huge pages refused, mapping the ring with normal pages");
+                }
+                Err(err) => return Err(self.mmap_error(err)),
+            }
+        }
+        self.map_ring(flags).map_err(|err| self.mmap_error(err))?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(len = self.mapped_len(), "ring mapped");
+        Ok(())
+    }
+
+    //a mapping refused while the ring does not fit under RLIMIT_MEMLOCK was to be locked, by
+    //MAP_LOCKED or by mlockall(MCL_FUTURE) which locks every later mapping; other refusals
+    //are policy
+    fn mmap_error(&self, source: io::Error) -> Error {
+        let needed = self.mapped_len() as u64;
+        match (source.raw_os_error(), memlock_limit()) {
+            (Some(EAGAIN) | Some(EPERM) | Some(EACCES), Some(limit)) if needed > limit => {
+                Error::MissingPrivilege {
+                    context: "mmap",
+                    privilege: Privilege::MemLock { needed, limit },
+                    source,
+                }
+            }
+            (Some(EPERM) | Some(EACCES), _) => Error::os("mmap", source),
+            _ => Error::Mmap(source),
+        }
+    }
+
+    //maps the ring, without MAP_LOCKED if the kernel refuses to lock that much memory
+    fn map_ring(&mut self, flags: c_int) -> io::Result<()> {
+        match self.map_ring_once(flags) {
+            Err(err)
+                if flags & MAP_LOCKED != 0
+                    && matches!(
+                        err.raw_os_error(),
+                        Some(EAGAIN) | Some(EPERM) | Some(ENOMEM)
+                    ) =>
+            {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(
+                    len = self.mapped_len(),
+                    %err,
+                    "could not lock the ring in memory, mapping it unlocked"
+                );
+                self.map_ring_once(flags & !MAP_LOCKED)
+            }
+            res => res,
+        }
+    }
+
+    fn map_ring_once(&mut self, flags: c_int) -> io::Result<()> {
+        match unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                self.mapped_len(),
+                PROT_READ | PROT_WRITE,
+                flags,
+                self.socket.fd,
+                0,
+            )
+        } as isize
+        {
+            -1 => Err(io::Error::last_os_error()),
+            map => {
+                let len = self.mapped_len();
+                let mapping = Arc::get_mut(&mut self.mapping).expect("ring is set up unshared");
+                mapping.map = Some(map as *mut u8);
+                mapping.len = len;
+                self.mmap = mapping.map;
+                self.memory_locked = flags & MAP_LOCKED != 0;
+                Ok(())
+            }
+        }
+    }
+
+    #[inline]
+    ///Returns true if woken up by the shutdown handle
+    pub(crate) fn wait_for_block(&mut self, mut timeout_ms: c_int) -> bool {
+        let strategy = self.wait_strategy;
+        for _ in 0..strategy.spins {
+            if self.is_next_ready() {
+                return false;
+            }
+            std::hint::spin_loop();
+        }
+        for _ in 0..strategy.yields {
+            if self.is_next_ready() {
+                return false;
+            }
+            std::thread::yield_now();
+        }
+        if strategy.spins > 0 || strategy.yields > 0 {
+            if self.shutdown.as_ref().is_some_and(|s| s.is_signaled()) {
+                return true;
+            }
+            if self.is_next_ready() {
+                return false;
+            }
+        }
+        if let Some(cap) = strategy.poll_timeout {
+            let cap = cap.as_millis().clamp(1, c_int::MAX as u128) as c_int;
+            if timeout_ms < 0 || timeout_ms > cap {
+                timeout_ms = cap;
+            }
+        }
+
+        let mut pfds = [
+            pollfd {
+                fd: self.socket.fd,
+                events: POLLIN | POLLERR,
+                revents: 0,
+            },
+            pollfd {
+                fd: self.shutdown.as_ref().map(|s| s.fd()).unwrap_or(-1),
+                events: POLLIN,
+                revents: 0,
+            },
+        ];
+
+        unsafe {
+            poll(pfds.as_mut_ptr(), 2, timeout_ms);
+        }
+        if pfds[0].revents & POLLERR != 0 {
+            self.take_socket_error();
+        }
+        pfds[1].revents & POLLIN != 0
+    }
+
+    ///Clears a pending socket error, which would otherwise keep poll() returning at once,
+    ///and remembers if it says the interface went down
+    pub(crate) fn take_socket_error(&mut self) {
+        if let Ok(Some(err)) = self.socket.take_error() {
+            if err.raw_os_error() == Some(ENETDOWN) {
+                self.link_down = true;
+            }
+        }
+    }
+
+    ///Returns `Error::InterfaceDown` once per link down event, if the ring reports them
+    pub(crate) fn check_link_down(&mut self) -> Result<()> {
+        if mem::take(&mut self.link_down) && self.report_link_down {
+            return Err(Error::InterfaceDown(self.socket.if_name.clone()));
+        }
+        Ok(())
+    }
+
+    fn mapped_len(&self) -> usize {
+        self.opts.tp_block_size as usize * self.opts.tp_block_nr as usize
+    }
+
+    #[inline]
+    fn get_single_block<'a>(&mut self, count: u32) -> Option<Block<'a>> {
+        //TODO: clean up all this typecasting
+        let offset = count as isize * self.opts.tp_block_size as isize;
+
+        let block = unsafe {
+            std::slice::from_raw_parts_mut(
+                self.mmap?.offset(offset),
+                self.opts.tp_block_size as usize,
+            )
+        };
+
+        Block::from_raw(block)
+    }
+}
+
+unsafe impl Send for Ring {}
+
+impl Drain<'_> {
+    ///Next block left in the ring, `None` once there are no more
+    ///
+    ///Each block borrows the drain, so it has to be dropped before asking for the next one.
+    pub fn next_block(&mut self) -> Option<Block<'_>> {
+        if let Some(block) = self.ring.next_ready_block() {
+            return Some(block);
+        }
+        if self.waited {
+            return None;
+        }
+        self.waited = true;
+        //the kernel picks the timeout when it is left at 0, usually well under this
+        let tov = match self.ring.opts.tp_retire_blk_tov {
+            0 => DRAIN_DEFAULT_TOV_MS,
+            tov => tov as c_int,
+        };
+        let deadline = Instant::now() + Duration::from_millis(2 * tov as u64);
+        loop {
+            if let Some(block) = self.ring.next_ready_block() {
+                return Some(block);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            let remaining = (deadline - now).as_millis().max(1) as c_int;
+            //a signaled shutdown handle, as when draining on shutdown, ends every wait at once
+            if self.ring.wait_for_block(remaining) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+}
+
+//whether RLIMIT_MEMLOCK leaves room to lock `len` bytes
+fn memlock_allows(len: usize) -> bool {
+    memlock_limit().is_none_or(|limit| len as u64 <= limit)
+}
+
+//RLIMIT_MEMLOCK in bytes, None if it does not apply; root is assumed to have CAP_IPC_LOCK,
+//which lifts the limit
+fn memlock_limit() -> Option<u64> {
+    if unsafe { geteuid() } == 0 {
+        return None;
+    }
+    let mut limit = rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { getrlimit(RLIMIT_MEMLOCK, &mut limit) } != 0 || limit.rlim_cur == RLIM_INFINITY {
+        return None;
+    }
+    Some(limit.rlim_cur)
+}
+
+//Hugepagesize in /proc/meminfo, in bytes
+fn default_hugepage_size() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("Hugepagesize:"))?;
+    let kb: u64 = line.trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kb * 1024)
+}
+
+///Opens the raw or cooked packet socket `settings` ask for, not bound yet
+pub(crate) fn open_socket(settings: &RingSettings) -> Result<Socket> {
+    let kind = if settings.cooked {
+        SOCK_DGRAM
+    } else {
+        SOCK_RAW
+    };
+    if settings.any_interface {
+        Socket::open_any(socket::PF_PACKET, kind, settings.protocol)
+    } else {
+        Socket::open(
+            &settings.if_name,
+            socket::PF_PACKET,
+            kind,
+            settings.protocol,
+        )
+    }
+}
+
+///Binds a packet socket to its interface, or to all of them for `open_any()` sockets
+pub(crate) fn bind_socket(socket: &Socket, protocol: EtherType) -> Result<()> {
+    let mut sa = sockaddr_ll {
+        sll_family: AF_PACKET as u16,
+        sll_protocol: protocol.to_raw().to_be(),
+        sll_ifindex: socket.if_index as c_int,
+        sll_hatype: 519,
+        sll_pkttype: (PACKET_HOST //can we just use 255 here lol
+            | PACKET_BROADCAST
+            | PACKET_MULTICAST
+            | PACKET_OTHERHOST
+            | PACKET_OUTGOING),
+        sll_halen: ETH_ALEN as u8,
+        sll_addr: [0; 8],
+    };
+
+    //get the size before we change the pointer type
+    let size = mem::size_of_val(&sa);
+    //we have to do this cast because Linux uses multiple sockaddr_
+    //family structs and casts them to sockaddr after populating them
+    let addr_ptr = &mut sa as *mut sockaddr_ll as *mut sockaddr;
+
+    match unsafe { bind(socket.fd, addr_ptr, size as socklen_t) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error("bind")),
+    }
+}
+
+///Takes the promiscuous and multicast memberships `settings` ask for, returns the share of
+///IFF_PROMISC with `Promiscuous::InterfaceFlag`
+pub(crate) fn join_memberships(
+    socket: &mut Socket,
+    settings: &RingSettings,
+) -> Result<Option<PromiscuousFlag>> {
+    if settings.any_interface {
+        return Ok(None);
+    }
+    let flag = match settings.promiscuous {
+        Promiscuous::Off => None,
+        Promiscuous::Membership => {
+            socket.set_promiscuous(true)?;
+            None
+        }
+        Promiscuous::InterfaceFlag => Some(PromiscuousFlag::set(socket)?),
+    };
+    if settings.all_multicast {
+        socket.set_all_multicast(true)?;
+    }
+    Ok(flag)
+}
+
+///Applies `RingSettings::busy_poll`
+pub(crate) fn set_busy_poll(socket: &mut Socket, busy_poll: &BusyPoll) -> Result<()> {
+    socket.set_busy_poll(busy_poll.micros)?;
+    if busy_poll.prefer {
+        socket.set_prefer_busy_poll(true)?;
+    }
+    if let Some(budget) = busy_poll.budget {
+        socket.set_busy_poll_budget(budget)?;
+    }
+    Ok(())
+}
+
+///Filter to attach for `settings`, combining `filter`, `sample` and `snaplen`
+pub(crate) fn socket_filter(settings: &RingSettings) -> Result<Option<FilterProgram>> {
+    combine_filter(settings.filter.as_ref(), settings.sample, settings.snaplen)
+}
+
+fn combine_filter(
+    filter: Option<&FilterProgram>,
+    sample: Option<u32>,
+    snaplen: Option<u32>,
+) -> Result<Option<FilterProgram>> {
+    if snaplen == Some(0) {
+        return Err(Error::InvalidGeometry(String::from(
+            "the snap length must be at least 1 byte",
+        )));
+    }
+    let filter = match (filter, sample) {
+        (Some(filter), Some(one_in)) => Some(filters::sampled(filter, one_in)?),
+        (Some(filter), None) => Some(filter.clone()),
+        (None, Some(one_in)) => Some(filters::sample(one_in)),
+        (None, None) => None,
+    };
+    match (filter, snaplen) {
+        (Some(filter), Some(snaplen)) => filters::truncated(&filter, snaplen).map(Some),
+        (None, Some(snaplen)) => Ok(Some(filters::snaplen(snaplen))),
+        (filter, None) => Ok(filter),
+    }
+}
+
+///Applies `RingSettings::rcvbuf` and `RingSettings::copy_thresh`
+pub(crate) fn set_buffers(socket: &mut Socket, settings: &RingSettings) -> Result<()> {
+    if let Some(size) = settings.rcvbuf {
+        socket.set_recv_buffer(size, settings.rcvbuf_force)?;
+    }
+    if let Some(thresh) = settings.copy_thresh {
+        socket.setsockopt(PACKET_COPY_THRESH, thresh as c_int)?;
+    }
+    Ok(())
+}
+
+///Joins the fanout group `settings` ask for, the process id by default
+pub(crate) fn join_fanout(socket: &mut Socket, settings: &RingSettings) -> Result<()> {
+    let group = settings
+        .fanout_group
+        .map(c_int::from)
+        .unwrap_or_else(|| unsafe { getpid() } & 0xFFFF);
+    let fanout = group | (fanout_arg(settings) << 16);
+    socket.setsockopt(PACKET_FANOUT, fanout)?;
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        group,
+        method = %settings.fanout_method,
+        flags = settings.fanout_flags.bits(),
+        "joined fanout group"
+    );
+    Ok(())
+}
+
+//fanout method and flags as PACKET_FANOUT takes them, above the group id
+fn fanout_arg(settings: &RingSettings) -> c_int {
+    settings.fanout_method.as_raw() | settings.fanout_flags.bits()
+}
+
+#[cfg(feature = "tracing")]
+fn trace_block(block: &Block) {
+    let status = block.status();
+    if status.contains(TpStatus::BLK_TMO) {
+        tracing::trace!(
+            seq = block.seq_num(),
+            packets = block.packet_count(),
+            "block retired by timeout"
+        );
+    }
+    if status.contains(TpStatus::LOSING) {
+        tracing::debug!(
+            seq = block.seq_num(),
+            "kernel dropped packets before this block"
+        );
+    }
+}
 
 ///This is very easy because the Linux kernel has its own counters that are reset every time
 ///getsockopt() is called
 #[inline]
-pub fn get_rx_statistics(fd: i32) -> Result<tpacket3::TpacketStatsV3, Error> {
+pub fn get_rx_statistics(fd: i32) -> Result<tpacket3::TpacketStatsV3> {
     let mut optval = tpacket3::TpacketStatsV3 {
         tp_packets: 0,
         tp_drops: 0,
         tp_freeze_q_cnt: 0,
     };
-    socket::get_sock_opt(
-        fd,
-        PACKET_STATISTICS,
-        &(&mut optval as *mut _ as *mut c_void),
-    )?;
+    socket::get_sock_opt(fd, PACKET_STATISTICS, &mut optval)?;
     Ok(optval)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tpacket3::{SockAddrLl, Tpacket3Hdr};
+
+    const FIRST: usize = tpacket3::TPACKET_BLOCK_DESC_LEN as usize;
+
+    //block with packets of 10, 20 and 30 bytes
+    fn block_buf() -> Vec<u8> {
+        let mut builder = BlockBuilder::new(4096, 1);
+        for len in &[10, 20, 30] {
+            let data = vec![*len as u8; *len];
+            assert!(builder.push(&Tpacket3Hdr::default(), &SockAddrLl::default(), &data));
+        }
+        builder.finish()
+    }
+
+    fn set_u32(buf: &mut [u8], at: usize, value: u32) {
+        buf[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn next_offset(buf: &[u8], at: usize) -> usize {
+        u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]) as usize
+    }
+
+    fn corrupt(buf: &mut [u8]) -> (usize, Result<usize>) {
+        let block = Block::from_raw(buf).unwrap();
+        let unchecked = block.get_raw_packets().len();
+        (unchecked, block.try_get_raw_packets().map(|p| p.len()))
+    }
+
+    #[test]
+    fn checked_iteration_of_a_valid_block() {
+        let mut buf = block_buf();
+        let block = Block::from_raw(&mut buf).unwrap();
+        let checked = block.try_get_raw_packets().unwrap();
+        let unchecked = block.get_raw_packets();
+        let lens: Vec<usize> = checked.iter().map(|p| p.payload().len()).collect();
+        assert_eq!(lens, vec![10, 20, 30]);
+        for (a, b) in checked.iter().zip(&unchecked) {
+            assert_eq!(a.payload(), b.payload());
+        }
+        assert_eq!(checked[2].payload(), &[30; 30][..]);
+    }
+
+    #[test]
+    fn detects_a_blk_len_past_the_block() {
+        let mut buf = block_buf();
+        let len = buf.len() as u32;
+        set_u32(&mut buf, 20, len + 1);
+        assert!(matches!(corrupt(&mut buf).1, Err(Error::BlockCorrupt(_))));
+    }
+
+    #[test]
+    fn detects_headers_and_offsets_past_blk_len() {
+        //the first packet runs past the end of the block
+        let mut buf = block_buf();
+        set_u32(&mut buf, FIRST, 8192);
+        let (unchecked, checked) = corrupt(&mut buf);
+        assert_eq!(unchecked, 0);
+        assert!(matches!(checked, Err(Error::BlockCorrupt(_))));
+
+        //the third packet header starts past blk_len
+        let mut buf = block_buf();
+        let second = FIRST + next_offset(&buf, FIRST);
+        let third = second + next_offset(&buf, second);
+        set_u32(&mut buf, 20, third as u32 + 8);
+        assert!(matches!(corrupt(&mut buf).1, Err(Error::BlockCorrupt(_))));
+
+        //more packets than the block holds
+        let mut buf = block_buf();
+        set_u32(&mut buf, 12, 4);
+        assert!(matches!(corrupt(&mut buf).1, Err(Error::BlockCorrupt(_))));
+    }
+
+    #[test]
+    fn detects_a_missing_next_offset() {
+        let mut buf = block_buf();
+        set_u32(&mut buf, FIRST, 0);
+        assert!(matches!(corrupt(&mut buf).1, Err(Error::BlockCorrupt(_))));
+    }
+
+    #[test]
+    fn detects_data_past_its_frame() {
+        let mut buf = block_buf();
+        //tp_snaplen of the first packet
+        set_u32(&mut buf, FIRST + 12, 4000);
+        let (unchecked, checked) = corrupt(&mut buf);
+        assert_eq!(unchecked, 3);
+        assert!(matches!(checked, Err(Error::BlockCorrupt(_))));
+        //the unchecked payload is clamped to the frame
+        let block = Block::from_raw(&mut buf).unwrap();
+        let packets = block.get_raw_packets();
+        assert!(packets[0].payload().len() < next_offset(block.raw_data, FIRST));
+    }
+
+    #[test]
+    fn seq_gaps() {
+        let gap = SeqGap {
+            expected: 5,
+            received: 8,
+        };
+        assert_eq!(gap.lost(), 3);
+    }
+}
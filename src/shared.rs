@@ -0,0 +1,460 @@
+//!Reference counted blocks and packets that outlive the borrow of the ring
+//!
+//!`Block::into_shared()` lends a block out of the ring: the ring skips it and the kernel cannot
+//!refill it until the `SharedBlock` and every `Packet` taken from it are dropped, at which point
+//!it goes back to the kernel. Cloning a `Packet` only bumps a counter, so one packet can be
+//!handed to many tasks without copying it. Blocks not read straight from a TPACKET_V3 ring are
+//!copied once instead.
+//!
+//!Lent blocks are not available to the kernel, so holding on to packets for long leaves fewer
+//!blocks to capture into and eventually drops packets.
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use libc::{c_void, munmap};
+
+use crate::rx::{self, RawPacket};
+use crate::tpacket3::{self, TpStatus, Tpacket3Hdr, TpacketBlockDesc};
+
+///Block whose memory stays valid until it and all `Packet`s taken from it are dropped, see
+///`Block::into_shared()`
+#[derive(Clone, Debug)]
+pub struct SharedBlock {
+    block_desc: TpacketBlockDesc,
+    memory: Arc<BlockMemory>,
+}
+
+///Cheap to clone handle to a single packet of a `SharedBlock`
+///
+///Dereferences to the packet bytes like `RawPacket::payload()`; `raw()` gives access to the
+///rest of its details.
+#[derive(Clone)]
+pub struct Packet {
+    block: Arc<BlockMemory>,
+    hdr: Tpacket3Hdr,
+    start: usize,
+    end: usize,
+}
+
+//blocks of one ring lent out as SharedBlocks, shared by the ring and its clones
+#[derive(Debug)]
+pub(crate) struct Leases {
+    lent: Vec<AtomicBool>,
+    state: Mutex<LeaseState>,
+}
+
+#[derive(Debug, Default)]
+struct LeaseState {
+    outstanding: usize,
+    //mapping (address, length) released while blocks were lent, unmapped with the last one
+    unmap: Option<(usize, usize)>,
+}
+
+pub(crate) struct Lease {
+    leases: Arc<Leases>,
+    index: u32,
+}
+
+pub(crate) struct BlockMemory {
+    ptr: *mut u8,
+    len: usize,
+    //None for copied blocks, which own the memory behind `ptr`
+    lease: Option<Lease>,
+}
+
+impl Leases {
+    pub(crate) fn new(blocks: u32) -> Leases {
+        Leases {
+            lent: (0..blocks).map(|_| AtomicBool::new(false)).collect(),
+            state: Mutex::new(LeaseState::default()),
+        }
+    }
+
+    ///Whether block `index` is lent out and must not be handed out by the ring
+    #[inline]
+    pub(crate) fn is_lent(&self, index: u32) -> bool {
+        self.lent
+            .get(index as usize)
+            .is_some_and(|lent| lent.load(Ordering::Acquire))
+    }
+
+    ///Leaves unmapping the ring to the last lent block, returns false if none is lent
+    pub(crate) fn defer_unmap(&self, map: *mut u8, len: usize) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.outstanding == 0 {
+            return false;
+        }
+        state.unmap = Some((map as usize, len));
+        true
+    }
+
+    ///Drops a lent block's hold on the mapping, unmapping it after the last one if the ring is
+    ///gone
+    fn unpin(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.outstanding -= 1;
+        if state.outstanding == 0 {
+            if let Some((map, len)) = state.unmap.take() {
+                unsafe {
+                    munmap(map as *mut c_void, len);
+                }
+            }
+        }
+    }
+
+    fn lend(&self, index: u32) {
+        self.state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .outstanding += 1;
+        self.lent[index as usize].store(true, Ordering::Release);
+    }
+}
+
+impl Lease {
+    ///Ticket for lending block `index`, taken only by `SharedBlock::new()`
+    pub(crate) fn new(leases: &Arc<Leases>, index: u32) -> Lease {
+        Lease {
+            leases: leases.clone(),
+            index,
+        }
+    }
+}
+
+impl fmt::Debug for Lease {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Lease").field("index", &self.index).finish()
+    }
+}
+
+impl BlockMemory {
+    fn copy(data: &[u8]) -> BlockMemory {
+        BlockMemory::owned(data.to_vec())
+    }
+
+    //takes over `buf`, which is freed with the memory
+    pub(crate) fn owned(buf: Vec<u8>) -> BlockMemory {
+        let len = buf.len();
+        let buf = Box::into_raw(buf.into_boxed_slice());
+        BlockMemory {
+            ptr: buf as *mut u8,
+            len,
+            lease: None,
+        }
+    }
+
+    //the bytes of owned memory, valid until it is dropped
+    pub(crate) unsafe fn bytes_mut<'a>(&mut self) -> &'a mut [u8] {
+        std::slice::from_raw_parts_mut(self.ptr, self.len)
+    }
+
+    #[inline]
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl fmt::Debug for BlockMemory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BlockMemory")
+            .field("len", &self.len)
+            .field("lease", &self.lease)
+            .finish()
+    }
+}
+
+impl Drop for BlockMemory {
+    fn drop(&mut self) {
+        let lease = match self.lease.take() {
+            Some(lease) => lease,
+            None => {
+                unsafe {
+                    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+                        self.ptr, self.len,
+                    )));
+                }
+                return;
+            }
+        };
+        //hand the block back to the kernel, then to the ring
+        unsafe {
+            std::ptr::write_volatile(
+                self.ptr.add(tpacket3::TP_BLK_STATUS_OFFSET) as *mut u32,
+                tpacket3::TP_STATUS_KERNEL as u32,
+            );
+        }
+        let leases = &lease.leases;
+        leases.lent[lease.index as usize].store(false, Ordering::Release);
+        leases.unpin();
+    }
+}
+
+//the memory is only read through shared references, and the lease is synchronized
+unsafe impl Send for BlockMemory {}
+unsafe impl Sync for BlockMemory {}
+
+impl SharedBlock {
+    ///Takes over `raw_data`, lending it out of its ring with `lease` or copying it without one
+    pub(crate) fn new(
+        block_desc: TpacketBlockDesc,
+        raw_data: &mut [u8],
+        lease: Option<Lease>,
+    ) -> SharedBlock {
+        let memory = match lease {
+            Some(lease) => {
+                lease.leases.lend(lease.index);
+                BlockMemory {
+                    ptr: raw_data.as_mut_ptr(),
+                    len: raw_data.len(),
+                    lease: Some(lease),
+                }
+            }
+            None => BlockMemory::copy(raw_data),
+        };
+        SharedBlock {
+            block_desc,
+            memory: Arc::new(memory),
+        }
+    }
+
+    //takes over memory that a `Block` owns, without copying it
+    pub(crate) fn from_memory(block_desc: TpacketBlockDesc, memory: BlockMemory) -> SharedBlock {
+        SharedBlock {
+            block_desc,
+            memory: Arc::new(memory),
+        }
+    }
+
+    ///Whether the block is still in the ring's memory rather than a copy of it
+    pub fn is_zero_copy(&self) -> bool {
+        self.memory.lease.is_some()
+    }
+
+    ///Status flags of the block as retired by the kernel
+    pub fn status(&self) -> TpStatus {
+        TpStatus(self.block_desc.hdr.block_status)
+    }
+
+    ///Returns a `Vec` of details and references to the packets in the block; their
+    ///`RawPacket::to_shared()` does not copy
+    pub fn get_raw_packets(&self) -> Vec<RawPacket<'_>> {
+        let mut packets = rx::get_raw_packets(&self.block_desc, self.memory.bytes());
+        for packet in packets.iter_mut() {
+            packet.shared = Some(&self.memory);
+        }
+        packets
+    }
+
+    ///Handles to all packets in the block
+    pub fn packets(&self) -> Vec<Packet> {
+        self.get_raw_packets()
+            .iter()
+            .map(RawPacket::to_shared)
+            .collect()
+    }
+}
+
+impl Packet {
+    ///Packet sharing the memory of `block`, `data` must point into it
+    pub(crate) fn shared(block: &Arc<BlockMemory>, raw: &RawPacket) -> Packet {
+        let start = raw.data.as_ptr() as usize - block.ptr as usize;
+        Packet {
+            block: block.clone(),
+            hdr: raw.tpacket3_hdr.clone(),
+            start,
+            end: start + raw.data.len(),
+        }
+    }
+
+    ///Packet in memory of its own
+    pub(crate) fn copied(raw: &RawPacket) -> Packet {
+        Packet {
+            block: Arc::new(BlockMemory::copy(raw.data)),
+            hdr: raw.tpacket3_hdr.clone(),
+            start: 0,
+            end: raw.data.len(),
+        }
+    }
+
+    ///The packet as a `RawPacket`, for its headers and metadata
+    pub fn raw(&self) -> RawPacket<'_> {
+        RawPacket {
+            tpacket3_hdr: self.hdr.clone(),
+            data: &self.block.bytes()[self.start..self.end],
+            shared: Some(&self.block),
+        }
+    }
+
+    ///Packet bytes, see `RawPacket::payload()`
+    pub fn payload(&self) -> &[u8] {
+        let frame = &self.block.bytes()[self.start..self.end];
+        let start = (self.hdr.tp_mac as usize).min(frame.len());
+        let end = (start + self.hdr.tp_snaplen as usize).min(frame.len());
+        &frame[start..end]
+    }
+
+    ///Details of the packet
+    pub fn tpacket3_hdr(&self) -> &Tpacket3Hdr {
+        &self.hdr
+    }
+}
+
+impl Deref for Packet {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.payload()
+    }
+}
+
+impl AsRef<[u8]> for Packet {
+    fn as_ref(&self) -> &[u8] {
+        self.payload()
+    }
+}
+
+impl fmt::Debug for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Packet")
+            .field("hdr", &self.hdr)
+            .field("len", &self.payload().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::{
+        mmap, msync, MAP_ANONYMOUS, MAP_FAILED, MAP_PRIVATE, MS_ASYNC, PROT_READ, PROT_WRITE,
+    };
+
+    use super::*;
+    use crate::tpacket3::{BlockBuilder, SockAddrLl, TP_STATUS_KERNEL, TP_STATUS_USER};
+
+    const BLOCK_SIZE: usize = 4096;
+    const BLOCKS: usize = 2;
+
+    //block with packets of 10 and 20 bytes
+    fn block_buf() -> Vec<u8> {
+        let mut builder = BlockBuilder::new(BLOCK_SIZE, 1);
+        for len in &[10, 20] {
+            let data = vec![*len as u8; *len];
+            assert!(builder.push(&Tpacket3Hdr::default(), &SockAddrLl::default(), &data));
+        }
+        builder.finish()
+    }
+
+    //anonymous memory standing in for a mapped ring, every block filled by the kernel
+    fn map_ring() -> *mut u8 {
+        let map = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                BLOCK_SIZE * BLOCKS,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(map, MAP_FAILED);
+        let ring = unsafe { std::slice::from_raw_parts_mut(map as *mut u8, BLOCK_SIZE * BLOCKS) };
+        for block in ring.chunks_mut(BLOCK_SIZE) {
+            block.copy_from_slice(&block_buf());
+        }
+        map as *mut u8
+    }
+
+    fn is_mapped(map: *mut u8) -> bool {
+        unsafe { msync(map as *mut c_void, BLOCK_SIZE * BLOCKS, MS_ASYNC) == 0 }
+    }
+
+    fn raw_block<'a>(map: *mut u8, index: u32) -> &'a mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(map.add(index as usize * BLOCK_SIZE), BLOCK_SIZE) }
+    }
+
+    fn lend(leases: &Arc<Leases>, map: *mut u8, index: u32) -> SharedBlock {
+        let raw = raw_block(map, index);
+        let desc = tpacket3::get_tpacket_block_desc(raw).unwrap().1;
+        SharedBlock::new(desc, raw, Some(Lease::new(leases, index)))
+    }
+
+    fn status(map: *mut u8, index: u32) -> u8 {
+        raw_block(map, index)[tpacket3::TP_BLK_STATUS_OFFSET]
+    }
+
+    #[test]
+    fn lent_blocks_go_back_to_the_kernel_with_the_last_packet() {
+        let map = map_ring();
+        let leases = Arc::new(Leases::new(BLOCKS as u32));
+        let block = lend(&leases, map, 0);
+        assert!(block.is_zero_copy());
+        assert!(leases.is_lent(0));
+        assert!(!leases.is_lent(1));
+
+        let packets = block.packets();
+        let copy = packets[1].clone();
+        drop(packets);
+        drop(block);
+        //the packet still points into the block
+        assert!(leases.is_lent(0));
+        assert_eq!(status(map, 0), TP_STATUS_USER);
+        assert_eq!(&copy[..], &[20; 20][..]);
+
+        drop(copy);
+        assert!(!leases.is_lent(0));
+        assert_eq!(status(map, 0), TP_STATUS_KERNEL);
+        assert!(!leases.defer_unmap(map, BLOCK_SIZE * BLOCKS));
+        unsafe {
+            munmap(map as *mut c_void, BLOCK_SIZE * BLOCKS);
+        }
+    }
+
+    #[test]
+    fn unmapping_waits_for_the_last_lent_block() {
+        let map = map_ring();
+        let leases = Arc::new(Leases::new(BLOCKS as u32));
+        let first = lend(&leases, map, 0);
+        let second = lend(&leases, map, 1);
+        let packet = second.packets().remove(0);
+        drop(second);
+
+        //the ring is gone
+        assert!(leases.defer_unmap(map, BLOCK_SIZE * BLOCKS));
+        drop(first);
+        assert!(is_mapped(map));
+        assert_eq!(&packet[..], &[10; 10][..]);
+        drop(packet);
+        assert!(!is_mapped(map));
+    }
+
+    #[test]
+    fn unmapping_is_left_to_the_ring_when_nothing_is_lent() {
+        let map = map_ring();
+        let leases = Arc::new(Leases::new(BLOCKS as u32));
+        assert!(!leases.defer_unmap(map, BLOCK_SIZE * BLOCKS));
+        drop(lend(&leases, map, 1));
+        assert!(!leases.defer_unmap(map, BLOCK_SIZE * BLOCKS));
+        assert!(is_mapped(map));
+        unsafe {
+            munmap(map as *mut c_void, BLOCK_SIZE * BLOCKS);
+        }
+    }
+
+    #[test]
+    fn blocks_without_a_lease_are_copied() {
+        let mut buf = block_buf();
+        let desc = tpacket3::get_tpacket_block_desc(&buf).unwrap().1;
+        let block = SharedBlock::new(desc, &mut buf, None);
+        assert!(!block.is_zero_copy());
+        buf.iter_mut().for_each(|b| *b = 0);
+
+        let packets = block.packets();
+        drop(block);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(&packets[0][..], &[10; 10][..]);
+        assert_eq!(packets[1].raw().payload(), &[20; 20][..]);
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use libc::{c_int, c_void, close, eventfd, write, EFD_CLOEXEC, EFD_NONBLOCK};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug)]
+struct EventFd(c_int);
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            close(self.0);
+        }
+    }
+}
+
+///Cloneable handle that wakes up and stops rings waiting for blocks, from any thread
+///
+///Backed by an eventfd that rings poll alongside their socket; once signaled it stays
+///signaled.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    fd: Arc<EventFd>,
+}
+
+impl ShutdownHandle {
+    ///Creates a handle that is not signaled yet
+    pub fn new() -> Result<ShutdownHandle> {
+        let fd = unsafe { eventfd(0, EFD_NONBLOCK | EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error("eventfd"));
+        }
+        Ok(ShutdownHandle {
+            fd: Arc::new(EventFd(fd)),
+        })
+    }
+
+    ///Wakes up every ring using this handle, their `recv_block()` returns `Error::Shutdown`
+    pub fn signal(&self) {
+        let one: u64 = 1;
+        unsafe {
+            write(self.fd.0, &one as *const u64 as *const c_void, 8);
+        }
+    }
+
+    ///Whether `signal()` was called
+    pub fn is_signaled(&self) -> bool {
+        let mut pfd = libc::pollfd {
+            fd: self.fd.0,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pfd, 1, 0) > 0 }
+    }
+
+    ///File descriptor that becomes readable once signaled, e.g. for an external event loop
+    pub fn fd(&self) -> c_int {
+        self.fd.0
+    }
+}
@@ -0,0 +1,48 @@
+//!DLT_LINUX_SLL ("Linux cooked capture") pseudo-header used when writing cooked captures to pcap
+
+use crate::tpacket3::SockAddrLl;
+
+///pcap link type of captures prefixed with a `LinuxSllHeader`
+pub const DLT_LINUX_SLL: u16 = 113;
+///Length of the pseudo-header
+pub const SLL_HDR_LEN: usize = 16;
+
+///Pseudo-header carrying the link-layer metadata that SOCK_DGRAM sockets strip from packets
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LinuxSllHeader {
+    ///PACKET_HOST, PACKET_OUTGOING etc.
+    pub pkttype: u16,
+    ///ARPHRD_* hardware type
+    pub hatype: u16,
+    ///Number of meaningful bytes in `addr`
+    pub halen: u16,
+    ///Link-layer source address
+    pub addr: [u8; 8],
+    ///Ethertype in host byte order
+    pub protocol: u16,
+}
+
+impl LinuxSllHeader {
+    ///Serializes the header in the on-disk (big endian) layout
+    pub fn to_bytes(&self) -> [u8; SLL_HDR_LEN] {
+        let mut buf = [0; SLL_HDR_LEN];
+        buf[0..2].copy_from_slice(&self.pkttype.to_be_bytes());
+        buf[2..4].copy_from_slice(&self.hatype.to_be_bytes());
+        buf[4..6].copy_from_slice(&self.halen.to_be_bytes());
+        buf[6..14].copy_from_slice(&self.addr);
+        buf[14..16].copy_from_slice(&self.protocol.to_be_bytes());
+        buf
+    }
+}
+
+impl From<&SockAddrLl> for LinuxSllHeader {
+    fn from(sll: &SockAddrLl) -> LinuxSllHeader {
+        LinuxSllHeader {
+            pkttype: sll.sll_pkttype as u16,
+            hatype: sll.sll_hatype,
+            halen: sll.sll_halen as u16,
+            addr: sll.sll_addr,
+            protocol: sll.sll_protocol,
+        }
+    }
+}
@@ -0,0 +1,261 @@
+//!Batteries-included capture modeled after gopacket's packet source
+//!
+//!`Sniffer` opens a single ring in a fanout group of its own and hands out packets one by one,
+//!copied out of the ring and decoded, so there are no blocks to release and no lifetimes to
+//!track. Copying costs throughput; use `Capture` or `Ring` when every cycle counts.
+//!
+//!```no_run
+//!use af_packet::sniffer::Sniffer;
+//!
+//!for packet in Sniffer::new("eth0").snaplen(128).open()? {
+//!    let packet = packet?;
+//!    if let Some(flow) = packet.flow {
+//!        println!("{:?} {} -> {}", packet.timestamp, flow.src, flow.dst);
+//!    }
+//!}
+//!# Ok::<(), af_packet::Error>(())
+//!```
+
+use std::fmt;
+use std::time::SystemTime;
+
+use crate::error::{Error, Result};
+use crate::filter::FilterProgram;
+use crate::flow::FlowKey;
+use crate::group;
+use crate::rx::{LinkInfo, Promiscuous, RawPacket, Ring, RingSettings, VlanTag};
+use crate::shutdown::ShutdownHandle;
+use crate::stats::RingStats;
+use crate::tpacket3;
+use crate::tunnel::Encapsulation;
+
+const ARPHRD_ETHER: u16 = 1;
+const ARPHRD_LOOPBACK: u16 = 772;
+const ETH_HLEN: usize = 14;
+
+///Settings of a capture on one interface, `open()` starts it
+#[derive(Clone, Debug)]
+pub struct Sniffer {
+    settings: RingSettings,
+    #[cfg(feature = "pcap-filter")]
+    expr: Option<String>,
+}
+
+///Ethernet header of a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct EthernetHeader {
+    pub dst: [u8; 6],
+    pub src: [u8; 6],
+    pub ethertype: u16,
+}
+
+///Packet copied out of the ring with its metadata and decoded headers
+#[derive(Clone, Debug)]
+pub struct SniffedPacket {
+    ///Time the packet was received
+    pub timestamp: SystemTime,
+    ///Captured bytes from the link-layer header on, at most the snap length
+    pub data: Vec<u8>,
+    ///Length of the packet on the wire
+    pub len: u32,
+    ///Interface, direction and link-layer source of the packet
+    pub link: Option<LinkInfo>,
+    ///VLAN tag stripped by the NIC or the kernel
+    pub vlan: Option<VlanTag>,
+    ///None in cooked mode and on links other than Ethernet
+    pub ethernet: Option<EthernetHeader>,
+    ///Addresses, ports and protocol of IPv4 and IPv6 packets
+    pub flow: Option<FlowKey>,
+    ///Tunnel the packet carries another one in, see `tunnel::decapsulate()`
+    pub encapsulation: Option<Encapsulation>,
+    //start of the network header in `data`
+    l3: usize,
+}
+
+///Packets of a running `Sniffer`, stops when its `ShutdownHandle` is signaled
+///
+///Yields an error and goes on when the ring reports one, e.g. the interface going down with
+///`RingSettings::report_link_down`.
+pub struct Packets {
+    ring: Ring,
+    pending: Vec<SniffedPacket>,
+}
+
+impl Sniffer {
+    ///Starts a sniffer on `if_name` in promiscuous mode, with rings sized like `Capture`'s
+    pub fn new(if_name: &str) -> Sniffer {
+        Sniffer {
+            settings: RingSettings {
+                if_name: String::from(if_name),
+                auto_size: Some(tpacket3::DEFAULT_AUTO_SIZE_BUDGET),
+                ..RingSettings::default()
+            },
+            #[cfg(feature = "pcap-filter")]
+            expr: None,
+        }
+    }
+
+    ///tcpdump filter expression such as `tcp and port 443`, see `FilterProgram::compile()`;
+    ///takes the place of `filter_program()`
+    #[cfg(feature = "pcap-filter")]
+    pub fn filter(mut self, expr: &str) -> Sniffer {
+        self.expr = Some(String::from(expr));
+        self
+    }
+
+    ///Classic BPF filter to capture with, e.g. one of `filters`
+    pub fn filter_program(mut self, filter: FilterProgram) -> Sniffer {
+        self.settings.filter = Some(filter);
+        self
+    }
+
+    ///Keep at most `snaplen` bytes of every packet, see `RingSettings::snaplen`
+    pub fn snaplen(mut self, snaplen: u32) -> Sniffer {
+        self.settings.snaplen = Some(snaplen);
+        self
+    }
+
+    ///Whether to put the interface into promiscuous mode, on by default
+    pub fn promiscuous(mut self, promiscuous: bool) -> Sniffer {
+        self.settings.promiscuous = if promiscuous {
+            Promiscuous::Membership
+        } else {
+            Promiscuous::Off
+        };
+        self
+    }
+
+    ///Do not deliver packets transmitted by this host
+    pub fn ignore_outgoing(mut self, ignore_outgoing: bool) -> Sniffer {
+        self.settings.ignore_outgoing = ignore_outgoing;
+        self
+    }
+
+    ///Full ring settings, overriding anything set before except the filter expression
+    pub fn settings(mut self, settings: RingSettings) -> Sniffer {
+        self.settings = settings;
+        self
+    }
+
+    ///Opens the ring and starts capturing
+    ///
+    ///The ring gets a fanout group of its own unless the settings name one, so that it sees
+    ///all traffic of the interface rather than a share of it.
+    pub fn open(self) -> Result<Packets> {
+        let mut settings = self.settings;
+        if settings.fanout_group.is_none() {
+            settings.fanout_group = Some(group::unique_fanout_group());
+        }
+        #[allow(unused_mut)]
+        let mut ring = Ring::new(settings)?;
+        //the expression is compiled for the link type the ring ended up with
+        #[cfg(feature = "pcap-filter")]
+        if let Some(expr) = &self.expr {
+            ring.set_filter(&FilterProgram::compile(expr, ring.link_type())?)?;
+        }
+        Ok(Packets {
+            ring,
+            pending: Vec::new(),
+        })
+    }
+}
+
+impl SniffedPacket {
+    fn decode(packet: &RawPacket) -> SniffedPacket {
+        let link = packet.link_info();
+        let l2 = packet.l2_header();
+        let ethernet = match link {
+            Some(link)
+                if (link.hatype == ARPHRD_ETHER || link.hatype == ARPHRD_LOOPBACK)
+                    && l2.len() >= ETH_HLEN =>
+            {
+                let mut dst = [0; 6];
+                let mut src = [0; 6];
+                dst.copy_from_slice(&l2[..6]);
+                src.copy_from_slice(&l2[6..12]);
+                Some(EthernetHeader {
+                    dst,
+                    src,
+                    ethertype: u16::from_be_bytes([l2[12], l2[13]]),
+                })
+            }
+            _ => None,
+        };
+        SniffedPacket {
+            timestamp: packet.timestamp(),
+            data: packet.to_vec(),
+            len: packet.tpacket3_hdr.tp_len,
+            link,
+            vlan: packet.vlan(),
+            ethernet,
+            flow: FlowKey::from_packet(packet),
+            encapsulation: packet.decapsulate().map(|t| t.encapsulation),
+            l3: l2.len(),
+        }
+    }
+
+    ///Network header and everything after it that was captured
+    pub fn network(&self) -> &[u8] {
+        &self.data[self.l3.min(self.data.len())..]
+    }
+
+    ///Whether the packet was cut short by the snap length or to fit the ring
+    pub fn truncated(&self) -> bool {
+        (self.data.len() as u32) < self.len
+    }
+}
+
+impl Packets {
+    ///Returns a handle that ends the iteration from another thread
+    pub fn shutdown_handle(&mut self) -> Result<ShutdownHandle> {
+        self.ring.shutdown_handle()
+    }
+
+    ///Kernel counters since the last call, see `Ring::statistics()`
+    pub fn statistics(&mut self) -> Result<RingStats> {
+        self.ring.statistics()
+    }
+
+    ///Underlying ring
+    pub fn ring(&self) -> &Ring {
+        &self.ring
+    }
+
+    fn fill(&mut self) -> Result<()> {
+        let mut block = self.ring.recv_block()?;
+        //packets are handed out from the back
+        self.pending.extend(
+            block
+                .get_raw_packets()
+                .iter()
+                .rev()
+                .map(SniffedPacket::decode),
+        );
+        block.mark_as_consumed();
+        Ok(())
+    }
+}
+
+impl Iterator for Packets {
+    type Item = Result<SniffedPacket>;
+
+    fn next(&mut self) -> Option<Result<SniffedPacket>> {
+        while self.pending.is_empty() {
+            match self.fill() {
+                Ok(()) => {}
+                Err(Error::Shutdown) => return None,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        self.pending.pop().map(Ok)
+    }
+}
+
+impl fmt::Debug for Packets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Packets")
+            .field("ring", &self.ring)
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
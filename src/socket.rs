@@ -1,22 +1,145 @@
 extern crate libc;
 
 use libc::{
-    c_char, c_int, c_short, c_uint, c_ulong, c_void, getsockopt, if_nametoindex, ioctl, setsockopt,
-    socket, socklen_t, ETH_P_ALL, SOCK_RAW, SOL_PACKET, IF_NAMESIZE
+    c_char, c_int, c_short, c_uint, c_ulong, c_void, close, fcntl, getsockopt, if_indextoname,
+    if_nametoindex, ioctl, iovec, mmsghdr, recv, sendmmsg, sendto, setsockopt, sockaddr,
+    sockaddr_ll, socket, socklen_t, ETH_P_8021Q, ETH_P_ALL, ETH_P_ARP, ETH_P_IP, ETH_P_IPV6,
+    F_GETFL, F_SETFL, IF_NAMESIZE, MSG_TRUNC, O_NONBLOCK, SOCK_NONBLOCK, SOCK_RAW, SOL_PACKET,
+    SOL_SOCKET, SO_ERROR,
 };
-pub use libc::{AF_PACKET, IFF_PROMISC, PF_PACKET};
+pub use libc::{AF_PACKET, IFF_PROMISC, PF_PACKET, SOCK_DGRAM};
 
-use std::ffi::CString;
-use std::io::{self, Error, ErrorKind};
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::io;
 use std::mem;
 
+use crate::error::{Error, Privilege, Result};
+use crate::filter::FilterProgram;
+
 const IFREQUNIONSIZE: usize = 24;
 
 const SIOCGIFFLAGS: c_ulong = 35091; //0x00008913;
 const SIOCSIFFLAGS: c_ulong = 35092; //0x00008914;
+const SIOCGIFHWADDR: c_ulong = 0x8927;
+
+///Kernel limit on the number of messages per sendmmsg() call (UIO_MAXIOV)
+pub const MAX_BATCH: usize = 1024;
+
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+
+const SO_ATTACH_FILTER: c_int = 26;
+const SO_DETACH_FILTER: c_int = 27;
+const SO_ATTACH_BPF: c_int = 50;
+const SO_RCVBUF: c_int = 8;
+const SO_RCVBUFFORCE: c_int = 33;
+const SO_BUSY_POLL: c_int = 46;
+const SO_INCOMING_CPU: c_int = 49;
+const SO_PREFER_BUSY_POLL: c_int = 69;
+const SO_BUSY_POLL_BUDGET: c_int = 70;
 
 pub const PACKET_FANOUT: c_int = 18;
 
+const PACKET_ADD_MEMBERSHIP: c_int = 1;
+const PACKET_DROP_MEMBERSHIP: c_int = 2;
+const PACKET_MR_MULTICAST: u16 = 0;
+const PACKET_MR_PROMISC: u16 = 1;
+const PACKET_MR_ALLMULTI: u16 = 2;
+const PACKET_MR_UNICAST: u16 = 3;
+
+///Interface name reported by sockets bound to all interfaces
+pub const ANY_INTERFACE: &str = "any";
+
+//struct packet_mreq
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct PacketMreq {
+    mr_ifindex: c_int,
+    mr_type: u16,
+    mr_alen: u16,
+    mr_address: [u8; 8],
+}
+
+///Extra traffic a socket asks its interface to accept, held until the socket is closed or the
+///membership is dropped
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MembershipKind {
+    ///Frames sent to this multicast MAC address (PACKET_MR_MULTICAST)
+    Multicast([u8; 6]),
+    ///All multicast frames (PACKET_MR_ALLMULTI)
+    AllMulticast,
+    ///Frames sent to this additional unicast MAC address (PACKET_MR_UNICAST)
+    Unicast([u8; 6]),
+    ///Every frame, i.e. promiscuous mode (PACKET_MR_PROMISC)
+    Promiscuous,
+}
+
+impl MembershipKind {
+    fn to_mreq(self, if_index: c_int) -> PacketMreq {
+        let (mr_type, addr) = match self {
+            MembershipKind::Multicast(addr) => (PACKET_MR_MULTICAST, Some(addr)),
+            MembershipKind::AllMulticast => (PACKET_MR_ALLMULTI, None),
+            MembershipKind::Unicast(addr) => (PACKET_MR_UNICAST, Some(addr)),
+            MembershipKind::Promiscuous => (PACKET_MR_PROMISC, None),
+        };
+        let mut mreq = PacketMreq {
+            mr_ifindex: if_index,
+            mr_type,
+            ..PacketMreq::default()
+        };
+        if let Some(addr) = addr {
+            mreq.mr_alen = addr.len() as u16;
+            mreq.mr_address[..addr.len()].copy_from_slice(&addr);
+        }
+        mreq
+    }
+}
+
+///Protocol a packet socket receives, as an ethertype
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EtherType {
+    ///Every protocol (ETH_P_ALL)
+    All,
+    ///IPv4 (ETH_P_IP)
+    Ipv4,
+    ///IPv6 (ETH_P_IPV6)
+    Ipv6,
+    ///ARP (ETH_P_ARP)
+    Arp,
+    ///802.1Q VLAN tagged frames (ETH_P_8021Q)
+    Vlan,
+    ///Any other ethertype, in host byte order
+    Other(u16),
+}
+
+impl EtherType {
+    ///Ethertype value in host byte order
+    pub fn to_raw(self) -> u16 {
+        match self {
+            EtherType::All => ETH_P_ALL as u16,
+            EtherType::Ipv4 => ETH_P_IP as u16,
+            EtherType::Ipv6 => ETH_P_IPV6 as u16,
+            EtherType::Arp => ETH_P_ARP as u16,
+            EtherType::Vlan => ETH_P_8021Q as u16,
+            EtherType::Other(raw) => raw,
+        }
+    }
+}
+
+impl From<u16> for EtherType {
+    fn from(raw: u16) -> EtherType {
+        match raw as c_int {
+            ETH_P_ALL => EtherType::All,
+            ETH_P_IP => EtherType::Ipv4,
+            ETH_P_IPV6 => EtherType::Ipv6,
+            ETH_P_ARP => EtherType::Arp,
+            ETH_P_8021Q => EtherType::Vlan,
+            _ => EtherType::Other(raw),
+        }
+    }
+}
+
 #[repr(C)]
 struct IfReq {
     //TODO: these are actually both unions, implement them as such now that Rust supports it
@@ -31,18 +154,17 @@ impl IfReq {
 
     fn from_short(i: c_short) -> IfReq {
         let mut req = IfReq::default();
-        //TODO: find a better way to do this
-        let bytes: [u8; 2] = unsafe { mem::transmute(i) };
+        let bytes = i.to_ne_bytes();
         req.data[0] = bytes[0];
         req.data[1] = bytes[1];
         req
     }
 
-    fn with_if_name(if_name: &str) -> io::Result<IfReq> {
+    fn with_if_name(if_name: &str) -> Result<IfReq> {
         let mut if_req = IfReq::default();
 
         if if_name.len() >= if_req.ifr_name.len() {
-            return Err(Error::new(ErrorKind::Other, "Interface name too long"));
+            return Err(Error::InvalidInterfaceName(String::from(if_name)));
         }
 
         // basically a memcpy
@@ -78,34 +200,78 @@ pub struct Socket {
 }
 
 impl Socket {
-    pub fn from_if_name(if_name: &str, socket_type: c_int) -> io::Result<Socket> {
+    ///Opens a SOCK_RAW socket for every protocol on `if_name` without binding it, see
+    ///`SocketBuilder` for other kinds
+    pub fn from_if_name(if_name: &str, socket_type: c_int) -> Result<Socket> {
+        Socket::with_protocol(if_name, socket_type, EtherType::All)
+    }
+
+    ///Opens a socket that only receives frames of the given protocol
+    pub fn with_protocol(if_name: &str, socket_type: c_int, protocol: EtherType) -> Result<Socket> {
+        Socket::open(if_name, socket_type, SOCK_RAW, protocol)
+    }
+
+    ///Opens a socket of the given kind, SOCK_RAW to receive whole frames or SOCK_DGRAM to have
+    ///the kernel strip the link-layer header ("cooked" mode)
+    pub fn open(
+        if_name: &str,
+        socket_type: c_int,
+        kind: c_int,
+        protocol: EtherType,
+    ) -> Result<Socket> {
+        let if_index = get_if_index(if_name)?;
+        let mut sock = Socket::open_any(socket_type, kind, protocol)?;
+        sock.if_name = String::from(if_name);
+        sock.if_index = if_index;
+        Ok(sock)
+    }
+
+    ///Opens a socket that is not tied to an interface; binding it uses ifindex 0 and receives
+    ///from all interfaces
+    pub fn open_any(socket_type: c_int, kind: c_int, protocol: EtherType) -> Result<Socket> {
         //this typecasting sucks :(
-        let fd = unsafe { socket(socket_type, SOCK_RAW, (ETH_P_ALL as u16).to_be() as i32) };
+        let fd = unsafe { socket(socket_type, kind, protocol.to_raw().to_be() as i32) };
         if fd < 0 {
-            return Err(Error::last_os_error());
+            return Err(privilege_error(
+                "socket",
+                Privilege::NetRaw,
+                io::Error::last_os_error(),
+            ));
         }
 
         Ok(Socket {
-            if_name: String::from(if_name),
-            if_index: get_if_index(if_name)?,
+            if_name: String::from(ANY_INTERFACE),
+            if_index: 0,
             sock_type: socket_type,
             fd,
         })
     }
 
-    fn ioctl(&self, ident: c_ulong, if_req: IfReq) -> io::Result<IfReq> {
+    fn ioctl(&self, ident: c_ulong, if_req: IfReq) -> Result<IfReq> {
         let mut req: Box<IfReq> = Box::new(if_req);
         match unsafe { ioctl(self.fd, ident, &mut *req) } {
-            -1 => Err(Error::last_os_error()),
+            -1 => Err(privilege_error(
+                "ioctl",
+                Privilege::NetAdmin,
+                io::Error::last_os_error(),
+            )),
             _ => Ok(*req),
         }
     }
 
-    fn get_flags(&self) -> io::Result<IfReq> {
+    fn get_flags(&self) -> Result<IfReq> {
         self.ioctl(SIOCGIFFLAGS, IfReq::with_if_name(&self.if_name)?)
     }
 
-    pub fn set_flag(&mut self, flag: c_ulong) -> io::Result<()> {
+    ///ARPHRD_* hardware type of the socket's interface, e.g. 1 for Ethernet or 803 for 802.11
+    ///with radiotap headers
+    pub fn hardware_type(&self) -> Result<u16> {
+        let req = self.ioctl(SIOCGIFHWADDR, IfReq::with_if_name(&self.if_name)?)?;
+        //ifr_hwaddr is a sockaddr, its family is the hardware type
+        Ok(u16::from_ne_bytes([req.data[0], req.data[1]]))
+    }
+
+    pub fn set_flag(&mut self, flag: c_ulong) -> Result<()> {
         let flags = &self.get_flags()?.ifr_flags();
         let new_flags = flags | flag as c_short;
         let mut if_req = IfReq::with_if_name(&self.if_name)?;
@@ -114,7 +280,59 @@ impl Socket {
         Ok(())
     }
 
-    pub fn setsockopt<T>(&mut self, opt: c_int, opt_val: T) -> io::Result<()> {
+    ///Clears an IFF_* flag set with `set_flag()`
+    pub fn clear_flag(&mut self, flag: c_ulong) -> Result<()> {
+        let flags = &self.get_flags()?.ifr_flags();
+        let new_flags = flags & !(flag as c_short);
+        let mut if_req = IfReq::with_if_name(&self.if_name)?;
+        if_req.data = IfReq::from_short(new_flags).data;
+        self.ioctl(SIOCSIFFLAGS, if_req)?;
+        Ok(())
+    }
+
+    ///Whether an IFF_* flag is set on the interface; IFF_PROMISC only counts `set_flag()`,
+    ///not memberships
+    pub fn has_flag(&self, flag: c_ulong) -> Result<bool> {
+        Ok(self.get_flags()?.ifr_flags() & flag as c_short != 0)
+    }
+
+    ///Joins a multicast group, or asks for extra traffic, on the socket's interface
+    ///
+    ///The kernel counts memberships per interface and drops them when the socket is closed,
+    ///even if the process dies, unlike flags set with `set_flag()`. Joining twice needs two
+    ///drops.
+    pub fn add_membership(&mut self, kind: MembershipKind) -> Result<()> {
+        let mreq = kind.to_mreq(self.if_index as c_int);
+        self.setsockopt(PACKET_ADD_MEMBERSHIP, mreq)
+    }
+
+    ///Drops a membership taken with `add_membership()`
+    pub fn drop_membership(&mut self, kind: MembershipKind) -> Result<()> {
+        let mreq = kind.to_mreq(self.if_index as c_int);
+        self.setsockopt(PACKET_DROP_MEMBERSHIP, mreq)
+    }
+
+    ///Puts the interface into promiscuous mode for as long as this socket holds the membership,
+    ///see `add_membership()`
+    pub fn set_promiscuous(&mut self, enabled: bool) -> Result<()> {
+        self.set_membership(MembershipKind::Promiscuous, enabled)
+    }
+
+    ///Receives all multicast traffic on the interface without full promiscuous mode, see
+    ///`add_membership()`
+    pub fn set_all_multicast(&mut self, enabled: bool) -> Result<()> {
+        self.set_membership(MembershipKind::AllMulticast, enabled)
+    }
+
+    fn set_membership(&mut self, kind: MembershipKind, enabled: bool) -> Result<()> {
+        if enabled {
+            self.add_membership(kind)
+        } else {
+            self.drop_membership(kind)
+        }
+    }
+
+    pub fn setsockopt<T>(&mut self, opt: c_int, opt_val: T) -> Result<()> {
         match unsafe {
             setsockopt(
                 self.fd,
@@ -125,25 +343,506 @@ impl Socket {
             )
         } {
             0 => Ok(()),
-            _ => Err(io::Error::last_os_error()),
+            _ => Err(Error::last_os_error("setsockopt")),
         }
     }
 
-    pub fn getsockopt(&mut self, opt: c_int, opt_val: &*mut c_void) -> io::Result<()> {
+    pub fn getsockopt<T>(&mut self, opt: c_int, opt_val: &mut T) -> Result<()> {
         get_sock_opt(self.fd, opt, opt_val)
     }
+
+    ///Returns and clears the pending socket error (SO_ERROR), e.g. ENETDOWN after the interface
+    ///went down
+    pub fn take_error(&self) -> Result<Option<io::Error>> {
+        let mut err: c_int = 0;
+        let mut optlen = mem::size_of::<c_int>() as socklen_t;
+        match unsafe {
+            getsockopt(
+                self.fd,
+                SOL_SOCKET,
+                SO_ERROR,
+                &mut err as *mut _ as *mut c_void,
+                &mut optlen,
+            )
+        } {
+            0 if err == 0 => Ok(None),
+            0 => Ok(Some(io::Error::from_raw_os_error(err))),
+            _ => Err(Error::last_os_error("getsockopt")),
+        }
+    }
+
+    ///Reads the next frame into `buf`, returns its length, or None if the socket is
+    ///non-blocking and nothing is queued
+    ///
+    ///A frame longer than `buf` is truncated; the length returned is still its full length.
+    pub fn recv_frame(&self, buf: &mut [u8]) -> Result<Option<usize>> {
+        let len = unsafe {
+            recv(
+                self.fd,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len(),
+                MSG_TRUNC,
+            )
+        };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(Error::os("recv", err)),
+            };
+        }
+        Ok(Some(len as usize))
+    }
+
+    ///Switches the socket between blocking and non-blocking mode (O_NONBLOCK)
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> Result<()> {
+        let flags = unsafe { fcntl(self.fd, F_GETFL) };
+        if flags < 0 {
+            return Err(Error::last_os_error("fcntl"));
+        }
+        let flags = match nonblocking {
+            true => flags | O_NONBLOCK,
+            false => flags & !O_NONBLOCK,
+        };
+        match unsafe { fcntl(self.fd, F_SETFL, flags) } {
+            -1 => Err(Error::last_os_error("fcntl")),
+            _ => Ok(()),
+        }
+    }
+
+    ///Transmits a whole Ethernet frame out of the socket's interface, returns the number of
+    ///bytes sent
+    pub fn send_frame(&self, frame: &[u8]) -> Result<usize> {
+        self.send_to(frame, self.if_index as i32, EtherType::Other(0), &[])
+    }
+
+    ///Transmits a frame out of interface `if_index`
+    ///
+    ///On SOCK_DGRAM sockets the kernel builds the link-layer header from `protocol` and the
+    ///destination hardware address `addr`; SOCK_RAW sockets send `frame` as is.
+    pub fn send_to(
+        &self,
+        frame: &[u8],
+        if_index: i32,
+        protocol: EtherType,
+        addr: &[u8],
+    ) -> Result<usize> {
+        let mut sa: sockaddr_ll = unsafe { mem::zeroed() };
+        if addr.len() > sa.sll_addr.len() {
+            return Err(Error::os(
+                "sendto",
+                io::Error::from_raw_os_error(libc::EINVAL),
+            ));
+        }
+        sa.sll_family = AF_PACKET as u16;
+        sa.sll_protocol = protocol.to_raw().to_be();
+        sa.sll_ifindex = if_index;
+        sa.sll_halen = addr.len() as u8;
+        sa.sll_addr[..addr.len()].copy_from_slice(addr);
+
+        let sent = unsafe {
+            sendto(
+                self.fd,
+                frame.as_ptr() as *const c_void,
+                frame.len(),
+                0,
+                &sa as *const sockaddr_ll as *const sockaddr,
+                mem::size_of::<sockaddr_ll>() as socklen_t,
+            )
+        };
+        if sent < 0 {
+            return Err(Error::last_os_error("sendto"));
+        }
+        Ok(sent as usize)
+    }
+
+    ///Transmits whole Ethernet frames out of the socket's interface with as few sendmmsg()
+    ///calls as possible, returns how many frames were sent
+    ///
+    ///Stops early and returns the count so far if the kernel accepts only part of a batch,
+    ///e.g. because the socket's send buffer is full.
+    pub fn send_batch(&self, frames: &[&[u8]]) -> Result<usize> {
+        let mut sa: sockaddr_ll = unsafe { mem::zeroed() };
+        sa.sll_family = AF_PACKET as u16;
+        sa.sll_ifindex = self.if_index as i32;
+
+        let mut sent = 0;
+        for chunk in frames.chunks(MAX_BATCH) {
+            let mut iovs: Vec<iovec> = chunk
+                .iter()
+                .map(|frame| iovec {
+                    iov_base: frame.as_ptr() as *mut c_void,
+                    iov_len: frame.len(),
+                })
+                .collect();
+            let mut msgs: Vec<mmsghdr> = iovs
+                .iter_mut()
+                .map(|iov| {
+                    let mut msg: mmsghdr = unsafe { mem::zeroed() };
+                    msg.msg_hdr.msg_name = &mut sa as *mut sockaddr_ll as *mut c_void;
+                    msg.msg_hdr.msg_namelen = mem::size_of::<sockaddr_ll>() as socklen_t;
+                    msg.msg_hdr.msg_iov = iov;
+                    msg.msg_hdr.msg_iovlen = 1;
+                    msg
+                })
+                .collect();
+
+            let n = unsafe { sendmmsg(self.fd, msgs.as_mut_ptr(), msgs.len() as c_uint, 0) };
+            if n < 0 {
+                if sent > 0 {
+                    return Ok(sent);
+                }
+                return Err(Error::last_os_error("sendmmsg"));
+            }
+            sent += n as usize;
+            if (n as usize) < chunk.len() {
+                break;
+            }
+        }
+        Ok(sent)
+    }
+
+    ///Sets the receive buffer size (SO_RCVBUF), which the kernel doubles for bookkeeping and
+    ///caps at net.core.rmem_max. With `force` the cap is ignored (SO_RCVBUFFORCE), which needs
+    ///CAP_NET_ADMIN.
+    pub fn set_recv_buffer(&mut self, size: u32, force: bool) -> Result<()> {
+        let size = size.min(c_int::MAX as u32) as c_int;
+        if force {
+            self.set_socket_opt("setsockopt(SO_RCVBUFFORCE)", SO_RCVBUFFORCE, size)
+        } else {
+            self.set_socket_opt("setsockopt(SO_RCVBUF)", SO_RCVBUF, size)
+        }
+    }
+
+    ///Receive buffer size the kernel actually uses
+    pub fn recv_buffer(&self) -> Result<usize> {
+        let mut size: c_int = 0;
+        let mut optlen = mem::size_of::<c_int>() as socklen_t;
+        match unsafe {
+            getsockopt(
+                self.fd,
+                SOL_SOCKET,
+                SO_RCVBUF,
+                &mut size as *mut _ as *mut c_void,
+                &mut optlen,
+            )
+        } {
+            0 => Ok(size as usize),
+            _ => Err(Error::last_os_error("getsockopt(SO_RCVBUF)")),
+        }
+    }
+
+    ///CPU recorded in SO_INCOMING_CPU, None while unset
+    ///
+    ///Packet sockets do not record the CPU their traffic arrives on, so this is only what
+    ///`set_incoming_cpu()` stored; `affinity::incoming_cpus()` measures where traffic arrives.
+    pub fn incoming_cpu(&self) -> Result<Option<usize>> {
+        let mut cpu: c_int = -1;
+        let mut optlen = mem::size_of::<c_int>() as socklen_t;
+        match unsafe {
+            getsockopt(
+                self.fd,
+                SOL_SOCKET,
+                SO_INCOMING_CPU,
+                &mut cpu as *mut _ as *mut c_void,
+                &mut optlen,
+            )
+        } {
+            0 if cpu >= 0 => Ok(Some(cpu as usize)),
+            0 => Ok(None),
+            _ => Err(Error::last_os_error("getsockopt(SO_INCOMING_CPU)")),
+        }
+    }
+
+    ///Stores `cpu` in SO_INCOMING_CPU, e.g. to tell the CPU a socket is served from to tools
+    ///reading it back
+    pub fn set_incoming_cpu(&mut self, cpu: usize) -> Result<()> {
+        let cpu = c_int::try_from(cpu).map_err(|_| {
+            Error::os(
+                "setsockopt(SO_INCOMING_CPU)",
+                io::Error::from_raw_os_error(libc::EINVAL),
+            )
+        })?;
+        self.set_socket_opt("setsockopt(SO_INCOMING_CPU)", SO_INCOMING_CPU, cpu)
+    }
+
+    ///Busy polls the device queue for up to `micros` microseconds before sleeping in poll(),
+    ///0 turns it off (SO_BUSY_POLL). Values above net.core.busy_read need CAP_NET_ADMIN.
+    pub fn set_busy_poll(&mut self, micros: u32) -> Result<()> {
+        self.set_socket_opt("setsockopt(SO_BUSY_POLL)", SO_BUSY_POLL, micros as c_int)
+    }
+
+    ///Keeps device interrupts suppressed while the socket busy polls (SO_PREFER_BUSY_POLL,
+    ///Linux 5.11+)
+    pub fn set_prefer_busy_poll(&mut self, enabled: bool) -> Result<()> {
+        self.set_socket_opt(
+            "setsockopt(SO_PREFER_BUSY_POLL)",
+            SO_PREFER_BUSY_POLL,
+            enabled as c_int,
+        )
+    }
+
+    ///Packets processed per busy poll (SO_BUSY_POLL_BUDGET, Linux 5.11+), raising it above the
+    ///default of 8 needs CAP_NET_ADMIN
+    pub fn set_busy_poll_budget(&mut self, budget: u16) -> Result<()> {
+        self.set_socket_opt(
+            "setsockopt(SO_BUSY_POLL_BUDGET)",
+            SO_BUSY_POLL_BUDGET,
+            budget as c_int,
+        )
+    }
+
+    ///Attaches a classic BPF filter, replacing any filter attached before
+    pub fn attach_filter(&mut self, filter: &FilterProgram) -> Result<()> {
+        self.set_socket_opt(
+            "setsockopt(SO_ATTACH_FILTER)",
+            SO_ATTACH_FILTER,
+            filter.as_fprog(),
+        )?;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(if_name = %self.if_name, instructions = filter.len(), "filter attached");
+        Ok(())
+    }
+
+    ///Runs an eBPF socket filter program on every packet before it reaches the socket,
+    ///replacing any filter attached before
+    ///
+    ///`prog_fd` is a loaded BPF_PROG_TYPE_SOCKET_FILTER program, e.g. from
+    ///`ebpf::open_pinned()` or from an ELF object loaded with aya or libbpf. The socket keeps
+    ///its own reference, so the descriptor may be closed afterwards.
+    pub fn set_ebpf_filter(&mut self, prog_fd: c_int) -> Result<()> {
+        self.set_socket_opt("setsockopt(SO_ATTACH_BPF)", SO_ATTACH_BPF, prog_fd)
+    }
+
+    ///Removes the classic or eBPF filter attached to the socket
+    pub fn detach_filter(&mut self) -> Result<()> {
+        self.set_socket_opt("setsockopt(SO_DETACH_FILTER)", SO_DETACH_FILTER, 0 as c_int)
+    }
+
+    fn set_socket_opt<T>(&mut self, context: &'static str, opt: c_int, opt_val: T) -> Result<()> {
+        match unsafe {
+            setsockopt(
+                self.fd,
+                SOL_SOCKET,
+                opt,
+                &opt_val as *const _ as *const c_void,
+                mem::size_of::<T>() as socklen_t,
+            )
+        } {
+            0 => Ok(()),
+            _ => Err(privilege_error(
+                context,
+                Privilege::NetAdmin,
+                io::Error::last_os_error(),
+            )),
+        }
+    }
+}
+
+///Interface a `SocketBuilder` binds its socket to
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BindTarget {
+    ///Interface by name
+    Name(String),
+    ///Interface by index
+    Index(c_uint),
+    ///All interfaces, the socket's `if_name` is `ANY_INTERFACE`
+    Any,
+}
+
+///Opens a bound packet socket of any type and protocol, for applications that read and write
+///frames without a ring
+///
+///```no_run
+///use af_packet::socket::{EtherType, SocketBuilder};
+///
+///let socket = SocketBuilder::new()
+///    .interface("eth0")
+///    .protocol(EtherType::Arp)
+///    .cooked(true)
+///    .nonblocking(true)
+///    .open()?;
+///let mut buf = [0; 2048];
+///if let Some(len) = socket.recv_frame(&mut buf)? {
+///    println!("{} byte ARP packet", len);
+///}
+///# Ok::<(), af_packet::Error>(())
+///```
+#[derive(Clone, Debug)]
+pub struct SocketBuilder {
+    target: BindTarget,
+    protocol: EtherType,
+    cooked: bool,
+    nonblocking: bool,
+}
+
+impl SocketBuilder {
+    ///SOCK_RAW socket receiving every protocol on all interfaces, blocking
+    pub fn new() -> SocketBuilder {
+        SocketBuilder {
+            target: BindTarget::Any,
+            protocol: EtherType::All,
+            cooked: false,
+            nonblocking: false,
+        }
+    }
+
+    ///Binds to the interface named `if_name`
+    pub fn interface(mut self, if_name: &str) -> SocketBuilder {
+        self.target = BindTarget::Name(String::from(if_name));
+        self
+    }
+
+    ///Binds to the interface with index `if_index`
+    pub fn if_index(mut self, if_index: c_uint) -> SocketBuilder {
+        self.target = BindTarget::Index(if_index);
+        self
+    }
+
+    ///Binds to all interfaces
+    pub fn any_interface(mut self) -> SocketBuilder {
+        self.target = BindTarget::Any;
+        self
+    }
+
+    ///Binds to `target`
+    pub fn bind(mut self, target: BindTarget) -> SocketBuilder {
+        self.target = target;
+        self
+    }
+
+    ///Only receive frames of this protocol; `EtherType::Other(0)` receives nothing, for
+    ///sockets that only send
+    pub fn protocol(mut self, protocol: EtherType) -> SocketBuilder {
+        self.protocol = protocol;
+        self
+    }
+
+    ///Open a SOCK_DGRAM socket, which receives packets without their link-layer header and
+    ///builds it when sending, instead of a SOCK_RAW one
+    pub fn cooked(mut self, cooked: bool) -> SocketBuilder {
+        self.cooked = cooked;
+        self
+    }
+
+    ///Open the socket in non-blocking mode (SOCK_NONBLOCK)
+    pub fn nonblocking(mut self, nonblocking: bool) -> SocketBuilder {
+        self.nonblocking = nonblocking;
+        self
+    }
+
+    ///Opens and binds the socket
+    pub fn open(&self) -> Result<Socket> {
+        let mut kind = if self.cooked { SOCK_DGRAM } else { SOCK_RAW };
+        if self.nonblocking {
+            kind |= SOCK_NONBLOCK;
+        }
+        let mut socket = Socket::open_any(PF_PACKET, kind, self.protocol)?;
+        let named = match &self.target {
+            BindTarget::Name(if_name) => {
+                get_if_index(if_name).map(|index| (if_name.clone(), index))
+            }
+            BindTarget::Index(index) => get_if_name(*index).map(|if_name| (if_name, *index)),
+            BindTarget::Any => Ok((String::from(ANY_INTERFACE), 0)),
+        };
+        let bound = named.and_then(|(if_name, if_index)| {
+            socket.if_name = if_name;
+            socket.if_index = if_index;
+            crate::rx::bind_socket(&socket, self.protocol)
+        });
+        if let Err(err) = bound {
+            unsafe {
+                close(socket.fd);
+            }
+            return Err(err);
+        }
+        Ok(socket)
+    }
 }
 
-pub fn get_sock_opt(fd: i32, opt: c_int, opt_val: &*mut c_void) -> io::Result<()> {
-    let mut optlen = mem::size_of_val(&opt_val) as socklen_t;
-    match unsafe { getsockopt(fd, SOL_PACKET, opt, *opt_val, &mut optlen) } {
+impl Default for SocketBuilder {
+    fn default() -> SocketBuilder {
+        SocketBuilder::new()
+    }
+}
+
+pub fn get_sock_opt<T>(fd: i32, opt: c_int, opt_val: &mut T) -> Result<()> {
+    let mut optlen = mem::size_of::<T>() as socklen_t;
+    match unsafe {
+        getsockopt(
+            fd,
+            SOL_PACKET,
+            opt,
+            opt_val as *mut _ as *mut c_void,
+            &mut optlen,
+        )
+    } {
         0 => Ok(()),
-        _ => Err(io::Error::last_os_error()),
+        _ => Err(Error::last_os_error("getsockopt")),
     }
 }
 
-pub fn get_if_index(name: &str) -> io::Result<c_uint> {
-    let name = CString::new(name)?;
-    let index = unsafe { if_nametoindex(name.as_ptr()) };
-    Ok(index)
+///Whether the calling thread has capability `cap`, a CAP_* number, in its effective set
+///according to /proc/thread-self/status; None if that cannot be read
+pub fn has_capability(cap: u32) -> Option<bool> {
+    let status = std::fs::read_to_string("/proc/thread-self/status").ok()?;
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?
+        .trim();
+    let caps = u64::from_str_radix(caps, 16).ok()?;
+    Some(cap < 64 && caps & (1 << cap) != 0)
+}
+
+//EPERM and EACCES are blamed on the capability the call needs only if the thread lacks it,
+//since LSMs and seccomp refuse with them too; packet level options need no capability, so
+//they are not checked
+pub(crate) fn privilege_error(
+    context: &'static str,
+    privilege: Privilege,
+    source: io::Error,
+) -> Error {
+    let cap = match privilege {
+        Privilege::NetRaw => CAP_NET_RAW,
+        Privilege::NetAdmin => CAP_NET_ADMIN,
+        Privilege::MemLock { .. } => return Error::os(context, source),
+    };
+    match source.raw_os_error() {
+        Some(libc::EPERM) | Some(libc::EACCES) if has_capability(cap) == Some(false) => {
+            Error::MissingPrivilege {
+                context,
+                privilege,
+                source,
+            }
+        }
+        _ => Error::os(context, source),
+    }
+}
+
+pub fn get_if_index(name: &str) -> Result<c_uint> {
+    let c_name = CString::new(name).map_err(|_| Error::InvalidInterfaceName(String::from(name)))?;
+    match unsafe { if_nametoindex(c_name.as_ptr()) } {
+        0 => match io::Error::last_os_error().raw_os_error() {
+            Some(libc::ENODEV) | Some(libc::ENXIO) => {
+                Err(Error::NoSuchInterface(String::from(name)))
+            }
+            _ => Err(Error::last_os_error("if_nametoindex")),
+        },
+        index => Ok(index),
+    }
+}
+
+///Name of the interface with index `if_index`
+pub fn get_if_name(if_index: c_uint) -> Result<String> {
+    let mut name = [0 as c_char; IF_NAMESIZE];
+    if unsafe { if_indextoname(if_index, name.as_mut_ptr()) }.is_null() {
+        return match io::Error::last_os_error().raw_os_error() {
+            Some(libc::ENXIO) | Some(libc::ENODEV) => {
+                Err(Error::NoSuchInterface(if_index.to_string()))
+            }
+            _ => Err(Error::last_os_error("if_indextoname")),
+        };
+    }
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
 }
@@ -0,0 +1,61 @@
+//!Abstraction over anything that hands out blocks of packets: live rings, capture files and
+//!test doubles
+
+use std::task::{Context, Poll};
+
+use crate::error::Result;
+use crate::offline;
+use crate::rx::{Block, Drain, RawPacket, Ring};
+
+///Blocking source of packet blocks
+pub trait PacketSource {
+    ///Waits for the next block, `None` means the source is exhausted
+    fn next_block(&mut self) -> Result<Option<Block<'_>>>;
+
+    ///Calls `f` for every packet until the source is exhausted, consuming blocks as it goes
+    fn for_each_packet<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&RawPacket),
+        Self: Sized,
+    {
+        while let Some(mut block) = self.next_block()? {
+            for packet in block.get_raw_packets() {
+                f(&packet);
+            }
+            block.mark_as_consumed();
+        }
+        Ok(())
+    }
+}
+
+///Non-blocking source of packet blocks for async consumers
+pub trait AsyncPacketSource {
+    ///Returns the next block if one is ready, otherwise arranges for the task to be woken up
+    fn poll_next_block<'a>(&'a mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Block<'a>>>>;
+}
+
+impl PacketSource for Ring {
+    ///Never returns `None`, a live ring is only exhausted when it is dropped
+    fn next_block(&mut self) -> Result<Option<Block<'_>>> {
+        Ok(Some(self.get_block()))
+    }
+}
+
+impl PacketSource for Drain<'_> {
+    fn next_block(&mut self) -> Result<Option<Block<'_>>> {
+        Ok(Drain::next_block(self))
+    }
+}
+
+impl PacketSource for offline::Ring {
+    fn next_block(&mut self) -> Result<Option<Block<'_>>> {
+        self.get_block()
+    }
+}
+
+impl AsyncPacketSource for offline::Ring {
+    ///Reading a file never waits for traffic, so this is always ready
+    fn poll_next_block<'a>(&'a mut self, _cx: &mut Context<'_>) -> Poll<Result<Option<Block<'a>>>> {
+        Poll::Ready(self.get_block())
+    }
+}
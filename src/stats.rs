@@ -0,0 +1,197 @@
+use std::fmt;
+use std::time::Duration;
+
+///Snapshot of a ring's statistics as returned by `Ring::statistics()`
+///
+///Kernel counters are reset every time they are read, so all counts cover the interval since
+///the previous call
+#[derive(Clone, Debug, Default)]
+pub struct RingStats {
+    ///Packets seen by the kernel, including dropped ones
+    pub packets: u64,
+    ///Packets dropped by the kernel because the ring was full
+    pub drops: u64,
+    ///Number of times the kernel froze the queue
+    pub freeze_q_cnt: u64,
+    ///Blocks currently handed over to userspace and not yet consumed
+    pub ready_blocks: u32,
+    ///Total number of blocks in the ring
+    pub total_blocks: u32,
+    ///Blocks skipped according to their sequence numbers
+    pub seq_gaps: u64,
+    ///Packets the ring passed on to others of its group, for rings that can roll over
+    pub rollover: Option<RolloverStats>,
+    ///Time elapsed since the previous call, `None` on the first call
+    pub interval: Option<Duration>,
+}
+
+///Rollover counters of a ring in a PACKET_FANOUT_ROLLOVER group or one with
+///PACKET_FANOUT_FLAG_ROLLOVER, see `Ring::rollover_statistics()`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct RolloverStats {
+    ///Packets passed on to another ring because this one had no room
+    pub all: u64,
+    ///Packets passed on because a single flow was filling this ring
+    pub huge: u64,
+    ///Packets no ring of the group had room for
+    pub failed: u64,
+}
+
+///Lifetime totals of a ring, returned by `Ring::close()`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CloseReport {
+    ///Packets seen by the kernel, including dropped ones
+    pub packets: u64,
+    ///Packets dropped by the kernel because the ring was full
+    pub drops: u64,
+    ///Number of times the kernel froze the queue
+    pub freeze_q_cnt: u64,
+    ///Blocks handed out by the ring
+    pub blocks: u64,
+    ///Blocks lost to sequence gaps, see `Ring::blocks_lost()`
+    pub blocks_lost: u64,
+    ///Time from setting the ring up to closing it
+    pub duration: Duration,
+}
+
+///Memory taken by a ring and how it is laid out, returned by `Ring::memory_info()`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MemoryInfo {
+    ///Bytes mapped for the ring, 0 once it is closed
+    pub mapped_bytes: u64,
+    ///Bytes locked in RAM, all of the mapping or none of it
+    pub locked_bytes: u64,
+    ///Size of each block in bytes
+    pub block_size: u32,
+    ///Number of blocks
+    pub block_nr: u32,
+    ///Size of each frame in bytes, TPACKET_V3 rings only use it to check the layout
+    pub frame_size: u32,
+    ///Number of frames
+    pub frame_nr: u32,
+    ///Size in bytes of the pages backing the ring
+    pub page_size: u64,
+    ///Whether those are huge pages, see `RingSettings::hugepages`
+    pub hugepages: bool,
+}
+
+impl RingStats {
+    ///Adds the counters of `other` to this snapshot, e.g. to sum up several rings
+    pub fn accumulate(&mut self, other: &RingStats) {
+        self.packets += other.packets;
+        self.drops += other.drops;
+        self.freeze_q_cnt += other.freeze_q_cnt;
+        self.ready_blocks += other.ready_blocks;
+        self.total_blocks += other.total_blocks;
+        self.seq_gaps += other.seq_gaps;
+        self.rollover = match (self.rollover, other.rollover) {
+            (Some(a), Some(b)) => Some(RolloverStats {
+                all: a.all + b.all,
+                huge: a.huge + b.huge,
+                failed: a.failed + b.failed,
+            }),
+            (a, b) => a.or(b),
+        };
+        self.interval = self.interval.max(other.interval);
+    }
+
+    ///Fraction of packets dropped, between 0 and 1
+    pub fn drop_rate(&self) -> f64 {
+        if self.packets == 0 {
+            return 0.0;
+        }
+        self.drops as f64 / self.packets as f64
+    }
+
+    ///Fraction of blocks waiting to be consumed, between 0 and 1
+    pub fn saturation(&self) -> f64 {
+        if self.total_blocks == 0 {
+            return 0.0;
+        }
+        self.ready_blocks as f64 / self.total_blocks as f64
+    }
+}
+
+impl CloseReport {
+    ///Fraction of packets dropped, between 0 and 1
+    pub fn drop_rate(&self) -> f64 {
+        if self.packets == 0 {
+            return 0.0;
+        }
+        self.drops as f64 / self.packets as f64
+    }
+}
+
+impl MemoryInfo {
+    ///Adds the mapped and locked bytes of `other`, e.g. to sum up the rings of several
+    ///interfaces; the layout is kept
+    pub fn accumulate(&mut self, other: &MemoryInfo) {
+        self.mapped_bytes += other.mapped_bytes;
+        self.locked_bytes += other.locked_bytes;
+    }
+}
+
+impl fmt::Display for MemoryInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes mapped, {} locked, {} blocks of {} bytes, {} frames of {} bytes, {} byte {}",
+            self.mapped_bytes,
+            self.locked_bytes,
+            self.block_nr,
+            self.block_size,
+            self.frame_nr,
+            self.frame_size,
+            self.page_size,
+            if self.hugepages {
+                "huge pages"
+            } else {
+                "pages"
+            }
+        )
+    }
+}
+
+impl fmt::Display for CloseReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} packets, {} dropped ({:.4}%), {} freezes, {} blocks, {} lost, in {:.3}s",
+            self.packets,
+            self.drops,
+            self.drop_rate() * 100.0,
+            self.freeze_q_cnt,
+            self.blocks,
+            self.blocks_lost,
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
+impl fmt::Display for RingStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} packets, {} dropped ({:.4}%), {} freezes, {}/{} blocks ready ({:.2}%), {} seq gaps",
+            self.packets,
+            self.drops,
+            self.drop_rate() * 100.0,
+            self.freeze_q_cnt,
+            self.ready_blocks,
+            self.total_blocks,
+            self.saturation() * 100.0,
+            self.seq_gaps
+        )?;
+        if let Some(rollover) = &self.rollover {
+            write!(
+                f,
+                ", {} rolled over ({} huge, {} failed)",
+                rollover.all, rollover.huge, rollover.failed
+            )?;
+        }
+        if let Some(interval) = self.interval {
+            write!(f, " in {:.3}s", interval.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
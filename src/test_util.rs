@@ -0,0 +1,102 @@
+//!Helpers for end-to-end tests on a temporary veth pair, enabled with the `test_util` feature
+//!
+//!Creating the pair needs CAP_NET_ADMIN, capturing on it CAP_NET_RAW.
+
+use libc::IFF_UP;
+
+use crate::error::Result;
+use crate::group::unique_fanout_group;
+use crate::netlink::{
+    Message, NetlinkSocket, IFLA_IFNAME, IFLA_INFO_DATA, IFLA_INFO_KIND, IFLA_LINKINFO, NLM_F_ACK,
+    NLM_F_CREATE, NLM_F_EXCL, NLM_F_REQUEST, RTM_DELLINK, RTM_NEWLINK,
+};
+use crate::rx::RingSettings;
+use crate::socket::get_if_index;
+use crate::tpacket3::TpacketReq3;
+use crate::tx::Player;
+
+const VETH_INFO_PEER: u16 = 1;
+
+///A veth pair that is deleted again when dropped
+///
+///Frames injected on `tx` come out of `rx`, where a ring can capture them.
+#[derive(Debug)]
+pub struct VethPair {
+    ///End frames are injected on
+    pub tx: String,
+    ///End frames are captured on
+    pub rx: String,
+    player: Player,
+}
+
+impl VethPair {
+    ///Creates a pair named `<prefix>0` and `<prefix>1` and brings both ends up
+    pub fn create(prefix: &str) -> Result<VethPair> {
+        let tx = format!("{}0", prefix);
+        let rx = format!("{}1", prefix);
+        let mut nl = NetlinkSocket::open(0)?;
+
+        let mut msg = Message::new(
+            RTM_NEWLINK,
+            NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL,
+        );
+        msg.ifinfomsg(0, 0, 0);
+        msg.attr_str(IFLA_IFNAME, &tx);
+        let linkinfo = msg.begin_nested(IFLA_LINKINFO);
+        msg.attr(IFLA_INFO_KIND, b"veth");
+        let data = msg.begin_nested(IFLA_INFO_DATA);
+        let peer = msg.begin_nested(VETH_INFO_PEER);
+        msg.ifinfomsg(0, 0, 0);
+        msg.attr_str(IFLA_IFNAME, &rx);
+        msg.end_nested(peer);
+        msg.end_nested(data);
+        msg.end_nested(linkinfo);
+        nl.request(msg)?;
+
+        for name in [&tx, &rx].iter() {
+            let index = get_if_index(name)? as i32;
+            let mut up = Message::new(RTM_NEWLINK, NLM_F_REQUEST | NLM_F_ACK);
+            up.ifinfomsg(index, IFF_UP as u32, IFF_UP as u32);
+            nl.request(up)?;
+        }
+
+        let player = Player::open_socket(&tx)?;
+        Ok(VethPair { tx, rx, player })
+    }
+
+    ///Sends a whole Ethernet frame into the `tx` end
+    pub fn inject(&self, frame: &[u8]) -> Result<()> {
+        self.player.send_frame(frame)
+    }
+
+    ///Settings for a small ring capturing on the `rx` end, in a fanout group of its own so
+    ///that rings on other pairs of the same process can be opened alongside
+    pub fn ring_settings(&self) -> RingSettings {
+        RingSettings {
+            if_name: self.rx.clone(),
+            fanout_group: Some(unique_fanout_group()),
+            ring_settings: TpacketReq3 {
+                tp_block_size: 1 << 16,
+                tp_block_nr: 4,
+                tp_frame_size: 2048,
+                tp_frame_nr: 128,
+                tp_retire_blk_tov: 10,
+                ..TpacketReq3::default()
+            },
+            ..RingSettings::default()
+        }
+    }
+}
+
+impl Drop for VethPair {
+    fn drop(&mut self) {
+        //deleting one end removes the whole pair
+        if let Ok(index) = get_if_index(&self.tx) {
+            if let Ok(mut nl) = NetlinkSocket::open(0) {
+                let mut msg = Message::new(RTM_DELLINK, NLM_F_REQUEST | NLM_F_ACK);
+                msg.ifinfomsg(index as i32, 0, 0);
+                let _ = nl.request(msg);
+            }
+        }
+    }
+}
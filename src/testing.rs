@@ -0,0 +1,240 @@
+//!Test doubles that do not need a network interface or CAP_NET_RAW
+
+use std::collections::VecDeque;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::Result;
+use crate::offline::{self, Packet};
+use crate::pcapng::DLT_EN10MB;
+use crate::rx::Block;
+use crate::source::{AsyncPacketSource, PacketSource};
+use crate::tpacket3::{self, BlockBuilder, SockAddrLl, TpStatus, Tpacket3Hdr};
+
+///Default size of the blocks built by `MockRing`
+pub const DEFAULT_BLOCK_SIZE: usize = 1 << 16;
+
+///In-memory ring that hands out properly laid out TPACKET_V3 blocks built from queued frames
+///
+///Frames queued with `push_frame()` are treated as Ethernet frames received now; use
+///`push_packet()` for full control over the packet header and link-layer metadata.
+#[derive(Clone, Debug)]
+pub struct MockRing {
+    queue: VecDeque<Packet>,
+    blocks: Vec<Vec<u8>>,
+    block_size: usize,
+    max_packets_per_block: usize,
+    seq_num: u64,
+}
+
+impl MockRing {
+    ///Creates a ring with the given frames already queued
+    pub fn new<I>(frames: I) -> MockRing
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let mut ring = MockRing {
+            queue: VecDeque::new(),
+            blocks: Vec::new(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            max_packets_per_block: usize::MAX,
+            seq_num: 1,
+        };
+        for frame in frames {
+            ring.push_frame(frame.as_ref());
+        }
+        ring
+    }
+
+    ///Size of the blocks to build, frames larger than a block are truncated
+    pub fn set_block_size(&mut self, block_size: usize) {
+        self.block_size = block_size;
+    }
+
+    ///Limits the number of packets per block, e.g. to exercise iteration over many blocks
+    pub fn set_max_packets_per_block(&mut self, max: usize) {
+        self.max_packets_per_block = max.max(1);
+    }
+
+    ///Queues an Ethernet frame timestamped with the current time
+    pub fn push_frame(&mut self, frame: &[u8]) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let packet = offline::make_packet(
+            DLT_EN10MB,
+            1,
+            now.as_secs() as u32,
+            now.subsec_nanos(),
+            frame.len() as u32,
+            frame.to_vec(),
+        );
+        self.queue.push_back(packet);
+    }
+
+    ///Queues a packet with explicit header fields, see `BlockBuilder::push()` for which ones
+    ///are used
+    pub fn push_packet(&mut self, hdr: Tpacket3Hdr, sll: SockAddrLl, data: &[u8]) {
+        self.queue.push_back(Packet {
+            hdr,
+            sll,
+            data: data.to_vec(),
+        });
+    }
+
+    ///Pretends the kernel retired `count` blocks that were never seen, to simulate overload
+    pub fn skip_blocks(&mut self, count: u64) {
+        self.seq_num += count;
+    }
+
+    ///Number of frames not yet handed out
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    ///Number of blocks handed out so far
+    pub fn blocks_returned(&self) -> usize {
+        self.blocks.len()
+    }
+
+    ///Number of handed out blocks that were given back with `Block::mark_as_consumed()`
+    pub fn blocks_consumed(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter(|b| b[tpacket3::TP_BLK_STATUS_OFFSET] & tpacket3::TP_STATUS_USER == 0)
+            .count()
+    }
+
+    ///Builds the next block from queued frames, `None` once the queue is empty
+    pub fn get_block(&mut self) -> Option<Block<'_>> {
+        let mut builder = BlockBuilder::new(self.block_size, self.seq_num);
+        while (builder.len() as usize) < self.max_packets_per_block {
+            let mut packet = match self.queue.pop_front() {
+                Some(packet) => packet,
+                None => break,
+            };
+            if builder.push(&packet.hdr, &packet.sll, &packet.data) {
+                continue;
+            }
+            if builder.is_empty() {
+                packet.data.truncate(builder.remaining());
+                packet.hdr.tp_status |= TpStatus::COPY.bits();
+                builder.push(&packet.hdr, &packet.sll, &packet.data);
+            } else {
+                self.queue.push_front(packet);
+            }
+            break;
+        }
+        if builder.is_empty() {
+            return None;
+        }
+        self.seq_num += 1;
+        self.blocks.push(builder.finish());
+        let raw = self.blocks.last_mut()?;
+        Block::from_raw(raw)
+    }
+}
+
+impl PacketSource for MockRing {
+    fn next_block(&mut self) -> Result<Option<Block<'_>>> {
+        Ok(self.get_block())
+    }
+}
+
+impl AsyncPacketSource for MockRing {
+    fn poll_next_block<'a>(&'a mut self, _cx: &mut Context<'_>) -> Poll<Result<Option<Block<'a>>>> {
+        Poll::Ready(Ok(self.get_block()))
+    }
+}
+
+//frames for the unit tests of the parsers and filters
+#[cfg(test)]
+pub(crate) mod frames {
+    use std::net::Ipv6Addr;
+
+    pub(crate) const ETH_P_IP: u16 = 0x0800;
+    pub(crate) const ETH_P_IPV6: u16 = 0x86dd;
+    pub(crate) const ETH_P_ARP: u16 = 0x0806;
+    pub(crate) const IPPROTO_TCP: u8 = 6;
+    pub(crate) const IPPROTO_UDP: u8 = 17;
+
+    pub(crate) fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0xff; 6];
+        frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    //Ethernet frame with an 802.1Q tag still in it
+    pub(crate) fn tagged(vlan_id: u16, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut inner = vlan_id.to_be_bytes().to_vec();
+        inner.extend_from_slice(&ethertype.to_be_bytes());
+        inner.extend_from_slice(payload);
+        ethernet(0x8100, &inner)
+    }
+
+    pub(crate) fn ipv4(protocol: u8, src: [u8; 4], dst: [u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0x12, 0x34, 0, 0, 64, protocol, 0, 0]);
+        packet.extend_from_slice(&src);
+        packet.extend_from_slice(&dst);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    //IPv4 fragment at `offset` bytes into the datagram
+    pub(crate) fn ipv4_fragment(id: u16, offset: usize, more: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = ipv4(IPPROTO_UDP, [10, 0, 0, 1], [10, 0, 0, 2], payload);
+        packet[4..6].copy_from_slice(&id.to_be_bytes());
+        let field = (offset / 8) as u16 | if more { 0x2000 } else { 0 };
+        packet[6..8].copy_from_slice(&field.to_be_bytes());
+        packet
+    }
+
+    pub(crate) fn ipv6(next_header: u8, src: Ipv6Addr, dst: Ipv6Addr, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[next_header, 64]);
+        packet.extend_from_slice(&src.octets());
+        packet.extend_from_slice(&dst.octets());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    pub(crate) fn udp(sport: u16, dport: u16, payload: &[u8]) -> Vec<u8> {
+        let mut segment = sport.to_be_bytes().to_vec();
+        segment.extend_from_slice(&dport.to_be_bytes());
+        segment.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+        segment.extend_from_slice(&[0, 0]);
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    pub(crate) fn tcp(sport: u16, dport: u16) -> Vec<u8> {
+        let mut segment = sport.to_be_bytes().to_vec();
+        segment.extend_from_slice(&dport.to_be_bytes());
+        segment.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x02, 0xff, 0xff, 0, 0, 0, 0]);
+        segment
+    }
+
+    pub(crate) fn tcp4(src: [u8; 4], dst: [u8; 4], sport: u16, dport: u16) -> Vec<u8> {
+        ethernet(ETH_P_IP, &ipv4(IPPROTO_TCP, src, dst, &tcp(sport, dport)))
+    }
+
+    pub(crate) fn udp4(src: [u8; 4], dst: [u8; 4], sport: u16, dport: u16) -> Vec<u8> {
+        ethernet(
+            ETH_P_IP,
+            &ipv4(IPPROTO_UDP, src, dst, &udp(sport, dport, b"payload")),
+        )
+    }
+
+    pub(crate) fn udp6(src: Ipv6Addr, dst: Ipv6Addr, sport: u16, dport: u16) -> Vec<u8> {
+        ethernet(
+            ETH_P_IPV6,
+            &ipv6(IPPROTO_UDP, src, dst, &udp(sport, dport, b"payload")),
+        )
+    }
+}
@@ -0,0 +1,120 @@
+//!TPACKET_V2 frame ring, used where TPACKET_V3 is not available
+//!
+//!A V2 ring is a sequence of fixed size frames, each handed back to the kernel on its own.
+//!`rx::Ring` copies ready frames into blocks laid out like TPACKET_V3 ones and releases the
+//!frames right away, so `Block` and `RawPacket` look the same with either version.
+
+use libc::{c_int, c_uint};
+use nom::number::complete::{le_u16, le_u32};
+
+use crate::tpacket3::{self, BlockBuilder, SockAddrLl, TpStatus, Tpacket3Hdr, TpacketReq3};
+
+pub const TPACKET_V2: c_int = 1;
+
+///Offset of the sockaddr_ll the kernel stores after every tpacket2_hdr
+pub const TPACKET2_SLL_OFFSET: usize = 32;
+
+///Ring geometry for TPACKET_V1 and V2 (struct tpacket_req)
+#[derive(Clone, Debug)]
+#[repr(C)]
+pub struct TpacketReq {
+    pub tp_block_size: c_uint,
+    pub tp_block_nr: c_uint,
+    pub tp_frame_size: c_uint,
+    pub tp_frame_nr: c_uint,
+}
+
+impl TpacketReq {
+    ///Frames per block
+    pub fn frames_per_block(&self) -> c_uint {
+        self.tp_block_size / self.tp_frame_size
+    }
+}
+
+impl From<&TpacketReq3> for TpacketReq {
+    ///Same memory as the V3 ring, cut into `tp_frame_size` frames
+    fn from(req: &TpacketReq3) -> TpacketReq {
+        //smallest aligned frame that holds the headers
+        let frame_size = req.tp_frame_size.clamp(64, req.tp_block_size);
+        TpacketReq {
+            tp_block_size: req.tp_block_size,
+            tp_block_nr: req.tp_block_nr,
+            tp_frame_size: frame_size,
+            tp_frame_nr: req.tp_block_size / frame_size * req.tp_block_nr,
+        }
+    }
+}
+
+///Contains details about an individual packet in a frame
+#[derive(Clone, Debug, Default)]
+pub struct Tpacket2Hdr {
+    pub tp_status: u32,
+    pub tp_len: u32,
+    pub tp_snaplen: u32,
+    pub tp_mac: u16,
+    pub tp_net: u16,
+    pub tp_sec: u32,
+    pub tp_nsec: u32,
+    pub tp_vlan_tci: u16,
+    pub tp_vlan_tpid: u16,
+}
+
+named!(
+    pub get_tpacket2_hdr<Tpacket2Hdr>,
+    do_parse!(
+        tp_status: le_u32 >> tp_len: le_u32 >> tp_snaplen: le_u32 >> tp_mac: le_u16
+            >> tp_net: le_u16 >> tp_sec: le_u32 >> tp_nsec: le_u32 >> tp_vlan_tci: le_u16
+            >> tp_vlan_tpid: le_u16 >> (Tpacket2Hdr {
+            tp_status,
+            tp_len,
+            tp_snaplen,
+            tp_mac,
+            tp_net,
+            tp_sec,
+            tp_nsec,
+            tp_vlan_tci,
+            tp_vlan_tpid
+        })
+    )
+);
+
+///Copies the packet in `frame` into `builder`, returns false if it does not fit
+///
+///A packet that does not fit in an empty block is truncated and flagged `TpStatus::COPY`.
+///The frame is not released, that is left to the caller.
+pub(crate) fn push_frame(builder: &mut BlockBuilder, frame: &[u8]) -> bool {
+    let hdr = match get_tpacket2_hdr(frame) {
+        Ok((_, hdr)) => hdr,
+        Err(_) => return true,
+    };
+    let sll = frame
+        .get(TPACKET2_SLL_OFFSET..)
+        .and_then(|raw| tpacket3::get_sockaddr_ll(raw).ok())
+        .map(|(_, sll)| sll)
+        .unwrap_or_else(SockAddrLl::default);
+    let mac = hdr.tp_mac as usize;
+    let data = match frame.get(mac..mac + hdr.tp_snaplen as usize) {
+        Some(data) => data,
+        None => return true,
+    };
+
+    let mut hdr3 = Tpacket3Hdr {
+        tp_sec: hdr.tp_sec,
+        tp_nsec: hdr.tp_nsec,
+        tp_len: hdr.tp_len,
+        tp_status: hdr.tp_status,
+        tp_net: hdr.tp_net.saturating_sub(hdr.tp_mac),
+        ..Tpacket3Hdr::default()
+    };
+    hdr3.hv1.tp_vlan_tci = hdr.tp_vlan_tci as u32;
+    hdr3.hv1.tp_vlan_tpid = hdr.tp_vlan_tpid;
+    if builder.push(&hdr3, &sll, data) {
+        return true;
+    }
+    if !builder.is_empty() {
+        return false;
+    }
+    hdr3.tp_status |= TpStatus::COPY.bits();
+    let len = builder.remaining();
+    builder.push(&hdr3, &sll, &data[..len])
+}
@@ -1,12 +1,63 @@
-use libc::{c_int, c_uint};
-use nom::number::complete::{le_u16, le_u32, le_u64};
+use libc::{c_int, c_uint, sysconf, _SC_PAGESIZE};
+use nom::number::complete::{be_u16, le_i32, le_u16, le_u32, le_u64, le_u8};
+use std::ops::{BitAnd, BitOr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::{Error, Result};
 
 pub const TP_STATUS_KERNEL: u8 = 0;
 pub const TP_STATUS_USER: u8 = 1;
-//const TP_STATUS_COPY: u8 = 1 << 1;
-//const TP_STATUS_LOSING: u8 = 1 << 2;
-//const TP_STATUS_CSUMNOTREADY: u8 = 1 << 3;
-//const TP_STATUS_CSUM_VALID: u8 = 1 << 7;
+
+///TP_STATUS_* flags reported for a packet (`tp_status`) or a block (`block_status`)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TpStatus(pub u32);
+
+impl TpStatus {
+    ///Owned by the kernel
+    pub const KERNEL: TpStatus = TpStatus(0);
+    ///Owned by userspace
+    pub const USER: TpStatus = TpStatus(1);
+    ///Packet was truncated because it did not fit in a frame
+    pub const COPY: TpStatus = TpStatus(1 << 1);
+    ///The kernel dropped packets since the last time this flag was reported
+    pub const LOSING: TpStatus = TpStatus(1 << 2);
+    ///Checksum will be computed by the NIC on transmit, it is not valid yet
+    pub const CSUMNOTREADY: TpStatus = TpStatus(1 << 3);
+    ///`tp_vlan_tci` holds a valid tag
+    pub const VLAN_VALID: TpStatus = TpStatus(1 << 4);
+    ///Block was retired by the timer rather than because it was full
+    pub const BLK_TMO: TpStatus = TpStatus(1 << 5);
+    ///`tp_vlan_tpid` holds a valid TPID
+    pub const VLAN_TPID_VALID: TpStatus = TpStatus(1 << 6);
+    ///Checksum was verified by the NIC
+    pub const CSUM_VALID: TpStatus = TpStatus(1 << 7);
+
+    ///Raw flag bits
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    ///Whether all flags in `other` are set
+    pub fn contains(self, other: TpStatus) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for TpStatus {
+    type Output = TpStatus;
+
+    fn bitor(self, other: TpStatus) -> TpStatus {
+        TpStatus(self.0 | other.0)
+    }
+}
+
+impl BitAnd for TpStatus {
+    type Output = TpStatus;
+
+    fn bitand(self, other: TpStatus) -> TpStatus {
+        TpStatus(self.0 & other.0)
+    }
+}
 
 pub const TPACKET_V3: c_int = 2;
 
@@ -14,7 +65,33 @@ const TP_FT_REQ_FILL_RXHASH: c_uint = 1; //0x1;
 
 pub const TP_BLK_STATUS_OFFSET: usize = 8;
 
-#[derive(Clone, Debug)]
+///Frames and the block private area are aligned to this many bytes by the kernel
+pub const TPACKET_ALIGNMENT: c_uint = 16;
+///Size of the block descriptor at the start of every block
+pub const TPACKET_BLOCK_DESC_LEN: c_uint = 48;
+///Minimum frame size: an aligned tpacket3_hdr followed by a sockaddr_ll
+pub const TPACKET3_HDRLEN: c_uint = 48 + 20;
+///Offset of the sockaddr_ll the kernel stores after every tpacket3_hdr
+pub const TPACKET3_SLL_OFFSET: usize = 48;
+///Cache line size assumed when padding frames
+pub const CACHE_LINE_SIZE: c_uint = 64;
+
+///Returns the PACKET_RESERVE value that makes every frame holding an Ethernet packet of
+///`snaplen` bytes occupy a whole number of `align` sized lines, so that adjacent frames never
+///share one
+///
+///`align` must be a power of two no smaller than `TPACKET_ALIGNMENT`, e.g. `CACHE_LINE_SIZE`.
+///This mirrors the offset calculation in the kernel's tpacket_rcv(): the network header is
+///placed on an aligned offset past the header area and the MAC header right before it.
+pub fn frame_reserve_for(snaplen: c_uint, align: c_uint) -> c_uint {
+    const ETH_HLEN: c_uint = 14;
+    let netoff = (TPACKET3_HDRLEN + 16 + TPACKET_ALIGNMENT - 1) & !(TPACKET_ALIGNMENT - 1);
+    let frame = netoff - ETH_HLEN + snaplen;
+    let padded = (frame + align - 1) & !(align - 1);
+    padded - frame
+}
+
+#[derive(Clone, Debug, Default)]
 #[repr(C)]
 pub struct TpacketStatsV3 {
     pub tp_packets: c_uint,
@@ -22,6 +99,15 @@ pub struct TpacketStatsV3 {
     pub tp_freeze_q_cnt: c_uint,
 }
 
+//struct tpacket_rollover_stats
+#[derive(Clone, Debug, Default)]
+#[repr(C)]
+pub struct TpacketRolloverStats {
+    pub tp_all: u64,
+    pub tp_huge: u64,
+    pub tp_failed: u64,
+}
+
 #[derive(Clone, Debug)]
 #[repr(C)]
 ///Lower-level settings about ring buffer allocation and behavior
@@ -44,31 +130,39 @@ pub struct TpacketReq3 {
 }
 
 #[derive(Clone, Debug)]
+#[allow(dead_code)]
 pub struct TpacketBlockDesc {
     version: u32,
-    offset_to_priv: u32,
+    pub(crate) offset_to_priv: u32,
     pub hdr: TpacketBDHeader,
 }
 
 #[derive(Clone, Debug)]
+#[allow(dead_code)]
 pub struct TpacketBDHeader {
-    block_status: u32,
+    pub block_status: u32,
     pub num_pkts: u32,
-    offset_to_first_pkt: u32,
-    blk_len: u32,
-    seq_num: u64,
-    ts_first_pkt: TpacketBDTS,
-    ts_last_pkt: TpacketBDTS,
+    pub(crate) offset_to_first_pkt: u32,
+    pub(crate) blk_len: u32,
+    pub seq_num: u64,
+    pub(crate) ts_first_pkt: TpacketBDTS,
+    pub(crate) ts_last_pkt: TpacketBDTS,
 }
 
 #[derive(Clone, Debug)]
-struct TpacketBDTS {
-    ts_sec: u32,
-    ts_nsec: u32,
+pub(crate) struct TpacketBDTS {
+    pub(crate) ts_sec: u32,
+    pub(crate) ts_nsec: u32,
+}
+
+impl TpacketBDTS {
+    pub(crate) fn to_system_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::new(self.ts_sec as u64, self.ts_nsec)
+    }
 }
 
 ///Contains details about individual packets in a block
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Tpacket3Hdr {
     pub tp_next_offset: u32,
     pub tp_sec: u32,
@@ -83,7 +177,8 @@ pub struct Tpacket3Hdr {
 }
 
 ///Contains VLAN tags and RX Hash value (if enabled)
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
+#[allow(dead_code)]
 pub struct TpacketHdrVariant1 {
     pub tp_rxhash: u32,
     pub tp_vlan_tci: u32,
@@ -91,18 +186,272 @@ pub struct TpacketHdrVariant1 {
     tp_padding: u16,
 }
 
+///Link-layer address information the kernel stores after every packet header
+#[derive(Clone, Debug, Default)]
+pub struct SockAddrLl {
+    pub sll_family: u16,
+    ///Ethertype in host byte order
+    pub sll_protocol: u16,
+    pub sll_ifindex: i32,
+    ///ARPHRD_* hardware type of the interface
+    pub sll_hatype: u16,
+    ///PACKET_HOST, PACKET_OUTGOING etc.
+    pub sll_pkttype: u8,
+    pub sll_halen: u8,
+    pub sll_addr: [u8; 8],
+}
+
 impl Default for TpacketReq3 {
+    ///Same as `TpacketReq3::balanced()`
     fn default() -> TpacketReq3 {
+        TpacketReq3::balanced()
+    }
+}
+
+///Line-rate traffic a ring sized by `TpacketReq3::auto_size()` buffers, memory permitting
+pub const AUTO_SIZE_BUFFER: Duration = Duration::from_secs(1);
+///Memory budget of every ring `Capture::builder()` opens unless its geometry is set, the
+///size of `TpacketReq3::balanced()`
+pub const DEFAULT_AUTO_SIZE_BUDGET: u64 = 64 << 20;
+//largest block the kernel allocates in one piece, order 10 with 4 KiB pages
+const MAX_AUTO_BLOCK_SIZE: u64 = 4 << 20;
+const MIN_AUTO_BLOCK_SIZE: u64 = 64 << 10;
+//blocks an auto-sized ring is split into when memory allows, so that a few blocks held by
+//userspace leave the kernel plenty to fill
+const AUTO_BLOCK_NR: u64 = 64;
+
+impl TpacketReq3 {
+    ///4 MiB in 64 blocks of 64 KiB, for low traffic and tight RLIMIT_MEMLOCK budgets
+    pub fn small() -> TpacketReq3 {
+        TpacketReq3::with_blocks(64 << 10, 64, 10)
+    }
+
+    ///64 MiB in 64 blocks of 1 MiB, the default
+    pub fn balanced() -> TpacketReq3 {
+        TpacketReq3::with_blocks(1 << 20, 64, 60)
+    }
+
+    ///512 MiB in 128 blocks of 4 MiB, for 10G and faster links; locking it in memory needs
+    ///a raised RLIMIT_MEMLOCK or CAP_IPC_LOCK
+    pub fn high_throughput() -> TpacketReq3 {
+        TpacketReq3::with_blocks(4 << 20, 128, 100)
+    }
+
+    fn with_blocks(block_size: c_uint, block_nr: c_uint, retire_blk_tov: c_uint) -> TpacketReq3 {
+        let frame_size = 2048;
         TpacketReq3 {
-            tp_block_size: 32768,
-            tp_block_nr: 10000,
-            tp_frame_size: 2048,
-            tp_frame_nr: 160000,
-            tp_retire_blk_tov: 100,
+            tp_block_size: block_size,
+            tp_block_nr: block_nr,
+            tp_frame_size: frame_size,
+            tp_frame_nr: block_size / frame_size * block_nr,
+            tp_retire_blk_tov: retire_blk_tov,
             tp_sizeof_priv: 0,
             tp_feature_req_word: TP_FT_REQ_FILL_RXHASH,
         }
     }
+
+    ///Checks the ring geometry the same way the kernel does in packet_set_ring(), so that
+    ///mistakes are reported with the name of the offending parameter instead of a bare EINVAL
+    pub fn validate(&self) -> Result<()> {
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) } as c_uint;
+
+        if self.tp_block_nr == 0 {
+            return Err(invalid("tp_block_nr must be greater than 0".to_string()));
+        }
+        if self.tp_block_size == 0 || !self.tp_block_size.is_multiple_of(page_size) {
+            return Err(invalid(format!(
+                "tp_block_size ({}) must be a non-zero multiple of the page size ({})",
+                self.tp_block_size, page_size
+            )));
+        }
+        if self.tp_frame_size < TPACKET3_HDRLEN {
+            return Err(invalid(format!(
+                "tp_frame_size ({}) must be at least {}",
+                self.tp_frame_size, TPACKET3_HDRLEN
+            )));
+        }
+        if !self.tp_frame_size.is_multiple_of(TPACKET_ALIGNMENT) {
+            return Err(invalid(format!(
+                "tp_frame_size ({}) must be a multiple of {}",
+                self.tp_frame_size, TPACKET_ALIGNMENT
+            )));
+        }
+        if self.tp_frame_size > self.tp_block_size {
+            return Err(invalid(format!(
+                "tp_frame_size ({}) must not exceed tp_block_size ({})",
+                self.tp_frame_size, self.tp_block_size
+            )));
+        }
+        let priv_len = (self.tp_sizeof_priv as u64 + TPACKET_ALIGNMENT as u64 - 1)
+            & !(TPACKET_ALIGNMENT as u64 - 1);
+        if TPACKET_BLOCK_DESC_LEN as u64 + priv_len >= self.tp_block_size as u64 {
+            return Err(invalid(format!(
+                "tp_sizeof_priv ({}) leaves no room for packets in a block of {} bytes",
+                self.tp_sizeof_priv, self.tp_block_size
+            )));
+        }
+        let frames_per_block = self.tp_block_size / self.tp_frame_size;
+        if self.tp_frame_nr as u64 != frames_per_block as u64 * self.tp_block_nr as u64 {
+            return Err(invalid(format!(
+                "tp_frame_nr ({}) must equal tp_block_nr ({}) * frames per block ({})",
+                self.tp_frame_nr, self.tp_block_nr, frames_per_block
+            )));
+        }
+        Ok(())
+    }
+
+    ///Geometry that buffers `AUTO_SIZE_BUFFER` of line-rate traffic on a link of `speed_mbps`
+    ///Mbit/s within `max_memory` bytes; with an unknown speed the whole budget is used
+    ///
+    ///Blocks are powers of two between 64 KiB and 4 MiB, at least 64 of them if the budget
+    ///allows. The retire timeout, private area, feature word and frame size are kept.
+    pub fn auto_size(&self, speed_mbps: Option<u32>, max_memory: u64) -> TpacketReq3 {
+        let page_size = (unsafe { sysconf(_SC_PAGESIZE) } as u64).max(1);
+        let wanted = match speed_mbps {
+            Some(speed) => (speed as f64 * 1e6 / 8.0 * AUTO_SIZE_BUFFER.as_secs_f64()) as u64,
+            None => max_memory,
+        };
+        let total = wanted.min(max_memory);
+        let frame_size = if self.tp_frame_size == 0 {
+            2048
+        } else {
+            self.tp_frame_size
+        };
+        //largest power of two that still makes `AUTO_BLOCK_NR` blocks
+        let per_block = (total / AUTO_BLOCK_NR).max(1);
+        let block_size = (1u64 << (63 - per_block.leading_zeros()))
+            .clamp(MIN_AUTO_BLOCK_SIZE, MAX_AUTO_BLOCK_SIZE)
+            .max(page_size)
+            .max(frame_size as u64) as c_uint;
+        //at least one block, even if that is more than the budget
+        let block_nr = (total / block_size as u64).clamp(1, c_uint::MAX as u64) as c_uint;
+        TpacketReq3 {
+            tp_block_size: block_size,
+            tp_block_nr: block_nr,
+            tp_frame_size: frame_size,
+            tp_frame_nr: block_size / frame_size * block_nr,
+            ..self.clone()
+        }
+    }
+}
+
+fn invalid(msg: String) -> Error {
+    Error::InvalidGeometry(msg)
+}
+
+#[inline]
+fn tpacket_align(x: usize) -> usize {
+    let align = TPACKET_ALIGNMENT as usize;
+    (x + align - 1) & !(align - 1)
+}
+
+///Lays out packets in a buffer the same way the kernel fills a TPACKET_V3 block, so that
+///packets that did not come from a live ring can be handed out as a `Block`
+#[derive(Clone, Debug)]
+pub struct BlockBuilder {
+    buf: Vec<u8>,
+    num_pkts: u32,
+    last_offset: usize,
+    next_offset: usize,
+}
+
+impl BlockBuilder {
+    ///Starts an empty block of `block_size` bytes with the given sequence number
+    pub fn new(block_size: usize, seq_num: u64) -> BlockBuilder {
+        let mut buf = vec![0; block_size.max(TPACKET_BLOCK_DESC_LEN as usize)];
+        buf[0..4].copy_from_slice(&1u32.to_le_bytes());
+        buf[4..8].copy_from_slice(&TPACKET_BLOCK_DESC_LEN.to_le_bytes());
+        buf[16..20].copy_from_slice(&TPACKET_BLOCK_DESC_LEN.to_le_bytes());
+        buf[24..32].copy_from_slice(&seq_num.to_le_bytes());
+        BlockBuilder {
+            buf,
+            num_pkts: 0,
+            last_offset: 0,
+            next_offset: TPACKET_BLOCK_DESC_LEN as usize,
+        }
+    }
+
+    ///Number of packets added so far
+    pub fn len(&self) -> u32 {
+        self.num_pkts
+    }
+
+    ///Whether no packet was added yet
+    pub fn is_empty(&self) -> bool {
+        self.num_pkts == 0
+    }
+
+    ///Bytes of packet data that still fit in the block
+    pub fn remaining(&self) -> usize {
+        let data_start = self.next_offset + tpacket_align(TPACKET3_HDRLEN as usize);
+        self.buf.len().saturating_sub(data_start)
+    }
+
+    ///Appends a packet, returns false if it does not fit in the remaining space
+    ///
+    ///Timestamps, `tp_len`, `tp_status` and `hv1` are taken from `hdr` as they are; offsets and
+    ///`tp_snaplen` are computed here, except `tp_net` which is read as the offset of the network
+    ///header from the start of `data`. `tp_len` of 0 means the packet was not truncated.
+    pub fn push(&mut self, hdr: &Tpacket3Hdr, sll: &SockAddrLl, data: &[u8]) -> bool {
+        if data.len() > self.remaining() {
+            return false;
+        }
+        let offset = self.next_offset;
+        let mac = tpacket_align(TPACKET3_HDRLEN as usize);
+        let len = if hdr.tp_len == 0 {
+            data.len() as u32
+        } else {
+            hdr.tp_len
+        };
+
+        let frame = &mut self.buf[offset..];
+        frame[4..8].copy_from_slice(&hdr.tp_sec.to_le_bytes());
+        frame[8..12].copy_from_slice(&hdr.tp_nsec.to_le_bytes());
+        frame[12..16].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        frame[16..20].copy_from_slice(&len.to_le_bytes());
+        frame[20..24].copy_from_slice(&hdr.tp_status.to_le_bytes());
+        frame[24..26].copy_from_slice(&(mac as u16).to_le_bytes());
+        frame[26..28].copy_from_slice(&(mac as u16 + hdr.tp_net).to_le_bytes());
+        frame[28..32].copy_from_slice(&hdr.hv1.tp_rxhash.to_le_bytes());
+        frame[32..36].copy_from_slice(&hdr.hv1.tp_vlan_tci.to_le_bytes());
+        frame[36..38].copy_from_slice(&hdr.hv1.tp_vlan_tpid.to_le_bytes());
+
+        let sll_frame = &mut frame[TPACKET3_SLL_OFFSET..];
+        sll_frame[0..2].copy_from_slice(&sll.sll_family.to_le_bytes());
+        sll_frame[2..4].copy_from_slice(&sll.sll_protocol.to_be_bytes());
+        sll_frame[4..8].copy_from_slice(&sll.sll_ifindex.to_le_bytes());
+        sll_frame[8..10].copy_from_slice(&sll.sll_hatype.to_le_bytes());
+        sll_frame[10] = sll.sll_pkttype;
+        sll_frame[11] = sll.sll_halen;
+        sll_frame[12..20].copy_from_slice(&sll.sll_addr);
+
+        frame[mac..mac + data.len()].copy_from_slice(data);
+
+        if self.num_pkts > 0 {
+            let prev = self.last_offset;
+            let next = (offset - prev) as u32;
+            self.buf[prev..prev + 4].copy_from_slice(&next.to_le_bytes());
+        } else {
+            self.buf[32..36].copy_from_slice(&hdr.tp_sec.to_le_bytes());
+            self.buf[36..40].copy_from_slice(&hdr.tp_nsec.to_le_bytes());
+        }
+        self.buf[40..44].copy_from_slice(&hdr.tp_sec.to_le_bytes());
+        self.buf[44..48].copy_from_slice(&hdr.tp_nsec.to_le_bytes());
+
+        self.num_pkts += 1;
+        self.last_offset = offset;
+        self.next_offset = tpacket_align(offset + mac + data.len());
+        true
+    }
+
+    ///Marks the block as handed over to userspace and returns its bytes
+    pub fn finish(mut self) -> Vec<u8> {
+        let blk_len = self.next_offset.min(self.buf.len()) as u32;
+        self.buf[8..12].copy_from_slice(&(TP_STATUS_USER as u32).to_le_bytes());
+        self.buf[12..16].copy_from_slice(&self.num_pkts.to_le_bytes());
+        self.buf[20..24].copy_from_slice(&blk_len.to_le_bytes());
+        self.buf
+    }
 }
 
 named!(
@@ -178,3 +527,34 @@ named!(
         })
     )
 );
+
+named!(
+    pub get_sockaddr_ll<SockAddrLl>,
+    do_parse!(
+        sll_family: le_u16
+            >> sll_protocol: be_u16
+            >> sll_ifindex: le_i32
+            >> sll_hatype: le_u16
+            >> sll_pkttype: le_u8
+            >> sll_halen: le_u8
+            >> sll_addr: take!(8)
+            >> (SockAddrLl {
+                sll_family,
+                sll_protocol,
+                sll_ifindex,
+                sll_hatype,
+                sll_pkttype,
+                sll_halen,
+                sll_addr: [
+                    sll_addr[0],
+                    sll_addr[1],
+                    sll_addr[2],
+                    sll_addr[3],
+                    sll_addr[4],
+                    sll_addr[5],
+                    sll_addr[6],
+                    sll_addr[7]
+                ]
+            })
+    )
+);
@@ -0,0 +1,344 @@
+//!Views of the inner packets of VXLAN, GENEVE and GRE tunnels
+//!
+//!Overlay traffic is decapsulated in place: the inner frame is a slice of the outer packet,
+//!so it can go through the same code as native traffic without copying. Tunnels are
+//!recognized by their IP protocol and well-known UDP ports; IPv4 fragments other than the
+//!first one and IPv6 packets with extension headers are not looked into.
+
+use crate::filters::IPPROTO_UDP;
+use crate::rx::RawPacket;
+
+///UDP port of VXLAN (RFC 7348)
+pub const VXLAN_PORT: u16 = 4789;
+///UDP port Linux used for VXLAN before it was standardized
+pub const VXLAN_LINUX_PORT: u16 = 8472;
+///UDP port of GENEVE (RFC 8926)
+pub const GENEVE_PORT: u16 = 6081;
+
+const IPPROTO_GRE: u8 = 47;
+//protocol type of Ethernet frames carried by GRE and GENEVE
+const ETH_P_TEB: u16 = 0x6558;
+const ETH_P_8021Q: u16 = 0x8100;
+const ETH_P_8021AD: u16 = 0x88a8;
+
+const GRE_CSUM: u16 = 0x8000;
+const GRE_KEY: u16 = 0x2000;
+const GRE_SEQ: u16 = 0x1000;
+const GRE_VERSION: u16 = 0x0007;
+
+///Tunnel a packet was carried in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encapsulation {
+    Vxlan {
+        ///VXLAN network identifier
+        vni: u32,
+    },
+    Geneve {
+        ///Virtual network identifier
+        vni: u32,
+    },
+    Gre {
+        ///Key field, e.g. the NVGRE virtual subnet id
+        key: Option<u32>,
+    },
+}
+
+///Inner packet of a tunnel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InnerFrame<'a> {
+    ///Ethernet frame, from its destination MAC on
+    Ethernet(&'a [u8]),
+    ///Packet without a link-layer header, e.g. IP over GRE
+    Network { ethertype: u16, data: &'a [u8] },
+}
+
+///Decapsulated packet, see `decapsulate()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tunneled<'a> {
+    pub encapsulation: Encapsulation,
+    pub inner: InnerFrame<'a>,
+}
+
+impl<'a> InnerFrame<'a> {
+    ///Ethertype of the inner packet, after any VLAN tags of an inner Ethernet frame
+    pub fn ethertype(&self) -> Option<u16> {
+        match *self {
+            InnerFrame::Ethernet(frame) => ethernet_payload(frame).map(|(ethertype, _)| ethertype),
+            InnerFrame::Network { ethertype, .. } => Some(ethertype),
+        }
+    }
+
+    ///Network header of the inner packet and everything after it, e.g. to decapsulate nested
+    ///tunnels with `decapsulate()`
+    pub fn network(&self) -> Option<&'a [u8]> {
+        match *self {
+            InnerFrame::Ethernet(frame) => ethernet_payload(frame).map(|(_, data)| data),
+            InnerFrame::Network { data, .. } => Some(data),
+        }
+    }
+}
+
+impl<'a> RawPacket<'a> {
+    ///Inner packet if this one is a VXLAN, GENEVE or GRE tunnel packet
+    pub fn decapsulate(&self) -> Option<Tunneled<'a>> {
+        decapsulate(self.l3_payload())
+    }
+}
+
+///Inner packet of the IPv4 or IPv6 packet `l3`, if it is a VXLAN, GENEVE or GRE tunnel packet
+pub fn decapsulate(l3: &[u8]) -> Option<Tunneled<'_>> {
+    let (protocol, l4) = transport(l3)?;
+    match protocol {
+        IPPROTO_GRE => gre(l4),
+        IPPROTO_UDP => {
+            let dport = u16::from_be_bytes([*l4.get(2)?, *l4.get(3)?]);
+            let payload = l4.get(8..)?;
+            match dport {
+                VXLAN_PORT | VXLAN_LINUX_PORT => vxlan(payload),
+                GENEVE_PORT => geneve(payload),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+//IP protocol and payload of an IP packet
+fn transport(l3: &[u8]) -> Option<(u8, &[u8])> {
+    match l3.first()? >> 4 {
+        4 => {
+            let ihl = (l3[0] & 0x0f) as usize * 4;
+            if ihl < 20 {
+                return None;
+            }
+            let frag = u16::from_be_bytes([*l3.get(6)?, *l3.get(7)?]);
+            if frag & 0x1fff != 0 {
+                return None;
+            }
+            Some((*l3.get(9)?, l3.get(ihl..)?))
+        }
+        6 => Some((*l3.get(6)?, l3.get(40..)?)),
+        _ => None,
+    }
+}
+
+fn vxlan(payload: &[u8]) -> Option<Tunneled<'_>> {
+    let hdr = payload.get(..8)?;
+    //the I flag marks a valid VNI
+    if hdr[0] & 0x08 == 0 {
+        return None;
+    }
+    Some(Tunneled {
+        encapsulation: Encapsulation::Vxlan {
+            vni: u32::from_be_bytes([0, hdr[4], hdr[5], hdr[6]]),
+        },
+        inner: InnerFrame::Ethernet(&payload[8..]),
+    })
+}
+
+fn geneve(payload: &[u8]) -> Option<Tunneled<'_>> {
+    let hdr = payload.get(..8)?;
+    if hdr[0] >> 6 != 0 {
+        return None;
+    }
+    let len = 8 + (hdr[0] & 0x3f) as usize * 4;
+    let protocol = u16::from_be_bytes([hdr[2], hdr[3]]);
+    Some(Tunneled {
+        encapsulation: Encapsulation::Geneve {
+            vni: u32::from_be_bytes([0, hdr[4], hdr[5], hdr[6]]),
+        },
+        inner: inner(protocol, payload.get(len..)?),
+    })
+}
+
+fn gre(l4: &[u8]) -> Option<Tunneled<'_>> {
+    let flags = u16::from_be_bytes([*l4.first()?, *l4.get(1)?]);
+    //version 1 is PPTP's enhanced GRE
+    if flags & GRE_VERSION != 0 {
+        return None;
+    }
+    let protocol = u16::from_be_bytes([*l4.get(2)?, *l4.get(3)?]);
+    let mut len = 4;
+    if flags & GRE_CSUM != 0 {
+        len += 4;
+    }
+    let key = if flags & GRE_KEY != 0 {
+        let key = l4.get(len..len + 4)?;
+        len += 4;
+        Some(u32::from_be_bytes([key[0], key[1], key[2], key[3]]))
+    } else {
+        None
+    };
+    if flags & GRE_SEQ != 0 {
+        len += 4;
+    }
+    Some(Tunneled {
+        encapsulation: Encapsulation::Gre { key },
+        inner: inner(protocol, l4.get(len..)?),
+    })
+}
+
+fn inner(protocol: u16, data: &[u8]) -> InnerFrame<'_> {
+    match protocol {
+        ETH_P_TEB => InnerFrame::Ethernet(data),
+        ethertype => InnerFrame::Network { ethertype, data },
+    }
+}
+
+//ethertype and payload of an Ethernet frame, after up to two VLAN tags
+fn ethernet_payload(frame: &[u8]) -> Option<(u16, &[u8])> {
+    let mut at = 12;
+    for _ in 0..3 {
+        let ethertype = u16::from_be_bytes([*frame.get(at)?, *frame.get(at + 1)?]);
+        if ethertype != ETH_P_8021Q && ethertype != ETH_P_8021AD {
+            return Some((ethertype, frame.get(at + 2..)?));
+        }
+        at += 4;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::frames::{self, ETH_P_IP, IPPROTO_UDP};
+
+    const OUTER_SRC: [u8; 4] = [192, 0, 2, 1];
+    const OUTER_DST: [u8; 4] = [192, 0, 2, 2];
+
+    fn inner_frame() -> Vec<u8> {
+        frames::udp4([10, 0, 0, 1], [10, 0, 0, 2], 40000, 53)
+    }
+
+    fn over_udp(dport: u16, tunnel_hdr: &[u8], inner: &[u8]) -> Vec<u8> {
+        let mut payload = tunnel_hdr.to_vec();
+        payload.extend_from_slice(inner);
+        frames::ipv4(
+            IPPROTO_UDP,
+            OUTER_SRC,
+            OUTER_DST,
+            &frames::udp(50000, dport, &payload),
+        )
+    }
+
+    fn over_gre(gre_hdr: &[u8], inner: &[u8]) -> Vec<u8> {
+        let mut payload = gre_hdr.to_vec();
+        payload.extend_from_slice(inner);
+        frames::ipv4(IPPROTO_GRE, OUTER_SRC, OUTER_DST, &payload)
+    }
+
+    #[test]
+    fn vxlan() {
+        let inner = inner_frame();
+        let vxlan_hdr = [0x08, 0, 0, 0, 0x12, 0x34, 0x56, 0];
+        for &port in &[VXLAN_PORT, VXLAN_LINUX_PORT] {
+            let packet = over_udp(port, &vxlan_hdr, &inner);
+            let tunneled = decapsulate(&packet).unwrap();
+            assert_eq!(
+                tunneled.encapsulation,
+                Encapsulation::Vxlan { vni: 0x12_3456 }
+            );
+            assert_eq!(tunneled.inner, InnerFrame::Ethernet(&inner));
+            assert_eq!(tunneled.inner.ethertype(), Some(ETH_P_IP));
+            assert_eq!(tunneled.inner.network(), Some(&inner[14..]));
+        }
+        //without the I flag the VNI is not valid
+        let no_vni = [0, 0, 0, 0, 0x12, 0x34, 0x56, 0];
+        assert!(decapsulate(&over_udp(VXLAN_PORT, &no_vni, &inner)).is_none());
+        assert!(decapsulate(&over_udp(4790, &vxlan_hdr, &inner)).is_none());
+    }
+
+    #[test]
+    fn geneve_skips_options() {
+        let inner = inner_frame();
+        //one 4 byte option
+        let hdr = [1, 0, 0x65, 0x58, 0, 0, 42, 0, 0x01, 0x02, 0x03, 0x04];
+        let packet = over_udp(GENEVE_PORT, &hdr, &inner);
+        let tunneled = decapsulate(&packet).unwrap();
+        assert_eq!(tunneled.encapsulation, Encapsulation::Geneve { vni: 42 });
+        assert_eq!(tunneled.inner, InnerFrame::Ethernet(&inner));
+
+        //GENEVE may carry IP without an Ethernet header
+        let hdr = [0, 0, 0x08, 0x00, 0, 0, 7, 0];
+        let packet = over_udp(GENEVE_PORT, &hdr, &inner[14..]);
+        let tunneled = decapsulate(&packet).unwrap();
+        assert_eq!(tunneled.inner.network(), Some(&inner[14..]));
+        //unknown version
+        let hdr = [0x40, 0, 0x65, 0x58, 0, 0, 7, 0];
+        assert!(decapsulate(&over_udp(GENEVE_PORT, &hdr, &inner)).is_none());
+    }
+
+    #[test]
+    fn gre() {
+        let inner = inner_frame();
+        let plain = [0, 0, 0x08, 0x00];
+        let packet = over_gre(&plain, &inner[14..]);
+        let tunneled = decapsulate(&packet).unwrap();
+        assert_eq!(tunneled.encapsulation, Encapsulation::Gre { key: None });
+        assert_eq!(
+            tunneled.inner,
+            InnerFrame::Network {
+                ethertype: ETH_P_IP,
+                data: &inner[14..]
+            }
+        );
+
+        //checksum, key and sequence number, carrying Ethernet as NVGRE does
+        let mut full = vec![0xb0, 0, 0x65, 0x58, 0xaa, 0xaa, 0, 0];
+        full.extend_from_slice(&0x0102_0304u32.to_be_bytes());
+        full.extend_from_slice(&[0, 0, 0, 9]);
+        let packet = over_gre(&full, &inner);
+        let tunneled = decapsulate(&packet).unwrap();
+        assert_eq!(
+            tunneled.encapsulation,
+            Encapsulation::Gre {
+                key: Some(0x0102_0304)
+            }
+        );
+        assert_eq!(tunneled.inner, InnerFrame::Ethernet(&inner));
+
+        //enhanced GRE of PPTP
+        let pptp = [0x30, 0x01, 0x88, 0x0b, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(decapsulate(&over_gre(&pptp, &inner)).is_none());
+    }
+
+    #[test]
+    fn nested_and_ipv6_tunnels() {
+        let inner = inner_frame();
+        let vxlan_hdr = [0x08, 0, 0, 0, 0, 0, 1, 0];
+        let mut outer_payload = vxlan_hdr.to_vec();
+        outer_payload.extend_from_slice(&inner);
+        let udp = frames::udp(50000, VXLAN_PORT, &outer_payload);
+        let v6 = frames::ipv6(
+            IPPROTO_UDP,
+            "2001:db8::1".parse().unwrap(),
+            "2001:db8::2".parse().unwrap(),
+            &udp,
+        );
+        let tunneled = decapsulate(&v6).unwrap();
+        assert_eq!(tunneled.encapsulation, Encapsulation::Vxlan { vni: 1 });
+
+        //GRE inside VXLAN
+        let gre = over_gre(&[0, 0, 0x08, 0x00], &inner[14..]);
+        let outer = over_udp(VXLAN_PORT, &vxlan_hdr, &frames::ethernet(ETH_P_IP, &gre));
+        let first = decapsulate(&outer).unwrap();
+        let second = decapsulate(first.inner.network().unwrap()).unwrap();
+        assert_eq!(second.inner.network(), Some(&inner[14..]));
+    }
+
+    #[test]
+    fn ignores_non_first_fragments_and_garbage() {
+        let inner = inner_frame();
+        let mut fragment = over_udp(VXLAN_PORT, &[0x08, 0, 0, 0, 0, 0, 1, 0], &inner);
+        assert!(decapsulate(&fragment).is_some());
+        fragment[6..8].copy_from_slice(&[0, 8]);
+        assert!(decapsulate(&fragment).is_none());
+
+        assert!(decapsulate(&[]).is_none());
+        assert!(decapsulate(&[0x45, 0, 0]).is_none());
+        assert!(decapsulate(&inner[14..]).is_none());
+        //cut short in the middle of the VXLAN header
+        let cut = over_udp(VXLAN_PORT, &[0x08, 0, 0], &[]);
+        assert!(decapsulate(&cut).is_none());
+    }
+}
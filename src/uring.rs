@@ -0,0 +1,155 @@
+//!Block waiting through io_uring poll requests instead of poll(), enabled with the `io-uring`
+//!feature
+//!
+//!In multishot mode a single IORING_OP_POLL_ADD keeps firing for every wakeup, so a busy ring
+//!costs one io_uring_enter() per wait and nothing to re-arm it.
+
+use std::fmt;
+
+use io_uring::{cqueue, opcode, types, IoUring};
+use libc::{POLLERR, POLLIN};
+
+use crate::error::{Error, Result};
+use crate::rx::{Block, Ring};
+use crate::source::PacketSource;
+
+const SOCKET_TOKEN: u64 = 1;
+const SHUTDOWN_TOKEN: u64 = 2;
+const QUEUE_DEPTH: u32 = 8;
+
+///Ring whose readiness is awaited with io_uring poll submissions
+pub struct UringRing {
+    ring: Ring,
+    uring: IoUring,
+    multishot: bool,
+    socket_armed: bool,
+    shutdown_armed: bool,
+}
+
+impl fmt::Debug for UringRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UringRing")
+            .field("ring", &self.ring)
+            .field("multishot", &self.multishot)
+            .finish()
+    }
+}
+
+impl UringRing {
+    ///Wraps a ring, re-arming a one-shot poll for every wait
+    pub fn new(ring: Ring) -> Result<UringRing> {
+        UringRing::with_mode(ring, false)
+    }
+
+    ///Wraps a ring with a multishot poll that stays armed across waits (Linux 5.13+)
+    pub fn multishot(ring: Ring) -> Result<UringRing> {
+        UringRing::with_mode(ring, true)
+    }
+
+    fn with_mode(ring: Ring, multishot: bool) -> Result<UringRing> {
+        let uring = IoUring::new(QUEUE_DEPTH).map_err(|e| Error::os("io_uring_setup", e))?;
+        Ok(UringRing {
+            ring,
+            uring,
+            multishot,
+            socket_armed: false,
+            shutdown_armed: false,
+        })
+    }
+
+    ///Waits for the next block, returns `Error::Shutdown` once the ring's `ShutdownHandle` is
+    ///signaled; set the handle up before the first call
+    pub fn recv_block(&mut self) -> Result<Block<'_>> {
+        loop {
+            if let Some(block) = self.ring.next_ready_block() {
+                return Ok(block);
+            }
+            if self.wait()? {
+                return Err(Error::Shutdown);
+            }
+        }
+    }
+
+    ///Submits whatever polls are not armed yet and blocks until one completes, returns true
+    ///if the shutdown handle fired
+    fn wait(&mut self) -> Result<bool> {
+        if !self.socket_armed {
+            let entry =
+                opcode::PollAdd::new(types::Fd(self.ring.socket.fd), (POLLIN | POLLERR) as u32)
+                    .multi(self.multishot)
+                    .build()
+                    .user_data(SOCKET_TOKEN);
+            self.push(&entry)?;
+            self.socket_armed = true;
+        }
+        if !self.shutdown_armed {
+            if let Some(handle) = self.ring.get_shutdown_handle() {
+                let entry = opcode::PollAdd::new(types::Fd(handle.fd()), POLLIN as u32)
+                    .build()
+                    .user_data(SHUTDOWN_TOKEN);
+                self.push(&entry)?;
+                self.shutdown_armed = true;
+            }
+        }
+
+        match self.uring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(ref e) if e.raw_os_error() == Some(libc::EINTR) => return Ok(false),
+            Err(e) => return Err(Error::os("io_uring_enter", e)),
+        }
+
+        let mut shutdown = false;
+        for cqe in self.uring.completion() {
+            if cqe.result() < 0 {
+                let err = std::io::Error::from_raw_os_error(-cqe.result());
+                return Err(Error::os("io_uring poll", err));
+            }
+            match cqe.user_data() {
+                //a multishot poll stays armed until the kernel drops the MORE flag
+                SOCKET_TOKEN if !cqueue::more(cqe.flags()) => self.socket_armed = false,
+                SHUTDOWN_TOKEN => {
+                    self.shutdown_armed = false;
+                    shutdown = true;
+                }
+                _ => {}
+            }
+        }
+        Ok(shutdown)
+    }
+
+    fn push(&mut self, entry: &io_uring::squeue::Entry) -> Result<()> {
+        //the queue is deeper than the two requests ever in flight
+        unsafe { self.uring.submission().push(entry) }
+            .map_err(|_| Error::os("io_uring", std::io::Error::other("submission queue full")))
+    }
+
+    ///Whether the socket poll is submitted as multishot
+    pub fn is_multishot(&self) -> bool {
+        self.multishot
+    }
+
+    ///Underlying ring, e.g. for statistics
+    pub fn get_ref(&self) -> &Ring {
+        &self.ring
+    }
+
+    ///Underlying ring, e.g. for statistics
+    pub fn get_mut(&mut self) -> &mut Ring {
+        &mut self.ring
+    }
+
+    ///Tears down the io_uring instance and returns the ring
+    pub fn into_inner(self) -> Ring {
+        self.ring
+    }
+}
+
+impl PacketSource for UringRing {
+    fn next_block(&mut self) -> Result<Option<Block<'_>>> {
+        match self.recv_block() {
+            Ok(block) => Ok(Some(block)),
+            Err(Error::Shutdown) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//!Formatting helpers for debugging output: hex dumps and one-line packet summaries
+//!
+//!Both are `Display` wrappers that format straight into the output, call `to_string()` on
+//!them for a `String`.
+
+use std::fmt;
+use std::time::UNIX_EPOCH;
+
+use crate::rx::{PacketDirection, RawPacket};
+
+const SUMMARY_BYTES: usize = 16;
+
+///Hex dump of `data` in the style of `hexdump -C`, see `hexdump()`
+#[derive(Clone, Copy, Debug)]
+pub struct HexDump<'a>(&'a [u8]);
+
+///One-line summary of a packet, see `RawPacket::summary()`
+#[derive(Clone, Copy, Debug)]
+pub struct Summary<'a, 'p>(&'p RawPacket<'a>);
+
+///Formats `data` as lines of 16 bytes with their offset and the printable ones as ASCII:
+///
+///```text
+///0000  ff ff ff ff ff ff 02 00  00 00 00 01 08 00 45 00  |..............E.|
+///```
+pub fn hexdump(data: &[u8]) -> HexDump<'_> {
+    HexDump(data)
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, line) in self.0.chunks(16).enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:04x} ", i * 16)?;
+            for j in 0..16 {
+                if j == 8 {
+                    write!(f, " ")?;
+                }
+                match line.get(j) {
+                    Some(byte) => write!(f, " {:02x}", byte)?,
+                    None => write!(f, "   ")?,
+                }
+            }
+            write!(f, "  |")?;
+            for &byte in line {
+                let c = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                };
+                write!(f, "{}", c)?;
+            }
+            write!(f, "|")?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> RawPacket<'a> {
+    ///Timestamp, direction, lengths, VLAN tag and first bytes of the packet on one line:
+    ///
+    ///```text
+    ///1697581330.965536123 host len 114 cap 60 truncated vlan 10: ff ff ff ff ff ff 02 00 ...
+    ///```
+    ///
+    ///The precision sets how many bytes are shown, 16 by default: `format!("{:.64}", summary)`.
+    pub fn summary<'p>(&'p self) -> Summary<'a, 'p> {
+        Summary(self)
+    }
+}
+
+impl fmt::Display for Summary<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let packet = self.0;
+        let hdr = &packet.tpacket3_hdr;
+        let since_epoch = packet
+            .timestamp()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        write!(
+            f,
+            "{}.{:09}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_nanos()
+        )?;
+        if let Some(direction) = packet.direction() {
+            write!(f, " {}", direction_name(direction))?;
+        }
+        write!(f, " len {} cap {}", hdr.tp_len, hdr.tp_snaplen)?;
+        if packet.truncated() {
+            write!(f, " truncated")?;
+        }
+        if let Some(vlan) = packet.vlan() {
+            write!(f, " vlan {}", vlan.id())?;
+        }
+        let payload = packet.payload();
+        let shown = payload.len().min(f.precision().unwrap_or(SUMMARY_BYTES));
+        write!(f, ":")?;
+        for byte in &payload[..shown] {
+            write!(f, " {:02x}", byte)?;
+        }
+        if shown < payload.len() {
+            write!(f, " ...")?;
+        }
+        Ok(())
+    }
+}
+
+fn direction_name(direction: PacketDirection) -> String {
+    match direction {
+        PacketDirection::Host => String::from("host"),
+        PacketDirection::Broadcast => String::from("broadcast"),
+        PacketDirection::Multicast => String::from("multicast"),
+        PacketDirection::OtherHost => String::from("otherhost"),
+        PacketDirection::Outgoing => String::from("outgoing"),
+        PacketDirection::Other(pkttype) => format!("pkttype {}", pkttype),
+    }
+}
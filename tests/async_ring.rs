@@ -0,0 +1,215 @@
+//!Timeouts and cancellation of `AsyncRing` on a veth pair, skipped where one cannot be created
+
+use std::future::{self, Future};
+use std::pin::{pin, Pin};
+use std::task::{Context, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures_core::Stream;
+
+use af_packet::async_ring::{AsyncRing, RecvHalf};
+use af_packet::filter::FilterProgram;
+use af_packet::rx::Ring;
+use af_packet::shutdown::ShutdownHandle;
+use af_packet::socket::EtherType;
+use af_packet::test_util::VethPair;
+use af_packet::Error;
+
+//ethertype reserved for local experiments, so that the ring sees nothing but the test frames
+const ETH_P_LOCAL: u16 = 0x88b5;
+
+fn frame(n: u32) -> Vec<u8> {
+    let mut frame = vec![0xff; 6];
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+    frame.extend_from_slice(&ETH_P_LOCAL.to_be_bytes());
+    frame.extend_from_slice(&n.to_be_bytes());
+    frame.resize(60, 0);
+    frame
+}
+
+fn number(payload: &[u8]) -> u32 {
+    u32::from_be_bytes([payload[14], payload[15], payload[16], payload[17]])
+}
+
+//ring on the `rx` end of a new veth pair that only captures the test frames
+fn open(prefix: &str) -> Option<(VethPair, AsyncRing)> {
+    let veth = match VethPair::create(prefix) {
+        Ok(veth) => veth,
+        Err(e) => {
+            eprintln!("skipping, cannot create a veth pair: {}", e);
+            return None;
+        }
+    };
+    let mut settings = veth.ring_settings();
+    settings.protocol = EtherType::Other(ETH_P_LOCAL);
+    let mut ring = Ring::new(settings).unwrap();
+    ring.set_shutdown_handle(ShutdownHandle::new().unwrap());
+    let ring = AsyncRing::new(ring).unwrap();
+    Some((veth, ring))
+}
+
+//receives blocks until `count` packets arrived, returns their numbers
+fn receive(ring: &mut AsyncRing, count: usize) -> Vec<u32> {
+    let mut seen = Vec::new();
+    while seen.len() < count {
+        let mut block = async_io::block_on(ring.recv_block_timeout(Duration::from_secs(2)))
+            .unwrap()
+            .expect("timed out waiting for the injected frames");
+        seen.extend(block.get_raw_packets().iter().map(|p| number(p.payload())));
+        block.mark_as_consumed();
+    }
+    seen
+}
+
+//numbers of the packets in the next block of `recv`, None if none arrived within `timeout`
+fn poll_numbers(recv: &mut RecvHalf, timeout: Duration) -> Option<Vec<u32>> {
+    let block = async_io::block_on(recv.recv_block_timeout(timeout)).unwrap();
+    block.map(|mut block| {
+        let numbers = block
+            .get_raw_packets()
+            .iter()
+            .map(|p| number(p.payload()))
+            .collect();
+        block.mark_as_consumed();
+        numbers
+    })
+}
+
+#[test]
+fn recv_block_timeout_expires_on_an_idle_ring() {
+    let (veth, mut ring) = match open("afto") {
+        Some(opened) => opened,
+        None => return,
+    };
+    let timeout = Duration::from_millis(100);
+    let start = Instant::now();
+    let res = async_io::block_on(ring.recv_block_timeout(timeout)).unwrap();
+    let elapsed = start.elapsed();
+    assert!(res.is_none());
+    assert!(elapsed >= timeout, "returned after {:?}", elapsed);
+    assert!(elapsed < timeout * 10, "returned after {:?}", elapsed);
+
+    //a timeout leaves the ring working
+    for n in 0..5 {
+        veth.inject(&frame(n)).unwrap();
+    }
+    assert_eq!(receive(&mut ring, 5), vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn dropping_a_pending_receive_loses_nothing() {
+    let (veth, mut ring) = match open("afcancel") {
+        Some(opened) => opened,
+        None => return,
+    };
+    let mut cx = Context::from_waker(Waker::noop());
+    {
+        //cancelled before its timer fires
+        let mut recv = pin!(ring.recv_block_timeout(Duration::from_secs(60)));
+        assert!(recv.as_mut().poll(&mut cx).is_pending());
+    }
+    {
+        let mut recv = pin!(ring.recv_block());
+        assert!(recv.as_mut().poll(&mut cx).is_pending());
+        //packets arrive while the task is registered, then it is cancelled
+        for n in 0..5 {
+            veth.inject(&frame(n)).unwrap();
+        }
+    }
+    for n in 5..10 {
+        veth.inject(&frame(n)).unwrap();
+    }
+    assert_eq!(receive(&mut ring, 10), (0..10).collect::<Vec<_>>());
+}
+
+#[test]
+fn shutdown_ends_a_pending_receive() {
+    let (_veth, mut ring) = match open("afstop") {
+        Some(opened) => opened,
+        None => return,
+    };
+    let shutdown = ring.get_ref().get_shutdown_handle().unwrap().clone();
+    let signal = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        shutdown.signal();
+    });
+    let res = async_io::block_on(ring.recv_block_timeout(Duration::from_secs(10)));
+    assert!(matches!(res, Err(Error::Shutdown)));
+    signal.join().unwrap();
+}
+
+#[test]
+fn control_half_filters_and_stops_the_receiving_half() {
+    let (veth, ring) = match open("afsplit") {
+        Some(opened) => opened,
+        None => return,
+    };
+    let (mut recv, mut control) = ring.split().unwrap();
+    let short = Duration::from_millis(50);
+
+    //a filter takes effect with the next receive
+    control.set_filter(&FilterProgram::drop_all()).unwrap();
+    assert_eq!(poll_numbers(&mut recv, short), None);
+    for n in 0..3 {
+        veth.inject(&frame(n)).unwrap();
+    }
+    assert_eq!(poll_numbers(&mut recv, Duration::from_millis(200)), None);
+
+    control.clear_filter().unwrap();
+    assert_eq!(poll_numbers(&mut recv, short), None);
+    for n in 3..6 {
+        veth.inject(&frame(n)).unwrap();
+    }
+    let mut seen = Vec::new();
+    while seen.len() < 3 {
+        seen.extend(poll_numbers(&mut recv, Duration::from_secs(2)).expect("timed out"));
+    }
+    assert_eq!(seen, vec![3, 4, 5]);
+    assert_eq!(control.statistics().unwrap().packets, 3);
+
+    control.shutdown();
+    let res = async_io::block_on(recv.recv_block_timeout(Duration::from_secs(10)));
+    assert!(matches!(res, Err(Error::Shutdown)));
+
+    //the control half keeps the socket open after the receiving half is gone
+    drop(recv);
+    veth.inject(&frame(6)).unwrap();
+    let mut packets = 0;
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while packets == 0 && Instant::now() < deadline {
+        packets += control.statistics().unwrap().packets;
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(packets, 1);
+}
+
+#[test]
+fn block_stream_yields_lent_blocks_until_shutdown() {
+    let (veth, mut ring) = match open("afbs") {
+        Some(opened) => opened,
+        None => return,
+    };
+    let shutdown = ring.get_ref().get_shutdown_handle().unwrap().clone();
+    for n in 0..5 {
+        veth.inject(&frame(n)).unwrap();
+    }
+    let mut stream = ring.block_stream();
+    let mut next = || async_io::block_on(future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)));
+
+    let mut blocks = Vec::new();
+    let mut seen = Vec::new();
+    while seen.len() < 5 {
+        let block = next().expect("stream ended early").unwrap();
+        let packets = block.get_raw_packets();
+        assert!(!packets.is_empty());
+        seen.extend(packets.iter().map(|p| number(p.payload())));
+        //kept alive while the stream is polled on
+        blocks.push(block);
+    }
+    assert_eq!(seen, vec![0, 1, 2, 3, 4]);
+
+    shutdown.signal();
+    assert!(next().is_none());
+    assert!(next().is_none());
+}
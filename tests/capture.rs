@@ -0,0 +1,254 @@
+//!Capture facade, dispatcher, stats monitor and adaptive ring on a veth pair, skipped where one
+//!cannot be created
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use af_packet::adaptive::{AdaptiveRing, Retune};
+use af_packet::capture::Capture;
+use af_packet::dispatch::{self, DispatchSettings};
+use af_packet::monitor::{Alert, AlertKind, MonitorSettings, StatsMonitor};
+use af_packet::rx::{FanoutMethod, Ring};
+use af_packet::socket::EtherType;
+use af_packet::test_util::VethPair;
+
+//ethertype reserved for local experiments, so that the rings see nothing but the test frames
+const ETH_P_LOCAL: u16 = 0x88b5;
+
+fn frame(n: u32) -> Vec<u8> {
+    let mut frame = vec![0xff; 6];
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+    frame.extend_from_slice(&ETH_P_LOCAL.to_be_bytes());
+    frame.extend_from_slice(&n.to_be_bytes());
+    frame.resize(60, 0);
+    frame
+}
+
+fn number(payload: &[u8]) -> u32 {
+    u32::from_be_bytes([payload[14], payload[15], payload[16], payload[17]])
+}
+
+fn create(prefix: &str) -> Option<VethPair> {
+    match VethPair::create(prefix) {
+        Ok(veth) => Some(veth),
+        Err(e) => {
+            eprintln!("skipping, cannot create a veth pair: {}", e);
+            None
+        }
+    }
+}
+
+//capture of two rings on the `rx` end that only sees the test frames
+fn capture(veth: &VethPair) -> Capture {
+    let settings = veth.ring_settings();
+    Capture::builder()
+        .interface(&veth.rx)
+        .protocol(EtherType::Other(ETH_P_LOCAL))
+        .fanout_method(FanoutMethod::Lb)
+        .ring_settings(settings.ring_settings)
+        .workers(2)
+        .open()
+        .unwrap()
+}
+
+//waits until `done` holds, for at most two seconds
+fn wait_until<F: FnMut() -> bool>(mut done: F) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while Instant::now() < deadline {
+        if done() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    done()
+}
+
+#[test]
+fn spawned_workers_see_every_packet_once() {
+    let veth = match create("afcp") {
+        Some(veth) => veth,
+        None => return,
+    };
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let workers = {
+        let seen = seen.clone();
+        capture(&veth)
+            .spawn(move |ring, packet| {
+                seen.lock().unwrap().push((ring, number(packet.payload())));
+            })
+            .unwrap()
+    };
+    for n in 0..20 {
+        veth.inject(&frame(n)).unwrap();
+    }
+    assert!(wait_until(|| seen.lock().unwrap().len() >= 20));
+    //dropping the workers stops and joins them
+    drop(workers);
+
+    let mut seen = seen.lock().unwrap().clone();
+    //round robin fanout spreads the packets over both rings
+    assert!(seen.iter().any(|&(ring, _)| ring == 0));
+    assert!(seen.iter().any(|&(ring, _)| ring == 1));
+    seen.sort_by_key(|&(_, n)| n);
+    let numbers: Vec<u32> = seen.iter().map(|&(_, n)| n).collect();
+    assert_eq!(numbers, (0..20).collect::<Vec<_>>());
+}
+
+#[test]
+fn statistics_are_summed_over_the_rings() {
+    let veth = match create("afcs") {
+        Some(veth) => veth,
+        None => return,
+    };
+    let mut capture = capture(&veth);
+    for n in 0..10 {
+        veth.inject(&frame(n)).unwrap();
+    }
+    //the kernel resets its counters on every read
+    let mut packets = 0;
+    assert!(wait_until(|| {
+        packets += capture.statistics().unwrap().packets;
+        packets >= 10
+    }));
+    assert_eq!(packets, 10);
+    assert_eq!(capture.statistics().unwrap().packets, 0);
+}
+
+#[test]
+fn dispatched_packets_reach_the_receiver_in_order() {
+    let veth = match create("afdp") {
+        Some(veth) => veth,
+        None => return,
+    };
+    let mut settings = veth.ring_settings();
+    settings.protocol = EtherType::Other(ETH_P_LOCAL);
+    let ring = Ring::new(settings).unwrap();
+    let (dispatcher, receiver) = dispatch::packets(ring, DispatchSettings::default()).unwrap();
+    for n in 0..5 {
+        veth.inject(&frame(n)).unwrap();
+    }
+    let numbers: Vec<u32> = (0..5)
+        .map(|_| {
+            let packet = receiver
+                .recv_timeout(Duration::from_secs(2))
+                .expect("timed out waiting for the injected frames");
+            number(&packet)
+        })
+        .collect();
+    assert_eq!(numbers, vec![0, 1, 2, 3, 4]);
+    assert_eq!(dispatcher.dropped(), 0);
+
+    //the ring comes back once the thread stopped, and the queue is closed
+    let ring = dispatcher.join().unwrap();
+    assert!(receiver.is_closed());
+    assert!(receiver.recv().is_none());
+    assert!(ring.get_shutdown_handle().unwrap().is_signaled());
+}
+
+#[test]
+fn adaptive_ring_keeps_receiving_across_a_rebuild() {
+    let veth = match create("afad") {
+        Some(veth) => veth,
+        None => return,
+    };
+    let mut settings = veth.ring_settings();
+    settings.protocol = EtherType::Other(ETH_P_LOCAL);
+    //asks for 50 ms once, then keeps it
+    let mut ring = AdaptiveRing::with_policy(settings, 10, |_, _| 50).unwrap();
+    ring.set_interval(Duration::from_millis(50));
+    let retunes = Arc::new(Mutex::new(Vec::new()));
+    {
+        let retunes = retunes.clone();
+        ring.on_retune(move |retune| retunes.lock().unwrap().push(*retune));
+    }
+    let shutdown = ring.shutdown_handle().unwrap();
+
+    //a steady trickle of frames until the ring is shut down; the ones in the block the old
+    //ring was filling when it is closed may be lost
+    let veth = Arc::new(veth);
+    let sender = {
+        let veth = veth.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let mut n = 0;
+            while !shutdown.is_signaled() && Instant::now() < deadline {
+                veth.inject(&frame(n)).unwrap();
+                n += 1;
+                thread::sleep(Duration::from_millis(2));
+            }
+            shutdown.signal();
+        })
+    };
+
+    let mut after_rebuild = Vec::new();
+    while after_rebuild.len() < 20 {
+        let mut block = ring
+            .recv_block()
+            .expect("nothing received after the rebuild");
+        if ring_rebuilt(&retunes) {
+            after_rebuild.extend(block.get_raw_packets().iter().map(|p| number(p.payload())));
+        }
+        block.mark_as_consumed();
+    }
+    shutdown.signal();
+    sender.join().unwrap();
+
+    assert_eq!(ring.rebuilds(), 1);
+    assert_eq!(ring.tov(), 50);
+    let retunes: Vec<Retune> = retunes.lock().unwrap().clone();
+    assert_eq!(retunes.len(), 1);
+    assert_eq!((retunes[0].from, retunes[0].to), (10, 50));
+    //nothing is handed out twice
+    let mut unique = after_rebuild.clone();
+    unique.sort_unstable();
+    unique.dedup();
+    assert_eq!(unique.len(), after_rebuild.len());
+}
+
+fn ring_rebuilt(retunes: &Mutex<Vec<Retune>>) -> bool {
+    !retunes.lock().unwrap().is_empty()
+}
+
+#[test]
+fn monitor_raises_and_clears_alerts() {
+    let veth = match create("afmn") {
+        Some(veth) => veth,
+        None => return,
+    };
+    let mut settings = veth.ring_settings();
+    settings.protocol = EtherType::Other(ETH_P_LOCAL);
+    let mut ring = Ring::new(settings).unwrap();
+    let settings = MonitorSettings {
+        interval: Duration::from_millis(50),
+        drop_rate: Some(0.01),
+        saturation: Some(0.5),
+    };
+    let (monitor, alerts) = StatsMonitor::channel(vec![ring.clone()], settings).unwrap();
+    let next_alert = || -> Alert {
+        alerts
+            .recv_timeout(Duration::from_secs(2))
+            .expect("no alert raised")
+    };
+    //let the monitor take its first sample before the ring overflows
+    thread::sleep(Duration::from_millis(100));
+
+    //nothing reads the ring, so it fills up and the kernel drops the rest
+    for n in 0..3000 {
+        veth.inject(&frame(n)).unwrap();
+    }
+    let mut raised = [next_alert(), next_alert()];
+    raised.sort_by_key(|alert| alert.kind == AlertKind::Saturation);
+    assert_eq!(raised[0].kind, AlertKind::DropRate);
+    assert!(raised[0].raised && raised[0].stats.drops > 0);
+    assert_eq!(raised[1].kind, AlertKind::Saturation);
+    assert!(raised[1].raised);
+
+    //once the ring is drained and nothing more is dropped both go back to normal
+    ring.discard_ready();
+    let cleared = [next_alert(), next_alert()];
+    assert!(cleared.iter().all(|alert| !alert.raised && alert.ring == 0));
+    assert_ne!(cleared[0].kind, cleared[1].kind);
+    assert_eq!(monitor.stop().len(), 1);
+}
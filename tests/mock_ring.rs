@@ -0,0 +1,132 @@
+//!`PacketSource` consumers run over `MockRing`, and over a veth pair where one can be created
+
+use std::thread;
+use std::time::Duration;
+
+use af_packet::rx::{Ring, SeqGap};
+use af_packet::source::PacketSource;
+use af_packet::test_util::VethPair;
+use af_packet::testing::MockRing;
+
+//ethertype reserved for local experiments, so that no other traffic on the veth matches
+const ETH_P_LOCAL: u16 = 0x88b5;
+
+fn frame(n: u32) -> Vec<u8> {
+    let mut frame = vec![0xff; 6];
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+    frame.extend_from_slice(&ETH_P_LOCAL.to_be_bytes());
+    frame.extend_from_slice(&n.to_be_bytes());
+    frame.resize(60, 0);
+    frame
+}
+
+fn number(payload: &[u8]) -> Option<u32> {
+    if payload.get(12..14)? != ETH_P_LOCAL.to_be_bytes() {
+        return None;
+    }
+    let n = payload.get(14..18)?;
+    Some(u32::from_be_bytes([n[0], n[1], n[2], n[3]]))
+}
+
+#[test]
+fn for_each_packet_visits_every_frame_in_order() {
+    let mut ring = MockRing::new((0..100).map(frame));
+    let mut seen = Vec::new();
+    ring.for_each_packet(|packet| seen.push(number(packet.payload()).unwrap()))
+        .unwrap();
+    assert_eq!(seen, (0..100).collect::<Vec<_>>());
+    assert_eq!(ring.queued(), 0);
+    //every block was handed back
+    assert_eq!(ring.blocks_consumed(), ring.blocks_returned());
+    assert!(ring.next_block().unwrap().is_none());
+}
+
+#[test]
+fn spreads_frames_over_many_blocks() {
+    let mut ring = MockRing::new((0..10).map(frame));
+    ring.set_max_packets_per_block(3);
+    let mut seen = Vec::new();
+    ring.for_each_packet(|packet| seen.push(number(packet.payload()).unwrap()))
+        .unwrap();
+    assert_eq!(seen, (0..10).collect::<Vec<_>>());
+    assert_eq!(ring.blocks_returned(), 4);
+    assert_eq!(ring.blocks_consumed(), 4);
+
+    let mut ring = MockRing::new((0..10).map(frame));
+    ring.set_max_packets_per_block(3);
+    let mut blocks = Vec::new();
+    while let Some(block) = ring.next_block().unwrap() {
+        blocks.push((block.seq_num(), block.packet_count()));
+    }
+    assert_eq!(blocks, vec![(1, 3), (2, 3), (3, 3), (4, 1)]);
+    //nothing was marked as consumed this time
+    assert_eq!(ring.blocks_consumed(), 0);
+}
+
+#[test]
+fn skipped_blocks_show_up_as_sequence_gaps() {
+    let mut ring = MockRing::new((0..6).map(frame));
+    ring.set_max_packets_per_block(2);
+    let mut expected = None;
+    let mut gaps = Vec::new();
+    let mut blocks_lost = 0;
+    let mut packets = 0;
+    loop {
+        let mut block = match ring.next_block().unwrap() {
+            Some(block) => block,
+            None => break,
+        };
+        let seq = block.seq_num();
+        if let Some(expected) = expected {
+            if seq != expected {
+                let gap = SeqGap {
+                    expected,
+                    received: seq,
+                };
+                blocks_lost += gap.lost();
+                gaps.push(gap);
+            }
+        }
+        expected = Some(seq + 1);
+        packets += block.get_raw_packets().len();
+        block.mark_as_consumed();
+        drop(block);
+        if seq == 1 {
+            ring.skip_blocks(5);
+        }
+    }
+    assert_eq!(
+        gaps,
+        vec![SeqGap {
+            expected: 2,
+            received: 7
+        }]
+    );
+    assert_eq!(blocks_lost, 5);
+    //skipping blocks only moves the sequence numbers, no queued frame is lost
+    assert_eq!(packets, 6);
+}
+
+#[test]
+fn drains_a_live_ring() {
+    let veth = match VethPair::create("afmock") {
+        Ok(veth) => veth,
+        Err(e) => {
+            eprintln!("skipping, cannot create a veth pair: {}", e);
+            return;
+        }
+    };
+    let mut ring = Ring::new(veth.ring_settings()).unwrap();
+    for n in 0..20 {
+        veth.inject(&frame(n)).unwrap();
+    }
+    //longer than the block timeout of the ring
+    thread::sleep(Duration::from_millis(50));
+
+    let mut seen = Vec::new();
+    ring.drain()
+        .unwrap()
+        .for_each_packet(|packet| seen.extend(number(packet.payload())))
+        .unwrap();
+    assert_eq!(seen, (0..20).collect::<Vec<_>>());
+}
@@ -0,0 +1,131 @@
+//!Which of `Error::MissingPrivilege` and `Error::PermissionDenied` a refusal is reported as,
+//!checked on threads that dropped capabilities; capabilities are per thread, so the rest of
+//!the process keeps them
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::thread;
+
+use libc::{c_int, c_long, syscall, SYS_capget, SYS_capset, AF_PACKET, SOCK_RAW};
+
+use af_packet::socket::{has_capability, EtherType, Socket};
+use af_packet::{offline, Error, Privilege};
+
+const CAP_DAC_OVERRIDE: u32 = 1;
+const CAP_DAC_READ_SEARCH: u32 = 2;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+//clears `caps` from the effective set of the calling thread
+fn drop_capabilities(caps: &[u32]) {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    let ret: c_long = unsafe { syscall(SYS_capget, &mut header, data.as_mut_ptr()) };
+    assert_eq!(ret, 0, "capget: {}", std::io::Error::last_os_error());
+    for &cap in caps {
+        data[(cap / 32) as usize].effective &= !(1 << (cap % 32));
+    }
+    let ret: c_long = unsafe { syscall(SYS_capset, &mut header, data.as_ptr()) };
+    assert_eq!(ret, 0, "capset: {}", std::io::Error::last_os_error());
+}
+
+//runs `f` on a thread without `caps`
+fn without<T: Send + 'static, F: FnOnce() -> T + Send + 'static>(caps: &'static [u32], f: F) -> T {
+    thread::spawn(move || {
+        drop_capabilities(caps);
+        f()
+    })
+    .join()
+    .unwrap()
+}
+
+fn open_socket() -> af_packet::Result<Socket> {
+    Socket::open_any(AF_PACKET, SOCK_RAW, EtherType::All)
+}
+
+#[test]
+fn socket_without_net_raw_is_a_missing_privilege() {
+    let err = without(&[CAP_NET_RAW], || {
+        assert_eq!(has_capability(CAP_NET_RAW), Some(false));
+        open_socket().unwrap_err()
+    });
+    match err {
+        Error::MissingPrivilege {
+            context: "socket",
+            privilege: Privilege::NetRaw,
+            ..
+        } => {}
+        err => panic!("unexpected error: {:?}", err),
+    }
+    assert!(err.to_string().contains("cap_net_raw"), "{}", err);
+}
+
+#[test]
+fn forced_receive_buffer_without_net_admin_is_a_missing_privilege() {
+    if has_capability(CAP_NET_RAW) != Some(true) {
+        eprintln!("skipping, CAP_NET_RAW is needed to open the socket");
+        return;
+    }
+    let err = without(&[CAP_NET_ADMIN], || {
+        let mut socket = open_socket().unwrap();
+        //the plain option is capped by the kernel instead of refused
+        socket.set_recv_buffer(1 << 20, false).unwrap();
+        socket.set_recv_buffer(1 << 20, true).unwrap_err()
+    });
+    match err {
+        Error::MissingPrivilege {
+            context: "setsockopt(SO_RCVBUFFORCE)",
+            privilege: Privilege::NetAdmin,
+            ..
+        } => {}
+        err => panic!("unexpected error: {:?}", err),
+    }
+    assert!(err.to_string().contains("cap_net_admin"), "{}", err);
+}
+
+#[test]
+fn unreadable_capture_file_is_permission_denied() {
+    let path = std::env::temp_dir().join(format!("af_packet-denied-{}.pcap", std::process::id()));
+    fs::write(&path, b"").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o000)).unwrap();
+    let err = {
+        let path = path.clone();
+        //root reads the file anyway unless it gives up overriding permissions
+        without(&[CAP_DAC_OVERRIDE, CAP_DAC_READ_SEARCH], move || {
+            offline::Ring::open(&path)
+                .err()
+                .expect("opened an unreadable file")
+        })
+    };
+    fs::remove_file(&path).unwrap();
+    assert!(
+        matches!(
+            err,
+            Error::PermissionDenied {
+                context: "open",
+                ..
+            }
+        ),
+        "{:?}",
+        err
+    );
+}
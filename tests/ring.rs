@@ -0,0 +1,185 @@
+//!Ownership of a ring's socket and mapping, and its socket options, on a veth pair; skipped
+//!where one cannot be created
+
+use std::fs;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use libc::EINVAL;
+
+use af_packet::group::{FanoutSet, RingGroup};
+use af_packet::rx::{FanoutMethod, Ring, RingSettings};
+use af_packet::socket::EtherType;
+use af_packet::test_util::VethPair;
+use af_packet::Error;
+
+//ethertype reserved for local experiments, so that the ring sees nothing but the test frames
+const ETH_P_LOCAL: u16 = 0x88b5;
+
+//descriptors and mappings are counted per process, so the tests take turns
+static SERIAL: Mutex<()> = Mutex::new(());
+
+fn serial() -> MutexGuard<'static, ()> {
+    SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+//open descriptors and mappings of the process
+fn resources() -> (usize, usize) {
+    let fds = fs::read_dir("/proc/self/fd").unwrap().count();
+    let maps = fs::read_to_string("/proc/self/maps")
+        .unwrap()
+        .lines()
+        .count();
+    (fds, maps)
+}
+
+fn frame(n: u32) -> Vec<u8> {
+    let mut frame = vec![0xff; 6];
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+    frame.extend_from_slice(&ETH_P_LOCAL.to_be_bytes());
+    frame.extend_from_slice(&n.to_be_bytes());
+    frame.resize(60, 0);
+    frame
+}
+
+//settings for a ring on the `rx` end of a new veth pair that only captures the test frames
+fn open(prefix: &str) -> Option<(VethPair, RingSettings)> {
+    let veth = match VethPair::create(prefix) {
+        Ok(veth) => veth,
+        Err(e) => {
+            eprintln!("skipping, cannot create a veth pair: {}", e);
+            return None;
+        }
+    };
+    let mut settings = veth.ring_settings();
+    settings.protocol = EtherType::Other(ETH_P_LOCAL);
+    Some((veth, settings))
+}
+
+//injects `count` frames and checks that `ring` receives them
+fn assert_receives(veth: &VethPair, ring: &mut Ring, count: u32) {
+    for n in 0..count {
+        veth.inject(&frame(n)).unwrap();
+    }
+    let mut seen = 0;
+    while seen < count {
+        let mut block = ring
+            .get_block_timeout(Duration::from_secs(2))
+            .expect("timed out waiting for the injected frames");
+        seen += block.packet_count();
+        block.mark_as_consumed();
+    }
+    assert_eq!(seen, count);
+}
+
+#[test]
+fn rings_of_a_group_outlive_it() {
+    let _serial = serial();
+    let (veth, settings) = match open("afgr") {
+        Some(opened) => opened,
+        None => return,
+    };
+    let before = resources();
+    let mut group = RingGroup::with_settings(settings, 2).unwrap();
+    let mut ring = group.rings()[0].clone();
+    drop(group);
+    assert_receives(&veth, &mut ring, 3);
+    drop(ring);
+    assert_eq!(resources(), before);
+}
+
+#[test]
+fn failed_ring_new_releases_the_socket_and_mapping() {
+    let _serial = serial();
+    let (_veth, settings) = match open("afnw") {
+        Some(opened) => opened,
+        None => return,
+    };
+    let _first = Ring::new(settings.clone()).unwrap();
+    let before = resources();
+    //joining the fanout group with another method fails after the ring is mapped and bound
+    let clash = RingSettings {
+        fanout_method: FanoutMethod::Lb,
+        ..settings
+    };
+    assert!(Ring::new(clash).is_err());
+    assert_eq!(resources(), before);
+}
+
+#[test]
+fn close_leaves_clones_working() {
+    let _serial = serial();
+    let (veth, settings) = match open("afcl") {
+        Some(opened) => opened,
+        None => return,
+    };
+    let before = resources();
+    let ring = Ring::new(settings).unwrap();
+    let mut clone = ring.clone();
+    ring.close().unwrap();
+    assert_receives(&veth, &mut clone, 3);
+    let report = clone.close().unwrap();
+    assert_eq!(report.packets, 3);
+    assert_eq!(resources(), before);
+}
+
+#[test]
+fn subscription_rings_outlive_the_subscription() {
+    let _serial = serial();
+    let (veth, settings) = match open("afsb") {
+        Some(opened) => opened,
+        None => return,
+    };
+    //the set holds the eventfd of its shutdown handle
+    let set = FanoutSet::new(settings).unwrap();
+    let before = resources();
+    let subscription = set.subscribe().unwrap();
+    let mut ring = subscription.ring().clone();
+    drop(subscription);
+    assert!(set.is_empty());
+    assert_receives(&veth, &mut ring, 3);
+    drop(ring);
+    assert_eq!(resources(), before);
+}
+
+#[test]
+fn incoming_cpu_is_stored_and_checked() {
+    let _serial = serial();
+    let (_veth, settings) = match open("afcpu") {
+        Some(opened) => opened,
+        None => return,
+    };
+    let mut ring = Ring::new(settings).unwrap();
+    assert_eq!(ring.incoming_cpu().unwrap(), None);
+    ring.socket.set_incoming_cpu(0).unwrap();
+    assert_eq!(ring.incoming_cpu().unwrap(), Some(0));
+
+    //an index the kernel cannot even be given is an invalid argument
+    let err = ring.socket.set_incoming_cpu(usize::MAX).unwrap_err();
+    assert!(matches!(err, Error::Os { .. }), "{:?}", err);
+    assert_eq!(err.io_error().and_then(|e| e.raw_os_error()), Some(EINVAL));
+    assert_eq!(ring.incoming_cpu().unwrap(), Some(0));
+}
+
+#[test]
+fn receive_buffer_is_set_past_rmem_max() {
+    let _serial = serial();
+    let (_veth, settings) = match open("afbuf") {
+        Some(opened) => opened,
+        None => return,
+    };
+    let rmem_max: usize = fs::read_to_string("/proc/sys/net/core/rmem_max")
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    let size = (rmem_max * 2) as u32;
+    let ring = Ring::new(RingSettings {
+        rcvbuf: Some(size),
+        rcvbuf_force: true,
+        ..settings
+    })
+    .unwrap();
+    //the kernel doubles the size to make room for its bookkeeping
+    assert_eq!(ring.socket.recv_buffer().unwrap(), size as usize * 2);
+}